@@ -0,0 +1,81 @@
+#[macro_use]
+extern crate nostalgia_derive;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use nostalgia::{Key, Record, Storage};
+use serde::{Deserialize, Serialize};
+
+const RECORDS: u32 = 10_000;
+
+#[derive(Storable, Serialize, Deserialize, Clone)]
+#[key = "id"]
+struct Trip {
+    id: u32,
+    origin: String,
+    destination: String,
+    distance: f64,
+}
+
+fn trips(count: u32) -> Vec<Trip> {
+    (0..count)
+        .map(|id| Trip {
+            id,
+            origin: format!("Stop {}", id % 100),
+            destination: format!("Stop {}", (id * 7) % 100),
+            distance: f64::from(id) * 0.25,
+        })
+        .collect()
+}
+
+fn storage() -> Storage {
+    let mut storage = Storage::temporary().expect("Could not open db storage");
+    storage
+        .save_batch(trips(RECORDS))
+        .expect("Could not save trips");
+    storage
+}
+
+fn writes(c: &mut Criterion) {
+    let mut storage = storage();
+
+    c.bench_function("save", |b| {
+        let trip = trips(1).remove(0);
+        b.iter(|| storage.save(black_box(&trip)).unwrap())
+    });
+
+    c.bench_function("save_batch 1000", |b| {
+        b.iter_batched(
+            || trips(1_000),
+            |trips| storage.save_batch(trips).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn reads(c: &mut Criterion) {
+    let mut storage = storage();
+
+    c.bench_function("get", |b| {
+        b.iter(|| storage.get::<Trip, _>(black_box(RECORDS / 2)).unwrap())
+    });
+
+    c.bench_function("query full scan", |b| {
+        b.iter(|| storage.query::<Trip>().unwrap().count())
+    });
+
+    c.bench_function("query nth", |b| {
+        b.iter(|| {
+            storage
+                .query::<Trip>()
+                .unwrap()
+                .nth(black_box(RECORDS as usize - 1))
+        })
+    });
+
+    c.bench_function("keys full scan", |b| {
+        b.iter(|| storage.keys::<Trip>().unwrap().count())
+    });
+}
+
+criterion_group!(benches, writes, reads);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // The gRPC service is generated from its description, with a protoc that ships as a crate
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("Could not find protoc");
+        std::env::set_var("PROTOC", protoc);
+        // Clients connect through a channel of their own, the 2018 edition lacks what the
+        // generated `connect` needs
+        tonic_build::configure()
+            .build_transport(false)
+            .compile_protos(&["proto/nostalgia.proto"], &["proto"])
+            .expect("Could not compile protos");
+    }
+}
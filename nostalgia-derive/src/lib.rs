@@ -1,16 +1,66 @@
 use proc_macro2::TokenStream;
 use quote::quote;
 use std::collections::HashMap;
-use syn::{parse_macro_input, Data, DeriveInput, Meta::NameValue};
+use syn::{
+    parse_macro_input, Data, DeriveInput,
+    Meta::{List, NameValue},
+    NestedMeta,
+};
 
-#[proc_macro_derive(Storable, attributes(key, db_name))]
+#[proc_macro_derive(
+    Storable,
+    attributes(
+        key,
+        db_name,
+        db_flags,
+        write_flags,
+        storable,
+        belongs_to,
+        fulltext,
+        geo,
+        index,
+        unique,
+        timestamps,
+        validate
+    )
+)]
 pub fn storable_macro(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the input tokens into a syntax tree
     let input = parse_macro_input!(input as DeriveInput);
 
     let name = input.ident;
-    let name_str = name.to_string();
-    let key_definition = find_key_name_and_type(&input.attrs, &input.data);
+    let config = Config::parse(&input.attrs);
+    let config_errors = &config.errors;
+    let db_name = config
+        .get("db_name")
+        .map(syn::LitStr::value)
+        .unwrap_or_else(|| name.to_string());
+    let type_tag = config
+        .get("type_tag")
+        .map(syn::LitStr::value)
+        .unwrap_or_else(|| name.to_string());
+    let key_definition = find_key_name_and_type(&name, &config, &input.data);
+    let flags_definition = find_flags(&config);
+    let codec_definition = find_codec_hooks(&config);
+    let (relations, mut indexes) = find_relations(&name, &input.attrs, &input.data);
+    let fulltext_definition = find_fulltext(&config, &input.data, &mut indexes);
+    let geo_definition = find_geo(&config, &input.data, &mut indexes);
+    let unique_definition = find_field_indexes(&input.data, &mut indexes);
+    let index_definition = index_methods(&indexes);
+    let metadata_definition = find_metadata(&config);
+    let partition_definition = find_partition(&config);
+    let timestamps_definition = find_timestamps(&name, &config, &input.data);
+    let validate_definition = find_validations(&config, &input.data);
+    let fields_definition =
+        find_query_fields(&name, &input.vis, &config, &input.attrs, &input.data);
+    let builder_definition = find_builder(&name, &input.vis, &config, &input.data);
+    let renamed_definition = find_renamed_fields(&input.attrs, &input.data);
+    let after_load_definition = find_after_load(&config);
+    let skipped_definition = find_skipped_fields(&name, &input.data);
+    let schema_definition = match config.get("versions") {
+        Some(_) => TokenStream::new(),
+        None => find_schema_version(&input.data),
+    };
 
     // Build the output, possibly using quasi-quotation
     let expanded = quote! {
@@ -18,58 +68,1542 @@ pub fn storable_macro(input: proc_macro::TokenStream) -> proc_macro::TokenStream
             #key_definition
 
             fn db_name() -> &'static str {
-                #name_str
+                #db_name
+            }
+
+            fn type_tag() -> &'static str {
+                #type_tag
             }
+
+            #schema_definition
+
+            #flags_definition
+
+            #codec_definition
+
+            #index_definition
+
+            #unique_definition
+
+            #renamed_definition
+
+            #fulltext_definition
+
+            #geo_definition
+
+            #metadata_definition
+
+            #partition_definition
+
+            #timestamps_definition
+
+            #validate_definition
+
+            #after_load_definition
         }
+
+        #skipped_definition
+
+        #relations
+
+        #fields_definition
+
+        #builder_definition
+
+        #config_errors
     };
 
     // Hand the output tokens back to the compiler
     proc_macro::TokenStream::from(expanded)
 }
 
-fn find_attr_keypairs(attrs: &Vec<syn::Attribute>) -> HashMap<String, syn::LitStr> {
-    let mut result = HashMap::new();
-    for attr in attrs {
-        match attr.parse_meta().unwrap() {
-            NameValue(nm) => match (nm.path.get_ident(), nm.lit) {
-                (Some(ident), syn::Lit::Str(s)) => {
-                    result.insert(ident.to_string(), s);
+// The settings `#[storable(...)]` takes, as `name = "value"` pairs and as bare flags
+const STORABLE_VALUES: &[&str] = &[
+    "key",
+    "key_encoding",
+    "db_name",
+    "type_tag",
+    "db_flags",
+    "write_flags",
+    "codec",
+    "serialize_with",
+    "deserialize_with",
+    "fulltext",
+    "geo",
+    "partition",
+    "validate_with",
+    "after_load",
+    "versions",
+];
+const STORABLE_FLAGS: &[&str] = &["rkyv", "metadata", "fields", "timestamps", "builder"];
+
+// Everything the type's attributes configure, read in a single pass.  The namespaced
+// `#[storable(key = "id", db_name = "people")]` form and the older standalone attributes like
+// `#[key = "id"]` and `#[timestamps]` can be mixed.  Attributes that belong to other crates
+// (serde, repr, docs, ...) are skipped.
+struct Config {
+    values: HashMap<String, syn::LitStr>,
+    flags: Vec<String>,
+    // Compile errors for settings inside #[storable(...)] that aren't understood
+    errors: TokenStream,
+}
+
+impl Config {
+    fn parse(attrs: &[syn::Attribute]) -> Config {
+        let mut config = Config {
+            values: HashMap::new(),
+            flags: vec![],
+            errors: TokenStream::new(),
+        };
+
+        for attr in attrs {
+            match attr.parse_meta() {
+                Ok(NameValue(nm)) => config.insert_value(nm),
+                Ok(syn::Meta::Path(path)) if path.is_ident("timestamps") => {
+                    config.flags.push("timestamps".to_string())
                 }
-                _ => unimplemented!(),
-            },
-            _ => unimplemented!(),
+                Ok(List(list)) if list.path.is_ident("storable") => {
+                    for nested in list.nested {
+                        config.insert_storable(nested);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        config
+    }
+
+    fn insert_value(&mut self, nm: syn::MetaNameValue) {
+        if let (Some(ident), syn::Lit::Str(s)) = (nm.path.get_ident(), nm.lit) {
+            self.values.insert(ident.to_string(), s);
+        }
+    }
+
+    fn insert_storable(&mut self, nested: NestedMeta) {
+        let known = match &nested {
+            NestedMeta::Meta(NameValue(nm)) => STORABLE_VALUES
+                .iter()
+                .any(|name| nm.path.is_ident(name) && matches!(nm.lit, syn::Lit::Str(_))),
+            NestedMeta::Meta(syn::Meta::Path(path)) => {
+                STORABLE_FLAGS.iter().any(|name| path.is_ident(name))
+            }
+            _ => false,
+        };
+        if !known {
+            self.errors.extend(
+                syn::Error::new_spanned(&nested, "unknown storable setting").to_compile_error(),
+            );
+            return;
+        }
+
+        match nested {
+            NestedMeta::Meta(NameValue(nm)) => self.insert_value(nm),
+            NestedMeta::Meta(syn::Meta::Path(path)) => {
+                if let Some(ident) = path.get_ident() {
+                    self.flags.push(ident.to_string());
+                }
+            }
+            _ => (),
         }
     }
 
+    fn get(&self, name: &str) -> Option<&syn::LitStr> {
+        self.values.get(name)
+    }
+
+    // Whether a bare flag such as `fields` was set
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|set| set == flag)
+    }
+}
+
+// Build to_binary / from_binary overrides from #[storable(serialize_with = "path")] and
+// #[storable(deserialize_with = "path")], or pick another codec with #[storable(codec = "json")].
+// #[storable(rkyv)] is the same as #[storable(codec = "rkyv")]
+fn find_codec_hooks(config: &Config) -> TokenStream {
+    let mut result = TokenStream::new();
+
+    if let Some(versions) = config.get("versions") {
+        return find_versions(config, versions);
+    }
+
+    let codec = match (config.get("codec"), config.has_flag("rkyv")) {
+        (Some(codec), _) => Some((codec.value(), codec.span())),
+        (None, true) => Some(("rkyv".to_string(), proc_macro2::Span::call_site())),
+        (None, false) => None,
+    };
+    let module = match codec {
+        Some((codec, _)) if codec == "bincode" => None,
+        Some((codec, _)) if codec == "json" => Some(quote!(::nostalgia::json)),
+        Some((codec, _)) if codec == "rkyv" => Some(quote!(::nostalgia::archive)),
+        Some((codec, span)) => {
+            return syn::Error::new(
+                span,
+                format!("unknown codec `{}`, expected bincode, json or rkyv", codec),
+            )
+            .to_compile_error()
+        }
+        None => None,
+    };
+    if let Some(module) = module {
+        return quote! {
+            fn to_binary(&self) -> ::std::result::Result<Vec<u8>, ::nostalgia::bincode::Error> {
+                #module::to_bytes(self)
+            }
+
+            fn from_binary(bytes: &[u8]) -> ::std::result::Result<Self, ::nostalgia::bincode::Error> {
+                #module::from_bytes(bytes)
+            }
+        };
+    }
+
+    if let Some(path) = config.get("serialize_with") {
+        let path = match path.parse::<syn::Path>() {
+            Ok(path) => path,
+            Err(e) => return e.to_compile_error(),
+        };
+        result.extend(quote! {
+            fn to_binary(&self) -> ::std::result::Result<Vec<u8>, ::nostalgia::bincode::Error> {
+                #path(self)
+            }
+        });
+    }
+
+    if let Some(path) = config.get("deserialize_with") {
+        let path = match path.parse::<syn::Path>() {
+            Ok(path) => path,
+            Err(e) => return e.to_compile_error(),
+        };
+        result.extend(quote! {
+            fn from_binary(bytes: &[u8]) -> ::std::result::Result<Self, ::nostalgia::bincode::Error> {
+                #path(bytes)
+            }
+        });
+    }
+
     result
 }
 
-fn find_key_name_and_type(attrs: &Vec<syn::Attribute>, data: &syn::Data) -> TokenStream {
-    let key_values = find_attr_keypairs(attrs);
-    let fuck = find_attr_keypairs(attrs);
+// Build to_binary / from_binary / is_outdated from #[storable(versions = "v1::Place, v2::Place")],
+// which lists the earlier layouts of the type, oldest first.  Values start with a tag byte, the
+// position of their layout in the list counting from 1, and the type itself comes after the last
+// one.  Older values are read as their own layout and upgraded with `From` one version at a time
+fn find_versions(config: &Config, versions: &syn::LitStr) -> TokenStream {
+    for setting in &["codec", "serialize_with", "deserialize_with"] {
+        if let Some(value) = config.get(setting) {
+            let message = format!("`{}` can't be combined with `versions`", setting);
+            return syn::Error::new(value.span(), message).to_compile_error();
+        }
+    }
+    if config.has_flag("rkyv") {
+        return syn::Error::new(versions.span(), "`rkyv` can't be combined with `versions`")
+            .to_compile_error();
+    }
+
+    let mut paths = vec![];
+    for path in versions.value().split(',') {
+        match syn::parse_str::<syn::Path>(path.trim()) {
+            Ok(path) => paths.push(path),
+            Err(_) => {
+                let message = format!("`{}` is not a type", path.trim());
+                return syn::Error::new(versions.span(), message).to_compile_error();
+            }
+        }
+    }
+    if paths.len() >= usize::from(u8::MAX) {
+        return syn::Error::new(versions.span(), "too many versions").to_compile_error();
+    }
+
+    let current = paths.len() as u8 + 1;
+    let arms = paths.iter().enumerate().map(|(position, path)| {
+        let tag = position as u8 + 1;
+        let upgrades = paths[position + 1..].iter().map(|later| {
+            quote! { let record: #later = ::std::convert::From::from(record); }
+        });
+        quote! {
+            Some((&#tag, rest)) => {
+                let record: #path = ::nostalgia::bincode::deserialize(rest)?;
+                #(#upgrades)*
+                Ok(::std::convert::From::from(record))
+            }
+        }
+    });
+
+    quote! {
+        fn to_binary(&self) -> ::std::result::Result<Vec<u8>, ::nostalgia::bincode::Error> {
+            let mut bytes = vec![#current];
+            ::nostalgia::bincode::serialize_into(&mut bytes, self)?;
+            Ok(bytes)
+        }
+
+        fn from_binary(bytes: &[u8]) -> ::std::result::Result<Self, ::nostalgia::bincode::Error> {
+            match bytes.split_first() {
+                Some((&#current, rest)) => ::nostalgia::bincode::deserialize(rest),
+                #(#arms)*
+                Some((tag, _)) => Err(Box::new(::nostalgia::bincode::ErrorKind::Custom(
+                    format!("unknown version {}", tag),
+                ))),
+                None => Err(Box::new(::nostalgia::bincode::ErrorKind::Custom(
+                    "missing version".to_string(),
+                ))),
+            }
+        }
+
+        fn is_outdated(bytes: &[u8]) -> bool {
+            bytes.first() != Some(&#current)
+        }
+    }
+}
+
+// Build db_flags / write_flags overrides from attributes like
+// #[db_flags = "INTEGER_KEY | DUP_SORT"]
+fn find_flags(config: &Config) -> TokenStream {
+    let mut result = TokenStream::new();
+
+    for (attr, flags_type, method) in &[
+        (
+            "db_flags",
+            quote!(::nostalgia::DatabaseFlags),
+            quote!(db_flags),
+        ),
+        (
+            "write_flags",
+            quote!(::nostalgia::WriteFlags),
+            quote!(write_flags),
+        ),
+    ] {
+        if let Some(value) = config.get(attr) {
+            let flags = value
+                .value()
+                .split('|')
+                .map(|flag| syn::Ident::new(flag.trim(), value.span()))
+                .collect::<Vec<_>>();
+
+            result.extend(quote! {
+                fn #method() -> #flags_type {
+                    #flags_type::empty() #(| #flags_type::#flags)*
+                }
+            });
+        }
+    }
+
+    result
+}
+
+// A secondary index the derived Record maintains.  `entries` is an expression evaluating to an
+// iterator of IndexEntry values for `self`
+struct IndexDefinition {
+    name: String,
+    entries: TokenStream,
+}
+
+// Build the indexes / index_entries methods for every index the type declares
+fn index_methods(indexes: &[IndexDefinition]) -> TokenStream {
+    if indexes.is_empty() {
+        return TokenStream::new();
+    }
+
+    let names = indexes.iter().map(|index| &index.name);
+    let entries = indexes.iter().map(|index| &index.entries);
+
+    quote! {
+        fn indexes() -> &'static [&'static str] {
+            &[#(#names),*]
+        }
+
+        fn index_entries(
+            &self,
+        ) -> ::std::result::Result<Vec<::nostalgia::IndexEntry>, ::nostalgia::StorageError> {
+            let mut entries = Vec::new();
+            #(entries.extend(#entries);)*
+            Ok(entries)
+        }
+    }
+}
+
+// Register an index for every field marked #[index] or #[unique], and build unique_indexes from
+// the #[unique] ones.  Fields that are an Option are only indexed when they hold a value, and
+// fields that are a collection like a Vec under each of their elements.  String values go
+// through the normalizers set with #[index(normalize = "lowercase, trim")], which
+// index_normalizers hands to queries.  Indexes with #[index(include = "id, name")] store those
+// fields next to each key and are listed in covering_indexes.  Indexes are named after their
+// field unless #[index(name = "by_created")] names them, and are read newest first when they
+// are declared with #[index(order = "desc")]
+fn find_field_indexes(data: &syn::Data, indexes: &mut Vec<IndexDefinition>) -> TokenStream {
+    let fields = match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => return TokenStream::new(),
+    };
+
+    let mut unique = vec![];
+    let mut normalized = vec![];
+    let mut covering = vec![];
+    let mut descending = vec![];
+    let mut renamed = vec![];
+    for field in fields {
+        let ident = match &field.ident {
+            Some(ident) => ident,
+            None => continue,
+        };
+        let attrs: Vec<&syn::Attribute> = field
+            .attrs
+            .iter()
+            .filter(|a| a.path.is_ident("index") || a.path.is_ident("unique"))
+            .collect();
+        if attrs.is_empty() {
+            continue;
+        }
+        let is_unique = attrs.iter().any(|a| a.path.is_ident("unique"));
+        let mut options = IndexOptions::default();
+        for attr in attrs {
+            if let Err(e) = parse_index_options(attr, &mut options) {
+                return e.to_compile_error();
+            }
+        }
+        let IndexOptions {
+            normalizers,
+            included,
+            name,
+            descending: is_descending,
+        } = options;
+
+        let name = name.unwrap_or_else(|| ident.to_string());
+        if *ident != name {
+            let field = ident.to_string();
+            renamed.push(quote!((#name, #field)));
+        }
+        let entry = if normalizers.is_empty() {
+            quote!(::nostalgia::IndexEntry::from_value(#name, value))
+        } else {
+            quote!(::nostalgia::IndexEntry::from_value(
+                #name,
+                &::nostalgia::Normalizer::normalize(
+                    &[#(::nostalgia::Normalizer::#normalizers),*],
+                    ::std::convert::AsRef::<str>::as_ref(value),
+                ),
+            ))
+        };
+        let entries = if is_collection(&field.ty) {
+            quote!(self.#ident.iter().map(|value| #entry))
+        } else if is_option(&field.ty) {
+            quote!(self.#ident.as_ref().map(|value| #entry))
+        } else {
+            quote!(Some({
+                let value = &self.#ident;
+                #entry
+            }))
+        };
+        let entries = if included.is_empty() {
+            entries
+        } else {
+            covering.push(name.clone());
+            quote! {{
+                let projection = ::nostalgia::bincode::serialize(&(#(&self.#included,)*))?;
+                #entries.map(move |entry| entry.with_projection(projection.clone()))
+            }}
+        };
+        indexes.push(IndexDefinition {
+            name: name.clone(),
+            entries,
+        });
+        if !normalizers.is_empty() {
+            normalized.push(quote! {
+                #name => &[#(::nostalgia::Normalizer::#normalizers),*],
+            });
+        }
+        if is_descending {
+            descending.push(name.clone());
+        }
+        if is_unique {
+            unique.push(name);
+        }
+    }
+
+    let mut methods = TokenStream::new();
+    if !unique.is_empty() {
+        methods.extend(quote! {
+            fn unique_indexes() -> &'static [&'static str] {
+                &[#(#unique),*]
+            }
+        });
+    }
+    if !covering.is_empty() {
+        methods.extend(quote! {
+            fn covering_indexes() -> &'static [&'static str] {
+                &[#(#covering),*]
+            }
+        });
+    }
+    if !descending.is_empty() {
+        methods.extend(quote! {
+            fn descending_indexes() -> &'static [&'static str] {
+                &[#(#descending),*]
+            }
+        });
+    }
+    if !renamed.is_empty() {
+        methods.extend(quote! {
+            fn renamed_indexes() -> &'static [(&'static str, &'static str)] {
+                &[#(#renamed),*]
+            }
+        });
+    }
+    if !normalized.is_empty() {
+        methods.extend(quote! {
+            fn index_normalizers(index: &str) -> &'static [::nostalgia::Normalizer] {
+                match index {
+                    #(#normalized)*
+                    _ => &[],
+                }
+            }
+        });
+    }
+    methods
+}
+
+// The options of a field's #[index] and #[unique] attributes
+#[derive(Default)]
+struct IndexOptions {
+    normalizers: Vec<syn::Ident>,
+    included: Vec<syn::Ident>,
+    name: Option<String>,
+    descending: bool,
+}
+
+// Read the options out of #[index(normalize = "lowercase, nfc, trim", include = "id, name",
+// name = "by_name", order = "desc")], where a bare #[index] has none of them
+fn parse_index_options(attr: &syn::Attribute, options: &mut IndexOptions) -> syn::Result<()> {
+    let invalid = || {
+        syn::Error::new_spanned(
+            attr,
+            "expected #[index], #[unique] or #[index(normalize = \"lowercase\", include = \"id\", name = \"by_id\", order = \"desc\")]",
+        )
+    };
+
+    let list = match attr.parse_meta()? {
+        syn::Meta::Path(_) => return Ok(()),
+        List(list) => list,
+        _ => return Err(invalid()),
+    };
+
+    for nested in list.nested {
+        let (option, names) = match nested {
+            NestedMeta::Meta(NameValue(nm)) => match nm.lit {
+                syn::Lit::Str(s) => (nm.path, s),
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        if option.is_ident("include") {
+            for name in names.value().split(',').map(str::trim) {
+                let field = syn::parse_str::<syn::Ident>(name).map_err(|_| {
+                    syn::Error::new(names.span(), format!("`{}` is not a field name", name))
+                })?;
+                options
+                    .included
+                    .push(syn::Ident::new(&field.to_string(), names.span()));
+            }
+            continue;
+        }
+        if option.is_ident("name") {
+            options.name = Some(names.value());
+            continue;
+        }
+        if option.is_ident("order") {
+            options.descending = match names.value().as_str() {
+                "asc" => false,
+                "desc" => true,
+                order => {
+                    return Err(syn::Error::new(
+                        names.span(),
+                        format!("unknown order `{}`, expected \"asc\" or \"desc\"", order),
+                    ))
+                }
+            };
+            continue;
+        }
+        if !option.is_ident("normalize") {
+            return Err(invalid());
+        }
+        for name in names.value().split(',').map(str::trim) {
+            let variant = match name {
+                "lowercase" => "Lowercase",
+                "nfc" => "Nfc",
+                "trim" => "Trim",
+                _ => {
+                    return Err(syn::Error::new(
+                        names.span(),
+                        format!("unknown normalizer `{}`", name),
+                    ))
+                }
+            };
+            options
+                .normalizers
+                .push(syn::Ident::new(variant, names.span()));
+        }
+    }
+    Ok(())
+}
+
+// Build BelongsTo impls from attributes like #[belongs_to(Mayor, key = "mayor_id")], along with
+// the index on the foreign key each of them needs
+fn find_relations(
+    name: &syn::Ident,
+    attrs: &[syn::Attribute],
+    data: &syn::Data,
+) -> (TokenStream, Vec<IndexDefinition>) {
+    let mut impls = TokenStream::new();
+    let mut indexes = vec![];
+
+    for attr in attrs.iter().filter(|a| a.path.is_ident("belongs_to")) {
+        let (parent, foreign_key) = match parse_belongs_to(attr) {
+            Ok(relation) => relation,
+            Err(e) => {
+                impls.extend(e.to_compile_error());
+                continue;
+            }
+        };
+
+        let field = match find_field(data, &foreign_key.value()) {
+            Some(field) => field,
+            None => {
+                impls.extend(
+                    syn::Error::new(foreign_key.span(), "This field does not exist on the type")
+                        .to_compile_error(),
+                );
+                continue;
+            }
+        };
+
+        let field_name = &field.ident;
+        let (parent_key, clear_parent_key) = if is_option(&field.ty) {
+            (
+                quote!(self.#field_name.clone().map(::std::convert::From::from)),
+                quote! {
+                    fn clear_parent_key(&mut self) -> bool {
+                        self.#field_name = None;
+                        true
+                    }
+                },
+            )
+        } else {
+            (
+                quote!(Some(::std::convert::From::from(self.#field_name.clone()))),
+                TokenStream::new(),
+            )
+        };
+
+        impls.extend(quote! {
+            impl ::nostalgia::BelongsTo<#parent> for #name {
+                fn foreign_key() -> &'static str {
+                    #foreign_key
+                }
+
+                fn parent_key(&self) -> Option<<#parent as ::nostalgia::Record>::Key> {
+                    #parent_key
+                }
+
+                #clear_parent_key
+            }
+        });
+
+        indexes.push(IndexDefinition {
+            name: foreign_key.value(),
+            entries: quote! {
+                <Self as ::nostalgia::BelongsTo<#parent>>::parent_key(self)
+                    .map(|key| ::nostalgia::IndexEntry::from_value(#foreign_key, &key))
+            },
+        });
+    }
+
+    (impls, indexes)
+}
+
+// Build fulltext_fields from #[fulltext = "name, bio"] and register the full-text index
+fn find_fulltext(
+    config: &Config,
+    data: &syn::Data,
+    indexes: &mut Vec<IndexDefinition>,
+) -> TokenStream {
+    let fields = match config.get("fulltext") {
+        Some(fields) => fields,
+        None => return TokenStream::new(),
+    };
+
+    let mut idents = vec![];
+    for name in fields.value().split(',').map(str::trim) {
+        match find_field(data, name) {
+            Some(field) => idents.push(field.ident.clone()),
+            None => {
+                return syn::Error::new(
+                    fields.span(),
+                    format!("The field `{}` does not exist on the type", name),
+                )
+                .to_compile_error()
+            }
+        }
+    }
+
+    indexes.push(IndexDefinition {
+        name: "fulltext".to_string(),
+        entries: quote!(::nostalgia::fulltext::index_entries(
+            &self.fulltext_fields()
+        )),
+    });
+
+    quote! {
+        fn fulltext_fields(&self) -> Vec<&str> {
+            vec![#(::std::convert::AsRef::<str>::as_ref(&self.#idents)),*]
+        }
+    }
+}
+
+// Build geo_point from #[geo = "lat, lon"] and register the geospatial index
+fn find_geo(config: &Config, data: &syn::Data, indexes: &mut Vec<IndexDefinition>) -> TokenStream {
+    let fields = match config.get("geo") {
+        Some(fields) => fields,
+        None => return TokenStream::new(),
+    };
+
+    let names = fields.value();
+    let names: Vec<&str> = names.split(',').map(str::trim).collect();
+    if names.len() != 2 {
+        return syn::Error::new(
+            fields.span(),
+            "expected the latitude and longitude fields, as in #[geo = \"lat, lon\"]",
+        )
+        .to_compile_error();
+    }
+
+    let mut idents = vec![];
+    for name in names {
+        match find_field(data, name) {
+            Some(field) => idents.push(field.ident.clone()),
+            None => {
+                return syn::Error::new(
+                    fields.span(),
+                    format!("The field `{}` does not exist on the type", name),
+                )
+                .to_compile_error()
+            }
+        }
+    }
+    let (lat, lon) = (&idents[0], &idents[1]);
+
+    indexes.push(IndexDefinition {
+        name: "geo".to_string(),
+        entries: quote!(::nostalgia::geo::index_entries(self.geo_point())),
+    });
+
+    quote! {
+        fn geo_point(&self) -> Option<(f64, f64)> {
+            Some((
+                ::std::convert::Into::<f64>::into(self.#lat),
+                ::std::convert::Into::<f64>::into(self.#lon),
+            ))
+        }
+    }
+}
+
+fn parse_belongs_to(attr: &syn::Attribute) -> syn::Result<(syn::Path, syn::LitStr)> {
+    let invalid = || {
+        syn::Error::new_spanned(
+            attr,
+            "expected #[belongs_to(Parent, key = \"foreign_key_field\")]",
+        )
+    };
+
+    let list = match attr.parse_meta()? {
+        List(list) => list,
+        _ => return Err(invalid()),
+    };
+
+    let mut parent = None;
+    let mut foreign_key = None;
+    for nested in list.nested {
+        match nested {
+            NestedMeta::Meta(syn::Meta::Path(path)) => parent = Some(path),
+            NestedMeta::Meta(NameValue(nm)) if nm.path.is_ident("key") => {
+                if let syn::Lit::Str(s) = nm.lit {
+                    foreign_key = Some(s);
+                }
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    match (parent, foreign_key) {
+        (Some(parent), Some(foreign_key)) => Ok((parent, foreign_key)),
+        _ => Err(invalid()),
+    }
+}
+
+// Opts the record in to the metadata envelope with #[storable(metadata)]
+fn find_metadata(config: &Config) -> TokenStream {
+    if !config.has_flag("metadata") {
+        return TokenStream::new();
+    }
+
+    quote! {
+        fn has_metadata() -> bool {
+            true
+        }
+    }
+}
+
+// Routes the record into its own environment with #[storable(partition = "name")]
+fn find_partition(config: &Config) -> TokenStream {
+    match config.get("partition") {
+        Some(partition) => quote! {
+            fn partition() -> Option<&'static str> {
+                Some(#partition)
+            }
+        },
+        None => TokenStream::new(),
+    }
+}
+
+// Generates a before_save hook from #[storable(timestamps)] or #[timestamps] that fills in
+// `created_at` the first time a record is saved and bumps `updated_at` on every save.  Both fields
+// must have the same type.
+fn find_timestamps(name: &syn::Ident, config: &Config, data: &syn::Data) -> TokenStream {
+    if !config.has_flag("timestamps") {
+        return TokenStream::new();
+    }
+
+    for field in &["created_at", "updated_at"] {
+        if find_field(data, field).is_none() {
+            return syn::Error::new(
+                name.span(),
+                format!("#[timestamps] needs a `{}` field", field),
+            )
+            .to_compile_error();
+        }
+    }
+
+    quote! {
+        fn before_save(&mut self) {
+            let now = ::nostalgia::Timestamp::now();
+            if ::nostalgia::Timestamp::is_unset(&self.created_at) {
+                self.created_at = ::std::clone::Clone::clone(&now);
+            }
+            self.updated_at = now;
+        }
+
+        fn has_before_save() -> bool {
+            true
+        }
+    }
+}
+
+// Generates a validate method from field rules such as #[validate(length(min = 1, max = 80))] and
+// #[validate(range(min = 0))], plus an optional #[storable(validate_with = "path")] function
+fn find_validations(config: &Config, data: &syn::Data) -> TokenStream {
+    let mut checks = TokenStream::new();
+
+    if let Some(path) = config.get("validate_with") {
+        let path = match path.parse::<syn::Path>() {
+            Ok(path) => path,
+            Err(e) => return e.to_compile_error(),
+        };
+        checks.extend(quote! {
+            if let Err(mut found) = #path(self) {
+                errors.append(&mut found);
+            }
+        });
+    }
+
+    let fields = match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => fields.named.iter().collect(),
+        _ => vec![],
+    };
+
+    for field in fields {
+        let ident = match &field.ident {
+            Some(ident) => ident,
+            None => continue,
+        };
+        let ident_str = ident.to_string();
+
+        for attr in field.attrs.iter().filter(|a| a.path.is_ident("validate")) {
+            let rules = match attr.parse_meta() {
+                Ok(List(list)) => list.nested,
+                Ok(other) => {
+                    return syn::Error::new_spanned(other, "expected #[validate(rule(...))]")
+                        .to_compile_error()
+                }
+                Err(e) => return e.to_compile_error(),
+            };
+
+            for rule in rules {
+                let rule = match rule {
+                    NestedMeta::Meta(List(rule)) => rule,
+                    other => {
+                        return syn::Error::new_spanned(other, "expected length(...) or range(...)")
+                            .to_compile_error()
+                    }
+                };
+
+                let value = if rule.path.is_ident("length") {
+                    quote!((self.#ident.len() as u64))
+                } else if rule.path.is_ident("range") {
+                    quote!(self.#ident)
+                } else {
+                    return syn::Error::new_spanned(rule.path, "unknown validation rule")
+                        .to_compile_error();
+                };
+                let suffix = if rule.path.is_ident("length") {
+                    " long"
+                } else {
+                    ""
+                };
+
+                for bound in rule.nested {
+                    let (name, lit) = match bound {
+                        NestedMeta::Meta(NameValue(nm)) => (nm.path, nm.lit),
+                        other => {
+                            return syn::Error::new_spanned(other, "expected min = .. or max = ..")
+                                .to_compile_error()
+                        }
+                    };
+                    let (op, word) = if name.is_ident("min") {
+                        (quote!(<), "least")
+                    } else if name.is_ident("max") {
+                        (quote!(>), "most")
+                    } else {
+                        return syn::Error::new_spanned(name, "expected min or max")
+                            .to_compile_error();
+                    };
+                    let message = match &lit {
+                        syn::Lit::Int(i) => format!("must be at {} {}{}", word, i, suffix),
+                        syn::Lit::Float(f) => format!("must be at {} {}{}", word, f, suffix),
+                        other => {
+                            return syn::Error::new_spanned(other, "expected a number")
+                                .to_compile_error()
+                        }
+                    };
+
+                    checks.extend(quote! {
+                        if #value #op #lit {
+                            errors.push(::nostalgia::FieldError::new(#ident_str, #message));
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    if checks.is_empty() {
+        return TokenStream::new();
+    }
+
+    quote! {
+        fn validate(&self) -> ::std::result::Result<(), Vec<::nostalgia::FieldError>> {
+            let mut errors = vec![];
+            #checks
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        }
+    }
+}
+
+// Runs a #[storable(after_load = "path")] function on every record read back from storage
+fn find_after_load(config: &Config) -> TokenStream {
+    let path = match config.get("after_load") {
+        Some(path) => path,
+        None => return TokenStream::new(),
+    };
+    let path = match path.parse::<syn::Path>() {
+        Ok(path) => path,
+        Err(e) => return e.to_compile_error(),
+    };
+
+    quote! {
+        fn after_load(&mut self) {
+            #path(self)
+        }
+    }
+}
+
+// Whether a field is marked with #[storable(skip)]
+fn is_skipped(field: &syn::Field) -> syn::Result<bool> {
+    let attrs: Vec<_> = field
+        .attrs
+        .iter()
+        .filter(|a| a.path.is_ident("storable"))
+        .collect();
+    if let Some(attr) = attrs.iter().find(|attr| !is_skip_attr(attr)) {
+        return Err(syn::Error::new_spanned(
+            attr,
+            "expected #[storable(skip)] on a field",
+        ));
+    }
+
+    Ok(attrs.iter().any(|attr| is_skip_attr(attr)))
+}
+
+fn is_skip_attr(attr: &syn::Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(List(list)) => {
+            list.nested.len() == 1
+                && matches!(
+                    list.nested.first(),
+                    Some(NestedMeta::Meta(syn::Meta::Path(path))) if path.is_ident("skip")
+                )
+        }
+        _ => false,
+    }
+}
+
+// A hash of the names and types of the fields that are stored, in order, so a storage can tell
+// when a database was written with a different layout.  FNV-1a, which is stable across builds
+// unlike std's hasher
+fn find_schema_version(data: &syn::Data) -> TokenStream {
+    let fields = match data {
+        Data::Struct(data) => &data.fields,
+        _ => return TokenStream::new(),
+    };
+
+    let mut layout = String::new();
+    for (position, field) in fields.iter().enumerate() {
+        if let Ok(true) = is_skipped(field) {
+            continue;
+        }
+        let name = field
+            .ident
+            .as_ref()
+            .map_or_else(|| position.to_string(), ToString::to_string);
+        let ty = &field.ty;
+        layout.push_str(&format!("{}:{};", name, quote!(#ty)));
+    }
+
+    let version = layout.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    quote! {
+        fn schema_version() -> u64 {
+            #version
+        }
+    }
+}
+
+// Implements Serialize and Deserialize for types with #[storable(skip)] fields, which don't
+// derive them themselves.  The other fields go through private copies of the struct that serde
+// derives for, and skipped fields are filled in with `Default::default()` when the record is read
+fn find_skipped_fields(name: &syn::Ident, data: &syn::Data) -> TokenStream {
+    let fields = match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => return TokenStream::new(),
+    };
+
+    let mut stored = vec![];
+    let mut skipped = vec![];
+    for field in fields {
+        match is_skipped(field) {
+            Ok(true) => skipped.push(field),
+            Ok(false) => stored.push(field),
+            Err(e) => return e.to_compile_error(),
+        }
+    }
+    if skipped.is_empty() {
+        return TokenStream::new();
+    }
+
+    let idents: Vec<_> = stored.iter().map(|field| &field.ident).collect();
+    let types: Vec<_> = stored.iter().map(|field| &field.ty).collect();
+    let skipped = skipped.iter().map(|field| &field.ident);
+
+    quote! {
+        const _: () = {
+            #[derive(::nostalgia::serde::Serialize)]
+            #[serde(crate = "::nostalgia::serde")]
+            struct Stored<'a> {
+                #(#idents: &'a #types,)*
+            }
+
+            #[derive(::nostalgia::serde::Deserialize)]
+            #[serde(crate = "::nostalgia::serde")]
+            struct Loaded {
+                #(#idents: #types,)*
+            }
+
+            impl ::nostalgia::serde::Serialize for #name {
+                fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where
+                    S: ::nostalgia::serde::Serializer,
+                {
+                    ::nostalgia::serde::Serialize::serialize(
+                        &Stored { #(#idents: &self.#idents,)* },
+                        serializer,
+                    )
+                }
+            }
+
+            impl<'de> ::nostalgia::serde::Deserialize<'de> for #name {
+                fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+                where
+                    D: ::nostalgia::serde::Deserializer<'de>,
+                {
+                    let loaded: Loaded = ::nostalgia::serde::Deserialize::deserialize(deserializer)?;
+                    Ok(#name {
+                        #(#idents: loaded.#idents,)*
+                        #(#skipped: ::std::default::Default::default(),)*
+                    })
+                }
+            }
+        };
+    }
+}
+
+// Generates a `{Name}Fields` companion from #[storable(fields)], with one method per named field
+// returning a typed `::nostalgia::Field` for the query builder
+fn find_query_fields(
+    name: &syn::Ident,
+    vis: &syn::Visibility,
+    config: &Config,
+    attrs: &[syn::Attribute],
+    data: &syn::Data,
+) -> TokenStream {
+    if !config.has_flag("fields") {
+        return TokenStream::new();
+    }
+
+    let fields = match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => {
+            return syn::Error::new(name.span(), "#[storable(fields)] needs named fields")
+                .to_compile_error()
+        }
+    };
+
+    let companion = syn::Ident::new(&format!("{}Fields", name), name.span());
+    let rename_all = serde_rename(attrs, "rename_all");
+    let methods = fields.iter().filter_map(|field| {
+        let ident = field.ident.as_ref()?;
+        let serialized = serialized_name(field, rename_all.as_deref())?;
+        let ty = &field.ty;
+        Some(quote! {
+            pub fn #ident(&self) -> ::nostalgia::Field<#name, #ty> {
+                ::nostalgia::Field::new(#serialized)
+            }
+        })
+    });
+
+    quote! {
+        #vis struct #companion;
+
+        impl #companion {
+            #(#methods)*
+        }
+
+        impl #name {
+            /// Typed handles for building `QueryBuilder` filters on this record's fields
+            #vis fn fields() -> #companion {
+                #companion
+            }
+        }
+    }
+}
+
+// Generates a `{Name}Builder` from #[storable(builder)], with a setter per stored field and a
+// `save` that fills in a generated key when an integer key wasn't set.  Unset optional fields are
+// `None`, and unset timestamps of #[timestamps] records are the time of saving
+fn find_builder(
+    name: &syn::Ident,
+    vis: &syn::Visibility,
+    config: &Config,
+    data: &syn::Data,
+) -> TokenStream {
+    if !config.has_flag("builder") {
+        return TokenStream::new();
+    }
+
+    let fields = match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => {
+            return syn::Error::new(name.span(), "#[storable(builder)] needs named fields")
+                .to_compile_error()
+        }
+    };
+
+    let builder = syn::Ident::new(&format!("{}Builder", name), name.span());
+    let key = config.get("key").map(syn::LitStr::value);
+    let timestamps = config.has_flag("timestamps");
+    let (mut slots, mut setters, mut required, mut values) = (vec![], vec![], vec![], vec![]);
+    for field in fields {
+        let (ident, ty) = match &field.ident {
+            Some(ident) => (ident, &field.ty),
+            None => continue,
+        };
+        match is_skipped(field) {
+            Ok(true) => {
+                values.push(quote! { #ident: ::std::default::Default::default() });
+                continue;
+            }
+            Ok(false) => (),
+            Err(e) => return e.to_compile_error(),
+        }
+
+        slots.push(quote! { #ident: ::std::option::Option<#ty> });
+        // Optional fields are set to the value they hold, so `.party("...")` sets `Some`
+        let (param, value) = match option_inner(ty) {
+            Some(inner) => (
+                setter_param(inner),
+                quote! { ::std::option::Option::Some(#ident.into()) },
+            ),
+            None => (setter_param(ty), quote! { #ident.into() }),
+        };
+        setters.push(quote! {
+            pub fn #ident(mut self, #ident: #param) -> Self {
+                self.#ident = ::std::option::Option::Some(#value);
+                self
+            }
+        });
+
+        let is_key = key.as_deref() == Some(&*ident.to_string());
+        let unset = if is_option(ty) {
+            quote! { ::std::option::Option::None }
+        } else if is_key && is_integer(ty) {
+            quote! { tx.next_key::<#name, #ty>()? }
+        } else if timestamps && (ident == "created_at" || ident == "updated_at") {
+            quote! { ::nostalgia::Timestamp::now() }
+        } else {
+            required.push(ident);
+            quote! { unreachable!() }
+        };
+        values.push(quote! {
+            #ident: match self.#ident {
+                ::std::option::Option::Some(value) => value,
+                ::std::option::Option::None => #unset,
+            }
+        });
+    }
+    let required_names = required.iter().map(|ident| ident.to_string());
+
+    quote! {
+        #[derive(Default)]
+        #vis struct #builder {
+            #(#slots,)*
+        }
+
+        impl #builder {
+            #(#setters)*
+
+            /// Validates and saves the record in a transaction of its own, see `save_in`
+            pub fn save(
+                self,
+                storage: &mut ::nostalgia::Storage,
+            ) -> ::std::result::Result<#name, ::nostalgia::StorageError> {
+                storage.transaction(|tx| self.save_in(tx))
+            }
+
+            /// Validates and saves the record as part of `tx`, generating its key when it wasn't
+            /// set, and returns the record.  Fails validation when a required field wasn't set
+            pub fn save_in(
+                self,
+                tx: &mut ::nostalgia::Transaction,
+            ) -> ::std::result::Result<#name, ::nostalgia::StorageError> {
+                let mut missing = vec![];
+                #(
+                    if self.#required.is_none() {
+                        missing.push(::nostalgia::FieldError::new(#required_names, "is required"));
+                    }
+                )*
+                if !missing.is_empty() {
+                    return Err(::nostalgia::StorageError::Validation(missing));
+                }
+
+                let record = #name {
+                    #(#values,)*
+                };
+                tx.save(&record)?;
+                Ok(record)
+            }
+        }
+
+        impl #name {
+            /// A builder that sets fields one at a time and saves the record, see `save`
+            #vis fn builder() -> #builder {
+                ::std::default::Default::default()
+            }
+        }
+    }
+}
+
+// The serialized name set with #[serde(rename = "...")] or #[serde(rename_all = "...")], taking
+// the `serialize` one when the two directions differ
+fn serde_rename(attrs: &[syn::Attribute], setting: &str) -> Option<String> {
+    let metas = attrs
+        .iter()
+        .filter(|a| a.path.is_ident("serde"))
+        .filter_map(|a| a.parse_meta().ok());
+    for meta in metas {
+        let nested = match meta {
+            List(list) => list.nested,
+            _ => continue,
+        };
+        for item in nested {
+            match item {
+                NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident(setting) => {
+                    if let syn::Lit::Str(name) = nv.lit {
+                        return Some(name.value());
+                    }
+                }
+                NestedMeta::Meta(List(list)) if list.path.is_ident(setting) => {
+                    for direction in list.nested {
+                        if let NestedMeta::Meta(syn::Meta::NameValue(nv)) = direction {
+                            if let (true, syn::Lit::Str(name)) =
+                                (nv.path.is_ident("serialize"), nv.lit)
+                            {
+                                return Some(name.value());
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+// Applies a serde `rename_all` rule to a snake_case field name the way serde does
+fn apply_rename_rule(rule: &str, field: &str) -> String {
+    let pascal = || {
+        field
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                    None => String::new(),
+                }
+            })
+            .collect::<String>()
+    };
+    match rule {
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => field.to_ascii_uppercase(),
+        "PascalCase" => pascal(),
+        "camelCase" => {
+            let pascal = pascal();
+            let mut chars = pascal.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+                None => pascal,
+            }
+        }
+        "kebab-case" => field.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => field.replace('_', "-").to_ascii_uppercase(),
+        _ => field.to_string(),
+    }
+}
+
+// The name a named field is serialized under
+fn serialized_name(field: &syn::Field, rename_all: Option<&str>) -> Option<String> {
+    let ident = field.ident.as_ref()?.to_string();
+    let ident = ident.trim_start_matches("r#");
+    Some(match (serde_rename(&field.attrs, "rename"), rename_all) {
+        (Some(name), _) => name,
+        (None, Some(rule)) => apply_rename_rule(rule, ident),
+        (None, None) => ident.to_string(),
+    })
+}
+
+// List the fields serde serializes under another name, so field filters can match the
+// serialized record
+fn find_renamed_fields(attrs: &[syn::Attribute], data: &syn::Data) -> TokenStream {
+    let fields = match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => return TokenStream::new(),
+    };
+
+    let rename_all = serde_rename(attrs, "rename_all");
+    let renamed: Vec<TokenStream> = fields
+        .iter()
+        .filter_map(|field| {
+            let ident = field.ident.as_ref()?.to_string();
+            let ident = ident.trim_start_matches("r#");
+            let serialized = serialized_name(field, rename_all.as_deref())?;
+            (ident != serialized).then(|| quote!((#ident, #serialized)))
+        })
+        .collect();
+    if renamed.is_empty() {
+        return TokenStream::new();
+    }
+
+    quote! {
+        fn renamed_fields() -> &'static [(&'static str, &'static str)] {
+            &[#(#renamed),*]
+        }
+    }
+}
+
+fn find_field<'a>(data: &'a syn::Data, name: &str) -> Option<&'a syn::Field> {
+    match data {
+        Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => fields
+            .named
+            .iter()
+            .find(|f| f.ident.as_ref().map(|i| i == name).unwrap_or(false)),
+        _ => None,
+    }
+}
+
+fn is_option(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident == "Option")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Setters take anything that converts into the field, except for integers, which are taken as
+// they are so literals like `.id(10)` infer their type
+fn setter_param(ty: &syn::Type) -> TokenStream {
+    if is_integer(ty) {
+        quote! { #ty }
+    } else {
+        quote! { impl ::std::convert::Into<#ty> }
+    }
+}
+
+// The type an `Option` holds
+fn option_inner(ty: &syn::Type) -> Option<&syn::Type> {
+    let segment = match ty {
+        syn::Type::Path(type_path) => type_path.path.segments.last()?,
+        _ => return None,
+    };
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if segment.ident == "Option" => {
+            match args.args.first()? {
+                syn::GenericArgument::Type(inner) => Some(inner),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Whether a field is a primitive integer, which keys can be generated for
+fn is_integer(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(type_path) => type_path.path.get_ident().is_some_and(|ident| {
+            [
+                "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128",
+                "isize",
+            ]
+            .contains(&&*ident.to_string())
+        }),
+        _ => false,
+    }
+}
+
+// Whether a field holds a collection whose elements are indexed one by one
+fn is_collection(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .map(|segment| {
+                ["Vec", "VecDeque", "HashSet", "BTreeSet"].contains(&&*segment.ident.to_string())
+            })
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn find_key_name_and_type(name: &syn::Ident, config: &Config, data: &syn::Data) -> TokenStream {
     match *data {
         Data::Struct(ref data) => match data.fields {
             syn::Fields::Named(ref fields) => {
-                if let Some(key_field) = find_key_name_in_struct(fields, key_values) {
+                match find_key_transform(fields) {
+                    Ok(Some((prop, with))) => {
+                        return quote! {
+                            type Key = Key<Vec<u8>>;
+
+                            fn key(&self) -> Self::Key {
+                                Key::from(#with(&self.#prop))
+                            }
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => return e.to_compile_error(),
+                }
+
+                let key = match config.get("key") {
+                    Some(key) => key,
+                    None => {
+                        return syn::Error::new(
+                            name.span(),
+                            "expected #[storable(key = \"field\")] or #[key = \"field\"]",
+                        )
+                        .to_compile_error()
+                    }
+                };
+
+                if let Some(key_field) = find_key_name_in_struct(fields, key) {
                     match (key_field.ident.as_ref(), key_field.ty.clone()) {
                         (Some(ident), syn::Type::Path(type_path)) => {
                             let prop = ident;
-                            let prop_type = type_path.path.get_ident().unwrap();
+                            let prop_type = type_path;
+
+                            // #[storable(key_encoding = "varint")] trades ordered scans for
+                            // shorter integer keys
+                            match config.get("key_encoding") {
+                                Some(encoding) if encoding.value() == "varint" => quote! {
+                                    type Key = ::nostalgia::Varint<#prop_type>;
 
-                            quote! {
-                                type Key = Key<#prop_type>;
+                                    fn key(&self) -> Self::Key {
+                                        ::nostalgia::Varint::from(self.#prop)
+                                    }
+                                },
+                                Some(encoding) if encoding.value() != "fixed" => syn::Error::new(
+                                    encoding.span(),
+                                    "expected key_encoding = \"fixed\" or \"varint\"",
+                                )
+                                .to_compile_error(),
+                                _ => quote! {
+                                    type Key = Key<#prop_type>;
 
-                                fn key(&self) -> Self::Key {
-                                    Key::from(self.#prop)
-                                }
+                                    fn key(&self) -> Self::Key {
+                                        Key::from(self.#prop)
+                                    }
+                                },
                             }
                         }
                         _ => unimplemented!(),
                     }
                 } else {
-                    let id = &fuck["key"];
-
-                    return syn::Error::new(id.span(), "This field does not exist on the type")
+                    return syn::Error::new(key.span(), "This field does not exist on the type")
                         .to_compile_error();
                 }
             }
@@ -79,17 +1613,50 @@ fn find_key_name_and_type(attrs: &Vec<syn::Attribute>, data: &syn::Data) -> Toke
     }
 }
 
+// Find a key field marked with #[key(with = "path::to_bytes")].  The function is handed a
+// reference to the field and returns the bytes the record is stored under
+fn find_key_transform(
+    fields: &syn::FieldsNamed,
+) -> Result<Option<(&syn::Ident, syn::Path)>, syn::Error> {
+    let (field, attr) = match fields.named.iter().find_map(|field| {
+        let attr = field.attrs.iter().find(|attr| attr.path.is_ident("key"))?;
+        Some((field, attr))
+    }) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let with = match attr.parse_meta()? {
+        List(list) => list.nested.into_iter().find_map(|nested| match nested {
+            NestedMeta::Meta(NameValue(nm)) if nm.path.is_ident("with") => match nm.lit {
+                syn::Lit::Str(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }),
+        _ => None,
+    };
+
+    match (with, field.ident.as_ref()) {
+        (Some(with), Some(ident)) => Ok(Some((ident, with.parse()?))),
+        _ => Err(syn::Error::new_spanned(
+            attr,
+            "expected #[key(with = \"path::to_bytes\")]",
+        )),
+    }
+}
+
 // Find the key field
 // Iterate over each of the fields in the struct and look for one named the same as
 // the argument passed to the key attr
-fn find_key_name_in_struct(
-    target_fields: &syn::FieldsNamed,
-    config: HashMap<String, syn::LitStr>,
-) -> Option<&syn::Field> {
+fn find_key_name_in_struct<'a>(
+    target_fields: &'a syn::FieldsNamed,
+    key: &syn::LitStr,
+) -> Option<&'a syn::Field> {
     target_fields.named.iter().find(|f| {
         let name = &f.ident;
         if let Some(n) = name {
-            if n.to_string() == config["key"].value() {
+            if n.to_string() == key.value() {
                 return true;
             }
         }
@@ -0,0 +1,52 @@
+/* C bindings for nostalgia's raw databases, see nostalgia-ffi/src/lib.rs */
+
+#ifndef NOSTALGIA_H
+#define NOSTALGIA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define NOSTALGIA_OK 0
+#define NOSTALGIA_NOT_FOUND 1
+#define NOSTALGIA_ERROR -1
+
+typedef struct NostalgiaStorage NostalgiaStorage;
+typedef struct NostalgiaScan NostalgiaScan;
+
+/* Bytes owned by the caller, freed with nostalgia_bytes_free */
+typedef struct NostalgiaBytes {
+    uint8_t *data;
+    size_t len;
+} NostalgiaBytes;
+
+int32_t nostalgia_open(const char *path, NostalgiaStorage **out);
+void nostalgia_close(NostalgiaStorage *storage);
+
+int32_t nostalgia_put(NostalgiaStorage *storage, const char *db,
+                      const uint8_t *key, size_t key_len,
+                      const uint8_t *value, size_t value_len);
+int32_t nostalgia_get(NostalgiaStorage *storage, const char *db,
+                      const uint8_t *key, size_t key_len, NostalgiaBytes *out);
+int32_t nostalgia_delete(NostalgiaStorage *storage, const char *db,
+                         const uint8_t *key, size_t key_len);
+
+int32_t nostalgia_scan(NostalgiaStorage *storage, const char *db,
+                       const uint8_t *start, size_t start_len, size_t limit,
+                       NostalgiaScan **out);
+int32_t nostalgia_scan_next(NostalgiaScan *scan, NostalgiaBytes *key, NostalgiaBytes *value);
+void nostalgia_scan_free(NostalgiaScan *scan);
+
+void nostalgia_bytes_free(NostalgiaBytes bytes);
+
+/* The last error on the calling thread, valid until the next call that fails on it */
+const char *nostalgia_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* NOSTALGIA_H */
@@ -0,0 +1,350 @@
+//! A C ABI over nostalgia's raw databases, so apps written in Swift, or in Kotlin through JNI, can
+//! read and write the databases they share with a Rust core.  `include/nostalgia.h` declares it.
+//!
+//! Keys and values are plain bytes, stored the way `nostalgia::RawDb` stores them, so records saved
+//! from Rust read back as their serialized form.  Every call but the ones that free something
+//! returns `NOSTALGIA_OK`, `NOSTALGIA_NOT_FOUND` or `NOSTALGIA_ERROR`; after an error,
+//! `nostalgia_last_error` describes it.  Bytes handed out belong to the caller, who frees them with
+//! `nostalgia_bytes_free`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+use nostalgia::Storage;
+
+/// The call succeeded
+pub const NOSTALGIA_OK: i32 = 0;
+/// There is no value under the key, or no entries left in a scan
+pub const NOSTALGIA_NOT_FOUND: i32 = 1;
+/// The call failed, see `nostalgia_last_error`
+pub const NOSTALGIA_ERROR: i32 = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An open storage
+pub struct NostalgiaStorage(Storage);
+
+/// Entries read by `nostalgia_scan`, handed out one at a time
+pub struct NostalgiaScan(std::vec::IntoIter<(Vec<u8>, Vec<u8>)>);
+
+/// Bytes owned by the caller
+#[repr(C)]
+pub struct NostalgiaBytes {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl NostalgiaBytes {
+    fn new(bytes: Vec<u8>) -> NostalgiaBytes {
+        let len = bytes.len();
+        let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        NostalgiaBytes { data, len }
+    }
+}
+
+// Keeps the error for `nostalgia_last_error` and turns the result into a status
+fn status(result: Result<i32, String>) -> i32 {
+    match result {
+        Ok(status) => status,
+        Err(reason) => {
+            let reason = CString::new(reason.replace('\0', ""))
+                .expect("Nul bytes were removed from the error");
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(reason));
+            NOSTALGIA_ERROR
+        }
+    }
+}
+
+unsafe fn text<'a>(text: *const c_char) -> Result<&'a str, String> {
+    if text.is_null() {
+        return Err("a string argument was null".to_string());
+    }
+    CStr::from_ptr(text).to_str().map_err(|e| e.to_string())
+}
+
+unsafe fn slice<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    match len {
+        0 => &[],
+        _ => std::slice::from_raw_parts(data, len),
+    }
+}
+
+unsafe fn opened<'a>(storage: *mut NostalgiaStorage) -> Result<&'a mut Storage, String> {
+    storage
+        .as_mut()
+        .map(|storage| &mut storage.0)
+        .ok_or_else(|| "the storage was null".to_string())
+}
+
+/// Opens the storage at `path`, creating it when it doesn't exist, and writes it to `out`
+///
+/// # Safety
+/// `path` must be a nul-terminated string and `out` must point to writable memory
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_open(
+    path: *const c_char,
+    out: *mut *mut NostalgiaStorage,
+) -> i32 {
+    status((|| {
+        let storage = Storage::new(text(path)?).map_err(|e| e.to_string())?;
+        *out = Box::into_raw(Box::new(NostalgiaStorage(storage)));
+        Ok(NOSTALGIA_OK)
+    })())
+}
+
+/// Closes a storage opened with `nostalgia_open`
+///
+/// # Safety
+/// `storage` must come from `nostalgia_open`, or be null, and can't be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_close(storage: *mut NostalgiaStorage) {
+    if !storage.is_null() {
+        drop(Box::from_raw(storage));
+    }
+}
+
+/// Stores `value` under `key` in the database `db`, replacing whatever was there
+///
+/// # Safety
+/// `storage` must be open, `db` a nul-terminated string, and `key` and `value` must point to at
+/// least `key_len` and `value_len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_put(
+    storage: *mut NostalgiaStorage,
+    db: *const c_char,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> i32 {
+    status((|| {
+        let raw = opened(storage)?.raw(text(db)?).map_err(|e| e.to_string())?;
+        raw.put_bytes(slice(key, key_len), slice(value, value_len))
+            .map_err(|e| e.to_string())?;
+        Ok(NOSTALGIA_OK)
+    })())
+}
+
+/// Copies the value stored under `key` in the database `db` to `out`
+///
+/// # Safety
+/// `storage` must be open, `db` a nul-terminated string, `key` must point to at least `key_len`
+/// bytes and `out` to writable memory
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_get(
+    storage: *mut NostalgiaStorage,
+    db: *const c_char,
+    key: *const u8,
+    key_len: usize,
+    out: *mut NostalgiaBytes,
+) -> i32 {
+    status((|| {
+        let raw = opened(storage)?.raw(text(db)?).map_err(|e| e.to_string())?;
+        match raw
+            .get_bytes(slice(key, key_len))
+            .map_err(|e| e.to_string())?
+        {
+            Some(value) => {
+                *out = NostalgiaBytes::new(value);
+                Ok(NOSTALGIA_OK)
+            }
+            None => Ok(NOSTALGIA_NOT_FOUND),
+        }
+    })())
+}
+
+/// Removes `key` from the database `db`
+///
+/// # Safety
+/// `storage` must be open, `db` a nul-terminated string and `key` must point to at least
+/// `key_len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_delete(
+    storage: *mut NostalgiaStorage,
+    db: *const c_char,
+    key: *const u8,
+    key_len: usize,
+) -> i32 {
+    status((|| {
+        let raw = opened(storage)?.raw(text(db)?).map_err(|e| e.to_string())?;
+        let deleted = raw
+            .delete_bytes(slice(key, key_len))
+            .map_err(|e| e.to_string())?;
+        Ok(if deleted {
+            NOSTALGIA_OK
+        } else {
+            NOSTALGIA_NOT_FOUND
+        })
+    })())
+}
+
+/// Reads at most `limit` entries of the database `db` in key order, from the first key at or
+/// after `start` on, and writes a scan over them to `out`.  Scanning again from just after the
+/// last key reads the next page
+///
+/// # Safety
+/// `storage` must be open, `db` a nul-terminated string, `start` must point to at least
+/// `start_len` bytes and `out` to writable memory
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_scan(
+    storage: *mut NostalgiaStorage,
+    db: *const c_char,
+    start: *const u8,
+    start_len: usize,
+    limit: usize,
+    out: *mut *mut NostalgiaScan,
+) -> i32 {
+    status((|| {
+        let raw = opened(storage)?.raw(text(db)?).map_err(|e| e.to_string())?;
+        let entries = raw
+            .scan_from(slice(start, start_len), limit)
+            .map_err(|e| e.to_string())?;
+        *out = Box::into_raw(Box::new(NostalgiaScan(entries.into_iter())));
+        Ok(NOSTALGIA_OK)
+    })())
+}
+
+/// Writes the next entry of a scan to `key` and `value`, or returns `NOSTALGIA_NOT_FOUND` when
+/// there are none left
+///
+/// # Safety
+/// `scan` must come from `nostalgia_scan`, and `key` and `value` must point to writable memory
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_scan_next(
+    scan: *mut NostalgiaScan,
+    key: *mut NostalgiaBytes,
+    value: *mut NostalgiaBytes,
+) -> i32 {
+    status((|| {
+        let scan = scan
+            .as_mut()
+            .ok_or_else(|| "the scan was null".to_string())?;
+        match scan.0.next() {
+            Some((next_key, next_value)) => {
+                *key = NostalgiaBytes::new(next_key);
+                *value = NostalgiaBytes::new(next_value);
+                Ok(NOSTALGIA_OK)
+            }
+            None => Ok(NOSTALGIA_NOT_FOUND),
+        }
+    })())
+}
+
+/// Frees a scan along with the entries it didn't hand out
+///
+/// # Safety
+/// `scan` must come from `nostalgia_scan`, or be null, and can't be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_scan_free(scan: *mut NostalgiaScan) {
+    if !scan.is_null() {
+        drop(Box::from_raw(scan));
+    }
+}
+
+/// Frees bytes handed out by `nostalgia_get` or `nostalgia_scan_next`
+///
+/// # Safety
+/// `bytes` must have been handed out by this library and not freed before
+#[no_mangle]
+pub unsafe extern "C" fn nostalgia_bytes_free(bytes: NostalgiaBytes) {
+    if !bytes.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            bytes.data, bytes.len,
+        )));
+    }
+}
+
+/// Describes the last error on the calling thread, or returns null when there was none.  The
+/// string stays valid until the next call that fails on the same thread
+#[no_mangle]
+pub extern "C" fn nostalgia_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(reason) => reason.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty() -> NostalgiaBytes {
+        NostalgiaBytes {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn take(bytes: NostalgiaBytes) -> Vec<u8> {
+        let copy = slice(bytes.data, bytes.len).to_vec();
+        nostalgia_bytes_free(bytes);
+        copy
+    }
+
+    #[test]
+    fn test_that_bytes_round_trip_through_the_c_abi() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let path = CString::new(dir.path().to_str().unwrap()).unwrap();
+        let db = CString::new("Place").unwrap();
+
+        unsafe {
+            let mut storage = ptr::null_mut();
+            assert_eq!(NOSTALGIA_OK, nostalgia_open(path.as_ptr(), &mut storage));
+
+            for (key, value) in &[(b"b", b"Vienna"), (b"a", b"Berlin")] {
+                let status =
+                    nostalgia_put(storage, db.as_ptr(), key.as_ptr(), 1, value.as_ptr(), 6);
+                assert_eq!(NOSTALGIA_OK, status);
+            }
+
+            let mut value = empty();
+            assert_eq!(
+                NOSTALGIA_OK,
+                nostalgia_get(storage, db.as_ptr(), b"b".as_ptr(), 1, &mut value)
+            );
+            assert_eq!(b"Vienna".to_vec(), take(value));
+            let mut value = empty();
+            assert_eq!(
+                NOSTALGIA_NOT_FOUND,
+                nostalgia_get(storage, db.as_ptr(), b"c".as_ptr(), 1, &mut value)
+            );
+
+            let mut scan = ptr::null_mut();
+            assert_eq!(
+                NOSTALGIA_OK,
+                nostalgia_scan(storage, db.as_ptr(), ptr::null(), 0, 10, &mut scan)
+            );
+            let mut keys = vec![];
+            let (mut key, mut value) = (empty(), empty());
+            while nostalgia_scan_next(scan, &mut key, &mut value) == NOSTALGIA_OK {
+                keys.push(take(key));
+                take(value);
+                key = empty();
+                value = empty();
+            }
+            nostalgia_scan_free(scan);
+            assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], keys);
+
+            assert_eq!(
+                NOSTALGIA_OK,
+                nostalgia_delete(storage, db.as_ptr(), b"a".as_ptr(), 1)
+            );
+            assert_eq!(
+                NOSTALGIA_NOT_FOUND,
+                nostalgia_delete(storage, db.as_ptr(), b"a".as_ptr(), 1)
+            );
+
+            assert_eq!(
+                NOSTALGIA_ERROR,
+                nostalgia_put(storage, ptr::null(), ptr::null(), 0, ptr::null(), 0)
+            );
+            let reason = CStr::from_ptr(nostalgia_last_error());
+            assert_eq!("a string argument was null", reason.to_str().unwrap());
+            nostalgia_close(storage);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Python bindings for reading and writing nostalgia storages, built into the `nostalgia` module
+//! with maturin.
+//!
+//! Databases are read by name with keys and values as `bytes`, the way `nostalgia::RawDb` reads
+//! them.  `key_u32`, `key_u64` and `key_str` encode keys the way record types do, and `get_json`
+//! and `items(..., json=True)` decode records stored with `#[storable(codec = "json")]`, leaving
+//! out any metadata envelope.  Reading never creates a database that isn't there.
+//!
+//! ```python
+//! import nostalgia
+//!
+//! storage = nostalgia.open_storage("/var/lib/app/db")
+//! place = storage.get_json("Place", nostalgia.key_u32(1))
+//! for key, place in storage.items("Place", json=True):
+//!     print(key, place["name"])
+//! ```
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use nostalgia::{Key, Metadata, Storage};
+
+// How many entries `items` reads at a time
+const PAGE: usize = 1000;
+
+create_exception!(nostalgia, StorageError, PyException);
+
+fn error(e: nostalgia::StorageError) -> PyErr {
+    StorageError::new_err(e.to_string())
+}
+
+// Decodes a value stored with the JSON codec into Python objects
+fn from_json(py: Python<'_>, value: &[u8]) -> PyResult<PyObject> {
+    let record = match Metadata::read(value) {
+        Some((_, record)) => record,
+        None => value,
+    };
+    let json = py.import("json")?;
+    Ok(json
+        .call_method1("loads", (PyBytes::new(py, record),))?
+        .unbind())
+}
+
+/// An open storage
+#[pyclass(name = "Storage", unsendable)]
+struct PyStorage {
+    storage: Storage,
+}
+
+#[pymethods]
+impl PyStorage {
+    /// The names of the databases in the storage
+    fn databases(&self) -> PyResult<Vec<String>> {
+        let databases = self.storage.list_databases().map_err(error)?;
+        Ok(databases.into_iter().map(|db| db.name).collect())
+    }
+
+    /// The value stored under `key` in the database `db`, or `None`
+    fn get<'py>(
+        &mut self,
+        py: Python<'py>,
+        db: &str,
+        key: &[u8],
+    ) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let raw = match self.storage.raw_existing(db).map_err(error)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let value = raw.get_bytes(key).map_err(error)?;
+        Ok(value.map(|value| PyBytes::new(py, &value)))
+    }
+
+    /// The record stored under `key` in the database `db` decoded from JSON, or `None`
+    fn get_json(&mut self, py: Python<'_>, db: &str, key: &[u8]) -> PyResult<Option<PyObject>> {
+        match self.get(py, db, key)? {
+            Some(value) => Ok(Some(from_json(py, value.as_bytes())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `value` under `key` in the database `db`, creating the database if needed
+    fn put(&mut self, db: &str, key: &[u8], value: &[u8]) -> PyResult<()> {
+        let raw = self.storage.raw(db).map_err(error)?;
+        raw.put_bytes(key, value).map_err(error)
+    }
+
+    /// Removes `key` from the database `db`, returning whether it was there
+    fn delete(&mut self, db: &str, key: &[u8]) -> PyResult<bool> {
+        match self.storage.raw_existing(db).map_err(error)? {
+            Some(raw) => raw.delete_bytes(key).map_err(error),
+            None => Ok(false),
+        }
+    }
+
+    /// Iterates over the keys and values of the database `db` in key order, from `start` on.
+    /// Values are decoded from JSON when `json` is true
+    #[pyo3(signature = (db, start = Vec::new(), json = false))]
+    fn items(slf: Py<Self>, db: String, start: Vec<u8>, json: bool) -> Items {
+        Items {
+            storage: slf,
+            db,
+            json,
+            page: Vec::new().into_iter(),
+            next: Some(start),
+        }
+    }
+}
+
+/// The entries of a database, read a page at a time
+#[pyclass(unsendable)]
+struct Items {
+    storage: Py<PyStorage>,
+    db: String,
+    json: bool,
+    page: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    // Where the next page starts, `None` once the last page was read
+    next: Option<Vec<u8>>,
+}
+
+#[pymethods]
+impl Items {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<(PyObject, PyObject)>> {
+        if self.page.len() == 0 {
+            let start = match self.next.take() {
+                Some(start) => start,
+                None => return Ok(None),
+            };
+            let mut storage = self.storage.borrow_mut(py);
+            let entries = match storage.storage.raw_existing(&self.db).map_err(error)? {
+                Some(raw) => raw.scan_from(&start, PAGE).map_err(error)?,
+                None => vec![],
+            };
+            if let (Some((last, _)), PAGE) = (entries.last(), entries.len()) {
+                let mut next = last.clone();
+                next.push(0);
+                self.next = Some(next);
+            }
+            self.page = entries.into_iter();
+        }
+
+        match self.page.next() {
+            Some((key, value)) => {
+                let value = if self.json {
+                    from_json(py, &value)?
+                } else {
+                    PyBytes::new(py, &value).into_any().unbind()
+                };
+                Ok(Some((PyBytes::new(py, &key).into_any().unbind(), value)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Opens the storage at `path`.  Only storages that exist are opened, unless `create` is true
+#[pyfunction]
+#[pyo3(signature = (path, create = false))]
+fn open_storage(path: &str, create: bool) -> PyResult<PyStorage> {
+    let storage = if create {
+        Storage::new(path)
+    } else {
+        Storage::open_existing(path)
+    };
+    Ok(PyStorage {
+        storage: storage.map_err(error)?,
+    })
+}
+
+/// The key of a record keyed by a `u32`
+#[pyfunction]
+fn key_u32(py: Python<'_>, key: u32) -> Bound<'_, PyBytes> {
+    let key: Vec<u8> = Key::from(key).into();
+    PyBytes::new(py, &key)
+}
+
+/// The key of a record keyed by a `u64`
+#[pyfunction]
+fn key_u64(py: Python<'_>, key: u64) -> Bound<'_, PyBytes> {
+    let key: Vec<u8> = Key::from(key).into();
+    PyBytes::new(py, &key)
+}
+
+/// The key of a record keyed by a string
+#[pyfunction]
+fn key_str<'py>(py: Python<'py>, key: &str) -> Bound<'py, PyBytes> {
+    let key: Vec<u8> = Key::from(key).into();
+    PyBytes::new(py, &key)
+}
+
+#[pymodule]
+#[pyo3(name = "nostalgia")]
+fn nostalgia_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyStorage>()?;
+    m.add("StorageError", m.py().get_type::<StorageError>())?;
+    m.add_function(wrap_pyfunction!(open_storage, m)?)?;
+    m.add_function(wrap_pyfunction!(key_u32, m)?)?;
+    m.add_function(wrap_pyfunction!(key_u64, m)?)?;
+    m.add_function(wrap_pyfunction!(key_str, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_python_reads_what_rust_wrote() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let path = dir.path().to_str().unwrap();
+        {
+            let mut storage = Storage::new(path).expect("Could not open db storage");
+            let places = storage.raw("Place").unwrap();
+            for (id, name) in [(1u32, "Vienna"), (2, "Berlin")].iter() {
+                let key: Vec<u8> = Key::from(*id).into();
+                let value = format!(r#"{{"id":{},"name":"{}"}}"#, id, name);
+                places.put_bytes(&key, value.as_bytes()).unwrap();
+            }
+        }
+
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "nostalgia").unwrap();
+            nostalgia_py(&module).unwrap();
+            let locals = pyo3::types::PyDict::new(py);
+            locals.set_item("nostalgia", module).unwrap();
+            locals.set_item("path", path).unwrap();
+            py.run(
+                pyo3::ffi::c_str!(
+                    r#"
+storage = nostalgia.open_storage(path)
+assert "Place" in storage.databases()
+assert storage.get_json("Place", nostalgia.key_u32(1))["name"] == "Vienna"
+assert storage.get("Place", nostalgia.key_u32(3)) is None
+assert storage.get("Missing", b"key") is None
+assert [place["name"] for _, place in storage.items("Place", json=True)] == ["Vienna", "Berlin"]
+assert [key for key, _ in storage.items("Place", start=nostalgia.key_u32(2))] == [nostalgia.key_u32(2)]
+assert storage.delete("Place", nostalgia.key_u32(1))
+assert not storage.delete("Place", nostalgia.key_u32(1))
+"#
+                ),
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+    }
+}
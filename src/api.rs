@@ -0,0 +1,300 @@
+//! The operations code usually needs from a storage, as a trait.
+//!
+//! Services that take a `StorageApi` instead of a `Storage` can be tested against
+//! `MemoryStorage`, which keeps records in memory and never touches the filesystem.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::record::{self, Record};
+use crate::StorageError;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{Repo, Storage};
+
+/// Saving, reading and deleting records, implemented by `Storage`, by `MemoryStorage` and, on
+/// wasm32, by `IndexedDbStorage`.
+///
+/// Writes don't always wait to be committed.  `Storage` has committed a write when it returns, but
+/// `IndexedDbStorage` returns as soon as the write is sent to IndexedDB.  A write it fails to
+/// commit is reported by the next write or by `IndexedDbStorage::flush`, which waits for them all
+pub trait StorageApi {
+    /// Saves a record, replacing any record stored under the same key
+    fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError>;
+
+    /// Saves a group of records together
+    fn save_batch<T: Record>(&mut self, records: Vec<T>) -> Result<(), StorageError>;
+
+    /// Retrieves a record by its key, or `None` when no record is stored under it
+    fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError>;
+
+    /// Deletes a record and returns whether it was stored
+    fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError>;
+
+    /// Reads every record of a type, in key order
+    fn all<T: Record>(&mut self) -> Result<Vec<T>, StorageError>;
+
+    /// Returns the first record that matches a predicate
+    fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError>;
+
+    /// The number of records of a type
+    fn count<T: Record>(&mut self) -> Result<usize, StorageError>;
+
+    /// Removes every record of a type
+    fn truncate<T: Record>(&mut self) -> Result<(), StorageError>;
+
+    /// Returns a `Repo` for the records of a single type
+    #[cfg(not(target_arch = "wasm32"))]
+    fn repository<T: Record>(&mut self) -> Repo<'_, T, Self>
+    where
+        Self: Sized,
+    {
+        Repo::new(self)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl StorageApi for Storage {
+    fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        Storage::save(self, record)
+    }
+
+    fn save_batch<T: Record>(&mut self, records: Vec<T>) -> Result<(), StorageError> {
+        Storage::save_batch(self, records)
+    }
+
+    fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        Storage::get(self, key)
+    }
+
+    fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError> {
+        Storage::delete(self, record)
+    }
+
+    fn all<T: Record>(&mut self) -> Result<Vec<T>, StorageError> {
+        Ok(Storage::query::<T>(self)?.collect())
+    }
+
+    fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
+        Storage::find(self, p)
+    }
+
+    fn count<T: Record>(&mut self) -> Result<usize, StorageError> {
+        Storage::count::<T>(self)
+    }
+
+    fn truncate<T: Record>(&mut self) -> Result<(), StorageError> {
+        Storage::truncate::<T>(self)
+    }
+}
+
+/// A `StorageApi` that keeps serialized records in memory, for tests.
+///
+/// Records go through the same hooks, validation and serialization as they do in a `Storage`,
+/// and missing keys are reported the same way, but indexes, relations and metadata aren't kept.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{MemoryStorage, StorageApi, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn rename<S: StorageApi>(storage: &mut S, id: u32, name: &str) -> Result<(), StorageError> {
+///     if let Some(mut place) = storage.get::<Place, _>(id)? {
+///         place.name = name.to_string();
+///         storage.save(&place)?;
+///     }
+///     Ok(())
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut storage = MemoryStorage::default();
+///     storage.save(&Place { id: 1, name: "Wien".to_string() })?;
+///
+///     rename(&mut storage, 1, "Vienna")?;
+///     assert_eq!("Vienna", storage.get::<Place, _>(1)?.expect("Empty record").name);
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    dbs: HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn put<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        let copy = record::before_save(record)?;
+        let record = copy.as_ref().unwrap_or(record);
+        record.validate().map_err(StorageError::Validation)?;
+
+        let bytes = record.to_binary()?;
+        self.insert_bytes(T::db_name(), record.key().into(), bytes);
+        Ok(())
+    }
+
+    /// The value stored under `key` in the database `db_name`
+    #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+    pub(crate) fn bytes(&self, db_name: &str, key: &[u8]) -> Option<&[u8]> {
+        self.dbs.get(db_name)?.get(key).map(Vec::as_slice)
+    }
+
+    /// Stores `value` under `key` in the database `db_name` as it is
+    pub(crate) fn insert_bytes(&mut self, db_name: &str, key: Vec<u8>, value: Vec<u8>) {
+        self.dbs
+            .entry(db_name.to_string())
+            .or_default()
+            .insert(key, value);
+    }
+}
+
+impl StorageApi for MemoryStorage {
+    fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        self.put(record)
+    }
+
+    fn save_batch<T: Record>(&mut self, records: Vec<T>) -> Result<(), StorageError> {
+        // Nothing is saved when one of the records is invalid, like a failed transaction
+        let mut copy = self.clone();
+        for record in &records {
+            copy.put(record)?;
+        }
+        *self = copy;
+        Ok(())
+    }
+
+    fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        let bytes = self
+            .dbs
+            .get(T::db_name())
+            .and_then(|db| db.get(&key.into().into()));
+        match bytes {
+            Some(bytes) => Ok(Some(record::load(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError> {
+        let key: Vec<u8> = record.key().into();
+        Ok(self
+            .dbs
+            .get_mut(T::db_name())
+            .and_then(|db| db.remove(&key))
+            .is_some())
+    }
+
+    fn all<T: Record>(&mut self) -> Result<Vec<T>, StorageError> {
+        let records = self
+            .dbs
+            .get(T::db_name())
+            .into_iter()
+            .flat_map(|db| db.values())
+            .map(|bytes| record::load(bytes))
+            .collect::<Result<_, _>>()?;
+        Ok(records)
+    }
+
+    fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
+        Ok(self.all::<T>()?.into_iter().find(|record| p(record)))
+    }
+
+    fn count<T: Record>(&mut self) -> Result<usize, StorageError> {
+        Ok(self.dbs.get(T::db_name()).map_or(0, BTreeMap::len))
+    }
+
+    fn truncate<T: Record>(&mut self) -> Result<(), StorageError> {
+        if let Some(db) = self.dbs.get_mut(T::db_name()) {
+            db.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FieldError, Key};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(validate_with = "check_stock")]
+    struct Product {
+        id: u32,
+        stock: i32,
+    }
+
+    fn check_stock(product: &Product) -> Result<(), Vec<FieldError>> {
+        if product.stock < 0 {
+            Err(vec![FieldError::new("stock", "can't be negative")])
+        } else {
+            Ok(())
+        }
+    }
+
+    fn restock<S: StorageApi>(storage: &mut S, amount: i32) -> Result<usize, StorageError> {
+        let mut products = storage.repository::<Product>();
+        let mut restocked = 0;
+        for mut product in products.all()? {
+            if product.stock == 0 {
+                product.stock = amount;
+                products.save(&product)?;
+                restocked += 1;
+            }
+        }
+        Ok(restocked)
+    }
+
+    fn exercise<S: StorageApi>(storage: &mut S) {
+        storage.truncate::<Product>().expect("Could not truncate");
+        assert_eq!(0, storage.count::<Product>().unwrap());
+
+        storage
+            .save_batch(vec![
+                Product { id: 1, stock: 0 },
+                Product { id: 2, stock: 5 },
+                Product { id: 3, stock: 0 },
+            ])
+            .unwrap();
+        assert!(storage
+            .save_batch(vec![
+                Product { id: 4, stock: 1 },
+                Product { id: 5, stock: -1 }
+            ])
+            .is_err());
+        assert_eq!(3, storage.count::<Product>().unwrap());
+
+        assert_eq!(2, restock(storage, 10).unwrap());
+        assert_eq!(10, storage.get::<Product, _>(3).unwrap().unwrap().stock);
+        assert!(storage.get::<Product, _>(4).unwrap().is_none());
+
+        let five = storage.find::<Product>(&|product| product.stock == 5);
+        assert!(storage.delete(&five.unwrap().unwrap()).unwrap());
+        assert!(!storage.delete(&Product { id: 2, stock: 5 }).unwrap());
+        let ids: Vec<u32> = storage
+            .all::<Product>()
+            .unwrap()
+            .iter()
+            .map(|product| product.id)
+            .collect();
+        assert_eq!(vec![1, 3], ids);
+    }
+
+    #[test]
+    fn test_that_the_memory_storage_behaves_like_storage() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        exercise(&mut storage);
+        exercise(&mut MemoryStorage::new());
+    }
+}
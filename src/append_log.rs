@@ -0,0 +1,173 @@
+//! An append-only log of entries stored in a single database.
+//!
+//! Entries are keyed by their offset in big endian, so they are read back in the order they were
+//! appended.  The next offset is kept apart from the entries, so offsets keep going up after the
+//! oldest entries are truncated.
+
+use lmdb::{Database, DatabaseFlags, Environment, Transaction, WriteFlags};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+use crate::queue::{entries_from, read_u64};
+use crate::StorageError;
+
+const ENTRY: u8 = b'e';
+const NEXT_OFFSET: &[u8] = b"n";
+
+/// The name of the database that holds a log's entries
+pub(crate) fn log_db_name(name: &str) -> String {
+    format!("{}#log", name)
+}
+
+pub(crate) fn log_db_flags() -> DatabaseFlags {
+    DatabaseFlags::empty()
+}
+
+fn entry_key(offset: u64) -> Vec<u8> {
+    let mut key = vec![ENTRY];
+    key.extend(&offset.to_be_bytes());
+    key
+}
+
+fn next_offset(txn: &impl Transaction, db: Database) -> Result<u64, StorageError> {
+    match txn.get(db, &NEXT_OFFSET) {
+        Ok(bytes) => Ok(read_u64(bytes)),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A persistent, append-only log of entries, see `Storage::log`.
+///
+/// Every entry gets the next offset, starting at 0, which is never handed out again even after
+/// the entry is truncated.  Readers keep track of the offset they got to and carry on reading from
+/// there, which makes a log fit for event sourcing and for replaying what happened.
+pub struct Log<'s, T> {
+    env: &'s Environment,
+    db: Database,
+    phantom: PhantomData<T>,
+}
+
+impl<'s, T: Serialize + DeserializeOwned> Log<'s, T> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> Log<'s, T> {
+        Log {
+            env,
+            db,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Adds an entry to the end of the log, returning its offset
+    pub fn append(&self, entry: &T) -> Result<u64, StorageError> {
+        self.append_all(std::slice::from_ref(entry))
+            .map(|offsets| offsets.start)
+    }
+
+    /// Adds entries to the end of the log in one transaction, returning their offsets
+    pub fn append_all(&self, entries: &[T]) -> Result<std::ops::Range<u64>, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let first = next_offset(&txn, self.db)?;
+        let mut offset = first;
+        for entry in entries {
+            let value = bincode::serialize(entry)?;
+            txn.put(self.db, &entry_key(offset), &value, WriteFlags::empty())?;
+            offset += 1;
+        }
+        txn.put(
+            self.db,
+            &NEXT_OFFSET,
+            &offset.to_be_bytes(),
+            WriteFlags::empty(),
+        )?;
+        txn.commit()?;
+        Ok(first..offset)
+    }
+
+    /// Every entry from `offset` on, along with its offset.  Entries before `offset` that were
+    /// truncated are skipped
+    pub fn read_from(&self, offset: u64) -> Result<Vec<(u64, T)>, StorageError> {
+        self.read(offset, usize::MAX)
+    }
+
+    /// At most `limit` entries from `offset` on, along with their offsets
+    pub fn read(&self, offset: u64, limit: usize) -> Result<Vec<(u64, T)>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        entries_from(&txn, self.db, &entry_key(offset), &[ENTRY], limit)?
+            .into_iter()
+            .map(|(key, value)| Ok((read_u64(&key[1..]), bincode::deserialize(&value)?)))
+            .collect()
+    }
+
+    /// Removes every entry before `offset`, returning how many were removed
+    pub fn truncate_before(&self, offset: u64) -> Result<usize, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let removed = entries_from(&txn, self.db, &[ENTRY], &[ENTRY], usize::MAX)?
+            .into_iter()
+            .take_while(|(key, _)| read_u64(&key[1..]) < offset)
+            .map(|(key, _)| txn.del(self.db, &key, None))
+            .collect::<Result<Vec<_>, _>>()?
+            .len();
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    /// The offset of the oldest entry still in the log, or `None` when it is empty
+    pub fn first_offset(&self) -> Result<Option<u64>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        Ok(entries_from(&txn, self.db, &[ENTRY], &[ENTRY], 1)?
+            .pop()
+            .map(|(key, _)| read_u64(&key[1..])))
+    }
+
+    /// The offset the next entry will get
+    pub fn next_offset(&self) -> Result<u64, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        next_offset(&txn, self.db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Storage;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Order {
+        Placed { id: u32 },
+        Shipped { id: u32 },
+    }
+
+    #[test]
+    fn test_that_entries_are_read_back_from_an_offset() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let orders = storage.log::<Order>("orders").expect("Could not open log");
+        assert_eq!(0, orders.next_offset().unwrap());
+        assert_eq!(None, orders.first_offset().unwrap());
+
+        assert_eq!(0, orders.append(&Order::Placed { id: 1 }).unwrap());
+        assert_eq!(
+            1..3,
+            orders
+                .append_all(&[Order::Placed { id: 2 }, Order::Shipped { id: 1 }])
+                .unwrap()
+        );
+
+        assert_eq!(
+            vec![(1, Order::Placed { id: 2 }), (2, Order::Shipped { id: 1 })],
+            orders.read_from(1).unwrap()
+        );
+        assert_eq!(1, orders.read(0, 1).unwrap().len());
+        assert!(orders.read_from(3).unwrap().is_empty());
+
+        assert_eq!(2, orders.truncate_before(2).unwrap());
+        assert_eq!(Some(2), orders.first_offset().unwrap());
+        assert_eq!(
+            vec![(2, Order::Shipped { id: 1 })],
+            orders.read_from(0).unwrap()
+        );
+
+        // Offsets aren't reused once the entries before them are gone
+        orders.truncate_before(10).unwrap();
+        assert_eq!(3, orders.append(&Order::Shipped { id: 2 }).unwrap());
+    }
+}
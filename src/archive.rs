@@ -0,0 +1,168 @@
+//! Records stored as rkyv archives.
+//!
+//! Types opt in with `#[storable(rkyv)]`, which stores them with `to_bytes` and reads them back
+//! with `from_bytes` instead of bincode.  `Storage::get_archived` then reads the archived form of
+//! a record without deserializing it at all.
+
+use lmdb::Transaction;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::validation::validators::DefaultValidator;
+use rkyv::{AlignedVec, Archive, CheckBytes, Deserialize, Infallible};
+
+use crate::metadata;
+use crate::type_tag::{self, TYPES_DB};
+use crate::{Record, Storage, StorageError};
+
+// The largest alignment an archived record needs
+const ALIGNMENT: usize = 16;
+
+fn custom<E: std::fmt::Display>(error: E) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(error.to_string()))
+}
+
+/// Archives a record with rkyv
+pub fn to_bytes<T>(record: &T) -> Result<Vec<u8>, bincode::Error>
+where
+    T: rkyv::Serialize<AllocSerializer<256>>,
+{
+    rkyv::to_bytes::<T, 256>(record)
+        .map(AlignedVec::into_vec)
+        .map_err(custom)
+}
+
+/// Checks an archive and deserializes the record in it
+pub fn from_bytes<T>(bytes: &[u8]) -> Result<T, bincode::Error>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, Infallible>,
+{
+    with_archived::<T, _, _>(bytes, |archived| {
+        archived.deserialize(&mut Infallible).map_err(custom)
+    })
+}
+
+// Checks an archive and hands it to `f`.  Values are only as aligned as LMDB leaves them, so an
+// archive that isn't aligned is copied into one that is.
+fn with_archived<T, F, R>(bytes: &[u8], f: F) -> Result<R, bincode::Error>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+    F: FnOnce(&T::Archived) -> Result<R, bincode::Error>,
+{
+    if (bytes.as_ptr() as usize).is_multiple_of(ALIGNMENT) {
+        return f(rkyv::check_archived_root::<T>(bytes).map_err(custom)?);
+    }
+
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    f(rkyv::check_archived_root::<T>(&aligned).map_err(custom)?)
+}
+
+impl Storage {
+    /// Reads the archived form of a record stored with `#[storable(rkyv)]` and hands it to `f`,
+    /// without deserializing it.  Returns `None` when there is no record under `key`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    ///
+    /// #[derive(
+    ///     Storable, serde::Serialize, serde::Deserialize,
+    ///     rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+    /// )]
+    /// #[archive(check_bytes)]
+    /// #[key = "id"]
+    /// #[db_name = "ArchivedPlace"]
+    /// #[storable(rkyv)]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     let name = storage.get_archived::<Place, _, _, _>(1, |place| place.name.to_string())?;
+    ///     assert_eq!(Some("Vienna".to_string()), name);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn get_archived<T, K, F, R>(&mut self, key: K, f: F) -> Result<Option<R>, StorageError>
+    where
+        T: Record + Archive,
+        T::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+        K: Into<T::Key>,
+        F: FnOnce(&T::Archived) -> R,
+    {
+        let storage = self.storage_for::<T>()?;
+        let db = match storage.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let key: Vec<u8> = key.into().into();
+        let bytes = match txn.get(db, &key) {
+            Ok(bytes) => bytes,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let value = metadata::stored::<T>(&key, bytes)?;
+        Ok(Some(with_archived::<T, _, _>(value, |archived| Ok(f(archived)))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+
+    #[derive(
+        Storable,
+        serde::Serialize,
+        serde::Deserialize,
+        rkyv::Archive,
+        rkyv::Serialize,
+        rkyv::Deserialize,
+        Debug,
+        PartialEq,
+    )]
+    #[archive(check_bytes)]
+    #[key = "id"]
+    #[storable(rkyv, metadata)]
+    struct Tile {
+        id: u32,
+        layer: String,
+        pixels: Vec<u8>,
+    }
+
+    #[test]
+    fn test_that_archived_records_round_trip_and_can_be_read_in_place() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let tile = Tile {
+            id: 1,
+            layer: "roads".to_string(),
+            pixels: vec![0, 255, 128],
+        };
+        storage.save(&tile).expect("Could not save tile");
+
+        assert_eq!(Some(tile), storage.get::<Tile, _>(1).unwrap());
+        let pixels = storage
+            .get_archived::<Tile, _, _, _>(1, |tile| (tile.layer.to_string(), tile.pixels.len()))
+            .expect("Could not read archive");
+        assert_eq!(Some(("roads".to_string(), 3)), pixels);
+        assert_eq!(
+            None,
+            storage
+                .get_archived::<Tile, _, _, _>(2, |tile| tile.id)
+                .unwrap()
+        );
+    }
+}
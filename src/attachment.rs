@@ -0,0 +1,92 @@
+//! Named binary attachments of records.
+//!
+//! Every attachment is stored in one database, keyed by its record's database name, its record's
+//! key and its own name, so the attachments of one record sit next to each other and go with it
+//! when it is deleted.
+
+use lmdb::{Database, RwTransaction};
+
+use crate::queue::entries_from;
+use crate::{Record, StorageError};
+
+/// The database that holds every attachment
+pub(crate) const ATTACHMENTS_DB: &str = "nostalgia#attachments";
+
+const SEPARATOR: u8 = 0;
+
+// The start of the keys of every attachment of the record stored under `key`.  Record keys can
+// hold any byte, so they are prefixed with their length
+pub(crate) fn attachments_prefix<T: Record>(key: &[u8]) -> Vec<u8> {
+    let mut prefix = T::db_name().as_bytes().to_vec();
+    prefix.push(SEPARATOR);
+    prefix.extend(&(key.len() as u32).to_be_bytes());
+    prefix.extend(key);
+    prefix
+}
+
+pub(crate) fn attachment_key<T: Record>(key: &[u8], name: &str) -> Vec<u8> {
+    let mut attachment = attachments_prefix::<T>(key);
+    attachment.extend(name.as_bytes());
+    attachment
+}
+
+/// Removes the attachments whose keys start with `prefix`, returning their keys
+pub(crate) fn delete_all(
+    txn: &mut RwTransaction,
+    db: Database,
+    prefix: &[u8],
+) -> Result<Vec<Vec<u8>>, StorageError> {
+    let keys: Vec<Vec<u8>> = entries_from(txn, db, prefix, prefix, usize::MAX)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    for key in &keys {
+        txn.del(db, key, None)?;
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct User {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_that_attachments_are_deleted_with_their_record() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let users: Vec<User> = (1..=2)
+            .map(|id| User {
+                id,
+                name: format!("user {}", id),
+            })
+            .collect();
+        for user in &users {
+            storage.save(user).unwrap();
+            storage.attach(user, "avatar", &[user.id as u8; 4]).unwrap();
+            storage.attach(user, "cv", b"%PDF").unwrap();
+        }
+        assert_eq!(
+            Some(vec![2; 4]),
+            storage.attachment(&users[1], "avatar").unwrap()
+        );
+
+        assert!(storage.detach(&users[0], "cv").unwrap());
+        assert!(!storage.detach(&users[0], "cv").unwrap());
+        assert_eq!(None, storage.attachment(&users[0], "cv").unwrap());
+
+        storage.transaction(|tx| tx.delete(&users[1])).unwrap();
+        assert_eq!(None, storage.attachment(&users[1], "avatar").unwrap());
+        assert_eq!(None, storage.attachment(&users[1], "cv").unwrap());
+        assert_eq!(
+            Some(vec![1; 4]),
+            storage.attachment(&users[0], "avatar").unwrap()
+        );
+    }
+}
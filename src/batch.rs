@@ -0,0 +1,332 @@
+use crate::index::IndexEntry;
+use crate::record::{self, Record};
+use crate::throttle::{Pacer, Throttle};
+use crate::validation::FieldError;
+use crate::Storage;
+use crate::StorageError;
+use crate::Transaction;
+
+type PutFn = fn(&mut Transaction, &[u8], &[u8], &[IndexEntry]) -> Result<(), StorageError>;
+type DeleteFn = fn(&mut Transaction, &[u8]) -> Result<(), StorageError>;
+
+/// A write buffered by a `Batch` until it is committed.  The record's type is erased, so each
+/// operation keeps the function that writes it for that type.
+enum Operation {
+    Put {
+        put: PutFn,
+        db_name: &'static str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        entries: Vec<IndexEntry>,
+    },
+    Delete {
+        delete: DeleteFn,
+        db_name: &'static str,
+        key: Vec<u8>,
+    },
+    // A save that failed validation, reported when the batch is committed
+    Invalid(Vec<FieldError>),
+    // A save whose record couldn't be serialized, reported the same way
+    Unserializable(String),
+}
+
+/// A marker for a position in a `Batch` that it can later be rolled back to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// Buffers saves and deletes in memory and writes all of them in a single transaction on commit,
+/// or in paced chunks when it is throttled, see `Batch::throttle`.
+///
+/// Savepoints can be taken while building up a batch so that a chunk of buffered operations can
+/// be undone without throwing the whole batch away.
+pub struct Batch<'s> {
+    storage: &'s mut Storage,
+    operations: Vec<Operation>,
+    throttle: Option<Throttle>,
+}
+
+impl<'s> Batch<'s> {
+    pub(crate) fn new(storage: &'s mut Storage) -> Batch<'s> {
+        Batch {
+            storage,
+            operations: vec![],
+            throttle: None,
+        }
+    }
+
+    /// Writes the batch under `throttle` when it is committed, in transactions of `chunk`
+    /// operations each with pauses in between, so a backfill doesn't keep the disk from readers.
+    /// A throttled batch isn't written atomically: a commit that fails partway leaves the
+    /// transactions before the failing one written
+    pub fn throttle(&mut self, throttle: Throttle) -> &mut Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Buffers a record to be saved when the batch is committed.  If the record fails validation
+    /// or can't be serialized the commit returns `StorageError::Validation` or
+    /// `StorageError::Codec`, unless the save is rolled back first
+    pub fn save<T: Record>(&mut self, record: &T) -> &mut Self {
+        let operation = match Self::put(record) {
+            Ok(operation) => operation,
+            Err(StorageError::Validation(errors)) => Operation::Invalid(errors),
+            Err(StorageError::Codec { source }) => Operation::Unserializable(source.to_string()),
+            Err(e) => Operation::Unserializable(e.to_string()),
+        };
+        self.operations.push(operation);
+        self
+    }
+
+    fn put<T: Record>(record: &T) -> Result<Operation, StorageError> {
+        let copy = record::before_save(record)?;
+        let record = copy.as_ref().unwrap_or(record);
+        record.validate().map_err(StorageError::Validation)?;
+
+        Ok(Operation::Put {
+            put: |tx, key, value, entries| tx.put_record::<T>(key, value, entries),
+            db_name: T::db_name(),
+            key: record.key().into(),
+            value: T::to_binary(record)?,
+            entries: record.index_entries()?,
+        })
+    }
+
+    /// Buffers a record to be deleted when the batch is committed
+    pub fn delete<T: Record>(&mut self, record: &T) -> &mut Self {
+        self.operations.push(Operation::Delete {
+            delete: |tx, key| tx.delete_key::<T>(key),
+            db_name: T::db_name(),
+            key: record.key().into(),
+        });
+        self
+    }
+
+    /// Retrieves a record as it will be once the batch is committed.
+    ///
+    /// The latest save or delete buffered for the key wins, and the stored record is only read
+    /// when nothing has been buffered for it.  Lets upserts merge with a record saved earlier in
+    /// the same batch.
+    pub fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        let key: Vec<u8> = key.into().into();
+
+        for operation in self.operations.iter().rev() {
+            match operation {
+                Operation::Put {
+                    db_name,
+                    key: buffered,
+                    value,
+                    ..
+                } if *db_name == T::db_name() && *buffered == key => {
+                    return Ok(Some(record::load(value)?))
+                }
+                Operation::Delete {
+                    db_name,
+                    key: buffered,
+                    ..
+                } if *db_name == T::db_name() && *buffered == key => return Ok(None),
+                _ => (),
+            }
+        }
+
+        self.storage.get_raw::<T>(&key)
+    }
+
+    /// Returns the number of buffered operations
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    /// Returns true when nothing has been buffered
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Marks the current position in the batch
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.operations.len())
+    }
+
+    /// Discards every operation buffered after the savepoint was taken
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        self.operations.truncate(savepoint.0);
+    }
+
+    /// Writes all of the buffered operations in one transaction, or in chunks when the batch is
+    /// throttled
+    pub fn commit(self) -> Result<(), StorageError> {
+        let operations = self.operations;
+        let throttle = match self.throttle {
+            Some(throttle) => throttle,
+            None => return self.storage.transaction(|tx| write(tx, &operations)),
+        };
+
+        // Nothing is written when a save failed, same as without a throttle
+        if let Some(failed) = operations.iter().find(|operation| operation.is_failed()) {
+            return write_failed(failed);
+        }
+        let mut pacer = Pacer::new(throttle);
+        for chunk in operations.chunks(throttle.chunk.max(1)) {
+            self.storage.transaction(|tx| write(tx, chunk))?;
+            pacer.committed(chunk.iter().map(Operation::len).sum());
+        }
+        Ok(())
+    }
+}
+
+impl Operation {
+    // The bytes of keys and values the operation writes
+    fn len(&self) -> u64 {
+        match self {
+            Operation::Put { key, value, .. } => (key.len() + value.len()) as u64,
+            Operation::Delete { key, .. } => key.len() as u64,
+            Operation::Invalid(_) | Operation::Unserializable(_) => 0,
+        }
+    }
+
+    // Whether the operation is a save that failed while being buffered
+    fn is_failed(&self) -> bool {
+        matches!(self, Operation::Invalid(_) | Operation::Unserializable(_))
+    }
+}
+
+fn write(tx: &mut Transaction, operations: &[Operation]) -> Result<(), StorageError> {
+    for operation in operations {
+        match operation {
+            Operation::Put {
+                put,
+                key,
+                value,
+                entries,
+                ..
+            } => put(tx, key, value, entries)?,
+            Operation::Delete { delete, key, .. } => delete(tx, key)?,
+            Operation::Invalid(_) | Operation::Unserializable(_) => return write_failed(operation),
+        }
+    }
+    Ok(())
+}
+
+// The error a save that failed while being buffered is reported with
+fn write_failed(operation: &Operation) -> Result<(), StorageError> {
+    match operation {
+        Operation::Invalid(errors) => Err(StorageError::Validation(errors.clone())),
+        Operation::Unserializable(message) => {
+            Err(Box::new(bincode::ErrorKind::Custom(message.clone())).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Import {
+        id: u32,
+        row: String,
+    }
+
+    impl Record for Import {
+        type Key = Key<u32>;
+
+        fn key(&self) -> Key<u32> {
+            Key::from(self.id)
+        }
+
+        fn db_name() -> &'static str {
+            "Import"
+        }
+    }
+
+    #[test]
+    fn test_that_we_can_roll_back_to_a_savepoint() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.truncate::<Import>().expect("Could not truncate");
+
+        let mut batch = storage.batch();
+        batch.save(&Import {
+            id: 1,
+            row: "first".to_string(),
+        });
+
+        let savepoint = batch.savepoint();
+        for id in 2..10 {
+            batch.save(&Import {
+                id,
+                row: "bad chunk".to_string(),
+            });
+        }
+        assert_eq!(9, batch.len());
+
+        batch.rollback_to(savepoint);
+        assert_eq!(1, batch.len());
+
+        batch.save(&Import {
+            id: 2,
+            row: "second".to_string(),
+        });
+        batch.commit().expect("Could not commit batch");
+
+        let rows: Vec<String> = storage
+            .query::<Import>()
+            .unwrap()
+            .map(|import| import.row)
+            .collect();
+        assert_eq!(vec!["first".to_string(), "second".to_string()], rows);
+    }
+
+    #[test]
+    fn test_that_gets_see_writes_buffered_in_the_batch() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Import {
+                id: 1,
+                row: "stored".to_string(),
+            })
+            .expect("Could not save");
+
+        let mut batch = storage.batch();
+        assert_eq!("stored", batch.get::<Import, _>(1).unwrap().unwrap().row);
+        assert_eq!(None, batch.get::<Import, _>(2).unwrap());
+
+        // Upsert that merges with whatever the batch already holds
+        for row in &["a", "b", "c"] {
+            let mut import = batch.get::<Import, _>(2).unwrap().unwrap_or(Import {
+                id: 2,
+                row: String::new(),
+            });
+            import.row.push_str(row);
+            batch.save(&import);
+        }
+        assert_eq!("abc", batch.get::<Import, _>(2).unwrap().unwrap().row);
+
+        let stored = batch.get::<Import, _>(1).unwrap().unwrap();
+        batch.delete(&stored);
+        assert_eq!(None, batch.get::<Import, _>(1).unwrap());
+
+        batch.commit().expect("Could not commit batch");
+        assert_eq!("abc", storage.get::<Import, _>(2).unwrap().unwrap().row);
+    }
+
+    #[test]
+    fn test_that_throttled_batches_commit_in_paced_chunks() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let started = std::time::Instant::now();
+        let mut batch = storage.batch();
+        batch.throttle(crate::Throttle::default().txns_per_sec(100).chunk(2));
+        for id in 0..6 {
+            batch.save(&Import {
+                id,
+                row: "backfill".to_string(),
+            });
+        }
+        batch.commit().expect("Could not commit batch");
+
+        // Three transactions at no more than 100 a second
+        assert!(started.elapsed() >= std::time::Duration::from_millis(30));
+        assert_eq!(6, storage.query::<Import>().unwrap().count());
+    }
+}
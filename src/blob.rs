@@ -0,0 +1,294 @@
+//! Large values stored in chunks.
+//!
+//! A blob is split into chunks of `CHUNK_SIZE` bytes, each stored under its own key, with a
+//! manifest recording the blob's length.  Blobs are written in a single transaction, so readers
+//! see either the whole of the new blob or the old one.
+
+use lmdb::{Database, DatabaseFlags, Environment, RoTransaction, RwTransaction};
+use lmdb::{Transaction, WriteFlags};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::StorageError;
+
+/// How many bytes of a blob are stored under each key
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+pub(crate) const MANIFESTS_DB: &str = "blobs#manifests";
+pub(crate) const CHUNKS_DB: &str = "blobs#chunks";
+
+pub(crate) fn blob_db_flags() -> DatabaseFlags {
+    DatabaseFlags::empty()
+}
+
+// Chunks are keyed by the length of the blob's name, the name and the chunk's index, so the
+// chunks of one blob sit next to each other in order
+fn chunk_key(name: &str, index: u64) -> Vec<u8> {
+    let mut key = (name.len() as u32).to_be_bytes().to_vec();
+    key.extend(name.as_bytes());
+    key.extend(&index.to_be_bytes());
+    key
+}
+
+fn read_len(
+    txn: &impl Transaction,
+    manifests: Database,
+    name: &str,
+) -> Result<Option<u64>, lmdb::Error> {
+    match txn.get(manifests, &name) {
+        Ok(bytes) if bytes.len() == 8 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(bytes);
+            Ok(Some(u64::from_be_bytes(len)))
+        }
+        Ok(_) => Err(lmdb::Error::Corrupted),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn chunk_count(len: u64) -> u64 {
+    len.div_ceil(CHUNK_SIZE as u64)
+}
+
+fn to_io(error: lmdb::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+/// Removes a blob's manifest and chunks.  Returns false when there was no such blob
+pub(crate) fn delete(
+    txn: &mut RwTransaction,
+    manifests: Database,
+    chunks: Database,
+    name: &str,
+) -> Result<bool, lmdb::Error> {
+    let len = match read_len(txn, manifests, name)? {
+        Some(len) => len,
+        None => return Ok(false),
+    };
+
+    for index in 0..chunk_count(len) {
+        match txn.del(chunks, &chunk_key(name, index), None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    txn.del(manifests, &name, None)?;
+    Ok(true)
+}
+
+/// Writes a blob chunk by chunk.  Nothing is visible to readers until `finish` is called, and
+/// dropping the writer without calling it leaves any blob stored under the name as it was
+pub struct BlobWriter<'s> {
+    txn: RwTransaction<'s>,
+    manifests: Database,
+    chunks: Database,
+    name: String,
+    buffer: Vec<u8>,
+    written: u64,
+}
+
+impl<'s> BlobWriter<'s> {
+    pub(crate) fn new(
+        env: &'s Environment,
+        manifests: Database,
+        chunks: Database,
+        name: &str,
+    ) -> Result<BlobWriter<'s>, StorageError> {
+        let mut txn = env.begin_rw_txn()?;
+        delete(&mut txn, manifests, chunks, name)?;
+
+        Ok(BlobWriter {
+            txn,
+            manifests,
+            chunks,
+            name: name.to_string(),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            written: 0,
+        })
+    }
+
+    fn put_chunk(&mut self) -> Result<(), lmdb::Error> {
+        let index = self.written / CHUNK_SIZE as u64;
+        self.txn.put(
+            self.chunks,
+            &chunk_key(&self.name, index),
+            &self.buffer,
+            WriteFlags::empty(),
+        )?;
+        self.written += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes what is left of the blob along with its manifest and commits it.  Returns the
+    /// blob's length
+    pub fn finish(mut self) -> Result<u64, StorageError> {
+        if !self.buffer.is_empty() {
+            self.put_chunk()?;
+        }
+        self.txn.put(
+            self.manifests,
+            &self.name,
+            &self.written.to_be_bytes(),
+            WriteFlags::empty(),
+        )?;
+        self.txn.commit()?;
+        Ok(self.written)
+    }
+}
+
+impl<'s> Write for BlobWriter<'s> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..taken]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.put_chunk().map_err(to_io)?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads a blob from a snapshot taken when the reader was opened
+pub struct BlobReader<'s> {
+    txn: RoTransaction<'s>,
+    chunks: Database,
+    name: String,
+    len: u64,
+    position: u64,
+}
+
+impl<'s> BlobReader<'s> {
+    /// Opens the blob called `name`, or returns `None` when there is no such blob
+    pub(crate) fn open(
+        env: &'s Environment,
+        manifests: Database,
+        chunks: Database,
+        name: &str,
+    ) -> Result<Option<BlobReader<'s>>, StorageError> {
+        let txn = env.begin_ro_txn()?;
+        let len = match read_len(&txn, manifests, name)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        Ok(Some(BlobReader {
+            txn,
+            chunks,
+            name: name.to_string(),
+            len,
+            position: 0,
+        }))
+    }
+
+    /// The blob's length in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the blob is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'s> Read for BlobReader<'s> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.position / CHUNK_SIZE as u64;
+        let offset = (self.position % CHUNK_SIZE as u64) as usize;
+        let chunk = self
+            .txn
+            .get(self.chunks, &chunk_key(&self.name, index))
+            .map_err(to_io)?;
+        if offset >= chunk.len() {
+            return Err(to_io(lmdb::Error::Corrupted));
+        }
+
+        let read = buf.len().min(chunk.len() - offset);
+        buf[..read].copy_from_slice(&chunk[offset..offset + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'s> Seek for BlobReader<'s> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't seek before the start of a blob",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Storage;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::CHUNK_SIZE;
+
+    #[test]
+    fn test_that_blobs_are_chunked_and_streamed_back() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.delete_blob("photo").expect("Could not delete blob");
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 123).map(|i| (i % 251) as u8).collect();
+        let mut writer = storage.blob_writer("photo").unwrap();
+        for part in data.chunks(4000) {
+            writer.write_all(part).unwrap();
+        }
+        assert_eq!(data.len() as u64, writer.finish().unwrap());
+
+        let mut reader = storage.blob_reader("photo").unwrap().unwrap();
+        assert_eq!(data.len() as u64, reader.len());
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert!(read == data);
+
+        reader.seek(SeekFrom::Start(CHUNK_SIZE as u64 - 2)).unwrap();
+        let mut across = [0; 4];
+        reader.read_exact(&mut across).unwrap();
+        assert_eq!(&data[CHUNK_SIZE - 2..CHUNK_SIZE + 2], &across);
+        drop(reader);
+
+        // An unfinished rewrite leaves the old blob in place, a finished one replaces it
+        let mut writer = storage.blob_writer("photo").unwrap();
+        writer.write_all(b"abandoned").unwrap();
+        drop(writer);
+        assert_eq!(
+            data.len() as u64,
+            storage.blob_reader("photo").unwrap().unwrap().len()
+        );
+        let mut writer = storage.blob_writer("photo").unwrap();
+        writer.write_all(b"small").unwrap();
+        writer.finish().unwrap();
+        let mut small = String::new();
+        let mut reader = storage.blob_reader("photo").unwrap().unwrap();
+        reader.read_to_string(&mut small).unwrap();
+        assert_eq!("small", small);
+        drop(reader);
+
+        assert!(storage.delete_blob("photo").unwrap());
+        assert!(storage.blob_reader("photo").unwrap().is_none());
+        assert!(!storage.delete_blob("photo").unwrap());
+    }
+}
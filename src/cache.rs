@@ -0,0 +1,134 @@
+//! Least recently used caches of deserialized records.
+//!
+//! A type gets a cache when its storage is opened with `StorageOptions::cache`.  Records are kept
+//! behind an `Arc`, so reading a cached record costs a clone of the pointer instead of a
+//! deserialization.  Writes made through the storage drop the records they touch from the cache,
+//! writes made by other processes or through `RawDb` don't.
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+type Cached = Arc<dyn Any + Send + Sync>;
+
+/// The cache of one record type, keyed by raw record keys
+pub(crate) struct ReadCache {
+    capacity: usize,
+    entries: HashMap<Vec<u8>, (Cached, u64)>,
+    // Keys by when they were last used, oldest first
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl ReadCache {
+    pub(crate) fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the record cached under `key` and marks it as the most recently used
+    pub(crate) fn get<T: Send + Sync + 'static>(&mut self, key: &[u8]) -> Option<Arc<T>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (record, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.order.insert(tick, key.to_vec());
+        *used = tick;
+
+        record.clone().downcast().ok()
+    }
+
+    /// Caches a record, evicting the least recently used one when the cache is full
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, key: &[u8], record: Arc<T>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_vec());
+        self.entries.insert(key.to_vec(), (record, self.tick));
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage, StorageOptions};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Quote {
+        id: u32,
+        price: u32,
+    }
+
+    #[test]
+    fn test_that_the_least_recently_used_record_is_evicted() {
+        let mut cache = ReadCache::new(2);
+        cache.insert(b"a", Arc::new(1u32));
+        cache.insert(b"b", Arc::new(2u32));
+        assert_eq!(Some(Arc::new(1u32)), cache.get(b"a"));
+        cache.insert(b"c", Arc::new(3u32));
+
+        assert_eq!(2, cache.len());
+        assert_eq!(None, cache.get::<u32>(b"b"));
+        assert_eq!(Some(Arc::new(3u32)), cache.get(b"c"));
+        assert_eq!(None, cache.get::<String>(b"a"));
+    }
+
+    #[test]
+    fn test_that_cached_reads_see_writes_made_through_the_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::default().cache::<Quote>(16);
+        let mut storage =
+            Storage::open_with(dir.path(), options).expect("Could not open db storage");
+        storage.save(&Quote { id: 1, price: 10 }).unwrap();
+
+        let first = storage.get_shared::<Quote, _>(1).unwrap().unwrap();
+        let second = storage.get_shared::<Quote, _>(1).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(None, storage.get_shared::<Quote, _>(2).unwrap());
+
+        storage.save(&Quote { id: 1, price: 11 }).unwrap();
+        assert_eq!(
+            11,
+            storage.get_shared::<Quote, _>(1).unwrap().unwrap().price
+        );
+
+        storage.delete(&Quote { id: 1, price: 11 }).unwrap();
+        assert_eq!(None, storage.get_shared::<Quote, _>(1).unwrap());
+
+        storage.save(&Quote { id: 3, price: 30 }).unwrap();
+        storage.get_shared::<Quote, _>(3).unwrap();
+        storage.truncate::<Quote>().unwrap();
+        assert_eq!(None, storage.get_shared::<Quote, _>(3).unwrap());
+    }
+}
@@ -0,0 +1,149 @@
+//! Record types that hold a bounded number of records or bytes, evicting their oldest records.
+
+use lmdb::{Database, RwTransaction, Transaction, WriteFlags};
+
+use crate::StorageError;
+
+/// The database the bytes stored for each capped type are counted in, by database name
+pub(crate) const CAPPED_DB: &str = "nostalgia#capped";
+
+/// How much a capped record type can hold, see `StorageOptions::capped`.  Neither limit is set by
+/// default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cap {
+    pub max_entries: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Cap {
+    /// Sets the most records the type can have
+    pub fn max_entries(mut self, max_entries: u64) -> Cap {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Sets the most bytes of keys and values the type's records can take up
+    pub fn max_bytes(mut self, max_bytes: u64) -> Cap {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub(crate) fn allows(&self, entries: u64, bytes: u64) -> bool {
+        self.max_entries.is_none_or(|max| entries <= max)
+            && self.max_bytes.is_none_or(|max| bytes <= max)
+    }
+}
+
+pub(crate) fn read_bytes(
+    txn: &impl Transaction,
+    db: Database,
+    db_name: &str,
+) -> Result<u64, StorageError> {
+    match txn.get(db, &db_name) {
+        Ok(bytes) if bytes.len() == 8 => {
+            let mut used = [0; 8];
+            used.copy_from_slice(bytes);
+            Ok(u64::from_be_bytes(used))
+        }
+        Ok(_) => Err(lmdb::Error::Corrupted.into()),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn write_bytes(
+    txn: &mut RwTransaction,
+    db: Database,
+    db_name: &str,
+    bytes: u64,
+) -> Result<(), StorageError> {
+    txn.put(db, &db_name, &bytes.to_be_bytes(), WriteFlags::empty())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage, StorageOptions};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Line {
+        id: u64,
+        text: String,
+    }
+
+    fn line(id: u64, len: usize) -> Line {
+        Line {
+            id,
+            text: "x".repeat(len),
+        }
+    }
+
+    fn ids(storage: &mut Storage) -> Vec<u64> {
+        storage
+            .query::<Line>()
+            .unwrap()
+            .map(|line| line.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_that_the_oldest_records_are_evicted_past_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::default().capped::<Line>(Cap::default().max_bytes(400));
+        let mut storage = Storage::open_with(dir.path(), options).unwrap();
+
+        // Every line takes 8 bytes of key, and a value of 8 bytes of id, 8 of length and the text
+        for id in 0..4 {
+            storage.save(&line(id, 76)).unwrap();
+        }
+        assert_eq!(vec![0, 1, 2, 3], ids(&mut storage));
+        storage.save(&line(4, 76)).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], ids(&mut storage));
+
+        // Growing a record counts only the difference, and deleting one frees its bytes
+        storage.save(&line(4, 276)).unwrap();
+        assert_eq!(vec![3, 4], ids(&mut storage));
+        storage.delete(&line(3, 76)).unwrap();
+        storage.save(&line(5, 76)).unwrap();
+        assert_eq!(vec![4, 5], ids(&mut storage));
+
+        storage.truncate::<Line>().unwrap();
+        storage
+            .transaction(|tx| {
+                for id in 10..14 {
+                    tx.save(&line(id, 76))?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec![10, 11, 12, 13], ids(&mut storage));
+    }
+
+    #[test]
+    fn test_that_a_record_bigger_than_the_cap_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::default().capped::<Line>(Cap::default().max_bytes(400));
+        let mut storage = Storage::open_with(dir.path(), options).unwrap();
+        for id in 0..3 {
+            storage.save(&line(id, 76)).unwrap();
+        }
+
+        match storage.save(&line(3, 400)) {
+            Err(StorageError::OverCap {
+                db_name, max_bytes, ..
+            }) => {
+                assert_eq!("Line", db_name);
+                assert_eq!(400, max_bytes);
+            }
+            result => panic!("Expected the record to be refused, got {:?}", result),
+        }
+        assert_eq!(vec![0, 1, 2], ids(&mut storage));
+
+        // Neither is growing a stored record past the cap
+        assert!(storage.save(&line(2, 400)).is_err());
+        assert_eq!(Some(line(2, 76)), storage.get::<Line, _>(2).unwrap());
+    }
+}
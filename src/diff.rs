@@ -0,0 +1,377 @@
+//! Differences between the databases of two environments, and patches that turn one into the other.
+//!
+//! Databases are compared entry by entry through their raw keys and values, so records of every
+//! type, their indexes and raw databases are compared without knowing their types.  Both sides are
+//! walked in key order at once, each from a single snapshot.  In databases with sorted duplicates
+//! every value of a key is an entry of its own, so values only ever show up as added or removed.
+//!
+//! The journal and a follower's position are left out: they describe how an environment was
+//! written rather than what it holds, and differ between a leader and its followers by design.
+
+use lmdb::{Database, DatabaseFlags, Environment, RoTransaction, Transaction};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::query;
+use crate::journal::{ChangeOp, JOURNAL_DB, REPLICA_DB};
+use crate::usage::database_names;
+use crate::{Storage, StorageError};
+
+/// The version of the patch format written by `Diff::write_patch`
+pub const PATCH_VERSION: u32 = 1;
+
+// A key and value borrowed from a transaction
+type Pair<'t> = (&'t [u8], &'t [u8]);
+
+#[derive(Serialize, Deserialize)]
+struct Patch {
+    version: u32,
+    ops: Vec<ChangeOp>,
+}
+
+/// An entry that differs between two environments.  `before` is its value in the first one and
+/// `after` its value in the second, `None` on the side that doesn't hold it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: Vec<u8>,
+    pub before: Option<Vec<u8>>,
+    pub after: Option<Vec<u8>>,
+}
+
+/// How one database differs between two environments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseDiff {
+    /// The database's name
+    pub name: String,
+    /// The database's flags, in the second environment when it exists there
+    pub flags: u32,
+    /// The entries that differ, in key order
+    pub changes: Vec<KeyChange>,
+}
+
+impl DatabaseDiff {
+    /// The keys only the second environment holds
+    pub fn added(&self) -> Vec<&[u8]> {
+        self.keys(|change| change.before.is_none())
+    }
+
+    /// The keys only the first environment holds
+    pub fn removed(&self) -> Vec<&[u8]> {
+        self.keys(|change| change.after.is_none())
+    }
+
+    /// The keys both environments hold with different values
+    pub fn changed(&self) -> Vec<&[u8]> {
+        self.keys(|change| change.before.is_some() && change.after.is_some())
+    }
+
+    fn keys<F: Fn(&KeyChange) -> bool>(&self, f: F) -> Vec<&[u8]> {
+        self.changes
+            .iter()
+            .filter(|change| f(change))
+            .map(|change| change.key.as_slice())
+            .collect()
+    }
+
+    fn is_dup_sort(&self) -> bool {
+        DatabaseFlags::from_bits_truncate(self.flags).contains(DatabaseFlags::DUP_SORT)
+    }
+}
+
+/// How two environments differ, see `diff`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Diff {
+    /// The databases that differ, by name
+    pub databases: Vec<DatabaseDiff>,
+}
+
+impl Diff {
+    /// Whether both environments hold the same entries
+    pub fn is_empty(&self) -> bool {
+        self.databases.is_empty()
+    }
+
+    /// The writes that turn the first environment into the second
+    pub fn patch(&self) -> Vec<ChangeOp> {
+        let mut ops = vec![];
+        for db in &self.databases {
+            let dup_sort = db.is_dup_sort();
+            for change in &db.changes {
+                match (&change.before, &change.after) {
+                    (_, Some(after)) => ops.push(ChangeOp::Put {
+                        db: db.name.clone(),
+                        flags: db.flags,
+                        key: change.key.clone(),
+                        value: after.clone(),
+                    }),
+                    (Some(before), None) => ops.push(ChangeOp::Delete {
+                        db: db.name.clone(),
+                        key: change.key.clone(),
+                        value: if dup_sort { Some(before.clone()) } else { None },
+                    }),
+                    (None, None) => {}
+                }
+            }
+        }
+        ops
+    }
+
+    /// Writes the patch to a file at `path`, to be applied with `Storage::apply_patch`.  Returns
+    /// the number of writes in it
+    pub fn write_patch<P: AsRef<Path>>(&self, path: P) -> Result<usize, StorageError> {
+        let patch = Patch {
+            version: PATCH_VERSION,
+            ops: self.patch(),
+        };
+        let mut file = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut file, &patch)?;
+        file.flush()?;
+        Ok(patch.ops.len())
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for db in &self.databases {
+            writeln!(
+                f,
+                "{}: {} added, {} removed, {} changed",
+                db.name,
+                db.added().len(),
+                db.removed().len(),
+                db.changed().len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the environments in the directories `a` and `b`, which can be a storage and a copy
+/// of it such as a backup or a follower.  Neither is created or written to, and neither may be
+/// open in this process already; compare open storages with `Storage::diff` instead.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let dir = tempfile::tempdir()?;
+///     let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+///     for path in &[&a, &b] {
+///         let mut storage = Storage::new(path.as_path())?;
+///         storage.save(&Place { id: 1, name: "Paris".to_string() })?;
+///     }
+///     Storage::new(b.as_path())?.save(&Place { id: 2, name: "Lyon".to_string() })?;
+///
+///     let diff = nostalgia::diff(&a, &b)?;
+///     assert_eq!(1, diff.databases.len());
+///     assert_eq!(1, diff.databases[0].added().len());
+///     print!("{}", diff);
+///     Ok(())
+/// }
+/// ```
+pub fn diff<P: Into<PathBuf>, Q: Into<PathBuf>>(a: P, b: Q) -> Result<Diff, StorageError> {
+    let a = Storage::open_existing(a)?;
+    let b = Storage::open_existing(b)?;
+    compare(a.env()?, b.env()?)
+}
+
+/// Compares every database of two environments
+pub(crate) fn compare(a: &Environment, b: &Environment) -> Result<Diff, StorageError> {
+    let (dbs_a, dbs_b) = (databases(a)?, databases(b)?);
+    let mut names: Vec<&String> = dbs_a.iter().chain(&dbs_b).map(|(name, _)| name).collect();
+    names.sort();
+    names.dedup();
+
+    let (txn_a, txn_b) = (a.begin_ro_txn()?, b.begin_ro_txn()?);
+    let mut diff = Diff::default();
+    for name in names {
+        let db_a = find(&dbs_a, name);
+        let db_b = find(&dbs_b, name);
+        let flags = match (db_a, db_b) {
+            (_, Some(db)) => txn_b.db_flags(db)?,
+            (Some(db), None) => txn_a.db_flags(db)?,
+            (None, None) => continue,
+        };
+
+        let changes = compare_entries(
+            &pairs(&txn_a, db_a)?,
+            &pairs(&txn_b, db_b)?,
+            flags.contains(DatabaseFlags::DUP_SORT),
+        );
+        if !changes.is_empty() {
+            diff.databases.push(DatabaseDiff {
+                name: name.clone(),
+                flags: flags.bits(),
+                changes,
+            });
+        }
+    }
+    Ok(diff)
+}
+
+/// Reads a patch written by `Diff::write_patch`
+pub(crate) fn read_patch(path: &Path) -> Result<Vec<ChangeOp>, StorageError> {
+    let patch: Patch = bincode::deserialize_from(BufReader::new(File::open(path)?))?;
+    if patch.version != PATCH_VERSION {
+        return Err(StorageError::InvalidPatch {
+            reason: format!(
+                "version {} isn't supported, only version {} is",
+                patch.version, PATCH_VERSION
+            ),
+        });
+    }
+    Ok(patch.ops)
+}
+
+fn databases(env: &Environment) -> Result<Vec<(String, Database)>, StorageError> {
+    let mut dbs = vec![];
+    for name in database_names(env)? {
+        if name == JOURNAL_DB || name == REPLICA_DB {
+            continue;
+        }
+        // Other tools can keep plain records in the unnamed database next to the names
+        match env.open_db(Some(&name)) {
+            Ok(db) => dbs.push((name, db)),
+            Err(lmdb::Error::Incompatible) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(dbs)
+}
+
+fn find(dbs: &[(String, Database)], name: &str) -> Option<Database> {
+    dbs.iter()
+        .find(|(db_name, _)| db_name == name)
+        .map(|(_, db)| *db)
+}
+
+// The entries of a database in order, borrowed from the transaction's snapshot
+fn pairs<'t>(txn: &'t RoTransaction, db: Option<Database>) -> Result<Vec<Pair<'t>>, StorageError> {
+    match db {
+        Some(db) => query::checked_iter(&txn.open_ro_cursor(db)?).collect(),
+        None => Ok(vec![]),
+    }
+}
+
+// Walks both sides in order at once.  With sorted duplicates a key's values are entries of their
+// own, ordered by value after key like LMDB orders them
+fn compare_entries(a: &[Pair], b: &[Pair], dup_sort: bool) -> Vec<KeyChange> {
+    let change = |key: &[u8], before: Option<&[u8]>, after: Option<&[u8]>| KeyChange {
+        key: key.to_vec(),
+        before: before.map(<[u8]>::to_vec),
+        after: after.map(<[u8]>::to_vec),
+    };
+
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let order = match (a.get(i), b.get(j)) {
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(x), Some(y)) if dup_sort => x.cmp(y),
+            (Some(x), Some(y)) => x.0.cmp(y.0),
+            (None, None) => break,
+        };
+        match order {
+            Ordering::Less => {
+                changes.push(change(a[i].0, Some(a[i].1), None));
+                i += 1;
+            }
+            Ordering::Greater => {
+                changes.push(change(b[j].0, None, Some(b[j].1)));
+                j += 1;
+            }
+            Ordering::Equal => {
+                if a[i].1 != b[j].1 {
+                    changes.push(change(a[i].0, Some(a[i].1), Some(b[j].1)));
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+    }
+
+    fn station(id: u32, line: &str) -> Station {
+        Station {
+            id,
+            line: line.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_that_a_patch_turns_one_environment_into_the_other() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        {
+            let mut a = Storage::new(a.as_path()).unwrap();
+            let mut b = Storage::new(b.as_path()).unwrap();
+            for storage in &mut [&mut a, &mut b] {
+                storage.save(&station(1, "M1")).unwrap();
+                storage.save(&station(2, "M1")).unwrap();
+            }
+            a.save(&station(3, "M4")).unwrap();
+            b.save(&station(2, "M4")).unwrap();
+            b.kv("settings").unwrap().set("theme", &"dark").unwrap();
+        }
+
+        let diff = diff(&a, &b).unwrap();
+        let names: Vec<_> = diff.databases.iter().map(|db| db.name.as_str()).collect();
+        assert_eq!(vec!["Station", "Station.line", "settings#kv"], names);
+        let records = &diff.databases[0];
+        assert_eq!(vec![&2u32.to_be_bytes()[..]], records.changed());
+        assert_eq!(vec![&3u32.to_be_bytes()[..]], records.removed());
+        assert!(records.added().is_empty());
+        // Index entries are duplicates, so moving one removes it from one key and adds it to another
+        let index = &diff.databases[1];
+        assert_eq!(1, index.added().len());
+        assert_eq!(2, index.removed().len());
+        assert!(index.changed().is_empty());
+        assert_eq!(
+            "Station: 0 added, 1 removed, 1 changed\n\
+             Station.line: 1 added, 2 removed, 0 changed\n\
+             settings#kv: 1 added, 0 removed, 0 changed\n",
+            diff.to_string()
+        );
+
+        let patch = dir.path().join("patch");
+        assert_eq!(6, diff.write_patch(&patch).unwrap());
+        let mut a = Storage::new(a.as_path()).unwrap();
+        assert_eq!(6, a.apply_patch(&patch).unwrap());
+        let m4: Vec<Station> = a.find_by_index("line", "M4").unwrap();
+        assert_eq!(vec![station(2, "M4")], m4);
+        assert!(a.get::<Station, _>(3u32).unwrap().is_none());
+
+        let b = Storage::new(b.as_path()).unwrap();
+        assert!(a.diff(&b).unwrap().is_empty());
+    }
+}
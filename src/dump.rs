@@ -0,0 +1,248 @@
+//! Dumps of every database in an environment, written to and read from a tar archive.
+//!
+//! The archive starts with `manifest.json`, which holds the dump's format version and the name,
+//! flags and entry count of every database, followed by one JSON Lines file per database in the
+//! manifest's order.  Each line holds one entry's key and value in hex, so a dump can be read
+//! without this crate and loaded into any engine that takes raw keys and values.
+
+use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::usage::database_names;
+use crate::query;
+use crate::StorageError;
+
+/// The version of the dump format written by `Storage::dump`
+pub const DUMP_VERSION: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    databases: Vec<DumpedDatabase>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DumpedDatabase {
+    name: String,
+    flags: u32,
+    entries: usize,
+    file: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    key: String,
+    value: String,
+}
+
+fn invalid(reason: impl Into<String>) -> StorageError {
+    StorageError::InvalidDump {
+        reason: reason.into(),
+    }
+}
+
+fn append(builder: &mut tar::Builder<File>, path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, bytes)
+}
+
+/// Writes every named database in the environment to a tar archive at `path`, as of a single
+/// snapshot.  Returns the number of entries written
+pub(crate) fn dump(env: &Environment, path: &Path) -> Result<usize, StorageError> {
+    let mut dbs = vec![];
+    for name in database_names(env)? {
+        // Other tools can keep plain records in the unnamed database next to the names
+        match env.open_db(Some(&name)) {
+            Ok(db) => dbs.push((name, db)),
+            Err(lmdb::Error::Incompatible) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let txn = env.begin_ro_txn()?;
+    let mut manifest = Manifest {
+        version: DUMP_VERSION,
+        databases: vec![],
+    };
+    let mut files = vec![];
+    for (i, (name, db)) in dbs.into_iter().enumerate() {
+        let mut lines = vec![];
+        let mut entries = 0;
+        let cursor = txn.open_ro_cursor(db)?;
+        for entry in query::checked_iter(&cursor) {
+            let (key, value) = entry?;
+            let line = Line {
+                key: hex::encode(key),
+                value: hex::encode(value),
+            };
+            serde_json::to_writer(&mut lines, &line)?;
+            lines.push(b'\n');
+            entries += 1;
+        }
+
+        let file = format!("databases/{:04}.jsonl", i);
+        manifest.databases.push(DumpedDatabase {
+            name,
+            flags: txn.db_flags(db)?.bits(),
+            entries,
+            file: file.clone(),
+        });
+        files.push((file, lines));
+    }
+
+    let mut builder = tar::Builder::new(File::create(path)?);
+    append(
+        &mut builder,
+        MANIFEST,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    for (file, lines) in &files {
+        append(&mut builder, file, lines)?;
+    }
+    builder.into_inner()?.sync_all()?;
+
+    Ok(manifest.databases.iter().map(|db| db.entries).sum())
+}
+
+/// Restores the databases of a dump at `path` into an environment without named databases, in a
+/// single transaction that nothing else may open databases during.  Returns the number of entries restored
+pub(crate) fn load(env: &Environment, path: &Path) -> Result<usize, StorageError> {
+    if !database_names(env)?.is_empty() {
+        return Err(StorageError::NotEmpty);
+    }
+
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut entries = archive.entries()?;
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.to_str() != Some(MANIFEST) {
+                return Err(invalid("the archive doesn't start with a manifest"));
+            }
+            let mut bytes = vec![];
+            entry.read_to_end(&mut bytes)?;
+            serde_json::from_slice(&bytes)?
+        }
+        None => return Err(invalid("the archive is empty")),
+    };
+    if manifest.version != DUMP_VERSION {
+        return Err(invalid(format!(
+            "version {} isn't supported, only version {} is",
+            manifest.version, DUMP_VERSION
+        )));
+    }
+
+    // The databases are created in the same transaction as their entries, so a dump that fails
+    // to load leaves the environment empty
+    let mut txn = env.begin_rw_txn()?;
+    let mut dbs = vec![];
+    for dumped in &manifest.databases {
+        let flags = DatabaseFlags::from_bits(dumped.flags)
+            .ok_or_else(|| invalid(format!("{} has unknown flags", dumped.name)))?;
+        // Safe since `Storage::load` makes sure no other handle shares the environment, so no
+        // other transaction can open a database before this one ends
+        dbs.push(unsafe { txn.create_db(Some(&dumped.name), flags)? });
+    }
+
+    let mut restored = 0;
+    for (dumped, db) in manifest.databases.iter().zip(dbs) {
+        let entry = match entries.next() {
+            Some(entry) => entry?,
+            None => return Err(invalid(format!("{} is missing", dumped.file))),
+        };
+        if entry.path()?.to_str() != Some(dumped.file.as_str()) {
+            return Err(invalid(format!("expected {} next", dumped.file)));
+        }
+
+        let mut count = 0;
+        for line in BufReader::new(entry).lines() {
+            let line: Line = serde_json::from_str(&line?)?;
+            let key = hex::decode(&line.key).map_err(|e| invalid(e.to_string()))?;
+            let value = hex::decode(&line.value).map_err(|e| invalid(e.to_string()))?;
+            txn.put(db, &key, &value, WriteFlags::empty())?;
+            count += 1;
+        }
+        if count != dumped.entries {
+            return Err(invalid(format!(
+                "{} holds {} entries, the manifest lists {}",
+                dumped.name, count, dumped.entries
+            )));
+        }
+        restored += count;
+    }
+    txn.commit()?;
+
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage, StorageError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+    }
+
+    fn station(id: u32, line: &str) -> Station {
+        Station {
+            id,
+            line: line.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_that_a_dump_restores_every_database() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let path = dir.path().join("dump.tar");
+
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.save(&station(1, "M1")).unwrap();
+        storage.save(&station(2, "M1")).unwrap();
+        storage.save(&station(3, "RER A")).unwrap();
+        storage
+            .kv("settings")
+            .unwrap()
+            .set("theme", &"dark")
+            .unwrap();
+        storage
+            .raw("blobs")
+            .unwrap()
+            .put_bytes(&[0, 255], &[1, 2, 3])
+            .unwrap();
+        let written = storage.dump(&path).unwrap();
+
+        let mut restored = Storage::temporary().expect("Could not open db storage");
+        assert_eq!(written, restored.load(&path).unwrap());
+        assert_eq!(
+            Some(station(3, "RER A")),
+            restored.get::<Station, _>(3u32).unwrap()
+        );
+        let m1: Vec<Station> = restored.find_by_index("line", "M1").unwrap();
+        assert_eq!(2, m1.len());
+        let theme: Option<String> = restored.kv("settings").unwrap().get("theme").unwrap();
+        assert_eq!(Some("dark".to_string()), theme);
+        let raw = restored.raw("blobs").unwrap().get_bytes(&[0, 255]).unwrap();
+        assert_eq!(Some(vec![1, 2, 3]), raw);
+
+        // Loading on top of existing databases would mix two environments
+        assert!(matches!(restored.load(&path), Err(StorageError::NotEmpty)));
+
+        std::fs::write(&path, b"not a tar archive").unwrap();
+        let mut empty = Storage::temporary().expect("Could not open db storage");
+        assert!(empty.load(&path).is_err());
+        assert!(empty.disk_usage().unwrap().databases.is_empty());
+    }
+}
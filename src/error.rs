@@ -0,0 +1,187 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::quota::{Quota, TenantUsage};
+use crate::validation::FieldError;
+use crate::KeyError;
+
+/// Errors that can arise from interacting with Storage
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("could not access database directory")]
+    FileError {
+        #[from]
+        source: std::io::Error,
+    },
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("could not process database command")]
+    DBError {
+        #[from]
+        source: lmdb::Error,
+    },
+
+    #[error("can't delete {parent} while {count} {child} records belong to it")]
+    RestrictViolation {
+        parent: &'static str,
+        child: &'static str,
+        count: usize,
+    },
+
+    #[error("can't set the {foreign_key} of {child} records to null")]
+    NotNullable {
+        child: &'static str,
+        foreign_key: &'static str,
+    },
+
+    #[error("storage is closed")]
+    Closed,
+
+    #[error("record failed validation: {}", display_field_errors(.0))]
+    Validation(Vec<FieldError>),
+
+    #[error("no record is stored under {key:?} in {db_name}")]
+    NotFound { db_name: &'static str, key: Vec<u8> },
+
+    #[error("{db_name} has no keys left to generate")]
+    KeysExhausted { db_name: &'static str },
+
+    #[error("could not decode the record stored under {key:?} in {db_name}")]
+    Undecodable { db_name: &'static str, key: Vec<u8> },
+
+    #[error("could not decode the key {key:?} stored in {db_name}")]
+    InvalidKey {
+        db_name: &'static str,
+        key: Vec<u8>,
+        source: KeyError,
+    },
+
+    #[error("{db_name} belongs to another partition")]
+    WrongPartition { db_name: &'static str },
+
+    #[error("{tenant:?} is not a valid tenant id")]
+    InvalidTenant { tenant: String },
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("{tenant} is over its quota of {quota:?}")]
+    QuotaExceeded {
+        tenant: String,
+        usage: TenantUsage,
+        quota: Quota,
+    },
+
+    #[error("a record of {bytes} bytes can't fit in {db_name}, which is capped at {max_bytes}")]
+    OverCap {
+        db_name: &'static str,
+        bytes: u64,
+        max_bytes: u64,
+    },
+
+    #[error("{registered} can't be stored in {db_name}, it is already used by {existing}")]
+    DbNameTaken {
+        db_name: &'static str,
+        existing: &'static str,
+        registered: &'static str,
+    },
+
+    #[error("{db_name} holds {stored} records, not {expected}")]
+    TypeMismatch {
+        db_name: &'static str,
+        stored: String,
+        expected: &'static str,
+    },
+
+    #[error("{db_name} was written with schema version {stored:x}, the type's is {current:x}")]
+    SchemaChanged {
+        db_name: &'static str,
+        stored: u64,
+        current: u64,
+    },
+
+    #[error("another record in {db_name} has the same {index}")]
+    UniqueViolation {
+        db_name: &'static str,
+        index: &'static str,
+    },
+
+    #[error("{db_name} has no index named {index}")]
+    UnknownIndex {
+        db_name: &'static str,
+        index: String,
+    },
+
+    #[error("index {index} of {db_name} doesn't include any fields")]
+    UncoveredIndex {
+        db_name: &'static str,
+        index: String,
+    },
+
+    #[error("{db_name} records don't keep metadata")]
+    NoMetadata { db_name: &'static str },
+
+    #[error("the group the write was committed with failed: {reason}")]
+    GroupCommitFailed { reason: String },
+
+    #[error("environment is still used by read handles")]
+    Shared,
+
+    #[error("read transaction has been open for {age:?}")]
+    ReadTooOld { age: std::time::Duration },
+
+    #[error("no storage exists at {path:?}")]
+    Missing { path: PathBuf },
+
+    #[error("a storage already exists at {path:?}")]
+    AlreadyExists { path: PathBuf },
+
+    #[error("storage already holds databases")]
+    NotEmpty,
+
+    #[error("invalid dump: {reason}")]
+    InvalidDump { reason: String },
+
+    #[error("invalid patch: {reason}")]
+    InvalidPatch { reason: String },
+
+    #[error("the copy of {database} doesn't match the original")]
+    MigrationMismatch { database: String },
+
+    #[error("expected change {expected} next, but the journal continues at {found}")]
+    ReplicationGap { expected: u64, found: u64 },
+
+    #[error("remote storage failed: {reason}")]
+    Remote { reason: String },
+
+    #[error("server failed: {reason}")]
+    Server { reason: String },
+
+    #[error("browser storage failed: {reason}")]
+    Browser { reason: String },
+
+    #[error("storage doesn't keep record versions")]
+    VersionsNotTracked,
+
+    #[error("{db_name} isn't a registered record type")]
+    UnregisteredType { db_name: String },
+
+    #[error("could not serialize or deserialize a value")]
+    Codec {
+        #[from]
+        source: bincode::Error,
+    },
+
+    #[error("could not convert record to or from JSON")]
+    Json {
+        #[from]
+        source: serde_json::Error,
+    },
+}
+
+fn display_field_errors(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(FieldError::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
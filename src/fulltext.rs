@@ -0,0 +1,126 @@
+//! Tokenizing used by the full-text index.
+//!
+//! Text is split on anything that isn't alphanumeric and lowercased, so "Ed Koch's" becomes
+//! `["ed", "koch", "s"]`.  Each distinct token of a record is stored as one index entry.
+
+use std::collections::BTreeSet;
+
+use crate::IndexEntry;
+
+/// The name of the index that holds a record type's full-text tokens
+pub const FULLTEXT_INDEX: &str = "fulltext";
+
+/// Splits text into lowercase alphanumeric tokens
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+/// Builds one full-text index entry for every distinct token in `fields`
+pub fn index_entries(fields: &[&str]) -> Vec<IndexEntry> {
+    fields
+        .iter()
+        .flat_map(|field| tokenize(field))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|token| IndexEntry::from_value(FULLTEXT_INDEX, &token))
+        .collect()
+}
+
+/// Counts how often the query's tokens appear in `fields`
+pub(crate) fn term_frequency(fields: &[&str], query: &[String]) -> usize {
+    fields
+        .iter()
+        .flat_map(|field| tokenize(field))
+        .filter(|token| query.contains(token))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::encode;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[fulltext = "title, body"]
+    struct Article {
+        id: u32,
+        title: String,
+        body: String,
+    }
+
+    fn article(id: u32, title: &str, body: &str) -> Article {
+        Article {
+            id,
+            title: title.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    fn search_ids(storage: &mut Storage, query: &str) -> Vec<u32> {
+        storage
+            .search::<Article>(query)
+            .expect("Could not search")
+            .iter()
+            .map(|a| a.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_that_text_is_tokenized_into_lowercase_words() {
+        assert_eq!(
+            vec!["ed", "koch", "s", "new", "york", "1978"],
+            tokenize("Ed Koch's New-York, 1978!")
+        );
+        assert!(tokenize("  ...  ").is_empty());
+    }
+
+    #[test]
+    fn test_that_index_entries_are_distinct_tokens() {
+        let entries = index_entries(&["the cat", "The Hat"]);
+        let tokens: Vec<Vec<u8>> = entries.into_iter().map(|e| e.value).collect();
+
+        assert_eq!(vec![encode("cat"), encode("hat"), encode("the")], tokens);
+        assert_eq!(
+            2,
+            term_frequency(&["the cat", "The Hat"], &["the".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_that_search_ranks_records_and_follows_updates() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        storage
+            .save_batch(vec![
+                article(1, "Subway", "The subway runs all night"),
+                article(
+                    2,
+                    "Subway fares",
+                    "Subway fares go up, subway riders grumble",
+                ),
+                article(3, "Parks", "Central Park reopens"),
+            ])
+            .expect("Could not save articles");
+
+        assert_eq!(vec![2, 1], search_ids(&mut storage, "SUBWAY"));
+        assert_eq!(vec![2, 1, 3], search_ids(&mut storage, "subway park"));
+        assert!(search_ids(&mut storage, "bridge").is_empty());
+
+        storage
+            .save(&article(1, "Bridges", "The bridge opens"))
+            .expect("Could not update article");
+        assert_eq!(vec![2], search_ids(&mut storage, "subway"));
+        assert_eq!(vec![1], search_ids(&mut storage, "bridge"));
+
+        storage
+            .delete(&article(2, "", ""))
+            .expect("Could not delete article");
+        assert!(search_ids(&mut storage, "subway").is_empty());
+    }
+}
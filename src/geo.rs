@@ -0,0 +1,284 @@
+//! Encoding used by the geospatial index.
+//!
+//! Latitude and longitude are each scaled to 32 bits and their bits interleaved into a Z-order
+//! code, so points close to each other mostly get codes close to each other.  A bounding box is
+//! covered by the code ranges of the cells that overlap it, and the records found in those ranges
+//! are then checked against the box itself.
+
+use crate::IndexEntry;
+
+/// The name of the index that holds a record type's geospatial codes
+pub const GEO_INDEX: &str = "geo";
+
+/// The mean radius of the earth in meters
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// An area between two latitudes and two longitudes, in degrees.  A box whose `west` edge lies
+/// east of its `east` edge crosses the antimeridian
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    /// The southern edge's latitude
+    pub south: f64,
+    /// The western edge's longitude
+    pub west: f64,
+    /// The northern edge's latitude
+    pub north: f64,
+    /// The eastern edge's longitude
+    pub east: f64,
+}
+
+impl BoundingBox {
+    /// The box between the south-west corner at `(south, west)` and the north-east corner at
+    /// `(north, east)`
+    pub fn new(south: f64, west: f64, north: f64, east: f64) -> BoundingBox {
+        BoundingBox {
+            south,
+            west,
+            north,
+            east,
+        }
+    }
+
+    /// Whether a `(latitude, longitude)` point lies in the box, edges included
+    pub fn contains(&self, (lat, lon): (f64, f64)) -> bool {
+        let within_lon = if self.west <= self.east {
+            lon >= self.west && lon <= self.east
+        } else {
+            lon >= self.west || lon <= self.east
+        };
+        lat >= self.south && lat <= self.north && within_lon
+    }
+
+    // The smallest box holding every point within `degrees` of arc of `point`
+    pub(crate) fn around((lat, lon): (f64, f64), degrees: f64) -> BoundingBox {
+        let south = (lat - degrees).max(-90.0);
+        let north = (lat + degrees).min(90.0);
+        // Once a pole is within reach every longitude is
+        if lat.abs() + degrees >= 90.0 {
+            return BoundingBox::new(south, -180.0, north, 180.0);
+        }
+
+        let spread = (degrees.to_radians().sin() / lat.to_radians().cos())
+            .asin()
+            .to_degrees();
+        let (mut west, mut east) = (lon - spread, lon + spread);
+        if west < -180.0 {
+            west += 360.0;
+        }
+        if east > 180.0 {
+            east -= 360.0;
+        }
+        BoundingBox::new(south, west, north, east)
+    }
+}
+
+fn scale(value: f64, min: f64, max: f64) -> u32 {
+    let fraction = ((value - min) / (max - min)).clamp(0.0, 1.0);
+    (fraction * f64::from(u32::MAX)) as u32
+}
+
+// Moves each bit of `value` to twice its position, leaving a zero bit between every two
+fn spread(value: u32) -> u64 {
+    let mut value = u64::from(value);
+    value = (value | (value << 16)) & 0x0000_ffff_0000_ffff;
+    value = (value | (value << 8)) & 0x00ff_00ff_00ff_00ff;
+    value = (value | (value << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+    (value | (value << 1)) & 0x5555_5555_5555_5555
+}
+
+fn interleave(lat: u32, lon: u32) -> u64 {
+    (spread(lat) << 1) | spread(lon)
+}
+
+/// The Z-order code of a point, as stored in the geospatial index
+pub fn encode(lat: f64, lon: f64) -> u64 {
+    interleave(scale(lat, -90.0, 90.0), scale(lon, -180.0, 180.0))
+}
+
+/// Builds the geospatial index entry for a `(latitude, longitude)` point.  Records without a
+/// point, or with coordinates that aren't finite, aren't indexed
+pub fn index_entries(point: Option<(f64, f64)>) -> Vec<IndexEntry> {
+    point
+        .filter(|(lat, lon)| lat.is_finite() && lon.is_finite())
+        .map(|(lat, lon)| IndexEntry::new(GEO_INDEX, encode(lat, lon).to_be_bytes().to_vec()))
+        .into_iter()
+        .collect()
+}
+
+/// The great-circle distance between two `(latitude, longitude)` points, in meters
+pub fn distance((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let half_dlat = (lat2 - lat1) / 2.0;
+    let half_dlon = (lon2 - lon1).to_radians() / 2.0;
+    let a = half_dlat.sin().powi(2) + lat1.cos() * lat2.cos() * half_dlon.sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+}
+
+/// How many meters `degrees` of arc span on the earth's surface
+pub(crate) fn arc_length(degrees: f64) -> f64 {
+    degrees.to_radians() * EARTH_RADIUS
+}
+
+/// The ranges of codes, in order and inclusive, of the cells that cover `bounds`
+pub(crate) fn cell_ranges(bounds: &BoundingBox) -> Vec<(u64, u64)> {
+    if bounds.west > bounds.east {
+        let mut ranges = cell_ranges(&BoundingBox::new(
+            bounds.south,
+            bounds.west,
+            bounds.north,
+            180.0,
+        ));
+        ranges.extend(cell_ranges(&BoundingBox::new(
+            bounds.south,
+            -180.0,
+            bounds.north,
+            bounds.east,
+        )));
+        ranges.sort_unstable();
+        return ranges;
+    }
+
+    let lat = (
+        u64::from(scale(bounds.south, -90.0, 90.0)),
+        u64::from(scale(bounds.north, -90.0, 90.0)),
+    );
+    let lon = (
+        u64::from(scale(bounds.west, -180.0, 180.0)),
+        u64::from(scale(bounds.east, -180.0, 180.0)),
+    );
+    if lat.0 > lat.1 || lon.0 > lon.1 {
+        return vec![];
+    }
+
+    // Cells about a quarter of the box's size, which keeps the ranges few and the codes they take
+    // in from outside the box a small part of them
+    let span = (lat.1 - lat.0).max(lon.1 - lon.0) + 1;
+    let depth = (34 - (63 - span.leading_zeros())).min(32);
+    let mut ranges = vec![];
+    cover(0, (0, 0), (lat, lon), depth, &mut ranges);
+    ranges
+}
+
+// Adds the ranges of the cell at `level` with its south-west corner at `corner`, or of its
+// quarters, that overlap the scaled box
+fn cover(
+    level: u32,
+    corner: (u64, u64),
+    bounds: ((u64, u64), (u64, u64)),
+    depth: u32,
+    ranges: &mut Vec<(u64, u64)>,
+) {
+    let size = 1u64 << (32 - level);
+    let (lat, lon) = corner;
+    let ((south, north), (west, east)) = bounds;
+    if lat > north || lat + size - 1 < south || lon > east || lon + size - 1 < west {
+        return;
+    }
+
+    let inside = lat >= south && lat + size - 1 <= north && lon >= west && lon + size - 1 <= east;
+    if inside || level == depth {
+        let start = interleave(lat as u32, lon as u32);
+        let end = start + (u128::from(size) * u128::from(size) - 1) as u64;
+        match ranges.last_mut() {
+            Some(last) if last.1.checked_add(1) == Some(start) => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+        return;
+    }
+
+    // The quarters in the order of their codes, since latitude takes the higher bit of each pair
+    let half = size / 2;
+    for (up, right) in [(0, 0), (0, half), (half, 0), (half, half)] {
+        cover(level + 1, (lat + up, lon + right), bounds, depth, ranges);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[geo = "lat, lon"]
+    struct City {
+        id: u32,
+        name: String,
+        lat: f64,
+        lon: f32,
+    }
+
+    fn city(id: u32, name: &str, lat: f64, lon: f32) -> City {
+        City {
+            id,
+            name: name.to_string(),
+            lat,
+            lon,
+        }
+    }
+
+    fn names(cities: &[City]) -> Vec<&str> {
+        cities.iter().map(|city| city.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_that_cells_cover_the_box() {
+        let bounds = BoundingBox::new(48.0, 1.5, 49.5, 3.5);
+        let ranges = cell_ranges(&bounds);
+        assert!(!ranges.is_empty() && ranges.len() < 64);
+        assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0));
+
+        for (lat, lon) in &[(48.0, 1.5), (48.8566, 2.3522), (49.5, 3.5)] {
+            let code = encode(*lat, *lon);
+            assert!(ranges
+                .iter()
+                .any(|(start, end)| code >= *start && code <= *end));
+        }
+        assert_eq!(
+            vec![(0, u64::MAX)],
+            cell_ranges(&BoundingBox::new(-90.0, -180.0, 90.0, 180.0))
+        );
+    }
+
+    #[test]
+    fn test_that_records_are_found_by_area_and_distance() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        for city in [
+            city(1, "Paris", 48.8566, 2.3522),
+            city(2, "Versailles", 48.8049, 2.1204),
+            city(3, "Lyon", 45.764, 4.8357),
+            city(4, "London", 51.5074, -0.1278),
+            city(5, "Suva", -18.1248, 178.4501),
+            city(6, "Apia", -13.8507, -171.7514),
+        ] {
+            storage.save(&city).unwrap();
+        }
+
+        let around_paris = storage
+            .within_bbox::<City>(BoundingBox::new(48.0, 1.5, 49.5, 3.5))
+            .unwrap();
+        assert_eq!(vec!["Paris", "Versailles"], names(&around_paris));
+
+        let pacific = storage
+            .within_bbox::<City>(BoundingBox::new(-20.0, 170.0, -10.0, -170.0))
+            .unwrap();
+        assert_eq!(vec!["Suva", "Apia"], names(&pacific));
+
+        let nearest = storage.nearest::<City>((48.85, 2.35), 3).unwrap();
+        assert_eq!(vec!["Paris", "Versailles", "London"], names(&nearest));
+        assert_eq!(6, storage.nearest::<City>((0.0, 0.0), 10).unwrap().len());
+
+        // Moving a city moves its index entry along with it
+        storage.save(&city(3, "Lyon", 48.9, 2.4)).unwrap();
+        let around_paris = storage
+            .within_bbox::<City>(BoundingBox::new(48.0, 1.5, 49.5, 3.5))
+            .unwrap();
+        assert_eq!(3, around_paris.len());
+
+        let paris = (48.8566, 2.3522);
+        let london = (51.5074, -0.1278);
+        assert!((distance(paris, london) - 343_500.0).abs() < 1_000.0);
+    }
+}
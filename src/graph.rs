@@ -0,0 +1,305 @@
+//! Labeled edges between records, stored as sorted duplicates.
+//!
+//! Every record with outgoing edges has one key in the edges database, made of its type's
+//! database name and its key.  Each edge is a duplicate under it holding the other record's
+//! database name, the label and the other record's key, so the edges of a record to one type, or
+//! to one type under one label, sit next to each other.
+
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, RwTransaction, Transaction, WriteFlags};
+
+use crate::type_tag::{self, TYPES_DB};
+use crate::{metadata, FieldError, Record, StorageError};
+
+/// The database that holds every edge
+pub(crate) const EDGES_DB: &str = "nostalgia#edges";
+
+pub(crate) fn edges_db_flags() -> DatabaseFlags {
+    DatabaseFlags::DUP_SORT
+}
+
+const SEPARATOR: u8 = 0;
+
+fn node<T: Record>(key: T::Key) -> Vec<u8> {
+    let mut node = T::db_name().as_bytes().to_vec();
+    node.push(SEPARATOR);
+    node.extend(key.into());
+    node
+}
+
+// The start of every edge to a `T`, or with a label the start of every edge to a `T` under it
+fn edge_prefix<T: Record>(label: Option<&str>) -> Vec<u8> {
+    let mut prefix = T::db_name().as_bytes().to_vec();
+    prefix.push(SEPARATOR);
+    if let Some(label) = label {
+        prefix.extend(label.as_bytes());
+        prefix.push(SEPARATOR);
+    }
+    prefix
+}
+
+fn edge<T: Record>(label: &str, key: T::Key) -> Vec<u8> {
+    let mut edge = edge_prefix::<T>(Some(label));
+    edge.extend(key.into());
+    edge
+}
+
+// Splits the part of an edge after its database name into the label and the key
+fn split_edge(rest: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = rest.iter().position(|byte| *byte == SEPARATOR)?;
+    Some((&rest[..end], &rest[end + 1..]))
+}
+
+/// Labeled, directed edges from records of one type to records of another, see `Storage::graph`.
+///
+/// An edge is kept apart from the records it joins, so deleting a record leaves its edges
+/// behind.  Edges to records that no longer exist are skipped when reading neighbors.  Labels
+/// can't contain a NUL byte.
+pub struct Graph<'s> {
+    env: &'s Environment,
+    db: Database,
+}
+
+impl<'s> Graph<'s> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> Graph<'s> {
+        Graph { env, db }
+    }
+
+    /// Adds an edge labeled `label` from the `A` under `from` to the `B` under `to`.  Adding an
+    /// edge that exists already does nothing
+    pub fn link<A, B, KA, KB>(&self, from: KA, to: KB, label: &str) -> Result<(), StorageError>
+    where
+        A: Record,
+        B: Record,
+        KA: Into<A::Key>,
+        KB: Into<B::Key>,
+    {
+        if label.as_bytes().contains(&SEPARATOR) {
+            return Err(StorageError::Validation(vec![FieldError::new(
+                "label",
+                "can't contain a NUL byte",
+            )]));
+        }
+
+        let mut txn = self.env.begin_rw_txn()?;
+        let edge = edge::<B>(label, to.into());
+        match txn.put(
+            self.db,
+            &node::<A>(from.into()),
+            &edge,
+            WriteFlags::NO_DUP_DATA,
+        ) {
+            Ok(()) | Err(lmdb::Error::KeyExist) => {}
+            Err(e) => return Err(e.into()),
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Removes the edge labeled `label` from the `A` under `from` to the `B` under `to`,
+    /// returning whether there was one
+    pub fn unlink<A, B, KA, KB>(&self, from: KA, to: KB, label: &str) -> Result<bool, StorageError>
+    where
+        A: Record,
+        B: Record,
+        KA: Into<A::Key>,
+        KB: Into<B::Key>,
+    {
+        let mut txn = self.env.begin_rw_txn()?;
+        let removed = remove(
+            &mut txn,
+            self.db,
+            &node::<A>(from.into()),
+            &edge::<B>(label, to.into()),
+        )?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    /// The `B`s the `A` under `from` has edges to, under any label, along with each edge's label.
+    /// Ordered by label and then by key
+    pub fn neighbors<A, B, K>(&self, from: K) -> Result<Vec<(String, B)>, StorageError>
+    where
+        A: Record,
+        B: Record,
+        K: Into<A::Key>,
+    {
+        self.read::<B>(node::<A>(from.into()), None)
+    }
+
+    /// The `B`s the `A` under `from` has edges labeled `label` to, ordered by key
+    pub fn neighbors_labeled<A, B, K>(&self, from: K, label: &str) -> Result<Vec<B>, StorageError>
+    where
+        A: Record,
+        B: Record,
+        K: Into<A::Key>,
+    {
+        Ok(self
+            .read::<B>(node::<A>(from.into()), Some(label))?
+            .into_iter()
+            .map(|(_, neighbor)| neighbor)
+            .collect())
+    }
+
+    fn read<B: Record>(
+        &self,
+        node: Vec<u8>,
+        label: Option<&str>,
+    ) -> Result<Vec<(String, B)>, StorageError> {
+        let records = match self.env.open_db(Some(B::db_name())) {
+            Ok(db) => db,
+            Err(lmdb::Error::NotFound) => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let types = match self.env.open_db(Some(TYPES_DB)) {
+            Ok(db) => Some(db),
+            Err(lmdb::Error::NotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let txn = self.env.begin_ro_txn()?;
+        type_tag::check::<B>(&txn, types)?;
+        let prefix = edge_prefix::<B>(label);
+        let edges: Vec<Vec<u8>> = {
+            let cursor = txn.open_ro_cursor(self.db)?;
+            // Lands on the first edge of `node` that sorts at or after `prefix`
+            match cursor.get(Some(&node), Some(&prefix), lmdb_sys::MDB_GET_BOTH_RANGE) {
+                Ok(_) => {}
+                Err(lmdb::Error::NotFound) => return Ok(vec![]),
+                Err(e) => return Err(e.into()),
+            }
+            let mut edges = vec![];
+            let mut op = lmdb_sys::MDB_GET_CURRENT;
+            loop {
+                match cursor.get(None, None, op) {
+                    Ok((_, edge)) if edge.starts_with(&prefix) => edges.push(edge.to_vec()),
+                    Ok(_) | Err(lmdb::Error::NotFound) => break,
+                    Err(e) => return Err(e.into()),
+                }
+                op = lmdb_sys::MDB_NEXT_DUP;
+            }
+            edges
+        };
+
+        // Past the database name and the separator after it
+        let start = B::db_name().len() + 1;
+        let mut neighbors = vec![];
+        for edge in &edges {
+            let (label, key) = match split_edge(&edge[start..]) {
+                Some(parts) => parts,
+                None => return Err(lmdb::Error::Corrupted.into()),
+            };
+            let neighbor = match txn.get(records, &key) {
+                Ok(bytes) => Some(metadata::decode::<B>(key, bytes)?),
+                Err(lmdb::Error::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(neighbor) = neighbor {
+                neighbors.push((String::from_utf8_lossy(label).into_owned(), neighbor));
+            }
+        }
+        Ok(neighbors)
+    }
+}
+
+// Removes a single edge.  `RwTransaction::del` can't be used on duplicates with lmdb 0.8, see
+// `index::remove`
+fn remove(
+    txn: &mut RwTransaction,
+    db: Database,
+    node: &[u8],
+    edge: &[u8],
+) -> Result<bool, StorageError> {
+    let mut cursor = txn.open_rw_cursor(db)?;
+    match cursor.get(Some(node), Some(edge), lmdb_sys::MDB_GET_BOTH) {
+        Ok(_) => {
+            cursor.del(WriteFlags::empty())?;
+            Ok(true)
+        }
+        Err(lmdb::Error::NotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Person {
+        id: u32,
+        name: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Project {
+        id: u32,
+        title: String,
+    }
+
+    #[test]
+    fn test_that_neighbors_are_found_by_type_and_label() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        for (id, name) in &[(1, "Ada"), (2, "Grace"), (3, "Edsger")] {
+            storage
+                .save(&Person {
+                    id: *id,
+                    name: name.to_string(),
+                })
+                .unwrap();
+        }
+        storage
+            .save(&Project {
+                id: 1,
+                title: "Compiler".to_string(),
+            })
+            .unwrap();
+
+        let graph = storage.graph().expect("Could not open graph");
+        graph.link::<Person, Person, _, _>(1, 2, "follows").unwrap();
+        graph.link::<Person, Person, _, _>(1, 3, "follows").unwrap();
+        graph.link::<Person, Person, _, _>(1, 3, "follows").unwrap();
+        graph.link::<Person, Person, _, _>(1, 2, "mentors").unwrap();
+        graph.link::<Person, Project, _, _>(1, 1, "owns").unwrap();
+        graph.link::<Person, Person, _, _>(2, 1, "follows").unwrap();
+        assert!(graph.link::<Person, Person, _, _>(2, 3, "a\0b").is_err());
+
+        let neighbors: Vec<(String, Person)> = graph.neighbors::<Person, Person, _>(1).unwrap();
+        let names: Vec<_> = neighbors
+            .iter()
+            .map(|(label, person)| (label.as_str(), person.name.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("follows", "Grace"),
+                ("follows", "Edsger"),
+                ("mentors", "Grace")
+            ],
+            names
+        );
+
+        let mentored = graph
+            .neighbors_labeled::<Person, Person, _>(1, "mentors")
+            .unwrap();
+        assert_eq!(
+            vec!["Grace"],
+            mentored.iter().map(|p| &p.name[..]).collect::<Vec<_>>()
+        );
+        let projects = graph.neighbors::<Person, Project, _>(1).unwrap();
+        assert_eq!("Compiler", projects[0].1.title);
+        assert!(graph.neighbors::<Person, Project, _>(2).unwrap().is_empty());
+
+        assert!(graph
+            .unlink::<Person, Person, _, _>(1, 3, "follows")
+            .unwrap());
+        assert!(!graph
+            .unlink::<Person, Person, _, _>(1, 3, "follows")
+            .unwrap());
+        let followed = graph
+            .neighbors_labeled::<Person, Person, _>(1, "follows")
+            .unwrap();
+        assert_eq!(1, followed.len());
+    }
+}
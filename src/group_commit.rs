@@ -0,0 +1,237 @@
+//! Funneling writes from many threads into shared transactions.
+//!
+//! Each commit waits for the disk to sync, so many small transactions spend most of their time
+//! waiting.  A `GroupCommit` hands every write to a single writer thread, which collects the
+//! writes that arrive within a short window and commits them together, so one sync covers all of
+//! them.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Record, RoStorage, RwStorage, Storage, StorageError, Transaction};
+
+// The most writes committed together, so a steady stream of writes still gets committed
+const MAX_BATCH: usize = 1024;
+
+type Write = Box<dyn FnOnce(&mut Transaction) -> Result<(), StorageError> + Send>;
+
+struct Request {
+    write: Write,
+    done: Sender<Result<(), StorageError>>,
+}
+
+struct Writer {
+    requests: Option<Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // The writer thread stops once every request is in and the channel is closed
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A handle that commits writes from any number of threads in shared transactions
+///
+/// Each write runs in its own nested transaction, so a write that fails is rolled back on its own
+/// and the others in its group are still committed.  Writes return once their group is committed.
+/// Clones share the writer thread, which stops when the last clone is dropped.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{GroupCommit, Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+/// use std::time::Duration;
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let storage = Storage::new("/tmp/nostalgia-group-commit")?;
+///     let writes = GroupCommit::new(storage, Duration::from_millis(2))?;
+///
+///     let threads: Vec<_> = (0..4)
+///         .map(|id| {
+///             let writes = writes.clone();
+///             std::thread::spawn(move || writes.save(Place { id, name: format!("Place {}", id) }))
+///         })
+///         .collect();
+///     for thread in threads {
+///         thread.join().expect("Writer panicked")?;
+///     }
+///
+///     assert!(writes.reader().get::<Place, _>(3)?.is_some());
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct GroupCommit {
+    reader: RoStorage,
+    writer: Arc<Writer>,
+}
+
+impl GroupCommit {
+    /// Starts the writer thread for `storage`, which commits the writes that arrive within
+    /// `window` of the first one together
+    pub fn new(storage: Storage, window: Duration) -> Result<GroupCommit, StorageError> {
+        let (reader, writer) = storage.split()?;
+        let (requests, received) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("nostalgia-group-commit".to_string())
+            .spawn(move || run(writer, received, window))?;
+
+        Ok(GroupCommit {
+            reader,
+            writer: Arc::new(Writer {
+                requests: Some(requests),
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    /// The handle reads go through
+    pub fn reader(&self) -> &RoStorage {
+        &self.reader
+    }
+
+    /// Saves a record, returning once it is committed
+    pub fn save<T: Record + Send + 'static>(&self, record: T) -> Result<(), StorageError> {
+        self.write(move |tx| tx.save(&record))
+    }
+
+    /// Deletes a record, returning once the delete is committed
+    pub fn delete<T: Record + Send + 'static>(&self, record: T) -> Result<(), StorageError> {
+        self.write(move |tx| tx.delete(&record).map(drop))
+    }
+
+    /// Runs `f` in the next group's transaction, returning once it is committed
+    pub fn write<F>(&self, f: F) -> Result<(), StorageError>
+    where
+        F: FnOnce(&mut Transaction) -> Result<(), StorageError> + Send + 'static,
+    {
+        let (done, result) = mpsc::channel();
+        self.writer
+            .requests
+            .as_ref()
+            .ok_or(StorageError::Closed)?
+            .send(Request {
+                write: Box::new(f),
+                done,
+            })
+            .map_err(|_| StorageError::Closed)?;
+        result.recv().unwrap_or(Err(StorageError::Closed))
+    }
+}
+
+fn run(mut storage: RwStorage, requests: Receiver<Request>, window: Duration) {
+    while let Ok(first) = requests.recv() {
+        let deadline = Instant::now() + window;
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match requests.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(request) => batch.push(request),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        commit(&mut storage, batch);
+    }
+}
+
+fn commit(storage: &mut Storage, batch: Vec<Request>) {
+    let (writes, waiters): (Vec<Write>, Vec<_>) = batch
+        .into_iter()
+        .map(|request| (request.write, request.done))
+        .unzip();
+
+    let mut results = Vec::with_capacity(writes.len());
+    let outcome = storage.transaction(|tx| {
+        for write in writes {
+            results.push(tx.nested(write));
+        }
+        Ok(())
+    });
+
+    for (waiter, result) in waiters.into_iter().zip(results) {
+        // Writes that went through are lost along with the transaction they were in
+        let result = match (&outcome, result) {
+            (Err(e), Ok(())) => Err(StorageError::GroupCommitFailed {
+                reason: e.to_string(),
+            }),
+            (_, result) => result,
+        };
+        // The thread that made the write may have stopped waiting for it
+        let _ = waiter.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FieldError, Key};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(validate_with = "check_amount")]
+    struct Payment {
+        id: u32,
+        amount: i64,
+    }
+
+    fn check_amount(payment: &Payment) -> Result<(), Vec<FieldError>> {
+        if payment.amount > 0 {
+            Ok(())
+        } else {
+            Err(vec![FieldError::new("amount", "must be positive")])
+        }
+    }
+
+    #[test]
+    fn test_that_writes_from_many_threads_are_committed_in_groups() {
+        let storage = Storage::temporary().expect("Could not open db storage");
+        let writes =
+            GroupCommit::new(storage, Duration::from_millis(5)).expect("Could not start writer");
+
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let writes = writes.clone();
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|n| {
+                            let id = thread * 50 + n;
+                            // Every tenth payment is invalid and fails on its own
+                            let amount = if id % 10 == 0 { 0 } else { 100 };
+                            writes.save(Payment { id, amount }).is_ok()
+                        })
+                        .filter(|saved| *saved)
+                        .count()
+                })
+            })
+            .collect();
+        let saved: usize = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .sum();
+
+        assert_eq!(360, saved);
+        assert_eq!(360, writes.reader().query::<Payment>().unwrap().count());
+        assert!(writes.reader().get::<Payment, _>(10).unwrap().is_none());
+
+        writes
+            .delete(Payment { id: 1, amount: 100 })
+            .expect("Could not delete");
+        assert!(writes.reader().get::<Payment, _>(1).unwrap().is_none());
+    }
+}
@@ -0,0 +1,140 @@
+//! A cheap handle to a storage that can be cloned into every part of an application.
+
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use crate::{Record, RoStorage, RwStorage, Storage, StorageError, Transaction};
+
+/// A cloneable handle to a storage, for code that would rather not pass `&mut Storage` around.
+///
+/// Reads go through the `RoStorage` the handle derefs to, so they run side by side on any
+/// thread.  Writes take turns behind a mutex around the one `RwStorage`.  Clones share both, and
+/// the storage is closed when the last one is dropped.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{Storage, StorageHandle, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn rename(places: &StorageHandle, id: u32, name: &str) -> Result<(), StorageError> {
+///     places.save(&Place { id, name: name.to_string() })
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let storage = StorageHandle::new(Storage::temporary()?)?;
+///     rename(&storage, 1, "Wien")?;
+///     rename(&storage.clone(), 1, "Vienna")?;
+///
+///     assert_eq!("Vienna", storage.get::<Place, _>(1)?.unwrap().name);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct StorageHandle {
+    reader: RoStorage,
+    writer: Arc<Mutex<RwStorage>>,
+}
+
+impl StorageHandle {
+    /// Splits `storage` into the handle's reader and writer, see `Storage::split`
+    pub fn new(storage: Storage) -> Result<StorageHandle, StorageError> {
+        let (reader, writer) = storage.split()?;
+        Ok(StorageHandle {
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Saves a record, waiting for writes from other clones to finish first
+    pub fn save<T: Record>(&self, record: &T) -> Result<(), StorageError> {
+        self.write(|storage| storage.save(record))
+    }
+
+    /// Deletes a record, waiting for writes from other clones to finish first.  Returns whether
+    /// it was stored
+    pub fn delete<T: Record>(&self, record: &T) -> Result<bool, StorageError> {
+        self.write(|storage| storage.delete(record))
+    }
+
+    /// Runs `f` in a write transaction, like `Storage::transaction`
+    pub fn transaction<R, F>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce(&mut Transaction) -> Result<R, StorageError>,
+    {
+        self.write(|storage| storage.transaction(f))
+    }
+
+    /// Hands the writing storage to `f` while holding the write lock, for anything the handle
+    /// doesn't offer itself
+    pub fn write<R, F>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce(&mut RwStorage) -> Result<R, StorageError>,
+    {
+        let mut writer = self.writer.lock().expect("Poisoned lock");
+        f(&mut writer)
+    }
+}
+
+impl Deref for StorageHandle {
+    type Target = RoStorage;
+
+    fn deref(&self) -> &RoStorage {
+        &self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Hit {
+        id: u32,
+        path: String,
+    }
+
+    #[test]
+    fn test_that_clones_write_from_any_thread() {
+        let storage = StorageHandle::new(Storage::temporary().expect("Could not open storage"))
+            .expect("Could not create handle");
+
+        let threads: Vec<_> = (0..4)
+            .map(|id| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    storage.save(&Hit {
+                        id,
+                        path: format!("/{}", id),
+                    })?;
+                    storage.get::<Hit, _>(id)
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().unwrap().is_some());
+        }
+
+        let hits = storage
+            .transaction(|tx| {
+                tx.delete(&Hit {
+                    id: 0,
+                    path: "/0".to_string(),
+                })?;
+                Ok(3)
+            })
+            .unwrap();
+        assert_eq!(hits, storage.query::<Hit>().unwrap().count());
+    }
+}
@@ -0,0 +1,708 @@
+#[cfg(not(target_arch = "wasm32"))]
+use lmdb::{Cursor, Database, DatabaseFlags, RwTransaction, Transaction, WriteFlags};
+use serde::Serialize;
+use serde_json::Value;
+#[cfg(not(target_arch = "wasm32"))]
+use std::ops::Bound;
+use unicode_normalization::UnicodeNormalization;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{Record, StorageError};
+
+/// A single value a record contributes to one of its secondary indexes.
+///
+/// Index databases map the value to the keys of every record that produced it, so the same value
+/// can point to any number of records.  Entries of a covering index also carry a projection of
+/// the record, stored next to its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub index: &'static str,
+    pub value: Vec<u8>,
+    pub projection: Option<Vec<u8>>,
+}
+
+impl IndexEntry {
+    /// Creates an entry from bytes that are already encoded
+    pub fn new<V: Into<Vec<u8>>>(index: &'static str, value: V) -> IndexEntry {
+        IndexEntry {
+            index,
+            value: value.into(),
+            projection: None,
+        }
+    }
+
+    /// Stores `projection` alongside the record's key, for indexes listed in
+    /// `Record::covering_indexes`
+    pub fn with_projection(mut self, projection: Vec<u8>) -> IndexEntry {
+        self.projection = Some(projection);
+        self
+    }
+
+    /// Creates an entry for a field value, encoded with `encode`
+    pub fn from_value<V: Serialize + ?Sized>(index: &'static str, value: &V) -> IndexEntry {
+        IndexEntry::new(index, encode(value))
+    }
+}
+
+/// A change applied to strings before they go into an index, and to the values an index is
+/// searched for, so values that differ only in ways the index doesn't care about match.  Set per
+/// index with `#[index(normalize = "lowercase, trim")]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalizer {
+    /// Lowercases every character, so "Ada@Example.com" matches "ada@example.com"
+    Lowercase,
+    /// Brings text to Unicode normalization form C, so composed and decomposed accents match
+    Nfc,
+    /// Removes whitespace at the start and the end
+    Trim,
+}
+
+impl Normalizer {
+    /// Applies `normalizers` to `value` in order
+    pub fn normalize(normalizers: &[Normalizer], value: &str) -> String {
+        normalizers
+            .iter()
+            .fold(value.to_string(), |value, normalizer| match normalizer {
+                Normalizer::Lowercase => value.to_lowercase(),
+                Normalizer::Nfc => value.nfc().collect(),
+                Normalizer::Trim => value.trim().to_string(),
+            })
+    }
+}
+
+/// Applies `normalizers` to a string value, or to each string in an array value.  Other values
+/// are left as they are
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn normalize_value(value: &Value, normalizers: &[Normalizer]) -> Value {
+    match value {
+        _ if normalizers.is_empty() => value.clone(),
+        Value::String(text) => Value::String(Normalizer::normalize(normalizers, text)),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| normalize_value(value, normalizers))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// Encodes a value the way it is stored in index databases.
+///
+/// Values go through `serde_json::Value` first so the encoding doesn't depend on the width of the
+/// Rust type, `3u32`, `3u64` and `3i8` all produce the same bytes.  That lets queries built from
+/// dynamic input find index entries.  Integers, floats and strings sort in their natural order.
+pub fn encode<V: Serialize + ?Sized>(value: &V) -> Vec<u8> {
+    encode_value(&serde_json::to_value(value).unwrap_or(Value::Null))
+}
+
+/// Encodes a `serde_json::Value` the way it is stored in index databases
+pub fn encode_value(value: &Value) -> Vec<u8> {
+    let mut bytes = vec![];
+    match value {
+        Value::Null => bytes.push(0),
+        Value::Bool(b) => bytes.extend(&[1, *b as u8]),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                bytes.push(2);
+                bytes.extend(&((i as u64) ^ (1 << 63)).to_be_bytes());
+            } else if let Some(u) = n.as_u64() {
+                // Only values above i64::MAX get here, so they sort after every i64
+                bytes.push(3);
+                bytes.extend(&u.to_be_bytes());
+            } else {
+                let bits = n.as_f64().unwrap_or(0.0).to_bits();
+                let ordered = if bits >> 63 == 1 {
+                    !bits
+                } else {
+                    bits | (1 << 63)
+                };
+                bytes.push(4);
+                bytes.extend(&ordered.to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            bytes.push(5);
+            bytes.extend(s.as_bytes());
+        }
+        other => {
+            bytes.push(6);
+            bytes.extend(other.to_string().as_bytes());
+        }
+    }
+    bytes
+}
+
+/// The name of the database that holds one of a record type's indexes
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn index_db_name(db_name: &str, index: &str) -> String {
+    format!("{}.{}", db_name, index)
+}
+
+/// Index databases store every record key for a value as a sorted duplicate
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn index_db_flags() -> DatabaseFlags {
+    DatabaseFlags::DUP_SORT
+}
+
+/// The data stored under a value in an index database: the record's key, or for a covering index
+/// the key's length as two bytes, the key and then the projection
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn entry_data(key: &[u8], projection: Option<&[u8]>) -> Vec<u8> {
+    let projection = match projection {
+        Some(projection) => projection,
+        None => return key.to_vec(),
+    };
+    let mut data = Vec::with_capacity(2 + key.len() + projection.len());
+    data.extend(&(key.len() as u16).to_be_bytes());
+    data.extend(key);
+    data.extend(projection);
+    data
+}
+
+/// Splits data stored in a covering index into the record's key and its projection
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn split_entry_data(data: &[u8]) -> Result<(&[u8], &[u8]), StorageError> {
+    if data.len() < 2 {
+        return Err(lmdb::Error::Corrupted.into());
+    }
+    let len = usize::from(u16::from_be_bytes([data[0], data[1]]));
+    if data.len() < 2 + len {
+        return Err(lmdb::Error::Corrupted.into());
+    }
+    Ok((&data[2..2 + len], &data[2 + len..]))
+}
+
+/// The record keys in data read with `lookup` or `range`, which for a covering index holds
+/// projections as well
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn record_keys(
+    data: Vec<Vec<u8>>,
+    covering: bool,
+) -> Result<Vec<Vec<u8>>, StorageError> {
+    if !covering {
+        return Ok(data);
+    }
+    data.iter()
+        .map(|data| Ok(split_entry_data(data)?.0.to_vec()))
+        .collect()
+}
+
+/// Returns the data stored under `value` in an index database, the keys of the records holding
+/// it unless the index is a covering one
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn lookup<T: Transaction>(
+    txn: &T,
+    db: Database,
+    value: &[u8],
+) -> Result<Vec<Vec<u8>>, StorageError> {
+    let mut cursor = txn.open_ro_cursor(db)?;
+    match cursor.iter_dup_of(&value) {
+        Ok(iter) => Ok(iter.map(|(_, key)| key.to_vec()).collect()),
+        Err(lmdb::Error::NotFound) => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the data stored under any value between `start` and `end`, ordered by value and then
+/// by data, like `lookup` does for one value
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn range<T: Transaction>(
+    txn: &T,
+    db: Database,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> Result<Vec<Vec<u8>>, StorageError> {
+    let cursor = txn.open_ro_cursor(db)?;
+
+    // lmdb 0.8's `iter_from` panics when nothing sorts after the start value, so the cursor is
+    // positioned by hand and the entry it lands on is read before iterating past it.
+    let first = match start {
+        Bound::Included(value) | Bound::Excluded(value) => {
+            cursor.get(Some(value), None, lmdb_sys::MDB_SET_RANGE)
+        }
+        Bound::Unbounded => cursor.get(None, None, lmdb_sys::MDB_FIRST),
+    };
+    let first = match first {
+        Ok((Some(value), key)) => (value, key),
+        Ok((None, _)) | Err(lmdb::Error::NotFound) => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut keys = vec![];
+    for entry in std::iter::once(Ok(first)).chain(crate::query::checked_iter(&cursor)) {
+        let (value, key) = entry?;
+        if let Bound::Excluded(start) = start {
+            if keys.is_empty() && value == start {
+                continue;
+            }
+        }
+        let before_end = match end {
+            Bound::Included(end) => value <= end,
+            Bound::Excluded(end) => value < end,
+            Bound::Unbounded => true,
+        };
+        if !before_end {
+            break;
+        }
+        keys.push(key.to_vec());
+    }
+    Ok(keys)
+}
+
+/// Puts data read with `lookup` or `range` in the order of `T`'s index, reversing it for the
+/// indexes in `Record::descending_indexes`
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn in_index_order<T: Record>(index: &str, mut data: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if T::descending_indexes().contains(&index) {
+        data.reverse();
+    }
+    data
+}
+
+/// Removes the data of a single record stored under `value` in an index database.
+///
+/// `RwTransaction::del` can't be used for this since lmdb 0.8 hands the data to LMDB through a
+/// dangling pointer, so the pair is found and deleted with a cursor instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn remove(
+    txn: &mut RwTransaction,
+    db: Database,
+    value: &[u8],
+    data: &[u8],
+) -> Result<(), StorageError> {
+    let mut cursor = txn.open_rw_cursor(db)?;
+    match cursor.get(Some(value), Some(data), lmdb_sys::MDB_GET_BOTH) {
+        Ok(_) => Ok(cursor.del(WriteFlags::empty())?),
+        Err(lmdb::Error::NotFound) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, QueryPlan, Storage};
+    use serde::Deserialize;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[storable(key = "id", db_name = "accounts", type_tag = "Account")]
+    struct AccountV1 {
+        id: u32,
+        email: String,
+        city: Option<String>,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[storable(key = "id", db_name = "accounts", type_tag = "Account")]
+    struct Account {
+        id: u32,
+        #[unique]
+        email: String,
+        #[index]
+        city: Option<String>,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Crate {
+        id: u32,
+        #[index]
+        tags: Vec<String>,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Release {
+        id: u32,
+        tags: Vec<String>,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Member {
+        id: u32,
+        #[unique(normalize = "trim, lowercase")]
+        email: String,
+        #[index(normalize = "nfc, lowercase")]
+        city: Option<String>,
+        #[index(normalize = "lowercase")]
+        tags: Vec<String>,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Book {
+        id: u32,
+        title: String,
+        #[unique(normalize = "lowercase", include = "id, title")]
+        isbn: String,
+        #[index(include = "title")]
+        year: u16,
+        #[index]
+        author: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Post {
+        id: u32,
+        #[index(name = "by_created", order = "desc")]
+        created: u64,
+    }
+
+    // A value serde can't serialize, kept out of the record but stored by its covering index
+    #[derive(Default, Debug, PartialEq)]
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("can't be serialized"))
+        }
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Draft {
+        id: u32,
+        #[index(include = "note")]
+        title: String,
+        #[serde(skip)]
+        note: Unserializable,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct BookTitle {
+        id: u32,
+        title: String,
+    }
+
+    fn account(id: u32, email: &str, city: Option<&str>) -> Account {
+        Account {
+            id,
+            email: email.to_string(),
+            city: city.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_that_index_values_are_encoded_independently_of_width() {
+        assert_eq!(encode(&3u32), encode(&3u64));
+        assert_eq!(encode(&3u32), encode(&3i8));
+        assert_eq!(encode("Paris"), encode(&"Paris".to_string()));
+        assert_ne!(encode(&3u32), encode("3"));
+    }
+
+    #[test]
+    fn test_that_index_values_sort_in_natural_order() {
+        let numbers = vec![
+            encode(&i64::MIN),
+            encode(&-1),
+            encode(&0),
+            encode(&1),
+            encode(&u32::MAX),
+            encode(&u64::MAX),
+        ];
+        let mut sorted = numbers.clone();
+        sorted.sort();
+        assert_eq!(numbers, sorted);
+
+        let floats = vec![encode(&-2.5), encode(&-0.5), encode(&0.5), encode(&10.25)];
+        let mut sorted = floats.clone();
+        sorted.sort();
+        assert_eq!(floats, sorted);
+
+        assert!(encode("apple") < encode("banana"));
+    }
+
+    #[test]
+    fn test_that_field_indexes_are_maintained_and_rebuilt() {
+        assert_eq!(&["email", "city"], Account::indexes());
+        assert_eq!(&["email"], Account::unique_indexes());
+
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&account(1, "ada@example.com", Some("London")))
+            .unwrap();
+        storage
+            .save(&account(2, "grace@example.com", None))
+            .unwrap();
+        // Saving a record again keeps its own value
+        storage
+            .save(&account(1, "ada@example.com", Some("Paris")))
+            .unwrap();
+        match storage.save(&account(3, "ada@example.com", None)) {
+            Err(StorageError::UniqueViolation { db_name, index }) => {
+                assert_eq!(("accounts", "email"), (db_name, index));
+            }
+            other => panic!("Expected a unique violation, got {:?}", other),
+        }
+
+        let in_paris = storage
+            .query_builder::<Account>()
+            .filter_eq("city", "Paris")
+            .fetch()
+            .unwrap();
+        assert_eq!(vec![account(1, "ada@example.com", Some("Paris"))], in_paris);
+
+        storage
+            .delete(&account(1, "ada@example.com", None))
+            .unwrap();
+        storage.save(&account(3, "ada@example.com", None)).unwrap();
+
+        // Records saved before the type had indexes are indexed once it is rebuilt
+        storage
+            .save(&AccountV1 {
+                id: 4,
+                email: "alan@example.com".to_string(),
+                city: Some("Paris".to_string()),
+            })
+            .unwrap();
+        assert_eq!(3, storage.reindex::<Account>().unwrap());
+        let in_paris = storage
+            .query_builder::<Account>()
+            .filter_eq("city", "Paris")
+            .fetch()
+            .unwrap();
+        assert_eq!(
+            vec![account(4, "alan@example.com", Some("Paris"))],
+            in_paris
+        );
+
+        storage
+            .save(&AccountV1 {
+                id: 5,
+                email: "alan@example.com".to_string(),
+                city: None,
+            })
+            .unwrap();
+        assert!(storage.reindex::<Account>().is_err());
+    }
+
+    #[test]
+    fn test_that_each_element_of_a_collection_is_indexed() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let tagged = |id, tags: &[&str]| Crate {
+            id,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        storage.save(&tagged(1, &["rust", "database"])).unwrap();
+        storage.save(&tagged(2, &["rust", "web", "rust"])).unwrap();
+        storage.save(&tagged(3, &[])).unwrap();
+
+        let rust: Vec<Crate> = storage.find_by_index("tags", "rust").unwrap();
+        assert_eq!(vec![1, 2], rust.iter().map(|c| c.id).collect::<Vec<_>>());
+        let web: Vec<Crate> = storage.find_by_index("tags", "web").unwrap();
+        assert_eq!(vec![tagged(2, &["rust", "web", "rust"])], web);
+        assert!(storage
+            .find_by_index::<Crate, _>("tags", "python")
+            .unwrap()
+            .is_empty());
+        assert!(storage.find_by_index::<Crate, _>("id", &1).is_err());
+
+        storage.save(&tagged(1, &["database"])).unwrap();
+        let rust: Vec<Crate> = storage.find_by_index("tags", "rust").unwrap();
+        assert_eq!(1, rust.len());
+
+        // Scans of fields without an index match the same way an index would
+        storage
+            .save(&Release {
+                id: 1,
+                tags: vec!["cli".to_string(), "rust".to_string()],
+            })
+            .unwrap();
+        let mut query = storage.query_builder::<Release>();
+        assert_eq!(1, query.filter_eq("tags", "cli").fetch().unwrap().len());
+    }
+
+    #[test]
+    fn test_that_normalized_indexes_match_regardless_of_case() {
+        assert_eq!(
+            "ada@example.com",
+            Normalizer::normalize(
+                &[Normalizer::Trim, Normalizer::Lowercase],
+                "  Ada@Example.COM "
+            )
+        );
+        // "é" composed and as "e" followed by a combining accent
+        assert_eq!(
+            "\u{e9}",
+            Normalizer::normalize(&[Normalizer::Nfc], "e\u{301}")
+        );
+
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let member = Member {
+            id: 1,
+            email: "Ada@Example.com".to_string(),
+            city: Some("Mont-Saint-Aignan".to_string()),
+            tags: vec!["Rust".to_string()],
+        };
+        storage.save(&member).unwrap();
+        storage
+            .save(&Member {
+                id: 2,
+                email: "grace@example.com".to_string(),
+                city: Some("Orl\u{e9}ans".to_string()),
+                tags: vec![],
+            })
+            .unwrap();
+
+        let found: Vec<Member> = storage.find_by_index("email", " ada@EXAMPLE.com").unwrap();
+        assert_eq!(vec![member], found);
+        let found: Vec<Member> = storage.find_by_index("tags", "RUST").unwrap();
+        assert_eq!(1, found.len());
+        let found = storage
+            .query_builder::<Member>()
+            .filter_eq("city", "ORLE\u{301}ANS")
+            .fetch()
+            .unwrap();
+        assert_eq!(vec![2], found.iter().map(|m| m.id).collect::<Vec<_>>());
+
+        assert!(matches!(
+            storage.save(&Member {
+                id: 3,
+                email: "ADA@example.com ".to_string(),
+                city: None,
+                tags: vec![],
+            }),
+            Err(StorageError::UniqueViolation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_that_covering_indexes_are_read_without_the_records() {
+        assert_eq!(&["isbn", "year"], Book::covering_indexes());
+        let book = |id, title: &str, isbn: &str, year| Book {
+            id,
+            title: title.to_string(),
+            isbn: isbn.to_string(),
+            year,
+            author: "Knuth".to_string(),
+        };
+        let title = |id, title: &str| BookTitle {
+            id,
+            title: title.to_string(),
+        };
+
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&book(1, "Fundamental Algorithms", "0-201-03801-3", 1968))
+            .unwrap();
+        storage
+            .save(&book(2, "Seminumerical Algorithms", "0-201-03802-1", 1969))
+            .unwrap();
+        storage
+            .save(&book(3, "Sorting and Searching", "0-201-03803-X", 1973))
+            .unwrap();
+
+        let found: Vec<BookTitle> = storage
+            .project_by_index::<Book, _, _>("isbn", "0-201-03803-x")
+            .unwrap();
+        assert_eq!(vec![title(3, "Sorting and Searching")], found);
+        let titles: Vec<String> = storage
+            .project_range::<Book, _, _, _>("year", 1968..1973)
+            .unwrap();
+        assert_eq!(
+            vec!["Fundamental Algorithms", "Seminumerical Algorithms"],
+            titles
+        );
+        assert!(matches!(
+            storage.project_by_index::<Book, BookTitle, _>("author", "Knuth"),
+            Err(StorageError::UncoveredIndex { .. })
+        ));
+
+        // The projection follows the record as it changes
+        storage
+            .save(&book(
+                1,
+                "Fundamental Algorithms, 3rd",
+                "0-201-03801-3",
+                1997,
+            ))
+            .unwrap();
+        let titles: Vec<String> = storage
+            .project_range::<Book, _, _, _>("year", 1990..)
+            .unwrap();
+        assert_eq!(vec!["Fundamental Algorithms, 3rd"], titles);
+        assert!(storage
+            .project_by_index::<Book, String, _>("year", &1968)
+            .unwrap()
+            .is_empty());
+
+        // Lookups for records still go through covering indexes
+        let found: Vec<Book> = storage.find_by_index("year", &1969).unwrap();
+        assert_eq!(2, found[0].id);
+        let found = storage
+            .query_builder::<Book>()
+            .filter_eq("isbn", "0-201-03801-3")
+            .fetch()
+            .unwrap();
+        assert_eq!(1, found[0].id);
+        assert!(matches!(
+            storage.save(&book(4, "Combinatorial Algorithms", "0-201-03801-3", 2011)),
+            Err(StorageError::UniqueViolation { .. })
+        ));
+        storage.delete(&book(3, "", "0-201-03803-X", 1973)).unwrap();
+        assert!(storage
+            .project_by_index::<Book, BookTitle, _>("isbn", "0-201-03803-X")
+            .unwrap()
+            .is_empty());
+        assert_eq!(2, storage.reindex::<Book>().unwrap());
+    }
+
+    #[test]
+    fn test_that_descending_indexes_return_the_greatest_values_first() {
+        assert_eq!(&["by_created"], Post::indexes());
+        assert_eq!(&["by_created"], Post::descending_indexes());
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        for (id, created) in [(1, 100), (2, 300), (3, 200), (4, 300)] {
+            storage.save(&Post { id, created }).unwrap();
+        }
+
+        let ids = |posts: Vec<Post>| posts.iter().map(|post| post.id).collect::<Vec<_>>();
+        let found = storage
+            .find_by_index::<Post, _>("by_created", &300)
+            .unwrap();
+        assert_eq!(vec![4, 2], ids(found));
+
+        // Filters on the field go through the index by its own name
+        let newest = storage
+            .query_builder::<Post>()
+            .filter_range("created", 150..)
+            .fetch()
+            .unwrap();
+        assert_eq!(vec![4, 2, 3], ids(newest));
+        let plan = storage
+            .query_builder::<Post>()
+            .filter_range::<u64, _>("created", ..)
+            .explain()
+            .unwrap();
+        assert_eq!(
+            QueryPlan::IndexRange {
+                index: "by_created".to_string(),
+                entries: 4
+            },
+            plan
+        );
+    }
+
+    #[test]
+    fn test_that_a_projection_that_cant_be_serialized_fails_the_save() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let draft = Draft {
+            id: 1,
+            title: "Untitled".to_string(),
+            note: Unserializable,
+        };
+
+        assert!(matches!(
+            storage.save(&draft),
+            Err(StorageError::Codec { .. })
+        ));
+        assert_eq!(None, storage.get::<Draft, _>(1).unwrap());
+        assert!(storage
+            .find_by_index::<Draft, _>("title", "Untitled")
+            .unwrap()
+            .is_empty());
+    }
+}
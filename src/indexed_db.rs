@@ -0,0 +1,268 @@
+//! Records kept in the browser's IndexedDB, for apps built for wasm32.  Only built with the `wasm`
+//! feature.
+//!
+//! IndexedDB only answers asynchronously while `StorageApi` is synchronous, so the storage reads
+//! every record into memory when it is opened and answers reads from there.  Writes are applied
+//! in memory right away and sent on to IndexedDB, which commits them in the order they were made.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use js_sys::{Array, Promise, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbDatabase, IdbFactory, IdbKeyRange, IdbObjectStore, IdbRequest, IdbTransactionMode, Window,
+    WorkerGlobalScope,
+};
+
+use crate::record::Record;
+use crate::{MemoryStorage, StorageApi, StorageError};
+
+// Every record is kept in one object store, under its database name and key
+const STORE: &str = "records";
+
+fn failure(reason: impl ToString) -> StorageError {
+    StorageError::Browser {
+        reason: reason.to_string(),
+    }
+}
+
+fn js_failure(value: JsValue) -> StorageError {
+    match value.dyn_ref::<js_sys::Error>() {
+        Some(error) => failure(String::from(error.message())),
+        None => failure(format!("{:?}", value)),
+    }
+}
+
+// The IndexedDB of the window or worker the app runs in
+fn factory() -> Result<IdbFactory, StorageError> {
+    let global = js_sys::global();
+    let factory = if let Some(window) = global.dyn_ref::<Window>() {
+        window.indexed_db()
+    } else if let Some(worker) = global.dyn_ref::<WorkerGlobalScope>() {
+        worker.indexed_db()
+    } else {
+        Ok(None)
+    };
+    factory
+        .map_err(js_failure)?
+        .ok_or_else(|| failure("IndexedDB is not available"))
+}
+
+// Waits for `request` to finish, returning its result
+async fn finished(request: &IdbRequest) -> Result<JsValue, StorageError> {
+    let done = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    let outcome = JsFuture::from(done).await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    match outcome {
+        Ok(_) => request.result().map_err(js_failure),
+        Err(_) => Err(match request.error() {
+            Ok(Some(error)) => failure(error.message()),
+            _ => failure("request failed"),
+        }),
+    }
+}
+
+// The IndexedDB key of the record stored under `key` in the database `db_name`
+fn idb_key(db_name: &str, key: &[u8]) -> JsValue {
+    Array::of2(&JsValue::from_str(db_name), &Uint8Array::from(key)).into()
+}
+
+/// A `StorageApi` that keeps records in the browser's IndexedDB, for apps built for wasm32.
+///
+/// The records are read into memory by `open`, so reads never wait.  Writes are applied in memory
+/// and sent on to IndexedDB.  A write IndexedDB fails to commit is reported by the next write, or
+/// by `flush`, which waits for every write made so far.  Indexes, relations and metadata aren't
+/// kept, like in `MemoryStorage`.
+///
+/// # Examples
+/// ```no_run
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{IndexedDbStorage, StorageApi, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// async fn remember(id: u32, name: &str) -> Result<(), StorageError> {
+///     let mut storage = IndexedDbStorage::open("places").await?;
+///     storage.save(&Place { id, name: name.to_string() })?;
+///     storage.flush().await
+/// }
+///
+/// fn main() {
+///     wasm_bindgen_futures::spawn_local(async {
+///         remember(1, "Vienna").await.expect("Could not save place");
+///     });
+/// }
+/// ```
+pub struct IndexedDbStorage {
+    db: IdbDatabase,
+    records: MemoryStorage,
+    // Why IndexedDB failed to commit a write, until it is reported
+    failed: Rc<RefCell<Option<String>>>,
+}
+
+impl IndexedDbStorage {
+    /// Opens the IndexedDB database `name`, creating it when it doesn't exist, and reads its
+    /// records into memory
+    pub async fn open(name: &str) -> Result<IndexedDbStorage, StorageError> {
+        let request = factory()?.open_with_u32(name, 1).map_err(js_failure)?;
+        let upgrading = request.clone();
+        let upgrade = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+            if let Ok(db) = upgrading.result() {
+                let _ = db
+                    .unchecked_into::<IdbDatabase>()
+                    .create_object_store(STORE);
+            }
+        });
+        request.set_onupgradeneeded(Some(upgrade.as_ref().unchecked_ref()));
+        let db: IdbDatabase = finished(&request).await?.unchecked_into();
+        request.set_onupgradeneeded(None);
+
+        let store = db
+            .transaction_with_str(STORE)
+            .and_then(|txn| txn.object_store(STORE))
+            .map_err(js_failure)?;
+        let keys = store.get_all_keys().map_err(js_failure)?;
+        let values = store.get_all().map_err(js_failure)?;
+        let keys: Array = finished(&keys).await?.unchecked_into();
+        let values: Array = finished(&values).await?.unchecked_into();
+
+        let mut records = MemoryStorage::new();
+        for (key, value) in keys.iter().zip(values.iter()) {
+            let key: Array = key.unchecked_into();
+            let db_name = key
+                .get(0)
+                .as_string()
+                .ok_or_else(|| failure("record stored without a database name"))?;
+            let key = Uint8Array::new(&key.get(1)).to_vec();
+            records.insert_bytes(&db_name, key, Uint8Array::new(&value).to_vec());
+        }
+
+        Ok(IndexedDbStorage {
+            db,
+            records,
+            failed: Rc::new(RefCell::new(None)),
+        })
+    }
+
+    /// Waits until IndexedDB has committed every write made so far, returning the first one it
+    /// failed to commit
+    pub async fn flush(&mut self) -> Result<(), StorageError> {
+        // Transactions on the same store run in the order they were made, so once this one has
+        // read something every earlier write is done
+        let store = self
+            .db
+            .transaction_with_str(STORE)
+            .and_then(|txn| txn.object_store(STORE))
+            .map_err(js_failure)?;
+        finished(&store.count().map_err(js_failure)?).await?;
+        self.check()
+    }
+
+    // Reports a write IndexedDB failed to commit since the last check
+    fn check(&self) -> Result<(), StorageError> {
+        match self.failed.borrow_mut().take() {
+            Some(reason) => Err(failure(reason)),
+            None => Ok(()),
+        }
+    }
+
+    // Sends the writes `f` makes to IndexedDB in one transaction
+    fn write<F>(&self, f: F) -> Result<(), StorageError>
+    where
+        F: FnOnce(&IdbObjectStore) -> Result<(), JsValue>,
+    {
+        self.check()?;
+        let txn = self
+            .db
+            .transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)
+            .map_err(js_failure)?;
+        let (failed, failing) = (self.failed.clone(), txn.clone());
+        let on_failure = Closure::<dyn FnMut(JsValue)>::new(move |_| {
+            let reason = match failing.error() {
+                Some(error) => error.message(),
+                None => "a write was not committed".to_string(),
+            };
+            failed.borrow_mut().get_or_insert(reason);
+        })
+        .into_js_value();
+        txn.set_onabort(Some(on_failure.unchecked_ref()));
+        txn.set_onerror(Some(on_failure.unchecked_ref()));
+
+        let store = txn.object_store(STORE).map_err(js_failure)?;
+        f(&store).map_err(js_failure)
+    }
+
+    // Sends the values stored under `keys` in `T`'s database to IndexedDB
+    fn put<T: Record>(&self, keys: &[Vec<u8>]) -> Result<(), StorageError> {
+        let records = &self.records;
+        self.write(|store| {
+            for key in keys {
+                if let Some(bytes) = records.bytes(T::db_name(), key) {
+                    store.put_with_key(&Uint8Array::from(bytes), &idb_key(T::db_name(), key))?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl StorageApi for IndexedDbStorage {
+    fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        self.records.save(record)?;
+        self.put::<T>(&[record.key().into()])
+    }
+
+    fn save_batch<T: Record>(&mut self, records: Vec<T>) -> Result<(), StorageError> {
+        let keys: Vec<Vec<u8>> = records.iter().map(|record| record.key().into()).collect();
+        self.records.save_batch(records)?;
+        self.put::<T>(&keys)
+    }
+
+    fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        self.records.get(key)
+    }
+
+    fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError> {
+        if !self.records.delete(record)? {
+            return Ok(false);
+        }
+        let key = idb_key(T::db_name(), &record.key().into());
+        self.write(|store| store.delete(&key).map(|_| ()))?;
+        Ok(true)
+    }
+
+    fn all<T: Record>(&mut self) -> Result<Vec<T>, StorageError> {
+        self.records.all()
+    }
+
+    fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
+        self.records.find(p)
+    }
+
+    fn count<T: Record>(&mut self) -> Result<usize, StorageError> {
+        self.records.count::<T>()
+    }
+
+    fn truncate<T: Record>(&mut self) -> Result<(), StorageError> {
+        self.records.truncate::<T>()?;
+        // Arrays sort after binary keys, so this covers every key of the database
+        let db_name = JsValue::from_str(T::db_name());
+        let range = IdbKeyRange::bound(&Array::of1(&db_name), &Array::of2(&db_name, &Array::new()))
+            .map_err(js_failure)?;
+        self.write(|store| store.delete(&range).map(|_| ()))
+    }
+}
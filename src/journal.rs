@@ -0,0 +1,296 @@
+//! The change journal, an ordered log of every committed write transaction.
+//!
+//! Storages opened with `StorageOptions::journal` add an entry to the journal database for each
+//! write transaction that changes anything, in the same transaction, so the journal holds exactly
+//! the transactions that committed and in the order they did.  Entries hold the raw writes made to
+//! every database, records along with their index entries, counters and type tags, which lets a
+//! follower replay them without knowing any record types.  Entries are keyed by their sequence
+//! number like the entries of a `Log`.
+
+use lmdb::{Database, DatabaseFlags, RwTransaction, Transaction, WriteFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::index;
+use crate::queue::{entries_from, read_u64};
+use crate::StorageError;
+
+/// The database that holds the journal
+pub(crate) const JOURNAL_DB: &str = "nostalgia#journal";
+
+/// The database where a follower keeps the sequence number of the next change it expects
+pub(crate) const REPLICA_DB: &str = "nostalgia#replica";
+
+const ENTRY: u8 = b'e';
+const NEXT_SEQ: &[u8] = b"n";
+const POSITION: &[u8] = b"position";
+
+/// A single write to one database, as kept in the journal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    /// Stores `value` under `key`, next to the values already there in a database with sorted
+    /// duplicates.  `flags` are the database's flags, used to create it when it doesn't exist
+    Put {
+        db: String,
+        flags: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Removes `key`, or only its duplicate `value` when one is given
+    Delete {
+        db: String,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
+    /// Removes every entry of a database
+    Clear { db: String },
+}
+
+impl ChangeOp {
+    /// The name of the database the write goes to
+    pub fn db(&self) -> &str {
+        match self {
+            ChangeOp::Put { db, .. } | ChangeOp::Delete { db, .. } | ChangeOp::Clear { db } => db,
+        }
+    }
+}
+
+/// The writes of one committed transaction, numbered in the order transactions committed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// The change's place in the journal, one more than the change before it
+    pub seq: u64,
+    /// The writes the transaction made, in the order it made them
+    pub ops: Vec<ChangeOp>,
+}
+
+/// Receives the changes a storage replicates, see `Storage::replicate_to`.  A `Storage` is a sink
+/// itself, applying what it receives with `Storage::apply_changes`
+pub trait ChangeSink {
+    /// The sequence number of the next change the sink expects
+    fn position(&mut self) -> Result<u64, StorageError>;
+
+    /// Takes changes in order, starting at `position`
+    fn send(&mut self, changes: Vec<Change>) -> Result<(), StorageError>;
+}
+
+fn entry_key(seq: u64) -> Vec<u8> {
+    let mut key = vec![ENTRY];
+    key.extend(&seq.to_be_bytes());
+    key
+}
+
+fn next_seq(txn: &impl Transaction, db: Database) -> Result<u64, StorageError> {
+    match txn.get(db, &NEXT_SEQ) {
+        Ok(bytes) => Ok(read_u64(bytes)),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds the writes of a transaction to the end of the journal, returning their sequence number
+pub(crate) fn append(
+    txn: &mut RwTransaction,
+    db: Database,
+    ops: &[ChangeOp],
+) -> Result<u64, StorageError> {
+    let seq = next_seq(txn, db)?;
+    txn.put(
+        db,
+        &entry_key(seq),
+        &bincode::serialize(ops)?,
+        WriteFlags::empty(),
+    )?;
+    txn.put(db, &NEXT_SEQ, &(seq + 1).to_be_bytes(), WriteFlags::empty())?;
+    Ok(seq)
+}
+
+/// At most `limit` changes from `seq` on, and the sequence number the next change will get
+pub(crate) fn read(
+    txn: &impl Transaction,
+    db: Database,
+    seq: u64,
+    limit: usize,
+) -> Result<(Vec<Change>, u64), StorageError> {
+    let changes = entries_from(txn, db, &entry_key(seq), &[ENTRY], limit)?
+        .into_iter()
+        .map(|(key, value)| {
+            Ok(Change {
+                seq: read_u64(&key[1..]),
+                ops: bincode::deserialize(&value)?,
+            })
+        })
+        .collect::<Result<_, StorageError>>()?;
+    Ok((changes, next_seq(txn, db)?))
+}
+
+/// Removes the changes before `seq`, returning how many were removed
+pub(crate) fn truncate_before(
+    txn: &mut RwTransaction,
+    db: Database,
+    seq: u64,
+) -> Result<usize, StorageError> {
+    let stale: Vec<_> = entries_from(txn, db, &[ENTRY], &[ENTRY], usize::MAX)?
+        .into_iter()
+        .take_while(|(key, _)| read_u64(&key[1..]) < seq)
+        .collect();
+    for (key, _) in &stale {
+        txn.del(db, key, None)?;
+    }
+    Ok(stale.len())
+}
+
+/// The sequence number of the next change a follower expects
+pub(crate) fn position(txn: &impl Transaction, db: Database) -> Result<u64, StorageError> {
+    match txn.get(db, &POSITION) {
+        Ok(bytes) => Ok(read_u64(bytes)),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn set_position(
+    txn: &mut RwTransaction,
+    db: Database,
+    seq: u64,
+) -> Result<(), StorageError> {
+    txn.put(db, &POSITION, &seq.to_be_bytes(), WriteFlags::empty())?;
+    Ok(())
+}
+
+/// Replays the writes of a change
+pub(crate) fn apply(
+    txn: &mut RwTransaction,
+    dbs: &mut HashMap<String, Database>,
+    ops: &[ChangeOp],
+) -> Result<(), StorageError> {
+    for op in ops {
+        let name = op.db();
+        let db = match (dbs.get(name), op) {
+            (Some(db), _) => *db,
+            // Safe because the caller holds the environment's write lock, so no other
+            // transaction can be opening databases, and only caches the handles once this
+            // transaction commits
+            (None, ChangeOp::Put { flags, .. }) => {
+                let flags = DatabaseFlags::from_bits_truncate(*flags);
+                let db = unsafe { txn.create_db(Some(name), flags)? };
+                dbs.insert(name.to_string(), db);
+                db
+            }
+            // There is nothing to remove from a database that doesn't exist
+            (None, _) => match unsafe { txn.open_db(Some(name)) } {
+                Ok(db) => {
+                    dbs.insert(name.to_string(), db);
+                    db
+                }
+                Err(lmdb::Error::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            },
+        };
+
+        match op {
+            ChangeOp::Put { key, value, .. } => txn.put(db, key, value, WriteFlags::empty())?,
+            ChangeOp::Delete {
+                key,
+                value: Some(value),
+                ..
+            } => index::remove(txn, db, key, value)?,
+            ChangeOp::Delete {
+                key, value: None, ..
+            } => match txn.del(db, key, None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            },
+            ChangeOp::Clear { .. } => txn.clear_db(db)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage, StorageOptions};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+    }
+
+    fn station(id: u32, line: &str) -> Station {
+        Station {
+            id,
+            line: line.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_that_followers_replay_the_journal_in_order() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let options = StorageOptions::default().journal(true);
+        let mut leader = Storage::open_with(dir.path(), options).expect("Could not open leader");
+        let mut follower = Storage::temporary().expect("Could not open follower");
+
+        leader.save(&station(1, "M1")).unwrap();
+        leader
+            .transaction(|tx| {
+                tx.save(&station(2, "M1"))?;
+                tx.save(&station(3, "M4"))?;
+                tx.increment::<Station, _>(3, "visits", 2)?;
+                Ok(())
+            })
+            .unwrap();
+        // Transactions that roll back leave nothing in the journal
+        let failed: Result<(), StorageError> = leader.transaction(|tx| {
+            tx.save(&station(4, "M7"))?;
+            Err(StorageError::Closed)
+        });
+        assert!(failed.is_err());
+        assert_eq!(2, leader.read_journal(0, 10).unwrap().len());
+
+        assert_eq!(2, leader.replicate_to(&mut follower).unwrap());
+        assert_eq!(0, leader.replicate_to(&mut follower).unwrap());
+        let m1: Vec<Station> = follower.find_by_index("line", "M1").unwrap();
+        assert_eq!(2, m1.len());
+        assert_eq!(2, follower.counter::<Station, _>(3, "visits").unwrap());
+
+        // Moving a record moves its index entry on the follower too
+        leader.save(&station(1, "M4")).unwrap();
+        leader.delete(&station(3, "M4")).unwrap();
+        leader.replicate_to(&mut follower).unwrap();
+        let m4: Vec<Station> = follower.find_by_index("line", "M4").unwrap();
+        assert_eq!(vec![station(1, "M4")], m4);
+        assert!(follower.get::<Station, _>(3u32).unwrap().is_none());
+
+        // Changes already applied are skipped, ones that skip ahead are refused
+        let changes = leader.read_journal(0, 10).unwrap();
+        assert_eq!(4, follower.apply_changes(changes).unwrap());
+        leader.save(&station(5, "M5")).unwrap();
+        leader.save(&station(6, "M6")).unwrap();
+        let latest = leader.read_journal(5, 10).unwrap();
+        assert!(matches!(
+            follower.apply_changes(latest),
+            Err(StorageError::ReplicationGap {
+                expected: 4,
+                found: 5
+            })
+        ));
+
+        // A follower that fell behind the truncated journal has to start over from a dump
+        assert_eq!(5, leader.truncate_journal(5).unwrap());
+        assert!(matches!(
+            leader.replicate_to(&mut follower),
+            Err(StorageError::ReplicationGap {
+                expected: 4,
+                found: 5
+            })
+        ));
+        assert_eq!(1, leader.read_journal(5, 10).unwrap().len());
+        assert!(leader.read_journal(6, 10).unwrap().is_empty());
+        assert!(leader.read_journal(7, 10).is_err());
+    }
+}
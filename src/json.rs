@@ -0,0 +1,51 @@
+//! Records stored as JSON.
+//!
+//! Types opt in with `#[storable(codec = "json")]`, which stores them with `to_bytes` and reads
+//! them back with `from_bytes` instead of bincode.  JSON takes more space but can be read by
+//! tools that don't know the record's layout.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+fn custom<E: std::fmt::Display>(error: E) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(error.to_string()))
+}
+
+/// Serializes a record as JSON
+pub fn to_bytes<T: Serialize>(record: &T) -> Result<Vec<u8>, bincode::Error> {
+    serde_json::to_vec(record).map_err(custom)
+}
+
+/// Deserializes a record stored as JSON
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, bincode::Error> {
+    serde_json::from_slice(bytes).map_err(custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[storable(key = "id", db_name = "people", codec = "json")]
+    struct Member {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_that_namespaced_attributes_pick_the_key_db_and_codec() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        assert_eq!("people", Member::db_name());
+
+        let member = Member {
+            id: 7,
+            name: "Ada".to_string(),
+        };
+        storage.save(&member).expect("Could not save member");
+
+        let key: Vec<u8> = Key::from(7u32).into();
+        let stored = storage.raw("people").unwrap().get_bytes(&key).unwrap();
+        assert_eq!(Some(br#"{"id":7,"name":"Ada"}"#.to_vec()), stored);
+        assert_eq!(Some(member), storage.get::<Member, _>(7).unwrap());
+    }
+}
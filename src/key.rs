@@ -1,42 +1,364 @@
+use serde::Serialize;
+use std::convert::{TryFrom, TryInto};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
 /// A struct to wrap any sized type that could be used as a key
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
 pub struct Key<T: Sized>(T);
 
+impl<T> Key<T> {
+    /// Returns a reference to the typed value wrapped by the key
+    pub fn value(&self) -> &T {
+        &self.0
+    }
+
+    /// Consumes the key and returns the typed value it wraps
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// Errors that can arise when decoding a key from the bytes stored in the database
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("expected a key of {expected} bytes but found {found}")]
+    InvalidLength { expected: usize, found: usize },
+
+    #[error("key is not a valid varint of its type")]
+    InvalidVarint,
+
+    #[error("key is not valid utf-8")]
+    InvalidUtf8 {
+        #[from]
+        source: std::string::FromUtf8Error,
+    },
+}
+
 // Implement From for any Sized type and wrap it in a Key struct
 impl<T> From<T> for Key<T> {
     fn from(input: T) -> Self {
-        Key::<T> { 0: input }
+        Key::<T>(input)
+    }
+}
+
+impl From<Key<&str>> for Key<String> {
+    fn from(key: Key<&str>) -> Key<String> {
+        Key::<String>(key.0.to_string())
+    }
+}
+
+impl From<Key<u32>> for Vec<u8> {
+    fn from(key: Key<u32>) -> Vec<u8> {
+        key.0.to_be_bytes().to_vec()
+    }
+}
+
+impl From<Key<u64>> for Vec<u8> {
+    fn from(key: Key<u64>) -> Vec<u8> {
+        key.0.to_be_bytes().to_vec()
+    }
+}
+
+impl From<Key<String>> for Vec<u8> {
+    fn from(key: Key<String>) -> Vec<u8> {
+        key.0.as_bytes().to_vec()
+    }
+}
+
+impl From<Key<&str>> for Vec<u8> {
+    fn from(key: Key<&str>) -> Vec<u8> {
+        key.0.as_bytes().to_vec()
     }
 }
 
-impl Into<Key<String>> for Key<&str> {
-    fn into(self) -> Key<String> {
-        Key::<String> {
-            0: self.0.to_string(),
+impl From<Key<Vec<u8>>> for Vec<u8> {
+    fn from(key: Key<Vec<u8>>) -> Vec<u8> {
+        key.0
+    }
+}
+
+// Signed integers are stored big endian with their sign bit flipped, so negative numbers sort
+// before positive ones
+macro_rules! signed_key {
+    ($($signed:ty => $unsigned:ty),*) => {
+        $(
+            impl From<Key<$signed>> for Vec<u8> {
+                fn from(key: Key<$signed>) -> Vec<u8> {
+                    const SIGN: $unsigned = 1 << (<$unsigned>::BITS - 1);
+                    ((key.0 as $unsigned) ^ SIGN).to_be_bytes().to_vec()
+                }
+            }
+
+            impl TryFrom<&[u8]> for Key<$signed> {
+                type Error = KeyError;
+
+                fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                    const SIGN: $unsigned = 1 << (<$unsigned>::BITS - 1);
+                    let raw = bytes.try_into().map_err(|_| KeyError::InvalidLength {
+                        expected: std::mem::size_of::<$signed>(),
+                        found: bytes.len(),
+                    })?;
+                    Ok(Key((<$unsigned>::from_be_bytes(raw) ^ SIGN) as $signed))
+                }
+            }
+        )*
+    };
+}
+
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// Floats are stored by their IEEE 754 bits, with every bit of negative numbers flipped and only
+// the sign bit of positive ones, so the bytes sort in numeric order.  -0.0 sorts just before 0.0
+// and NaN has no place in that order, so NaN keys are rejected in debug builds.
+macro_rules! float_key {
+    ($($float:ty => $bits:ty),*) => {
+        $(
+            impl From<Key<$float>> for Vec<u8> {
+                fn from(key: Key<$float>) -> Vec<u8> {
+                    debug_assert!(!key.0.is_nan(), "NaN can't be used as a key");
+                    const SIGN: $bits = 1 << (<$bits>::BITS - 1);
+                    let bits = key.0.to_bits();
+                    let ordered = if bits & SIGN == 0 { bits ^ SIGN } else { !bits };
+                    ordered.to_be_bytes().to_vec()
+                }
+            }
+
+            impl TryFrom<&[u8]> for Key<$float> {
+                type Error = KeyError;
+
+                fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                    const SIGN: $bits = 1 << (<$bits>::BITS - 1);
+                    let raw = bytes.try_into().map_err(|_| KeyError::InvalidLength {
+                        expected: std::mem::size_of::<$float>(),
+                        found: bytes.len(),
+                    })?;
+                    let ordered = <$bits>::from_be_bytes(raw);
+                    let bits = if ordered & SIGN == 0 { !ordered } else { ordered ^ SIGN };
+                    Ok(Key(<$float>::from_bits(bits)))
+                }
+            }
+        )*
+    };
+}
+
+float_key!(f32 => u32, f64 => u64);
+
+/// An integer key stored as a LEB128 varint, which takes a byte for every 7 bits that are in use
+/// instead of the integer's full width.  Signed integers are zigzag encoded first, so small
+/// negative numbers stay short too.
+///
+/// The bytes don't sort in numeric order, so scans over a type with varint keys don't come back
+/// in key order.  Chosen per type with `#[storable(key_encoding = "varint")]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct Varint<T>(T);
+
+impl<T> Varint<T> {
+    /// Returns a reference to the integer wrapped by the key
+    pub fn value(&self) -> &T {
+        &self.0
+    }
+
+    /// Consumes the key and returns the integer it wraps
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Varint<T> {
+    fn from(input: T) -> Self {
+        Varint(input)
+    }
+}
+
+fn encode_varint(mut value: u128) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
         }
+        bytes.push(byte | 0x80);
     }
 }
 
-impl Into<Vec<u8>> for Key<u32> {
-    fn into(self) -> Vec<u8> {
-        self.0.to_be_bytes().to_vec()
+// Decodes a varint that has to fill `bytes` exactly and fit in `bits` bits
+fn decode_varint(bytes: &[u8], bits: u32) -> Result<u128, KeyError> {
+    let mut value: u128 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let shift = 7 * i as u32;
+        let payload = byte & 0x7f;
+        let overflows = shift >= bits || (shift + 7 > bits && payload >> (bits - shift) != 0);
+        if overflows {
+            return Err(KeyError::InvalidVarint);
+        }
+        value |= u128::from(payload) << shift;
+
+        if byte & 0x80 == 0 {
+            return if i + 1 == bytes.len() {
+                Ok(value)
+            } else {
+                Err(KeyError::InvalidVarint)
+            };
+        }
     }
+    Err(KeyError::InvalidVarint)
+}
+
+macro_rules! unsigned_varint {
+    ($($unsigned:ty),*) => {
+        $(
+            impl From<Varint<$unsigned>> for Vec<u8> {
+                fn from(key: Varint<$unsigned>) -> Vec<u8> {
+                    encode_varint(key.0 as u128)
+                }
+            }
+
+            impl TryFrom<&[u8]> for Varint<$unsigned> {
+                type Error = KeyError;
+
+                fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                    Ok(Varint(decode_varint(bytes, <$unsigned>::BITS)? as $unsigned))
+                }
+            }
+        )*
+    };
+}
+
+unsigned_varint!(u16, u32, u64, u128);
+
+// Zigzag encoding maps 0, -1, 1, -2, ... to 0, 1, 2, 3, ...
+macro_rules! signed_varint {
+    ($($signed:ty => $unsigned:ty),*) => {
+        $(
+            impl From<Varint<$signed>> for Vec<u8> {
+                fn from(key: Varint<$signed>) -> Vec<u8> {
+                    let zigzag = ((key.0 << 1) ^ (key.0 >> (<$signed>::BITS - 1))) as $unsigned;
+                    encode_varint(zigzag as u128)
+                }
+            }
+
+            impl TryFrom<&[u8]> for Varint<$signed> {
+                type Error = KeyError;
+
+                fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+                    let zigzag = decode_varint(bytes, <$unsigned>::BITS)? as $unsigned;
+                    Ok(Varint(((zigzag >> 1) as $signed) ^ -((zigzag & 1) as $signed)))
+                }
+            }
+        )*
+    };
 }
 
-impl Into<Vec<u8>> for Key<u64> {
-    fn into(self) -> Vec<u8> {
-        self.0.to_be_bytes().to_vec()
+signed_varint!(i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// Times are stored as nanoseconds since the Unix epoch in a big endian i64 with its sign bit
+// flipped, so they sort in time order, including times before 1970.  That covers the years 1677
+// to 2262, times outside of it are clamped.
+fn encode_nanos(nanos: i64) -> Vec<u8> {
+    ((nanos as u64) ^ (1 << 63)).to_be_bytes().to_vec()
+}
+
+fn decode_nanos(bytes: &[u8]) -> Result<i64, KeyError> {
+    let raw: [u8; 8] = bytes.try_into().map_err(|_| KeyError::InvalidLength {
+        expected: 8,
+        found: bytes.len(),
+    })?;
+    Ok((u64::from_be_bytes(raw) ^ (1 << 63)) as i64)
+}
+
+fn clamp_nanos(nanos: u128) -> i64 {
+    i64::try_from(nanos).unwrap_or(i64::MAX)
+}
+
+impl From<Key<SystemTime>> for Vec<u8> {
+    fn from(key: Key<SystemTime>) -> Vec<u8> {
+        encode_nanos(match key.0.duration_since(UNIX_EPOCH) {
+            Ok(after) => clamp_nanos(after.as_nanos()),
+            Err(before) => -clamp_nanos(before.duration().as_nanos()),
+        })
     }
 }
 
-impl Into<Vec<u8>> for Key<String> {
-    fn into(self) -> Vec<u8> {
-        self.0.as_bytes().to_vec()
+#[cfg(feature = "chrono")]
+impl From<Key<chrono::DateTime<chrono::Utc>>> for Vec<u8> {
+    fn from(key: Key<chrono::DateTime<chrono::Utc>>) -> Vec<u8> {
+        encode_nanos(
+            key.0
+                .timestamp_nanos_opt()
+                .unwrap_or(if key.0.timestamp() < 0 {
+                    i64::MIN
+                } else {
+                    i64::MAX
+                }),
+        )
     }
 }
 
-impl Into<Vec<u8>> for Key<&str> {
-    fn into(self) -> Vec<u8> {
-        self.0.as_bytes().to_vec()
+impl TryFrom<&[u8]> for Key<u32> {
+    type Error = KeyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let raw: [u8; 4] = bytes.try_into().map_err(|_| KeyError::InvalidLength {
+            expected: 4,
+            found: bytes.len(),
+        })?;
+        Ok(Key(u32::from_be_bytes(raw)))
+    }
+}
+
+impl TryFrom<&[u8]> for Key<u64> {
+    type Error = KeyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let raw: [u8; 8] = bytes.try_into().map_err(|_| KeyError::InvalidLength {
+            expected: 8,
+            found: bytes.len(),
+        })?;
+        Ok(Key(u64::from_be_bytes(raw)))
+    }
+}
+
+impl TryFrom<&[u8]> for Key<String> {
+    type Error = KeyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Key(String::from_utf8(bytes.to_vec())?))
+    }
+}
+
+impl TryFrom<&[u8]> for Key<Vec<u8>> {
+    type Error = KeyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Key(bytes.to_vec()))
+    }
+}
+
+impl TryFrom<&[u8]> for Key<SystemTime> {
+    type Error = KeyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let nanos = decode_nanos(bytes)?;
+        let offset = Duration::from_nanos(nanos.unsigned_abs());
+        Ok(Key(if nanos < 0 {
+            UNIX_EPOCH - offset
+        } else {
+            UNIX_EPOCH + offset
+        }))
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl TryFrom<&[u8]> for Key<chrono::DateTime<chrono::Utc>> {
+    type Error = KeyError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(Key(chrono::DateTime::from_timestamp_nanos(decode_nanos(
+            bytes,
+        )?)))
     }
 }
 
@@ -65,14 +387,14 @@ mod tests {
     }
 
     impl Record for OtherThing {
-        type Key = std::string::String;
+        type Key = Key<std::string::String>;
 
         fn key(&self) -> Self::Key {
-            //Key::from(self.id.clone())
-            self.id.clone()
+            Key::from(self.id.clone())
         }
     }
 
+    #[allow(dead_code)]
     #[derive(Serialize, Deserialize)]
     struct AnotherThing {
         id: u32,
@@ -86,6 +408,30 @@ mod tests {
         }
     }
 
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    struct Release {
+        #[key(with = "version_key")]
+        version: (u16, u16, u16),
+        notes: String,
+    }
+
+    fn version_key(version: &(u16, u16, u16)) -> Vec<u8> {
+        let (major, minor, patch) = version;
+        [
+            major.to_be_bytes(),
+            minor.to_be_bytes(),
+            patch.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "taken_at"]
+    struct Sample {
+        taken_at: std::time::SystemTime,
+        celsius: f32,
+    }
+
     // A dummy function that ensures things compile
     fn get<T: Record>(_key: T::Key) {}
 
@@ -95,6 +441,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::init_numbered_fields, clippy::let_unit_value)]
     fn test_that_are_key_type_is_useful() {
         let a = Key::<u8> { 0: 0 };
         assert_eq!(0, a.0);
@@ -109,12 +456,308 @@ mod tests {
         assert_eq!("LOL".to_string(), d.0);
 
         let _e = get::<Thing>(Key::from(8));
-        let _f = get::<OtherThing>(String::from("LOL"));
+        let _f = get::<OtherThing>(Key::from(String::from("LOL")));
         let g = Key::from([0, 1, 2, 3]);
         assert_eq!([0, 1, 2, 3], g.0);
 
         let _h: Option<Thing> = get2(8);
         let _i: Option<OtherThing> = get2("Hello".to_string());
-        let _j: Option<OtherThing> = get2("Hi");
+        let _j: Option<OtherThing> = get2(Key::from("Hi"));
+    }
+
+    #[test]
+    fn test_that_keys_can_be_decoded_from_bytes() {
+        let bytes: Vec<u8> = Key::from(42u32).into();
+        let key = Key::<u32>::try_from(&bytes[..]).expect("Could not decode u32 key");
+        assert_eq!(42, key.0);
+
+        let bytes: Vec<u8> = Key::from(u64::MAX).into();
+        let key = Key::<u64>::try_from(&bytes[..]).expect("Could not decode u64 key");
+        assert_eq!(u64::MAX, key.0);
+
+        let bytes: Vec<u8> = Key::from("Vienna".to_string()).into();
+        let key = Key::<String>::try_from(&bytes[..]).expect("Could not decode String key");
+        assert_eq!("Vienna", key.0);
+
+        let bytes: Vec<u8> = Key::from(vec![3u8, 2, 1]).into();
+        let key = Key::<Vec<u8>>::try_from(&bytes[..]).expect("Could not decode byte key");
+        assert_eq!(vec![3u8, 2, 1], key.0);
+
+        assert!(Key::<u32>::try_from(&[0u8, 1][..]).is_err());
+        assert!(Key::<String>::try_from(&[0xffu8, 0xfe][..]).is_err());
+    }
+
+    #[test]
+    fn test_that_keys_round_trip_to_their_typed_value() {
+        let key = Key::from(1234u32);
+        let bytes: Vec<u8> = key.clone().into();
+        let decoded = Key::<u32>::try_from(&bytes[..]).expect("Could not decode u32 key");
+
+        assert_eq!(key, decoded);
+        assert_eq!(&1234, decoded.value());
+        assert_eq!(1234, decoded.into_inner());
+
+        let key: Key<String> = Key::from("Paris").into();
+        let bytes: Vec<u8> = key.clone().into();
+        let decoded = Key::<String>::try_from(&bytes[..]).expect("Could not decode String key");
+
+        assert_eq!("Paris", decoded.value());
+        assert_eq!(key, decoded);
+    }
+
+    #[test]
+    fn test_that_derived_keys_can_be_transformed_into_ordered_bytes() {
+        let mut storage = crate::Storage::temporary().expect("Could not open db storage");
+
+        let releases: Vec<Release> = [(1, 10, 0), (1, 2, 3), (0, 9, 1)]
+            .iter()
+            .map(|version| Release {
+                version: *version,
+                notes: format!("{:?}", version),
+            })
+            .collect();
+        storage
+            .save_batch(releases)
+            .expect("Could not save releases");
+
+        let versions: Vec<(u16, u16, u16)> = storage
+            .query::<Release>()
+            .unwrap()
+            .map(|release| release.version)
+            .collect();
+        assert_eq!(vec![(0, 9, 1), (1, 2, 3), (1, 10, 0)], versions);
+
+        let release: Option<Release> = storage.get(version_key(&(1, 2, 3))).unwrap();
+        assert_eq!("(1, 2, 3)", release.unwrap().notes);
+    }
+
+    #[test]
+    fn test_that_time_keys_sort_in_time_order() {
+        let times = [
+            UNIX_EPOCH - Duration::from_secs(86_400),
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::from_nanos(1),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        ];
+        let encoded: Vec<Vec<u8>> = times.iter().map(|time| Key::from(*time).into()).collect();
+
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        for (time, bytes) in times.iter().zip(&encoded) {
+            let decoded = Key::<SystemTime>::try_from(&bytes[..]).expect("Could not decode time");
+            assert_eq!(time, decoded.value());
+        }
+
+        let mut storage = crate::Storage::temporary().expect("Could not open db storage");
+        // serde can't serialize times before the epoch
+        storage
+            .save_batch(
+                times[1..]
+                    .iter()
+                    .rev()
+                    .map(|time| Sample {
+                        taken_at: *time,
+                        celsius: 20.5,
+                    })
+                    .collect(),
+            )
+            .expect("Could not save samples");
+        let taken: Vec<SystemTime> = storage
+            .keys::<Sample>()
+            .unwrap()
+            .map(Key::into_inner)
+            .collect();
+        assert_eq!(times[1..].to_vec(), taken);
+    }
+
+    #[test]
+    fn test_that_integer_and_string_keys_sort_like_their_values() {
+        crate::testing::assert_key_order(&[0u32, 1, 255, 256, 65_536, u32::MAX]);
+        crate::testing::assert_key_order(&[0u64, 1, u64::from(u32::MAX) + 1, u64::MAX]);
+        crate::testing::assert_key_order(&[
+            String::new(),
+            "a".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+            "é".to_string(),
+        ]);
+        crate::testing::assert_key_order(&[vec![], vec![0u8], vec![0, 0], vec![1]]);
+
+        crate::testing::assert_generated_key_order::<u32>(1000);
+        crate::testing::assert_generated_key_order::<u64>(1000);
+        crate::testing::assert_generated_key_order::<String>(1000);
+    }
+
+    #[test]
+    fn test_that_signed_keys_sort_like_their_values() {
+        crate::testing::assert_key_order(&[i8::MIN, -1, 0, 1, i8::MAX]);
+        crate::testing::assert_key_order(&[i16::MIN, -256, -1, 0, 255, i16::MAX]);
+        crate::testing::assert_key_order(&[i32::MIN, -65_536, -1, 0, 1, i32::MAX]);
+        crate::testing::assert_key_order(&[i64::MIN, -1, 0, i64::MAX]);
+        crate::testing::assert_key_order(&[i128::MIN, -1, 0, i128::MAX]);
+
+        crate::testing::assert_generated_key_order::<i8>(1000);
+        crate::testing::assert_generated_key_order::<i16>(1000);
+        crate::testing::assert_generated_key_order::<i32>(1000);
+        crate::testing::assert_generated_key_order::<i64>(1000);
+        crate::testing::assert_generated_key_order::<i128>(1000);
+
+        assert!(Key::<i16>::try_from(&[0u8][..]).is_err());
+    }
+
+    #[test]
+    fn test_that_float_keys_sort_like_their_values() {
+        let values = [
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.0,
+            1.5,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+        let encoded: Vec<Vec<u8>> = values
+            .iter()
+            .map(|value| Key::from(*value).into())
+            .collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        for (value, bytes) in values.iter().zip(&encoded) {
+            let decoded = Key::<f64>::try_from(&bytes[..]).expect("Could not decode f64 key");
+            assert_eq!(value.to_bits(), decoded.value().to_bits());
+        }
+
+        let mut floats: Vec<f32> = crate::testing::samples(1000);
+        floats.retain(|value| !value.is_nan());
+        floats.sort_by(f32::total_cmp);
+        let encoded: Vec<Vec<u8>> = floats
+            .iter()
+            .map(|value| Key::from(*value).into())
+            .collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "offset"]
+    struct Reading {
+        offset: i32,
+        value: f64,
+    }
+
+    #[test]
+    fn test_that_negative_keys_are_scanned_in_order() {
+        let mut storage = crate::Storage::temporary().expect("Could not open db storage");
+        storage
+            .save_batch(
+                [5, -3, 0, -100, 42]
+                    .iter()
+                    .map(|offset| Reading {
+                        offset: *offset,
+                        value: 0.5,
+                    })
+                    .collect(),
+            )
+            .expect("Could not save readings");
+
+        let offsets: Vec<i32> = storage
+            .keys::<Reading>()
+            .unwrap()
+            .map(Key::into_inner)
+            .collect();
+        assert_eq!(vec![-100, -3, 0, 5, 42], offsets);
+    }
+
+    #[test]
+    fn test_that_varint_keys_are_short_and_round_trip() {
+        let bytes: Vec<u8> = Varint::from(5u64).into();
+        assert_eq!(vec![5], bytes);
+        let bytes: Vec<u8> = Varint::from(300u32).into();
+        assert_eq!(vec![0xac, 0x02], bytes);
+        let bytes: Vec<u8> = Varint::from(-1i64).into();
+        assert_eq!(vec![1], bytes);
+
+        for value in [
+            0u64,
+            1,
+            127,
+            128,
+            16_383,
+            16_384,
+            u64::from(u32::MAX),
+            u64::MAX,
+        ] {
+            let bytes: Vec<u8> = Varint::from(value).into();
+            assert_eq!(value, *Varint::<u64>::try_from(&bytes[..]).unwrap().value());
+        }
+        for value in [i32::MIN, -65, -64, -1, 0, 1, 63, 64, i32::MAX] {
+            let bytes: Vec<u8> = Varint::from(value).into();
+            assert_eq!(value, *Varint::<i32>::try_from(&bytes[..]).unwrap().value());
+        }
+        let bytes: Vec<u8> = Varint::from(u128::MAX).into();
+        assert_eq!(
+            u128::MAX,
+            Varint::<u128>::try_from(&bytes[..]).unwrap().into_inner()
+        );
+
+        let too_wide: Vec<u8> = Varint::from(u64::from(u16::MAX) + 1).into();
+        assert!(Varint::<u16>::try_from(&too_wide[..]).is_err());
+        assert!(Varint::<u32>::try_from(&[0x80u8][..]).is_err());
+        assert!(Varint::<u32>::try_from(&[1u8, 1][..]).is_err());
+        assert!(Varint::<u32>::try_from(&[][..]).is_err());
+        let mut too_wide = vec![0xffu8; 18];
+        too_wide.push(0x04);
+        assert!(Varint::<u128>::try_from(&too_wide[..]).is_err());
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[storable(key = "id", key_encoding = "varint")]
+    struct Click {
+        id: u64,
+        x: u16,
+    }
+
+    #[test]
+    fn test_that_types_can_store_varint_keys() {
+        let mut storage = crate::Storage::temporary().expect("Could not open db storage");
+        storage
+            .save_batch(vec![
+                Click { id: 3, x: 10 },
+                Click {
+                    id: 1_000_000,
+                    x: 20,
+                },
+            ])
+            .expect("Could not save clicks");
+
+        assert_eq!(20, storage.get::<Click, _>(1_000_000).unwrap().unwrap().x);
+        let mut ids: Vec<u64> = storage
+            .keys::<Click>()
+            .unwrap()
+            .map(Varint::into_inner)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(vec![3, 1_000_000], ids);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_that_chrono_keys_match_system_time_keys() {
+        use chrono::{DateTime, Utc};
+
+        let now = SystemTime::now();
+        let time: Vec<u8> = Key::from(now).into();
+        let date_time: Vec<u8> = Key::from(DateTime::<Utc>::from(now)).into();
+        assert_eq!(time, date_time);
+
+        let decoded = Key::<DateTime<Utc>>::try_from(&date_time[..]).unwrap();
+        assert_eq!(DateTime::<Utc>::from(now), decoded.into_inner());
     }
 }
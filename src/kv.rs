@@ -0,0 +1,137 @@
+use lmdb::{Database, DatabaseFlags, Environment, Transaction, WriteFlags};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::StorageError;
+
+/// The name of the database that holds a key-value store's values
+pub(crate) fn kv_db_name(name: &str) -> String {
+    format!("{}#kv", name)
+}
+
+pub(crate) fn kv_db_flags() -> DatabaseFlags {
+    DatabaseFlags::empty()
+}
+
+/// Values of any serializable type stored under string keys, for settings and other small bits of
+/// data that don't need a `Record` type of their own.
+///
+/// Every call runs in its own transaction.  Use `Transaction::set_value` and friends to change
+/// values together with records.
+pub struct KvStore<'s> {
+    env: &'s Environment,
+    db: Database,
+}
+
+impl<'s> KvStore<'s> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> KvStore<'s> {
+        KvStore { env, db }
+    }
+
+    /// Stores `value` under `key`, replacing whatever was there
+    pub fn set<V: Serialize>(&self, key: &str, value: &V) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(value)?;
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.put(self.db, &key, &bytes, WriteFlags::empty())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns the value stored under `key`, or `None` when there isn't one
+    pub fn get<V: DeserializeOwned>(&self, key: &str) -> Result<Option<V>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        match txn.get(self.db, &key) {
+            Ok(bytes) => Ok(Some(bincode::deserialize(bytes)?)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the value stored under `key`.  Returns false when there wasn't one
+    pub fn remove(&self, key: &str) -> Result<bool, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        match txn.del(self.db, &key, None) {
+            Ok(()) => {
+                txn.commit()?;
+                Ok(true)
+            }
+            Err(lmdb::Error::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The keys that have a value, in sorted order
+    pub fn keys(&self) -> Result<Vec<String>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        let cursor = txn.open_ro_cursor(self.db)?;
+        let mut keys = vec![];
+        for entry in crate::query::checked_iter(&cursor) {
+            let (key, _) = entry?;
+            if let Ok(key) = String::from_utf8(key.to_vec()) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Storage, StorageError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Window {
+        width: u32,
+        height: u32,
+    }
+
+    #[test]
+    fn test_that_plain_values_are_stored_by_name() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let settings = storage.kv("settings").expect("Could not open kv store");
+        for key in settings.keys().unwrap() {
+            settings.remove(&key).unwrap();
+        }
+
+        settings.set("theme", &"dark".to_string()).unwrap();
+        settings.set("sounds", &true).unwrap();
+        settings
+            .set(
+                "window",
+                &Window {
+                    width: 800,
+                    height: 600,
+                },
+            )
+            .unwrap();
+        assert_eq!(Some("dark".to_string()), settings.get("theme").unwrap());
+        assert_eq!(
+            Some(Window {
+                width: 800,
+                height: 600
+            }),
+            settings.get("window").unwrap()
+        );
+        assert_eq!(None, settings.get::<String>("font").unwrap());
+        assert!(matches!(
+            settings.get::<Window>("sounds"),
+            Err(StorageError::Codec { .. })
+        ));
+        assert_eq!(vec!["sounds", "theme", "window"], settings.keys().unwrap());
+
+        let failed: Result<(), StorageError> = storage.transaction(|tx| {
+            tx.set_value("settings", "theme", &"light".to_string())?;
+            assert_eq!(
+                Some("light".to_string()),
+                tx.get_value("settings", "theme")?
+            );
+            Err(StorageError::Closed)
+        });
+        assert!(failed.is_err());
+
+        let settings = storage.kv("settings").unwrap();
+        assert_eq!(Some("dark".to_string()), settings.get("theme").unwrap());
+        assert!(settings.remove("theme").unwrap());
+        assert!(!settings.remove("theme").unwrap());
+    }
+}
@@ -18,12 +18,150 @@
 #[macro_use]
 extern crate nostalgia_derive;
 
+// Lets code generated by the derive macros refer to `::nostalgia` from inside this crate too
+extern crate self as nostalgia;
+
+// Everything built on LMDB, which doesn't build for wasm32.  Browser apps keep records with
+// `IndexedDbStorage` instead, through `StorageApi`
+macro_rules! native {
+    ($($item:item)*) => {
+        $(#[cfg(not(target_arch = "wasm32"))] $item)*
+    };
+}
+
+mod api;
+mod error;
+pub mod index;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod indexed_db;
+pub mod json;
 mod key;
-mod query;
 mod record;
-mod storage;
+mod timestamp;
+mod validation;
+
+native! {
+    mod append_log;
+    #[cfg(feature = "rkyv")]
+    pub mod archive;
+    mod attachment;
+    mod batch;
+    pub mod blob;
+    mod cache;
+    mod capped;
+    mod diff;
+    mod dump;
+    pub mod fulltext;
+    pub mod geo;
+    mod graph;
+    mod group_commit;
+    mod handle;
+    mod journal;
+    mod kv;
+    mod maintenance;
+    mod manager;
+    mod merge;
+    mod metadata;
+    pub mod metrics;
+    mod migrate;
+    mod options;
+    #[cfg(feature = "rayon")]
+    mod parallel;
+    mod profile;
+    #[cfg(any(feature = "remote", feature = "server"))]
+    mod protocol;
+    mod query;
+    mod query_builder;
+    mod queue;
+    mod quota;
+    mod raw;
+    mod read_pool;
+    mod read_view;
+    mod readahead;
+    mod readers;
+    mod registry;
+    mod relation;
+    #[cfg(feature = "remote")]
+    pub mod remote;
+    mod repository;
+    mod retention;
+    mod retry;
+    mod sequence;
+    #[cfg(feature = "server")]
+    pub mod server;
+    mod sorted_set;
+    mod split;
+    mod storage;
+    mod sync;
+    #[cfg(any(test, feature = "testing"))]
+    pub mod testing;
+    mod throttle;
+    mod time_series;
+    mod tracked;
+    mod transaction;
+    mod type_tag;
+    mod usage;
+    #[cfg(feature = "web")]
+    pub mod web;
+}
+
+pub use api::{MemoryStorage, StorageApi};
+pub use bincode;
+pub use error::StorageError;
+pub use index::{IndexEntry, Normalizer};
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub use indexed_db::IndexedDbStorage;
+pub use key::{Key, KeyError, Varint};
+pub use record::{Record, RecordRef};
+pub use serde;
+pub use timestamp::Timestamp;
+pub use validation::FieldError;
 
-pub use key::Key;
-use query::RoQuery;
-pub use record::Record;
-pub use storage::{Storage, StorageError};
+native! {
+    pub use append_log::Log;
+    pub use batch::{Batch, Savepoint};
+    pub use blob::{BlobReader, BlobWriter};
+    pub use capped::Cap;
+    pub use diff::{diff, DatabaseDiff, Diff, KeyChange, PATCH_VERSION};
+    pub use dump::DUMP_VERSION;
+    pub use geo::BoundingBox;
+    pub use graph::Graph;
+    pub use group_commit::GroupCommit;
+    pub use handle::StorageHandle;
+    pub use journal::{Change, ChangeOp, ChangeSink};
+    pub use kv::KvStore;
+    pub use lmdb::{DatabaseFlags, WriteFlags};
+    pub use maintenance::Maintenance;
+    pub use manager::StorageManager;
+    pub use merge::Merge;
+    pub use metadata::{Metadata, ENVELOPE_VERSION};
+    pub use metrics::MetricsSink;
+    #[cfg(feature = "prometheus")]
+    pub use metrics::PrometheusMetrics;
+    pub use migrate::{migrate_backend, MigrationProgress, UpgradeProgress};
+    pub use options::{Durability, Layout, OpenMode, ReadAgePolicy, StorageOptions, UpgradePolicy};
+    pub use profile::{SizeBucket, ValueProfile};
+    use query::{CheckedQuery, KeyQuery, RawScan, RoQuery};
+    pub use query_builder::{Condition, Field, QueryBuilder, QueryPlan};
+    pub use queue::{Delivery, Queue};
+    pub use quota::{Quota, TenantUsage};
+    pub use raw::RawDb;
+    pub use read_pool::ReadPoolStats;
+    pub use read_view::ReadView;
+    pub use readahead::AccessPattern;
+    pub use readers::ReaderSlot;
+    pub use registry::{DynRecord, RecordType};
+    pub use relation::{BelongsTo, OnDelete};
+    pub use repository::Repo;
+    pub use retention::Retention;
+    pub use retry::RetryPolicy;
+    pub use sorted_set::SortedSet;
+    pub use split::{RoStorage, RwStorage};
+    pub use storage::Storage;
+    pub use sync::{SyncChange, VersionVector};
+    pub use throttle::Throttle;
+    pub use time_series::{Aggregate, Bucket, DataPoint, TimeSeries};
+    pub use tracked::Tracked;
+    pub use transaction::Transaction;
+    pub use usage::{DatabaseUsage, DiskUsage};
+}
@@ -0,0 +1,323 @@
+//! Housekeeping that runs on a background thread while a storage is open.
+//!
+//! A `Maintenance` lists the tasks to run and how often.  `Storage::start_maintenance` hands them
+//! to a thread of their own, which runs every task in turn, waits for the interval plus a random
+//! jitter so storages opened together don't all wake at once, and starts over.  The thread works
+//! on its own handle to the storage's environment, so its writes take turns with the storage's
+//! like those of any other writer.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::metadata::Metadata;
+use crate::queue::entries_from;
+use crate::{DiskUsage, Record, Storage, StorageError};
+
+// How many records the built in tasks read per write transaction
+const CHUNK: usize = 1000;
+
+type Task = Box<dyn FnMut(&mut Storage) -> Result<(), StorageError> + Send>;
+
+/// The tasks a storage's maintenance thread runs, see `Storage::start_maintenance`
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{Maintenance, Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+/// use std::time::Duration;
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// #[storable(metadata)]
+/// struct Session {
+///   id: u32,
+///   user: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut storage = Storage::temporary()?;
+///     let maintenance = Maintenance::new(Duration::from_secs(60))
+///         .jitter(Duration::from_secs(10))
+///         .expire::<Session>(Duration::from_secs(24 * 60 * 60))
+///         .clear_stale_readers()
+///         .stats(|usage| println!("{} bytes used", usage.used_bytes));
+///     storage.start_maintenance(maintenance)?;
+///
+///     storage.save(&Session { id: 1, user: "ada".to_string() })?;
+///     storage.stop_maintenance();
+///     Ok(())
+/// }
+/// ```
+pub struct Maintenance {
+    interval: Duration,
+    jitter: Duration,
+    tasks: Vec<(String, Task)>,
+}
+
+impl Maintenance {
+    /// Runs no tasks yet, once every `interval`
+    pub fn new(interval: Duration) -> Maintenance {
+        Maintenance {
+            interval,
+            jitter: Duration::from_secs(0),
+            tasks: vec![],
+        }
+    }
+
+    /// Waits up to `jitter` longer than the interval, a different amount each time
+    pub fn jitter(mut self, jitter: Duration) -> Maintenance {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Runs `task` on every pass.  A task that fails is logged as an error, under `name`, and
+    /// runs again on the next pass
+    pub fn task<F>(mut self, name: &str, task: F) -> Maintenance
+    where
+        F: FnMut(&mut Storage) -> Result<(), StorageError> + Send + 'static,
+    {
+        self.tasks.push((name.to_string(), Box::new(task)));
+        self
+    }
+
+    /// Deletes the records of `T` that haven't been saved for longer than `max_age`, going by the
+    /// updated time in their metadata.  Fails with `StorageError::NoMetadata` when `T` doesn't
+    /// keep any, see `#[storable(metadata)]`
+    pub fn expire<T: Record + 'static>(self, max_age: Duration) -> Maintenance {
+        let name = format!("expire {}", T::db_name());
+        self.task(&name, move |storage| {
+            expire::<T>(storage, max_age).map(|_| ())
+        })
+    }
+
+    /// Deletes the records of `T` its retention doesn't keep, see `Storage::retain`.  The
+    /// retention has to be set before the maintenance is started
+    pub fn retention<T: Record + 'static>(self) -> Maintenance {
+        let name = format!("retention {}", T::db_name());
+        self.task(&name, |storage| storage.apply_retention::<T>().map(|_| ()))
+    }
+
+    /// Frees the reader slots of processes that exited without ending their read transactions
+    pub fn clear_stale_readers(self) -> Maintenance {
+        self.task("clear stale readers", |storage| {
+            storage.clear_stale_readers().map(|_| ())
+        })
+    }
+
+    /// Hands the storage's disk usage to `report`
+    pub fn stats<F>(self, mut report: F) -> Maintenance
+    where
+        F: FnMut(&DiskUsage) + Send + 'static,
+    {
+        self.task("stats", move |storage| {
+            report(&storage.disk_usage()?);
+            Ok(())
+        })
+    }
+
+    /// Writes the index entries of a chunk of `T`'s records again on every pass, picking up where
+    /// the last pass stopped and starting over after the last record.  Fills in an index added to
+    /// a type with records, or repairs one, without holding the write lock for long like
+    /// `Storage::reindex` does
+    pub fn reindex<T: Record + 'static>(self) -> Maintenance {
+        let name = format!("reindex {}", T::db_name());
+        let mut start = vec![];
+        self.task(&name, move |storage| {
+            start = reindex_chunk::<T>(storage, &start)?.unwrap_or_default();
+            Ok(())
+        })
+    }
+}
+
+/// The thread started by `Storage::start_maintenance`, stopped when this is dropped
+pub(crate) struct MaintenanceThread {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MaintenanceThread {
+    pub(crate) fn start(
+        storage: Storage,
+        maintenance: Maintenance,
+    ) -> Result<MaintenanceThread, StorageError> {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("nostalgia-maintenance".to_string())
+            .spawn(move || run(storage, maintenance, stopped))?;
+
+        Ok(MaintenanceThread {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for MaintenanceThread {
+    fn drop(&mut self) {
+        // The thread stops as soon as the channel is closed, after the task it is running
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(mut storage: Storage, mut maintenance: Maintenance, stopped: Receiver<()>) {
+    loop {
+        let wait = maintenance.interval + jitter(maintenance.jitter);
+        match stopped.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => return,
+        }
+
+        for (name, task) in &mut maintenance.tasks {
+            if let Err(e) = task(&mut storage) {
+                log::error!("maintenance task {} failed: {}", name, e);
+            }
+        }
+    }
+}
+
+// A random duration up to `max`.  Every `RandomState` is seeded differently, which is all the
+// randomness this needs
+fn jitter(max: Duration) -> Duration {
+    let nanos = max.as_nanos() as u64;
+    if nanos == 0 {
+        return max;
+    }
+    Duration::from_nanos(RandomState::new().build_hasher().finish() % (nanos + 1))
+}
+
+// Deletes the records of `T` last saved more than `max_age` ago, a chunk per write transaction,
+// and returns how many were deleted
+fn expire<T: Record>(storage: &mut Storage, max_age: Duration) -> Result<usize, StorageError> {
+    if !T::has_metadata() {
+        return Err(StorageError::NoMetadata {
+            db_name: T::db_name(),
+        });
+    }
+    let storage = storage.storage_for::<T>()?;
+    let db = match storage.existing_db(T::db_name())? {
+        Some(db) => db,
+        None => return Ok(0),
+    };
+    let cutoff = SystemTime::now() - max_age;
+
+    let mut deleted = 0;
+    let mut start = vec![];
+    loop {
+        let page = entries_from(&storage.env()?.begin_ro_txn()?, db, &start, &[], CHUNK)?;
+        let expired: Vec<&[u8]> = page
+            .iter()
+            .filter(|(_, value)| {
+                Metadata::read(value).is_some_and(|(metadata, _)| metadata.updated_at < cutoff)
+            })
+            .map(|(key, _)| key.as_slice())
+            .collect();
+        if !expired.is_empty() {
+            storage.transaction(|tx| {
+                for key in &expired {
+                    tx.delete_key::<T>(key)?;
+                }
+                Ok(())
+            })?;
+            deleted += expired.len();
+        }
+
+        match page.last() {
+            Some((last, _)) if page.len() == CHUNK => {
+                start = last.clone();
+                start.push(0);
+            }
+            _ => return Ok(deleted),
+        }
+    }
+}
+
+// Writes the index entries of up to a chunk of `T`'s records from `start` on again, returning
+// where the next chunk starts, or `None` after the last record
+fn reindex_chunk<T: Record>(
+    storage: &mut Storage,
+    start: &[u8],
+) -> Result<Option<Vec<u8>>, StorageError> {
+    let storage = storage.storage_for::<T>()?;
+    let db = match storage.existing_db(T::db_name())? {
+        Some(db) => db,
+        None => return Ok(None),
+    };
+    let page = entries_from(&storage.env()?.begin_ro_txn()?, db, start, &[], CHUNK)?;
+    storage.transaction(|tx| {
+        for (key, _) in &page {
+            tx.reindex_record::<T>(key)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(match page.last() {
+        Some((last, _)) if page.len() == CHUNK => {
+            let mut next = last.clone();
+            next.push(0);
+            Some(next)
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use serde::{Deserialize, Serialize};
+    use std::time::Instant;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(metadata)]
+    struct Token {
+        id: u32,
+        #[index]
+        owner: String,
+    }
+
+    #[test]
+    fn test_that_the_maintenance_thread_runs_its_tasks_until_stopped() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        for id in 0..3 {
+            storage
+                .save(&Token {
+                    id,
+                    owner: "ada".to_string(),
+                })
+                .unwrap();
+        }
+
+        let (reports, reported) = mpsc::channel();
+        let maintenance = Maintenance::new(Duration::from_millis(5))
+            .jitter(Duration::from_millis(5))
+            .expire::<Token>(Duration::from_millis(0))
+            .reindex::<Token>()
+            .stats(move |usage| {
+                let _ = reports.send(usage.used_bytes);
+            });
+        storage.start_maintenance(maintenance).unwrap();
+        assert!(reported.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let started = Instant::now();
+        while storage.query::<Token>().unwrap().count() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        let owned: Vec<Token> = storage.find_by_index("owner", "ada").unwrap();
+        assert!(owned.is_empty());
+
+        // The thread is gone once stopped, along with the sender its task held
+        storage.stop_maintenance();
+        while reported.try_recv().is_ok() {}
+        assert_eq!(Err(mpsc::TryRecvError::Disconnected), reported.try_recv());
+    }
+}
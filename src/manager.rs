@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{Storage, StorageError, StorageOptions};
+
+/// Opens and keeps track of several storage environments by name.
+///
+/// Environments are opened the first time they're asked for and stay open until they're closed.
+/// Unless configured otherwise an environment called "analytics" lives in the "analytics"
+/// directory under the manager's root and uses the default options.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{Storage, StorageManager, StorageOptions, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut manager = StorageManager::new("/tmp/nostalgia-manager");
+///     manager.configure(
+///         "analytics",
+///         "/tmp/nostalgia-analytics",
+///         StorageOptions::default().map_size(1024 * 1024 * 1024),
+///     );
+///
+///     manager.storage("places")?.save(&Place { id: 1, name: "Vienna".to_string() })?;
+///     manager.storage("analytics")?.truncate::<Place>()?;
+///
+///     Ok(())
+/// }
+/// ```
+pub struct StorageManager {
+    root: PathBuf,
+    configs: HashMap<String, (PathBuf, StorageOptions)>,
+    storages: HashMap<String, Storage>,
+}
+
+impl StorageManager {
+    /// Creates a manager that keeps environments under `root` unless they're configured elsewhere
+    pub fn new<P: Into<PathBuf>>(root: P) -> StorageManager {
+        StorageManager {
+            root: root.into(),
+            configs: HashMap::new(),
+            storages: HashMap::new(),
+        }
+    }
+
+    /// Sets where a named environment lives and how it is opened.  Takes effect the next time
+    /// the environment is opened
+    pub fn configure<P: Into<PathBuf>>(
+        &mut self,
+        name: &str,
+        path: P,
+        options: StorageOptions,
+    ) -> &mut Self {
+        self.configs
+            .insert(name.to_string(), (path.into(), options));
+        self
+    }
+
+    /// Returns the named environment, opening it if it isn't open yet
+    pub fn storage(&mut self, name: &str) -> Result<&mut Storage, StorageError> {
+        if !self.storages.contains_key(name) {
+            let (path, options) = match self.configs.get(name) {
+                Some((path, options)) => (path.clone(), options.clone()),
+                None => (self.root.join(name), StorageOptions::default()),
+            };
+            let storage = Storage::open_with(path, options)?;
+            self.storages.insert(name.to_string(), storage);
+        }
+
+        Ok(self
+            .storages
+            .get_mut(name)
+            .expect("Storage was just opened"))
+    }
+
+    /// Returns true when the named environment is currently open
+    pub fn is_open(&self, name: &str) -> bool {
+        self.storages.contains_key(name)
+    }
+
+    /// The names of the environments that are currently open
+    pub fn open_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.storages.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Closes the named environment and releases its files.  It is opened again on next use
+    pub fn close(&mut self, name: &str) {
+        self.storages.remove(name);
+    }
+
+    /// Closes every open environment
+    pub fn close_all(&mut self) {
+        self.storages.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Visit {
+        id: u32,
+        page: String,
+    }
+
+    #[test]
+    fn test_that_environments_are_opened_once_and_kept_apart() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("manager-root");
+        let elsewhere = tmp.path().join("manager-elsewhere");
+        let mut manager = StorageManager::new(&root);
+        manager.configure("archive", &elsewhere, StorageOptions::default().max_dbs(16));
+
+        for name in &["web", "archive"] {
+            manager
+                .storage(name)
+                .unwrap()
+                .truncate::<Visit>()
+                .expect("Could not truncate");
+        }
+
+        manager
+            .storage("web")
+            .unwrap()
+            .save(&Visit {
+                id: 1,
+                page: "/".to_string(),
+            })
+            .expect("Could not save visit");
+
+        assert_eq!(vec!["archive", "web"], manager.open_names());
+        assert_eq!(root.join("web"), manager.storage("web").unwrap().path());
+        assert_eq!(elsewhere, manager.storage("archive").unwrap().path());
+        assert_eq!(
+            0,
+            manager
+                .storage("archive")
+                .unwrap()
+                .query::<Visit>()
+                .unwrap()
+                .count()
+        );
+
+        manager.close("web");
+        assert!(!manager.is_open("web"));
+        let visit: Option<Visit> = manager.storage("web").unwrap().get(1).unwrap();
+        assert_eq!("/", visit.unwrap().page);
+    }
+}
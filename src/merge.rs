@@ -0,0 +1,169 @@
+//! Merging records that were written on two sides instead of letting the last write win.
+//!
+//! Changes applied from another storage overwrite the records stored under the same keys.  Types
+//! registered with `Storage::merge_on_apply` are merged with the stored record instead, and their
+//! index entries are written for the merged record rather than copied from the incoming change.
+
+use std::collections::HashMap;
+
+use crate::index::index_db_name;
+use crate::metadata;
+use crate::{Record, StorageError, Transaction};
+
+/// A record that can be merged with another version of itself, like one written on another
+/// replica.  Merging should give the same result whichever side is `ours`, and merging a record
+/// with itself should give it back, so replicas that exchange their changes end up the same.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{Merge, Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   visits: u32
+/// }
+///
+/// impl Merge for Place {
+///     fn merge(ours: Place, theirs: Place) -> Place {
+///         Place { id: ours.id, visits: ours.visits.max(theirs.visits) }
+///     }
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut storage = Storage::temporary()?;
+///     storage.save(&Place { id: 1, visits: 5 })?;
+///
+///     let saved = storage.save_merge(Place { id: 1, visits: 3 })?;
+///     assert_eq!(5, saved.visits);
+///     Ok(())
+/// }
+/// ```
+pub trait Merge: Record {
+    /// Combines the stored record, `ours`, with the one being written, `theirs`
+    fn merge(ours: Self, theirs: Self) -> Self;
+}
+
+type PutFn = fn(&mut Transaction, &[u8], &[u8]) -> Result<(), StorageError>;
+type DeleteFn = fn(&mut Transaction, &[u8]) -> Result<(), StorageError>;
+type MergeFn = fn(&[u8], &[u8]) -> Result<Vec<u8>, StorageError>;
+
+/// How records of one type are merged when changes are applied.  The type is erased, so the
+/// merger keeps the functions that write and delete records of that type.
+#[derive(Clone)]
+pub(crate) struct Merger {
+    pub index_dbs: Vec<String>,
+    pub put: PutFn,
+    pub delete: DeleteFn,
+    // Merges two serialized records into a serialized record
+    pub merge: MergeFn,
+}
+
+/// Mergers keyed by the db_name of the type they merge
+pub(crate) type Mergers = HashMap<&'static str, Merger>;
+
+impl Merger {
+    pub(crate) fn of<T: Merge>() -> Merger {
+        Merger {
+            index_dbs: T::indexes()
+                .iter()
+                .map(|index| index_db_name(T::db_name(), index))
+                .collect(),
+            put: put::<T>,
+            delete: |tx, key| match tx.get_bytes::<T>(key)? {
+                Some(_) => tx.delete_key::<T>(key),
+                None => Ok(()),
+            },
+            merge: |ours, theirs| {
+                let merged = T::merge(T::from_binary(ours)?, T::from_binary(theirs)?);
+                Ok(T::to_binary(&merged)?)
+            },
+        }
+    }
+}
+
+// Writes the incoming record stored as `theirs` under `key`, merged with the record stored there
+fn put<T: Merge>(tx: &mut Transaction, key: &[u8], theirs: &[u8]) -> Result<(), StorageError> {
+    let theirs: T = metadata::decode(key, theirs)?;
+    let record = match tx.get_record::<T>(key)? {
+        Some(ours) => T::merge(ours, theirs),
+        None => theirs,
+    };
+
+    let bytes = T::to_binary(&record)?;
+    tx.put_record::<T>(key, &bytes, &record.index_entries()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Storage, StorageOptions};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeSet;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+        exits: BTreeSet<String>,
+    }
+
+    impl Merge for Station {
+        fn merge(ours: Station, theirs: Station) -> Station {
+            Station {
+                id: ours.id,
+                line: ours.line.max(theirs.line),
+                exits: ours.exits.union(&theirs.exits).cloned().collect(),
+            }
+        }
+    }
+
+    fn station(id: u32, line: &str, exits: &[&str]) -> Station {
+        Station {
+            id,
+            line: line.to_string(),
+            exits: exits.iter().map(|exit| exit.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_that_applied_changes_are_merged_with_local_records() {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let options = StorageOptions::default().journal(true);
+        let mut leader = Storage::open_with(dir.path(), options).expect("Could not open leader");
+        let mut follower = Storage::temporary().expect("Could not open follower");
+        follower.merge_on_apply::<Station>();
+
+        follower.save(&station(1, "M4", &["north"])).unwrap();
+        leader.save(&station(1, "M1", &["south"])).unwrap();
+        leader.save(&station(2, "M1", &[])).unwrap();
+        leader.replicate_to(&mut follower).unwrap();
+
+        assert_eq!(
+            Some(station(1, "M4", &["north", "south"])),
+            follower.get::<Station, _>(1u32).unwrap()
+        );
+        // Index entries follow the merged record, not the one the leader saved
+        let m4: Vec<Station> = follower.find_by_index("line", "M4").unwrap();
+        assert_eq!(1, m4.len());
+        let m1: Vec<Station> = follower.find_by_index("line", "M1").unwrap();
+        assert_eq!(vec![station(2, "M1", &[])], m1);
+
+        leader.delete(&station(1, "M1", &[])).unwrap();
+        leader.replicate_to(&mut follower).unwrap();
+        assert!(follower.get::<Station, _>(1u32).unwrap().is_none());
+        assert!(follower
+            .find_by_index::<Station, _>("line", "M4")
+            .unwrap()
+            .is_empty());
+
+        let saved = follower.save_merge(station(2, "M5", &["west"])).unwrap();
+        assert_eq!(station(2, "M5", &["west"]), saved);
+    }
+}
@@ -0,0 +1,172 @@
+//! The optional envelope stored around a record's serialized value.
+//!
+//! Types opt in with `#[storable(metadata)]`.  Their values are stored as a format version byte,
+//! the created and updated times in milliseconds since the Unix epoch as big endian `u64`s, and
+//! then the record's own bytes.
+
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::record;
+use crate::{Record, StorageError};
+
+/// The current version of the envelope format
+pub const ENVELOPE_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 17;
+
+/// When a record was first saved and when it was last saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub version: u8,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn from_millis(bytes: &[u8]) -> SystemTime {
+    let millis = u64::from_be_bytes(bytes.try_into().unwrap_or([0; 8]));
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+impl Metadata {
+    /// Splits a stored value into its envelope and the record's bytes, or returns `None` when the
+    /// value has no envelope
+    pub fn read(bytes: &[u8]) -> Option<(Metadata, &[u8])> {
+        if bytes.len() < HEADER_LEN || bytes[0] != ENVELOPE_VERSION {
+            return None;
+        }
+
+        let metadata = Metadata {
+            version: bytes[0],
+            created_at: from_millis(&bytes[1..9]),
+            updated_at: from_millis(&bytes[9..17]),
+        };
+        Some((metadata, &bytes[HEADER_LEN..]))
+    }
+
+    fn write(&self, value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + value.len());
+        bytes.push(self.version);
+        bytes.extend(&to_millis(self.created_at).to_be_bytes());
+        bytes.extend(&to_millis(self.updated_at).to_be_bytes());
+        bytes.extend(value);
+        bytes
+    }
+}
+
+/// Wraps a serialized record in its envelope when the type has one, keeping the created time of
+/// the value it replaces
+pub(crate) fn wrap<T: Record>(previous: Option<&[u8]>, value: &[u8]) -> Vec<u8> {
+    if !T::has_metadata() {
+        return value.to_vec();
+    }
+
+    let now = SystemTime::now();
+    let created_at = previous
+        .and_then(Metadata::read)
+        .map(|(metadata, _)| metadata.created_at)
+        .unwrap_or(now);
+
+    Metadata {
+        version: ENVELOPE_VERSION,
+        created_at,
+        updated_at: now,
+    }
+    .write(value)
+}
+
+/// The record's own bytes within a stored value, without the envelope when the type has one
+pub(crate) fn unwrap<T: Record>(bytes: &[u8]) -> Option<&[u8]> {
+    if T::has_metadata() {
+        Metadata::read(bytes).map(|(_, value)| value)
+    } else {
+        Some(bytes)
+    }
+}
+
+/// The record's own bytes within the value stored under `key`, like `unwrap`, failing with
+/// `StorageError::Undecodable` when the envelope can't be read
+pub(crate) fn stored<'a, T: Record>(key: &[u8], bytes: &'a [u8]) -> Result<&'a [u8], StorageError> {
+    unwrap::<T>(bytes).ok_or_else(|| undecodable::<T>(key))
+}
+
+/// Deserializes the value stored under `key`, unwrapping the envelope when the type has one.  A
+/// value that can't be read fails with `StorageError::Undecodable`
+pub(crate) fn decode<T: Record>(key: &[u8], bytes: &[u8]) -> Result<T, StorageError> {
+    record::load(stored::<T>(key, bytes)?).map_err(|_| undecodable::<T>(key))
+}
+
+/// The error for a value stored under `key` that can't be read as a `T`
+pub(crate) fn undecodable<T: Record>(key: &[u8]) -> StorageError {
+    StorageError::Undecodable {
+        db_name: T::db_name(),
+        key: key.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Storage};
+    use serde::{Deserialize, Serialize};
+    use std::thread::sleep;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(metadata)]
+    struct Permit {
+        id: u32,
+        street: String,
+    }
+
+    fn permit(id: u32, street: &str) -> Permit {
+        Permit {
+            id,
+            street: street.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_that_the_envelope_keeps_created_and_updates_modified_times() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        storage
+            .save_batch(vec![permit(1, "Canal St"), permit(2, "Mott St")])
+            .expect("Could not save permits");
+        let first = storage
+            .metadata::<Permit, _>(1)
+            .expect("Could not read metadata")
+            .expect("Missing metadata");
+        assert_eq!(ENVELOPE_VERSION, first.version);
+        assert_eq!(first.created_at, first.updated_at);
+
+        sleep(Duration::from_millis(5));
+        let since = SystemTime::now();
+        sleep(Duration::from_millis(5));
+        storage
+            .save(&permit(1, "Bowery"))
+            .expect("Could not save permit");
+
+        let second = storage.metadata::<Permit, _>(1).unwrap().unwrap();
+        assert_eq!(first.created_at, second.created_at);
+        assert!(second.updated_at > first.updated_at);
+        assert_eq!(
+            Some(permit(1, "Bowery")),
+            storage.get::<Permit, _>(1).unwrap()
+        );
+        assert!(storage.metadata::<Permit, _>(3).unwrap().is_none());
+
+        let changed: Vec<Permit> = storage
+            .query::<Permit>()
+            .unwrap()
+            .modified_since(since)
+            .collect();
+        assert_eq!(vec![permit(1, "Bowery")], changed);
+    }
+}
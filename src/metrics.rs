@@ -0,0 +1,328 @@
+//! Measurements of what a storage does, handed to a `MetricsSink`.
+//!
+//! Writes are reported once the transaction they were made in commits, so work that is rolled
+//! back isn't counted.  With the `prometheus` feature, `PrometheusMetrics` is a sink made of
+//! ready-made collectors.  Commits slower than `StorageOptions::slow_commit` are also logged
+//! through the `log` crate, whether or not there is a sink.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Receives counters and timings from a storage.  Every method does nothing unless overridden,
+/// so a sink only needs to implement what it records
+pub trait MetricsSink: Send + Sync {
+    /// Counts one operation on a record type's database, like `"save"` on `"Person"`
+    fn operation(&self, _op: &'static str, _db_name: &str) {}
+
+    /// Adds to the bytes of records written to a record type's database
+    fn bytes_written(&self, _db_name: &str, _bytes: usize) {}
+
+    /// Records how long a write transaction ran before it was committed or aborted
+    fn transaction_duration(&self, _duration: Duration, _committed: bool) {}
+
+    /// Records how long a write transaction waited to start, behind the writers holding the lock
+    fn write_lock_wait(&self, _duration: Duration) {}
+
+    /// Records how long a commit took, which is mostly the time spent syncing to disk
+    fn commit_duration(&self, _duration: Duration) {}
+
+    /// Reports how much of the memory map is in use after a write transaction commits
+    fn map_utilization(&self, _used_bytes: u64, _map_size: u64) {}
+}
+
+/// A write made in a transaction that is reported once the transaction commits
+#[derive(Debug, Clone)]
+pub(crate) struct Write {
+    pub op: &'static str,
+    pub db_name: &'static str,
+    pub bytes: usize,
+    // The key of the record written, `None` for writes that don't change a record
+    pub key: Option<Vec<u8>>,
+}
+
+pub(crate) fn report(sink: &dyn MetricsSink, writes: &[Write]) {
+    for write in writes {
+        sink.operation(write.op, write.db_name);
+        if write.bytes > 0 {
+            sink.bytes_written(write.db_name, write.bytes);
+        }
+    }
+}
+
+/// Logs a commit that took `duration` as a warning under the `nostalgia::slow` target, with how
+/// long the transaction waited for the write lock and the bytes it wrote to each database
+pub(crate) fn log_slow_commit(duration: Duration, waited: Duration, writes: &[Write]) {
+    let mut bytes = BTreeMap::new();
+    for write in writes {
+        *bytes.entry(write.db_name).or_insert(0) += write.bytes;
+    }
+    let dbs: Vec<String> = bytes
+        .iter()
+        .map(|(db_name, bytes)| format!("{}:{}", db_name, bytes))
+        .collect();
+
+    log::warn!(
+        target: "nostalgia::slow",
+        "slow commit duration_ms={} wait_ms={} writes={} bytes={} dbs={}",
+        duration.as_millis(),
+        waited.as_millis(),
+        writes.len(),
+        bytes.values().sum::<usize>(),
+        dbs.join(",")
+    );
+}
+
+#[cfg(feature = "prometheus")]
+pub use self::collectors::PrometheusMetrics;
+
+#[cfg(feature = "prometheus")]
+mod collectors {
+    use prometheus::{
+        Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    };
+    use std::time::Duration;
+
+    use super::MetricsSink;
+
+    /// A `MetricsSink` that keeps its numbers in prometheus collectors
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{PrometheusMetrics, Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let registry = prometheus::Registry::new();
+    ///     let metrics = PrometheusMetrics::new()?;
+    ///     metrics.register(&registry)?;
+    ///
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.set_metrics(metrics);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    #[derive(Clone)]
+    pub struct PrometheusMetrics {
+        /// `nostalgia_operations_total`, labelled by `op` and `db`
+        pub operations: IntCounterVec,
+        /// `nostalgia_bytes_written_total`, labelled by `db`
+        pub bytes_written: IntCounterVec,
+        /// `nostalgia_transaction_seconds`, labelled by `outcome`
+        pub transaction_seconds: HistogramVec,
+        /// `nostalgia_write_lock_wait_seconds`
+        pub write_lock_wait_seconds: Histogram,
+        /// `nostalgia_commit_seconds`
+        pub commit_seconds: Histogram,
+        /// `nostalgia_map_used_bytes`
+        pub map_used_bytes: IntGauge,
+        /// `nostalgia_map_size_bytes`
+        pub map_size_bytes: IntGauge,
+    }
+
+    impl PrometheusMetrics {
+        /// Creates the collectors without registering them
+        pub fn new() -> Result<PrometheusMetrics, prometheus::Error> {
+            Ok(PrometheusMetrics {
+                operations: IntCounterVec::new(
+                    Opts::new("nostalgia_operations_total", "Operations by type"),
+                    &["op", "db"],
+                )?,
+                bytes_written: IntCounterVec::new(
+                    Opts::new("nostalgia_bytes_written_total", "Bytes of records written"),
+                    &["db"],
+                )?,
+                transaction_seconds: HistogramVec::new(
+                    HistogramOpts::new(
+                        "nostalgia_transaction_seconds",
+                        "How long write transactions ran",
+                    ),
+                    &["outcome"],
+                )?,
+                write_lock_wait_seconds: Histogram::with_opts(HistogramOpts::new(
+                    "nostalgia_write_lock_wait_seconds",
+                    "How long write transactions waited to start",
+                ))?,
+                commit_seconds: Histogram::with_opts(HistogramOpts::new(
+                    "nostalgia_commit_seconds",
+                    "How long commits took",
+                ))?,
+                map_used_bytes: IntGauge::new(
+                    "nostalgia_map_used_bytes",
+                    "Bytes of the memory map in use",
+                )?,
+                map_size_bytes: IntGauge::new(
+                    "nostalgia_map_size_bytes",
+                    "Bytes the memory map can grow to",
+                )?,
+            })
+        }
+
+        /// Registers every collector with `registry`
+        pub fn register(&self, registry: &Registry) -> Result<(), prometheus::Error> {
+            registry.register(Box::new(self.operations.clone()))?;
+            registry.register(Box::new(self.bytes_written.clone()))?;
+            registry.register(Box::new(self.transaction_seconds.clone()))?;
+            registry.register(Box::new(self.write_lock_wait_seconds.clone()))?;
+            registry.register(Box::new(self.commit_seconds.clone()))?;
+            registry.register(Box::new(self.map_used_bytes.clone()))?;
+            registry.register(Box::new(self.map_size_bytes.clone()))
+        }
+
+        fn transaction_histogram(&self, committed: bool) -> Histogram {
+            let outcome = if committed { "committed" } else { "aborted" };
+            self.transaction_seconds.with_label_values(&[outcome])
+        }
+    }
+
+    impl MetricsSink for PrometheusMetrics {
+        fn operation(&self, op: &'static str, db_name: &str) {
+            self.operations.with_label_values(&[op, db_name]).inc();
+        }
+
+        fn bytes_written(&self, db_name: &str, bytes: usize) {
+            self.bytes_written
+                .with_label_values(&[db_name])
+                .inc_by(bytes as u64);
+        }
+
+        fn transaction_duration(&self, duration: Duration, committed: bool) {
+            self.transaction_histogram(committed)
+                .observe(duration.as_secs_f64());
+        }
+
+        fn write_lock_wait(&self, duration: Duration) {
+            self.write_lock_wait_seconds.observe(duration.as_secs_f64());
+        }
+
+        fn commit_duration(&self, duration: Duration) {
+            self.commit_seconds.observe(duration.as_secs_f64());
+        }
+
+        fn map_utilization(&self, used_bytes: u64, map_size: u64) {
+            self.map_used_bytes.set(used_bytes as i64);
+            self.map_size_bytes.set(map_size as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage, StorageError};
+    use serde::{Deserialize, Serialize};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Shipment {
+        id: u32,
+        weight: u32,
+    }
+
+    #[derive(Default)]
+    struct Recorded {
+        operations: Vec<(&'static str, String)>,
+        bytes: usize,
+        transactions: Vec<bool>,
+        map_used: u64,
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Recorded>>);
+
+    impl MetricsSink for Recorder {
+        fn operation(&self, op: &'static str, db_name: &str) {
+            let mut recorded = self.0.lock().unwrap();
+            recorded.operations.push((op, db_name.to_string()));
+        }
+
+        fn bytes_written(&self, _db_name: &str, bytes: usize) {
+            self.0.lock().unwrap().bytes += bytes;
+        }
+
+        fn transaction_duration(&self, _duration: Duration, committed: bool) {
+            self.0.lock().unwrap().transactions.push(committed);
+        }
+
+        fn map_utilization(&self, used_bytes: u64, _map_size: u64) {
+            self.0.lock().unwrap().map_used = used_bytes;
+        }
+    }
+
+    #[test]
+    fn test_that_committed_work_is_reported_to_the_sink() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let recorder = Recorder::default();
+        storage.set_metrics(recorder.clone());
+
+        storage
+            .save_batch(vec![
+                Shipment { id: 1, weight: 10 },
+                Shipment { id: 2, weight: 20 },
+            ])
+            .expect("Could not save shipments");
+        let rolled_back: Result<(), StorageError> = storage.transaction(|tx| {
+            tx.save(&Shipment { id: 3, weight: 30 })?;
+            Err(StorageError::Closed)
+        });
+        assert!(rolled_back.is_err());
+        storage
+            .delete(&Shipment { id: 1, weight: 10 })
+            .expect("Could not delete shipment");
+        assert!(storage.get::<Shipment, _>(2).unwrap().is_some());
+
+        let recorded = recorder.0.lock().unwrap();
+        let operations: Vec<(&str, &str)> = recorded
+            .operations
+            .iter()
+            .map(|(op, db_name)| (*op, db_name.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("save", "Shipment"),
+                ("save", "Shipment"),
+                ("delete", "Shipment"),
+                ("get", "Shipment"),
+            ],
+            operations
+        );
+        assert_eq!(16, recorded.bytes);
+        assert_eq!(vec![true, false, true], recorded.transactions);
+        assert!(recorded.map_used > 0);
+    }
+
+    // Keeps what is logged under the slow operation target
+    struct Captured(Mutex<Vec<String>>);
+
+    impl log::Log for Captured {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            metadata.target() == "nostalgia::slow"
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURED: Captured = Captured(Mutex::new(Vec::new()));
+
+    #[test]
+    fn test_that_slow_commits_are_logged() {
+        log::set_logger(&CAPTURED).expect("Could not set logger");
+        log::set_max_level(log::LevelFilter::Warn);
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let options = crate::StorageOptions::default().slow_commit(Duration::from_secs(0));
+        let mut storage = Storage::open_with(dir.path(), options).expect("Could not open storage");
+
+        storage.save(&Shipment { id: 1, weight: 10 }).unwrap();
+
+        let logged = CAPTURED.0.lock().unwrap();
+        assert_eq!(1, logged.len());
+        assert!(logged[0].starts_with("slow commit duration_ms="));
+        assert!(logged[0].ends_with("writes=1 bytes=8 dbs=Shipment:8"));
+    }
+}
@@ -0,0 +1,391 @@
+//! Copies every database of one storage into another, entry by entry, and upgrades the records
+//! of a type stored in an earlier version of it.
+//!
+//! Only raw keys and values are copied, so records of every type, their indexes and raw databases
+//! all come across without knowing their types.  LMDB is the only engine so far, so both ends are
+//! a `Storage`; the copy reads one database at a time from one snapshot and writes each database
+//! in a transaction of its own, then reads both sides again to check they hold the same entries.
+
+use lmdb::{Database, Environment, Transaction, WriteFlags};
+
+use crate::metadata;
+use crate::query;
+use crate::queue::entries_from;
+use crate::usage::{database_names, entries};
+use crate::{Record, Storage, StorageError};
+
+// How many entries are copied between two progress reports
+const PROGRESS_EVERY: usize = 10_000;
+
+// How many records `upgrade_all` reads per write transaction
+const UPGRADE_CHUNK: usize = 1000;
+
+/// How far `migrate_backend` has come, handed to its progress callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// The database being copied
+    pub database: String,
+    /// Entries of the database copied so far
+    pub copied: usize,
+    /// Entries the database holds
+    pub entries: usize,
+    /// Databases copied completely so far
+    pub databases_done: usize,
+    /// Databases to copy in total
+    pub databases: usize,
+}
+
+/// Copies every database of `src` into `dst`, which can't hold any databases yet, and checks
+/// that both end up holding the same entries.  `progress` is called as entries are copied and
+/// once each database is done.  Returns the number of entries copied.
+///
+/// Fails with `StorageError::MigrationMismatch` when a database of `dst` doesn't match its source
+/// afterwards, which can only happen when something else writes to either side during the copy.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{migrate_backend, Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut src = Storage::temporary()?;
+///     src.save(&Place { id: 1, name: "Paris".to_string() })?;
+///
+///     let mut dst = Storage::temporary()?;
+///     let copied = migrate_backend(&src, &mut dst, |progress| {
+///         println!("{}: {}/{}", progress.database, progress.copied, progress.entries);
+///     })?;
+///     // The record, along with its type's tag and schema
+///     assert_eq!(3, copied);
+///     assert_eq!("Paris", dst.get::<Place, _>(1u32)?.unwrap().name);
+///     Ok(())
+/// }
+/// ```
+pub fn migrate_backend<F>(
+    src: &Storage,
+    dst: &mut Storage,
+    mut progress: F,
+) -> Result<usize, StorageError>
+where
+    F: FnMut(&MigrationProgress),
+{
+    let (src, dst) = (src.env()?, dst.env()?);
+    if !database_names(dst)?.is_empty() {
+        return Err(StorageError::NotEmpty);
+    }
+
+    let mut dbs = vec![];
+    for name in database_names(src)? {
+        // Other tools can keep plain records in the unnamed database next to the names
+        match src.open_db(Some(&name)) {
+            Ok(db) => dbs.push((name, db)),
+            Err(lmdb::Error::Incompatible) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let databases = dbs.len();
+    let mut total = 0;
+    for (done, (name, db)) in dbs.iter().enumerate() {
+        let mut report = MigrationProgress {
+            database: name.clone(),
+            copied: 0,
+            entries: 0,
+            databases_done: done,
+            databases,
+        };
+        let (copy, copied) = copy(src, *db, dst, name, |copied, entries| {
+            report.copied = copied;
+            report.entries = entries;
+            progress(&report);
+        })?;
+        verify(src, *db, dst, copy, name)?;
+
+        report.copied = copied;
+        report.entries = copied;
+        report.databases_done = done + 1;
+        progress(&report);
+        total += copied;
+    }
+    Ok(total)
+}
+
+// Copies one database into a new one of the same name and flags, returning the new one and the
+// number of entries copied.  `progress` is called every `PROGRESS_EVERY` entries
+fn copy<F>(
+    src: &Environment,
+    db: Database,
+    dst: &Environment,
+    name: &str,
+    mut progress: F,
+) -> Result<(Database, usize), StorageError>
+where
+    F: FnMut(usize, usize),
+{
+    let read = src.begin_ro_txn()?;
+    let total = entries(&read, db)?;
+    let copy = dst.create_db(Some(name), read.db_flags(db)?)?;
+
+    let mut write = dst.begin_rw_txn()?;
+    let mut copied = 0;
+    let cursor = read.open_ro_cursor(db)?;
+    for entry in query::checked_iter(&cursor) {
+        let (key, value) = entry?;
+        write.put(copy, &key, &value, WriteFlags::empty())?;
+        copied += 1;
+        if copied % PROGRESS_EVERY == 0 {
+            progress(copied, total);
+        }
+    }
+    write.commit()?;
+    Ok((copy, copied))
+}
+
+// Checks that a copied database holds exactly the entries of the original
+fn verify(
+    src: &Environment,
+    db: Database,
+    dst: &Environment,
+    copy: Database,
+    name: &str,
+) -> Result<(), StorageError> {
+    let original = src.begin_ro_txn()?;
+    let copied = dst.begin_ro_txn()?;
+    let original_cursor = original.open_ro_cursor(db)?;
+    let copied_cursor = copied.open_ro_cursor(copy)?;
+    let mut original_entries = query::checked_iter(&original_cursor);
+    let mut copied_entries = query::checked_iter(&copied_cursor);
+    loop {
+        match (
+            original_entries.next().transpose()?,
+            copied_entries.next().transpose()?,
+        ) {
+            (None, None) => return Ok(()),
+            (Some(a), Some(b)) if a == b => {}
+            _ => {
+                return Err(StorageError::MigrationMismatch {
+                    database: name.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// How far `Storage::migrate_all` has come, handed to its progress callback after every chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeProgress {
+    /// Records read so far
+    pub read: usize,
+    /// Records saved back in the current version so far
+    pub upgraded: usize,
+    /// Records the type's database held when the pass started
+    pub records: usize,
+}
+
+// Saves every record of `T` stored in an earlier version back in the current one, a chunk of
+// records per write transaction so other writers get a turn in between.  Returns how many were
+// upgraded
+pub(crate) fn upgrade_all<T, F>(
+    storage: &mut Storage,
+    mut progress: F,
+) -> Result<usize, StorageError>
+where
+    T: Record,
+    F: FnMut(&UpgradeProgress),
+{
+    let db = match storage.existing_db(T::db_name())? {
+        Some(db) => db,
+        None => return Ok(0),
+    };
+    let records = entries(&storage.env()?.begin_ro_txn()?, db)?;
+    let mut report = UpgradeProgress {
+        read: 0,
+        upgraded: 0,
+        records,
+    };
+
+    let mut start = vec![];
+    loop {
+        let page = entries_from(
+            &storage.env()?.begin_ro_txn()?,
+            db,
+            &start,
+            &[],
+            UPGRADE_CHUNK,
+        )?;
+        let outdated: Vec<&[u8]> = page
+            .iter()
+            .filter(|(_, value)| metadata::unwrap::<T>(value).is_some_and(T::is_outdated))
+            .map(|(key, _)| key.as_slice())
+            .collect();
+        if !outdated.is_empty() {
+            // Each record is checked again inside the transaction, in case it changed meanwhile
+            report.upgraded += storage.transaction(|tx| {
+                let mut upgraded = 0;
+                for key in &outdated {
+                    if tx.upgrade::<T>(key)? {
+                        upgraded += 1;
+                    }
+                }
+                Ok(upgraded)
+            })?;
+        }
+        report.read += page.len();
+        progress(&report);
+
+        match page.last() {
+            Some((last, _)) if page.len() == UPGRADE_CHUNK => {
+                start = last.clone();
+                start.push(0);
+            }
+            _ => return Ok(report.upgraded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+    }
+
+    #[test]
+    fn test_that_every_database_is_copied_and_reported() {
+        let mut src = Storage::temporary().expect("Could not open db storage");
+        for (id, line) in &[(1, "M1"), (2, "M1"), (3, "M4")] {
+            src.save(&Station {
+                id: *id,
+                line: line.to_string(),
+            })
+            .unwrap();
+        }
+        src.kv("settings").unwrap().set("theme", &"dark").unwrap();
+
+        let mut dst = Storage::temporary().expect("Could not open db storage");
+        let mut reports = vec![];
+        let copied =
+            migrate_backend(&src, &mut dst, |progress| reports.push(progress.clone())).unwrap();
+        let done: Vec<_> = reports
+            .iter()
+            .map(|report| (report.database.as_str(), report.copied))
+            .collect();
+        // The type's schema and tag are copied along with its records and index entries
+        assert_eq!(
+            vec![
+                ("Station", 3),
+                ("Station.line", 3),
+                ("nostalgia#schemas", 1),
+                ("nostalgia#types", 1),
+                ("settings#kv", 1)
+            ],
+            done
+        );
+        assert_eq!(9, copied);
+        assert_eq!(5, reports[4].databases_done);
+
+        let m1: Vec<Station> = dst.find_by_index("line", "M1").unwrap();
+        assert_eq!(2, m1.len());
+        assert!(matches!(
+            migrate_backend(&src, &mut dst, |_| {}),
+            Err(StorageError::NotEmpty)
+        ));
+    }
+
+    mod v1 {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        pub struct Ride {
+            pub id: u32,
+            pub minutes: u32,
+        }
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(versions = "v1::Ride")]
+    struct Ride {
+        id: u32,
+        seconds: u64,
+    }
+
+    impl From<v1::Ride> for Ride {
+        fn from(ride: v1::Ride) -> Ride {
+            Ride {
+                id: ride.id,
+                seconds: u64::from(ride.minutes) * 60,
+            }
+        }
+    }
+
+    // A `Ride` stored in its first version
+    fn ride_v1(id: u32) -> Vec<u8> {
+        let mut value = vec![1];
+        value.extend(bincode::serialize(&v1::Ride { id, minutes: 2 }).unwrap());
+        value
+    }
+
+    #[test]
+    fn test_that_outdated_records_are_upgraded_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = crate::StorageOptions::default().upgrade::<Ride>(crate::UpgradePolicy::Eager);
+        let mut storage =
+            Storage::open_with(dir.path(), options).expect("Could not open db storage");
+
+        let raw = storage.raw("Ride").unwrap();
+        for id in 0..2500u32 {
+            raw.put_bytes(&Vec::<u8>::from(Key::from(id)), &ride_v1(id))
+                .unwrap();
+        }
+        storage
+            .save(&Ride {
+                id: 2500,
+                seconds: 5,
+            })
+            .unwrap();
+
+        let mut reports = vec![];
+        let upgraded = storage
+            .migrate_all::<Ride, _>(|progress| reports.push(progress.clone()))
+            .unwrap();
+        assert_eq!(2500, upgraded);
+        let read: Vec<_> = reports.iter().map(|report| report.read).collect();
+        assert_eq!(vec![1000, 2000, 2501], read);
+        assert_eq!(2501, reports[2].records);
+        assert_eq!(0, storage.migrate_all::<Ride, _>(|_| {}).unwrap());
+
+        // Registering the type runs the pass when the policy is eager
+        let key = Vec::<u8>::from(Key::from(7u32));
+        storage
+            .raw("Ride")
+            .unwrap()
+            .put_bytes(&key, &ride_v1(7))
+            .unwrap();
+        storage.register::<Ride>().unwrap();
+        let stored = storage.raw("Ride").unwrap().get_bytes(&key).unwrap();
+        assert!(!Ride::is_outdated(&stored.unwrap()));
+        let ride = storage.get::<Ride, _>(7u32).unwrap();
+        assert_eq!(
+            Some(Ride {
+                id: 7,
+                seconds: 120
+            }),
+            ride
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use lmdb::{Environment, EnvironmentFlags};
+use lmdb_sys as ffi;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{Cap, Record, RetryPolicy, StorageError};
+
+/// What happens to a query whose read transaction has been open longer than allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadAgePolicy {
+    /// Logs a warning through the `log` crate once and keeps reading
+    Warn,
+    /// Stops the query.  Checked queries yield a `ReadTooOld` error as their last item, others
+    /// keep it for `RoQuery::error` and `KeyQuery::error`
+    Abort,
+}
+
+/// Whether opening a storage may create its environment, see `StorageOptions::open_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Opens the environment at the path, creating it when there is none
+    CreateIfMissing,
+    /// Fails with `StorageError::Missing` when there is no environment at the path yet
+    MustExist,
+    /// Fails with `StorageError::AlreadyExists` when there already is an environment at the path
+    MustCreate,
+}
+
+/// Where the databases of record types live, see `StorageOptions::layout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Every record type is stored in the storage's own environment, unless it has a partition
+    Shared,
+    /// Every record type is stored in an environment of its own, in a subdirectory of the
+    /// storage named after its partition or database
+    PerDatabase,
+}
+
+/// What happens to a record read back in an earlier version of its type, see
+/// `StorageOptions::upgrade`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradePolicy {
+    /// The record is upgraded every time it is read and stays stored as it was
+    OnRead,
+    /// The record is upgraded when it is read through `Storage::get` and saved back in the
+    /// current version, so the upgrade only runs once
+    WriteBack,
+    /// Every record is saved back in the current version by `Storage::migrate_all` when the type
+    /// is registered, and records read before then are written back like with `WriteBack`
+    Eager,
+}
+
+/// How much of a commit is on disk by the time it returns.  Modes other than `Strict` leave the
+/// OS to write commits out in its own time, which is much faster for many small writes; call
+/// `Storage::flush` to make sure they are on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Every commit is synced to disk before it returns
+    Strict,
+    /// Commits sync the data but not the meta page (`MDB_NOMETASYNC`).  A crash can lose the
+    /// last commit, but not corrupt the database
+    Relaxed,
+    /// Commits don't sync at all (`MDB_NOSYNC`, and `MDB_MAPASYNC` with `write_map`).  A crash can
+    /// lose any commit since the last flush, and with `write_map` a system crash can corrupt the
+    /// database
+    Async,
+}
+
+impl Durability {
+    fn flags(self, write_map: bool) -> EnvironmentFlags {
+        match self {
+            Durability::Strict => EnvironmentFlags::empty(),
+            Durability::Relaxed => EnvironmentFlags::NO_META_SYNC,
+            Durability::Async if write_map => {
+                EnvironmentFlags::NO_SYNC | EnvironmentFlags::MAP_ASYNC
+            }
+            Durability::Async => EnvironmentFlags::NO_SYNC,
+        }
+    }
+}
+
+/// Switches an open environment to another durability mode
+pub(crate) fn set_durability(
+    env: &Environment,
+    durability: Durability,
+    write_map: bool,
+) -> Result<(), lmdb::Error> {
+    let all =
+        EnvironmentFlags::NO_SYNC | EnvironmentFlags::NO_META_SYNC | EnvironmentFlags::MAP_ASYNC;
+    let check = |code| match code {
+        0 => Ok(()),
+        code => Err(lmdb::Error::from_err_code(code)),
+    };
+    unsafe {
+        check(ffi::mdb_env_set_flags(env.env(), all.bits(), 0))?;
+        check(ffi::mdb_env_set_flags(
+            env.env(),
+            durability.flags(write_map).bits(),
+            1,
+        ))
+    }
+}
+
+/// Settings used when a storage environment is opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageOptions {
+    /// The most bytes the data file can grow to
+    pub map_size: usize,
+    /// The most named databases, including index and counter databases, the environment can hold
+    pub max_dbs: u32,
+    /// Whether the OS reads ahead when pages of the data file are faulted in
+    pub readahead: bool,
+    /// Whether writes go straight to a writeable memory map instead of through `write` calls
+    pub write_map: bool,
+    /// Whether missing directories and databases are created when they're first used
+    pub create: bool,
+    /// Whether the environment has to exist already, or has to be new
+    pub open_mode: OpenMode,
+    /// How much of a commit is on disk by the time it returns
+    pub durability: Durability,
+    /// How write transactions that can't be started right away are retried, if at all
+    pub retry: Option<RetryPolicy>,
+    /// How long a query may keep its read transaction open, and what happens after that
+    pub max_read_age: Option<(Duration, ReadAgePolicy)>,
+    /// The record types whose reads through `Storage::get_shared` are cached, by database name,
+    /// with how many records each cache holds
+    pub caches: Vec<(&'static str, usize)>,
+    /// What happens to records read back in an earlier version of their type, by database name.
+    /// Types that aren't listed use `UpgradePolicy::OnRead`
+    pub upgrades: Vec<(&'static str, UpgradePolicy)>,
+    /// Whether every committed write transaction is added to the change journal
+    pub journal: bool,
+    /// Whether every record write is stamped with a version for syncing with other storages
+    pub versions: bool,
+    /// How long a commit may take before it is logged as slow, if at all
+    pub slow_commit: Option<Duration>,
+    /// The record types that evict their oldest records to stay within a cap, by database name
+    pub caps: Vec<(&'static str, Cap)>,
+    /// Whether record types share the storage's environment or get one each
+    pub layout: Layout,
+    /// The directories of environments kept somewhere other than a subdirectory of the storage,
+    /// by partition or database name
+    pub locations: Vec<(&'static str, PathBuf)>,
+    /// Whether deleting a record that isn't stored fails instead of returning false
+    pub strict_deletes: bool,
+    /// How many reset read transactions each read handle keeps for reuse, 0 for none
+    pub read_pool: usize,
+}
+
+impl Default for StorageOptions {
+    fn default() -> StorageOptions {
+        StorageOptions {
+            map_size: 256 * 1024 * 1024,
+            max_dbs: 2048,
+            readahead: true,
+            write_map: false,
+            create: true,
+            open_mode: OpenMode::CreateIfMissing,
+            durability: Durability::Strict,
+            retry: None,
+            max_read_age: None,
+            caches: vec![],
+            upgrades: vec![],
+            journal: false,
+            versions: false,
+            slow_commit: None,
+            caps: vec![],
+            layout: Layout::Shared,
+            locations: vec![],
+            strict_deletes: false,
+            read_pool: 0,
+        }
+    }
+}
+
+impl StorageOptions {
+    /// Sets the most bytes the data file can grow to
+    pub fn map_size(mut self, map_size: usize) -> StorageOptions {
+        self.map_size = map_size;
+        self
+    }
+
+    /// Sets the most named databases the environment can hold
+    pub fn max_dbs(mut self, max_dbs: u32) -> StorageOptions {
+        self.max_dbs = max_dbs;
+        self
+    }
+
+    /// Sets whether the OS reads ahead when pages are faulted in.  Turning it off helps random
+    /// reads from a database that is larger than memory
+    pub fn readahead(mut self, readahead: bool) -> StorageOptions {
+        self.readahead = readahead;
+        self
+    }
+
+    /// Sets whether writes go through a writeable memory map, which is faster but lets stray
+    /// pointer writes in the process corrupt the database
+    pub fn write_map(mut self, write_map: bool) -> StorageOptions {
+        self.write_map = write_map;
+        self
+    }
+
+    /// Sets whether missing directories and databases are created.  Turn it off to read an
+    /// environment written by another tool without adding anything to it
+    pub fn create(mut self, create: bool) -> StorageOptions {
+        self.create = create;
+        self
+    }
+
+    /// Sets whether the environment has to exist already, or has to be new, so a deploy that
+    /// attaches to existing data fails at a mistyped path instead of starting out empty.  The
+    /// environment exists once its data file does.  Partitions and tenant storages opened later
+    /// are created as needed either way
+    pub fn open_mode(mut self, mode: OpenMode) -> StorageOptions {
+        self.open_mode = mode;
+        self
+    }
+
+    /// Sets how much of a commit is on disk by the time it returns, see `Durability`.  Bulk loads
+    /// can run with `Durability::Async` and flush once at the end
+    pub fn durability(mut self, durability: Durability) -> StorageOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Retries write transactions that can't be started because another process grew the map or
+    /// holds a lock, instead of failing on the first attempt.  A grown map is only taken on while
+    /// no read handle from `Storage::split` shares the environment
+    pub fn retry(mut self, policy: RetryPolicy) -> StorageOptions {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Sets how long a query may keep its read transaction open.  Old read transactions keep the
+    /// pages they read from being reused, so the data file grows while they're open
+    pub fn max_read_age(mut self, age: Duration, policy: ReadAgePolicy) -> StorageOptions {
+        self.max_read_age = Some((age, policy));
+        self
+    }
+
+    /// Caches up to `capacity` records of `T` read through `Storage::get_shared`, dropping the
+    /// least recently used one when the cache is full.  Saves and deletes made through the storage
+    /// evict the records they change
+    pub fn cache<T: Record>(mut self, capacity: usize) -> StorageOptions {
+        self.caches.retain(|(db_name, _)| *db_name != T::db_name());
+        self.caches.push((T::db_name(), capacity));
+        self
+    }
+
+    /// Sets what happens to records of `T` read back in an earlier version of the type, listed
+    /// with `#[storable(versions = "...")]`
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, StorageOptions, UpgradePolicy, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// mod v1 {
+    ///     #[derive(serde::Serialize, serde::Deserialize)]
+    ///     pub struct Place {
+    ///         pub id: u32,
+    ///         pub name: std::string::String,
+    ///     }
+    /// }
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[storable(versions = "v1::Place")]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   country: Option<std::string::String>
+    /// }
+    ///
+    /// impl From<v1::Place> for Place {
+    ///     fn from(place: v1::Place) -> Place {
+    ///         Place { id: place.id, name: place.name, country: None }
+    ///     }
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let options = StorageOptions::default().upgrade::<Place>(UpgradePolicy::WriteBack);
+    ///     let mut storage = Storage::open_with("/tmp/nostalgia-upgrade", options)?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string(), country: None })?;
+    ///
+    ///     let place = storage.get::<Place, _>(1)?.expect("Empty record");
+    ///     assert_eq!(None, place.country);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn upgrade<T: Record>(mut self, policy: UpgradePolicy) -> StorageOptions {
+        self.upgrades
+            .retain(|(db_name, _)| *db_name != T::db_name());
+        self.upgrades.push((T::db_name(), policy));
+        self
+    }
+
+    /// The policy for records of `T` read back in an earlier version
+    pub(crate) fn upgrade_policy<T: Record>(&self) -> UpgradePolicy {
+        self.upgrades
+            .iter()
+            .find(|(db_name, _)| *db_name == T::db_name())
+            .map_or(UpgradePolicy::OnRead, |(_, policy)| *policy)
+    }
+
+    /// Keeps a journal of every write transaction that commits, which `Storage::replicate_to`
+    /// ships to followers.  The journal grows until it is truncated with
+    /// `Storage::truncate_journal`
+    pub fn journal(mut self, journal: bool) -> StorageOptions {
+        self.journal = journal;
+        self
+    }
+
+    /// Makes `Storage::delete` fail with `StorageError::NotFound` for a record that isn't stored,
+    /// instead of returning false
+    pub fn strict_deletes(mut self, strict_deletes: bool) -> StorageOptions {
+        self.strict_deletes = strict_deletes;
+        self
+    }
+
+    /// Lets every read handle from `Storage::split` keep up to `size` read transactions it is
+    /// done with, and renew them for later reads instead of beginning new ones.  Kept
+    /// transactions hold on to their reader slots, of which LMDB has 126.  The environment is
+    /// opened with `MDB_NOTLS` so they can be renewed on any thread
+    pub fn read_pool(mut self, size: usize) -> StorageOptions {
+        self.read_pool = size;
+        self
+    }
+
+    /// Keeps a version vector for every record written, which `Storage::changes_since` and
+    /// `Storage::apply_remote` use to sync storages that each take writes
+    pub fn versions(mut self, versions: bool) -> StorageOptions {
+        self.versions = versions;
+        self
+    }
+
+    /// Logs every commit that takes `threshold` or longer as a warning, with the databases it
+    /// wrote to and how many bytes, under the `nostalgia::slow` target of the `log` crate.
+    /// Commits stalled by a slow disk sync show up here first
+    pub fn slow_commit(mut self, threshold: Duration) -> StorageOptions {
+        self.slow_commit = Some(threshold);
+        self
+    }
+
+    /// Caps the records of `T`.  Every save that takes `T` past the cap deletes its records with
+    /// the smallest keys in the same transaction until it is within the cap again, the way
+    /// `delete` would, so types keyed by an id or time that only grows keep their newest records.
+    /// A record saved with a key smaller than all others can be the one evicted.  A record that
+    /// takes up more than `max_bytes` on its own fails with `StorageError::OverCap`
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Cap, Storage, StorageOptions, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Activity {
+    ///   id: u64,
+    ///   text: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let options = StorageOptions::default().capped::<Activity>(Cap::default().max_entries(2));
+    ///     let mut storage = Storage::open_with(dir.path(), options)?;
+    ///
+    ///     for (id, text) in [(1, "signed up"), (2, "logged in"), (3, "logged out")] {
+    ///         storage.save(&Activity { id, text: text.to_string() })?;
+    ///     }
+    ///     let ids: Vec<u64> = storage.query::<Activity>()?.map(|activity| activity.id).collect();
+    ///     assert_eq!(vec![2, 3], ids);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn capped<T: Record>(mut self, cap: Cap) -> StorageOptions {
+        self.caps.retain(|(db_name, _)| *db_name != T::db_name());
+        self.caps.push((T::db_name(), cap));
+        self
+    }
+
+    /// The cap of `T`, if it is capped
+    pub(crate) fn cap<T: Record>(&self) -> Option<Cap> {
+        self.caps
+            .iter()
+            .find(|(db_name, _)| *db_name == T::db_name())
+            .map(|(_, cap)| *cap)
+    }
+
+    /// Sets whether record types share the storage's environment or get one each.  With
+    /// `Layout::PerDatabase` every type is routed like a type with a partition, so transactions
+    /// only cover the records of one type
+    pub fn layout(mut self, layout: Layout) -> StorageOptions {
+        self.layout = layout;
+        self
+    }
+
+    /// Keeps the environment of `T` in the directory at `path`, so it can sit on a disk of its
+    /// own.  `T` gets an environment of its own even with `Layout::Shared`
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, StorageOptions, Layout, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Photo {
+    ///   id: u32,
+    ///   place: u32
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let root = dir.path();
+    ///     let options = StorageOptions::default()
+    ///         .layout(Layout::PerDatabase)
+    ///         .location::<Photo>(root.join("photos"));
+    ///     let mut storage = Storage::open_with(root.join("db"), options)?;
+    ///
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///     storage.save(&Photo { id: 1, place: 1 })?;
+    ///     assert!(root.join("db").join("Place").join("data.mdb").exists());
+    ///     assert!(root.join("photos").join("data.mdb").exists());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn location<T: Record>(mut self, path: PathBuf) -> StorageOptions {
+        let name = T::partition().unwrap_or(T::db_name());
+        self.locations.retain(|(located, _)| *located != name);
+        self.locations.push((name, path));
+        self
+    }
+
+    /// The environment the records of `T` are stored in, `None` for the storage's own
+    pub(crate) fn partition_of<T: Record>(&self) -> Option<&'static str> {
+        T::partition().or_else(|| {
+            let located = self.locations.iter().any(|(name, _)| *name == T::db_name());
+            if located || self.layout == Layout::PerDatabase {
+                Some(T::db_name())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The directory of the environment named `name`, for a storage at `root`
+    pub(crate) fn partition_path(&self, root: &Path, name: &str) -> PathBuf {
+        self.locations
+            .iter()
+            .find(|(located, _)| *located == name)
+            .map_or_else(|| root.join(name), |(_, path)| path.clone())
+    }
+
+    fn flags(&self) -> EnvironmentFlags {
+        let mut flags = EnvironmentFlags::empty();
+        if !self.readahead {
+            flags |= EnvironmentFlags::NO_READAHEAD;
+        }
+        if self.write_map {
+            flags |= EnvironmentFlags::WRITE_MAP;
+        }
+        if self.read_pool > 0 {
+            flags |= EnvironmentFlags::NO_TLS;
+        }
+        flags | self.durability.flags(self.write_map)
+    }
+
+    /// Checks the path against the open mode, before anything is created there
+    pub(crate) fn check_mode(&self, path: &Path) -> Result<(), StorageError> {
+        let exists = path.join("data.mdb").exists();
+        match self.open_mode {
+            OpenMode::MustExist if !exists => Err(StorageError::Missing {
+                path: path.to_path_buf(),
+            }),
+            OpenMode::MustCreate if exists => Err(StorageError::AlreadyExists {
+                path: path.to_path_buf(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn open(&self, path: &Path) -> Result<lmdb::Environment, lmdb::Error> {
+        let mut builder = lmdb::Environment::new();
+        builder.set_max_dbs(self.max_dbs);
+        builder.set_map_size(self.map_size);
+        builder.set_flags(self.flags());
+        builder.open(path)
+    }
+}
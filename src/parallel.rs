@@ -0,0 +1,192 @@
+//! Full scans spread across rayon's thread pool.
+//!
+//! The key space is split into chunks by walking the keys once without reading any values.  Each
+//! chunk is then read in its own read transaction on whichever thread picks it up.
+
+use lmdb::{Cursor, Transaction};
+use rayon::prelude::*;
+
+use crate::type_tag::{self, TYPES_DB};
+use crate::{metadata, query, usage};
+use crate::{Record, Storage, StorageError};
+
+// How many chunks each thread gets, so threads that finish early can take work from slow ones
+const CHUNKS_PER_THREAD: usize = 4;
+
+// Where a chunk starts and how many entries it holds.  The last chunk runs to the end
+struct Chunk {
+    start: Vec<u8>,
+    len: Option<usize>,
+}
+
+// Picks the first key of every chunk.  A chunk never starts between two values of the same key,
+// so databases with duplicate keys are split cleanly too.
+fn chunks(
+    txn: &lmdb::RoTransaction,
+    db: lmdb::Database,
+    chunk_len: usize,
+) -> Result<Vec<Chunk>, StorageError> {
+    let cursor = txn.open_ro_cursor(db)?;
+    let first = match cursor.get(None, None, lmdb_sys::MDB_FIRST) {
+        Ok((Some(key), value)) => (key, value),
+        Ok((None, _)) | Err(lmdb::Error::NotFound) => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut starts: Vec<(Vec<u8>, usize)> = vec![(first.0.to_vec(), 0)];
+    let mut previous = first.0;
+    for (position, entry) in query::checked_iter(&cursor).enumerate() {
+        let (key, _) = entry?;
+        let position = position + 1;
+        let (_, start) = starts.last().expect("There is always a first chunk");
+        if position - start >= chunk_len && key != previous {
+            starts.push((key.to_vec(), position));
+        }
+        previous = key;
+    }
+
+    let ends: Vec<Option<usize>> = starts
+        .iter()
+        .skip(1)
+        .map(|(_, position)| Some(*position))
+        .chain(std::iter::once(None))
+        .collect();
+    Ok(starts
+        .into_iter()
+        .zip(ends)
+        .map(|((start, position), end)| Chunk {
+            start,
+            len: end.map(|end| end - position),
+        })
+        .collect())
+}
+
+fn read_chunk<T: Record>(
+    env: &lmdb::Environment,
+    db: lmdb::Database,
+    chunk: &Chunk,
+) -> Result<Vec<Result<T, StorageError>>, StorageError> {
+    let txn = env.begin_ro_txn()?;
+    let cursor = txn.open_ro_cursor(db)?;
+    let first = match cursor.get(Some(&chunk.start), None, lmdb_sys::MDB_SET_KEY) {
+        Ok((key, value)) => (key.unwrap_or(&chunk.start), value),
+        Err(e) => return Err(e.into()),
+    };
+
+    let entries = std::iter::once(Ok(first)).chain(query::checked_iter(&cursor));
+    let decode = |entry: Result<(&[u8], &[u8]), StorageError>| {
+        let (key, value) = entry?;
+        metadata::decode::<T>(key, value)
+    };
+
+    Ok(match chunk.len {
+        Some(len) => entries.take(len).map(decode).collect(),
+        None => entries.map(decode).collect(),
+    })
+}
+
+impl Storage {
+    /// Reads every record in a type's database across rayon's thread pool.
+    ///
+    /// Records come out in no particular order.  A value that can't be read is yielded as an
+    /// error rather than skipped, as are errors from the read transactions.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use rayon::prelude::*;
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/nostalgia-par-query")?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     let longest = storage
+    ///         .par_query::<Place>()?
+    ///         .map(|place| place.map(|place| place.name.len()))
+    ///         .try_reduce(|| 0, |a, b| Ok(a.max(b)))?;
+    ///     assert_eq!(6, longest);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn par_query<T>(
+        &mut self,
+    ) -> Result<impl ParallelIterator<Item = Result<T, StorageError>> + '_, StorageError>
+    where
+        T: Record + Send,
+    {
+        let storage = self.storage_for::<T>()?;
+        let db = storage.existing_db(T::db_name())?;
+        let types = storage.existing_db(TYPES_DB)?;
+        let env = storage.env()?;
+
+        let chunks = match db {
+            Some(db) => {
+                let txn = env.begin_ro_txn()?;
+                type_tag::check::<T>(&txn, types)?;
+                let entries = usage::entries(&txn, db)?;
+                let threads = rayon::current_num_threads() * CHUNKS_PER_THREAD;
+                chunks(&txn, db, (entries / threads).max(1))?
+            }
+            None => vec![],
+        };
+
+        Ok(chunks.into_par_iter().flat_map_iter(move |chunk| {
+            let db = db.expect("Chunks are only made for existing databases");
+            match read_chunk::<T>(env, db, &chunk) {
+                Ok(records) => records,
+                Err(e) => vec![Err(e)],
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Sensor {
+        id: u32,
+        reading: f64,
+    }
+
+    #[test]
+    fn test_that_parallel_queries_read_every_record_once() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        assert_eq!(0, storage.par_query::<Sensor>().unwrap().count());
+
+        storage
+            .save_batch(
+                (0..1000)
+                    .map(|id| Sensor {
+                        id,
+                        reading: f64::from(id) / 2.0,
+                    })
+                    .collect(),
+            )
+            .expect("Could not save sensors");
+
+        let mut ids: Vec<u32> = storage
+            .par_query::<Sensor>()
+            .unwrap()
+            .map(|sensor| sensor.map(|sensor| sensor.id))
+            .collect::<Result<_, _>>()
+            .expect("Could not read sensors");
+        ids.sort_unstable();
+        assert_eq!((0..1000).collect::<Vec<u32>>(), ids);
+    }
+}
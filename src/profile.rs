@@ -0,0 +1,154 @@
+//! The sizes of the values stored for a record type, to guide compression and schema decisions.
+
+use lmdb::{Database, Transaction};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::query;
+use crate::StorageError;
+
+// How many of the largest records a profile lists
+const LARGEST: usize = 10;
+
+/// How the values of a record type's database are sized, see `Storage::profile`.  Sizes are of
+/// the values as stored, so they include any metadata envelope
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValueProfile {
+    /// How many records are stored
+    pub records: usize,
+    /// The bytes of every key taken together
+    pub key_bytes: u64,
+    /// The bytes of every value taken together
+    pub value_bytes: u64,
+    /// The size of the smallest value
+    pub min_value_bytes: usize,
+    /// The size of the largest value
+    pub max_value_bytes: usize,
+    /// How many values fall into each power of two of sizes, smallest first, leaving out those
+    /// none fall into
+    pub histogram: Vec<SizeBucket>,
+    /// The keys of the largest records with the sizes of their values, largest first
+    pub largest: Vec<(Vec<u8>, usize)>,
+}
+
+impl ValueProfile {
+    /// The average size of a key, 0 without records
+    pub fn average_key_bytes(&self) -> f64 {
+        self.key_bytes as f64 / self.records.max(1) as f64
+    }
+
+    /// The average size of a value, 0 without records
+    pub fn average_value_bytes(&self) -> f64 {
+        self.value_bytes as f64 / self.records.max(1) as f64
+    }
+}
+
+/// The values of a profile up to a size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeBucket {
+    /// The largest size in the bucket, the smallest being one more than the previous bucket's
+    pub max_bytes: usize,
+    /// How many values are in the bucket
+    pub records: usize,
+}
+
+// The power of two a value of `len` bytes is counted under
+fn bucket(len: usize) -> usize {
+    len.max(1).next_power_of_two()
+}
+
+/// Reads every entry of `db` and profiles the sizes of their values
+pub(crate) fn profile(txn: &impl Transaction, db: Database) -> Result<ValueProfile, StorageError> {
+    let mut profile = ValueProfile {
+        min_value_bytes: usize::MAX,
+        ..ValueProfile::default()
+    };
+    let mut histogram: Vec<SizeBucket> = vec![];
+    let mut largest = BinaryHeap::new();
+
+    let cursor = txn.open_ro_cursor(db)?;
+    for entry in query::checked_iter(&cursor) {
+        let (key, value) = entry?;
+        profile.records += 1;
+        profile.key_bytes += key.len() as u64;
+        profile.value_bytes += value.len() as u64;
+        profile.min_value_bytes = profile.min_value_bytes.min(value.len());
+        profile.max_value_bytes = profile.max_value_bytes.max(value.len());
+
+        let max_bytes = bucket(value.len());
+        match histogram.binary_search_by_key(&max_bytes, |bucket| bucket.max_bytes) {
+            Ok(found) => histogram[found].records += 1,
+            Err(at) => histogram.insert(
+                at,
+                SizeBucket {
+                    max_bytes,
+                    records: 1,
+                },
+            ),
+        }
+
+        // Holds the smallest of the largest on top, so it makes way for a larger one
+        largest.push(Reverse((value.len(), key.to_vec())));
+        if largest.len() > LARGEST {
+            largest.pop();
+        }
+    }
+
+    if profile.records == 0 {
+        profile.min_value_bytes = 0;
+    }
+    profile.histogram = histogram;
+    profile.largest = largest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((len, key))| (key, len))
+        .collect();
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Note {
+        id: u32,
+        body: Vec<u8>,
+    }
+
+    #[test]
+    fn test_that_value_sizes_are_profiled() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        assert_eq!(0, storage.profile::<Note>().unwrap().records);
+
+        // Values hold the id, the length of the body and the body
+        for id in 0..20 {
+            let len = if id < 15 { 4 } else { 100 * id as usize };
+            storage
+                .save(&Note {
+                    id,
+                    body: vec![0; len],
+                })
+                .unwrap();
+        }
+
+        let profile = storage.profile::<Note>().unwrap();
+        assert_eq!(20, profile.records);
+        assert_eq!(4.0, profile.average_key_bytes());
+        assert_eq!(16, profile.min_value_bytes);
+        assert_eq!(1912, profile.max_value_bytes);
+        let bucket = |max_bytes, records| SizeBucket { max_bytes, records };
+        assert_eq!(vec![bucket(16, 15), bucket(2048, 5)], profile.histogram);
+
+        assert_eq!(10, profile.largest.len());
+        let largest: Vec<u8> = Key::from(19u32).into();
+        assert_eq!((largest, 1912), profile.largest[0]);
+        assert!(profile
+            .largest
+            .windows(2)
+            .all(|pair| pair[0].1 >= pair[1].1));
+    }
+}
@@ -0,0 +1,27 @@
+//! The messages a nostalgia server and its remote clients exchange, see `remote` and `server`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::ChangeOp;
+
+/// An entry of a scan, with its key and value in hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub key: String,
+    pub value: String,
+}
+
+/// The value a batch expects to find under a key, `None` when it expects none
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Expected {
+    pub db: String,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+}
+
+/// Writes applied together, only while every expected value is still there
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct WriteBatch {
+    pub expect: Vec<Expected>,
+    pub ops: Vec<ChangeOp>,
+}
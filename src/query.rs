@@ -1,42 +1,636 @@
-use crate::Record;
-use lmdb::{Cursor, Transaction};
+use crate::metadata::{self, Metadata};
+use crate::usage;
+use crate::{ReadAgePolicy, Record, StorageError};
+use lmdb::Transaction;
+use lmdb_sys as ffi;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant, SystemTime};
+
+// A key and value read by a cursor
+type Entry<'a> = (&'a [u8], &'a [u8]);
+
+fn check(code: i32) -> Result<(), lmdb::Error> {
+    match code {
+        0 => Ok(()),
+        code => Err(lmdb::Error::from_err_code(code)),
+    }
+}
+
+/// A cursor that is opened once and kept for the whole query.  lmdb's own `Iter` borrows the
+/// cursor it was made from, which a query that also owns the transaction can't hold on to, so the
+/// raw cursor is kept instead and closed when the query is dropped.
+struct RawCursor {
+    cursor: *mut ffi::MDB_cursor,
+    positioned: bool,
+    done: bool,
+    remaining: usize,
+    opened: Instant,
+    max_age: Option<(Duration, ReadAgePolicy)>,
+    warned: bool,
+}
+
+impl RawCursor {
+    fn open(txn: &lmdb::RoTransaction, db: lmdb::Database) -> Result<RawCursor, StorageError> {
+        let remaining = usage::entries(txn, db)?;
+        let mut cursor = std::ptr::null_mut();
+        unsafe { check(ffi::mdb_cursor_open(txn.txn(), db.dbi(), &mut cursor))? };
+
+        Ok(RawCursor {
+            cursor,
+            positioned: false,
+            done: false,
+            remaining,
+            opened: Instant::now(),
+            max_age: None,
+            warned: false,
+        })
+    }
+
+    /// A cursor over a database that doesn't exist, which has no entries
+    fn empty() -> RawCursor {
+        RawCursor {
+            cursor: std::ptr::null_mut(),
+            positioned: false,
+            done: true,
+            remaining: 0,
+            opened: Instant::now(),
+            max_age: None,
+            warned: false,
+        }
+    }
+
+    // Opens a cursor over a database that may not exist
+    fn open_existing(
+        txn: &lmdb::RoTransaction,
+        db: Option<lmdb::Database>,
+    ) -> Result<RawCursor, StorageError> {
+        match db {
+            Some(db) => RawCursor::open(txn, db),
+            None => Ok(RawCursor::empty()),
+        }
+    }
+
+    // Returns an error once the transaction has been open too long under `ReadAgePolicy::Abort`
+    fn check_age(&mut self) -> Result<(), StorageError> {
+        let (max_age, policy) = match self.max_age {
+            Some(max_age) => max_age,
+            None => return Ok(()),
+        };
+        let age = self.opened.elapsed();
+        if age <= max_age {
+            return Ok(());
+        }
+
+        match policy {
+            ReadAgePolicy::Abort => Err(StorageError::ReadTooOld { age }),
+            ReadAgePolicy::Warn => {
+                if !self.warned {
+                    self.warned = true;
+                    log::warn!("read transaction has been open for {:?}", age);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Moves to the next entry and returns its key and value.  Both point into the memory map,
+    /// which stays valid for as long as the transaction the cursor was opened in.  Nothing more
+    /// is returned after the last entry or an error
+    fn next(&mut self) -> Option<Result<Entry<'_>, StorageError>> {
+        if self.done {
+            return None;
+        }
+        if let Err(e) = self.check_age() {
+            self.done = true;
+            self.remaining = 0;
+            return Some(Err(e));
+        }
+
+        let op = if self.positioned {
+            ffi::MDB_NEXT
+        } else {
+            ffi::MDB_FIRST
+        };
+        self.positioned = true;
+
+        let mut key = ffi::MDB_val {
+            mv_size: 0,
+            mv_data: std::ptr::null_mut(),
+        };
+        let mut data = ffi::MDB_val {
+            mv_size: 0,
+            mv_data: std::ptr::null_mut(),
+        };
+        unsafe {
+            match ffi::mdb_cursor_get(self.cursor, &mut key, &mut data, op) {
+                0 => {
+                    self.remaining = self.remaining.saturating_sub(1);
+                    Some(Ok((slice(&key), slice(&data))))
+                }
+                code => {
+                    self.done = true;
+                    self.remaining = 0;
+                    match code {
+                        ffi::MDB_NOTFOUND => None,
+                        code => Some(Err(lmdb::Error::from_err_code(code).into())),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves past `n` entries without handing out their keys or values, so skipping ahead doesn't
+    /// slice, copy or decode anything.  Returns false when the entries ran out first
+    fn skip(&mut self, n: usize) -> Result<bool, StorageError> {
+        if n == 0 {
+            return Ok(!self.done);
+        }
+        if self.done {
+            return Ok(false);
+        }
+        if let Err(e) = self.check_age() {
+            self.done = true;
+            self.remaining = 0;
+            return Err(e);
+        }
+
+        let mut key = ffi::MDB_val {
+            mv_size: 0,
+            mv_data: std::ptr::null_mut(),
+        };
+        let mut data = ffi::MDB_val {
+            mv_size: 0,
+            mv_data: std::ptr::null_mut(),
+        };
+        for _ in 0..n {
+            let op = if self.positioned {
+                ffi::MDB_NEXT
+            } else {
+                ffi::MDB_FIRST
+            };
+            self.positioned = true;
+
+            match unsafe { ffi::mdb_cursor_get(self.cursor, &mut key, &mut data, op) } {
+                0 => self.remaining = self.remaining.saturating_sub(1),
+                code => {
+                    self.done = true;
+                    self.remaining = 0;
+                    return match code {
+                        ffi::MDB_NOTFOUND => Ok(false),
+                        code => Err(lmdb::Error::from_err_code(code).into()),
+                    };
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+/// Iterates from a cursor's position in key order like lmdb's `Iter`, which ends quietly on an
+/// error, but yields the error instead.  An unpositioned cursor starts at the first entry, and
+/// nothing more is yielded after an error
+pub(crate) fn checked_iter<'c, 'txn, C: lmdb::Cursor<'txn>>(
+    cursor: &'c C,
+) -> impl Iterator<Item = Result<Entry<'txn>, StorageError>> + 'c {
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        match cursor.get(None, None, ffi::MDB_NEXT) {
+            Ok((key, value)) => Some(Ok((key.unwrap_or(&[]), value))),
+            Err(e) => {
+                done = true;
+                match e {
+                    lmdb::Error::NotFound => None,
+                    e => Some(Err(e.into())),
+                }
+            }
+        }
+    })
+}
+
+unsafe fn slice<'a>(val: &ffi::MDB_val) -> &'a [u8] {
+    if val.mv_size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(val.mv_data as *const u8, val.mv_size)
+    }
+}
+
+impl Drop for RawCursor {
+    fn drop(&mut self) {
+        if !self.cursor.is_null() {
+            unsafe { ffi::mdb_cursor_close(self.cursor) }
+        }
+    }
+}
 
 pub struct RoQuery<'txn, T> {
-    pub phantom: std::marker::PhantomData<T>,
-    pub db: lmdb::Database,
-    pub txn: lmdb::RoTransaction<'txn>,
-    pub iter: Option<lmdb::Iter<'txn>>,
+    phantom: std::marker::PhantomData<T>,
+    // Declared before the transaction so the cursor is closed before the transaction ends
+    cursor: RawCursor,
+    _txn: lmdb::RoTransaction<'txn>,
+    since: Option<SystemTime>,
+    // The error that ended the query before its last record
+    error: Option<StorageError>,
 }
 
-impl<'txn, T: 'txn + Record> RoQuery<'txn, T> {
-    pub fn new(db: lmdb::Database, txn: lmdb::RoTransaction<'txn>) -> RoQuery<'txn, T> {
-        RoQuery {
+impl<'txn, T: Record> RoQuery<'txn, T> {
+    pub fn new(
+        db: Option<lmdb::Database>,
+        txn: lmdb::RoTransaction<'txn>,
+    ) -> Result<RoQuery<'txn, T>, StorageError> {
+        Ok(RoQuery {
             phantom: std::marker::PhantomData::<T>,
-            db,
-            txn,
-            iter: None,
+            cursor: RawCursor::open_existing(&txn, db)?,
+            _txn: txn,
+            since: None,
+            error: None,
+        })
+    }
+
+    pub(crate) fn max_read_age(mut self, max_age: Option<(Duration, ReadAgePolicy)>) -> Self {
+        self.cursor.max_age = max_age;
+        self
+    }
+
+    /// Only yields records that were saved at or after `since`.  Records of types without a
+    /// metadata envelope are never yielded
+    pub fn modified_since(mut self, since: SystemTime) -> RoQuery<'txn, T> {
+        self.since = Some(since);
+        self
+    }
+
+    /// The error that ended the query before its last record, such as `ReadTooOld` under
+    /// `ReadAgePolicy::Abort`, or `None` while it hasn't stopped or when it read every record
+    pub fn error(&self) -> Option<&StorageError> {
+        self.error.as_ref()
+    }
+
+    /// Yields an error for every value that can't be read instead of skipping it
+    pub fn checked(self) -> CheckedQuery<'txn, T> {
+        CheckedQuery { query: self }
+    }
+
+    fn is_modified(since: Option<SystemTime>, bytes: &[u8]) -> bool {
+        match since {
+            Some(since) => match Metadata::read(bytes) {
+                Some((metadata, _)) => T::has_metadata() && metadata.updated_at >= since,
+                None => false,
+            },
+            None => true,
         }
     }
 }
 
+// `nth` is left to `next`, unlike `RawScan`'s, because a record that can't be read isn't counted
+// and so every record skipped over has to be read
 impl<'txn, T: 'txn + Record> Iterator for RoQuery<'txn, T> {
     type Item = T;
 
+    /// Yields the next record, skipping any that can't be deserialized.  Use `checked` to find
+    /// out about them instead.  An error ends the query and is kept for `error`
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.cursor.next()? {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            };
+            if !Self::is_modified(self.since, value) {
+                continue;
+            }
+            if let Ok(record) = metadata::decode(key, value) {
+                return Some(record);
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.cursor.size_hint()
+    }
+}
+
+/// Iterates over records like `RoQuery`, but yields an error instead of skipping a value that
+/// can't be read
+pub struct CheckedQuery<'txn, T> {
+    query: RoQuery<'txn, T>,
+}
+
+impl<'txn, T: 'txn + Record> Iterator for CheckedQuery<'txn, T> {
+    type Item = Result<T, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.query.cursor.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            if !RoQuery::<T>::is_modified(self.query.since, value) {
+                continue;
+            }
+
+            return Some(metadata::decode(key, value));
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.query.size_hint()
+    }
+}
+
+/// Iterates over the keys of a database without deserializing any of the stored values
+pub struct KeyQuery<'txn, T> {
+    phantom: std::marker::PhantomData<T>,
+    // Declared before the transaction so the cursor is closed before the transaction ends
+    cursor: RawCursor,
+    _txn: lmdb::RoTransaction<'txn>,
+    // The error that ended the query before its last key
+    error: Option<StorageError>,
+}
+
+impl<'txn, T: Record> KeyQuery<'txn, T> {
+    pub fn new(
+        db: Option<lmdb::Database>,
+        txn: lmdb::RoTransaction<'txn>,
+    ) -> Result<KeyQuery<'txn, T>, StorageError> {
+        Ok(KeyQuery {
+            phantom: std::marker::PhantomData::<T>,
+            cursor: RawCursor::open_existing(&txn, db)?,
+            _txn: txn,
+            error: None,
+        })
+    }
+
+    pub(crate) fn max_read_age(mut self, max_age: Option<(Duration, ReadAgePolicy)>) -> Self {
+        self.cursor.max_age = max_age;
+        self
+    }
+
+    /// The error that ended the query before its last key, see `RoQuery::error`
+    pub fn error(&self) -> Option<&StorageError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'txn, T: 'txn + Record> Iterator for KeyQuery<'txn, T> {
+    type Item = T::Key;
+
+    /// Yields the next key.  An error, or a key that can't be decoded, ends the query and is kept
+    /// for `error`
+    fn next(&mut self) -> Option<Self::Item> {
+        let decoded = match self.cursor.next()? {
+            Ok((key, _)) => T::Key::try_from(key).map_err(|source| StorageError::InvalidKey {
+                db_name: T::db_name(),
+                key: key.to_vec(),
+                source,
+            }),
+            Err(e) => Err(e),
+        };
+        match decoded {
+            Ok(key) => Some(key),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.cursor.size_hint()
+    }
+}
+
+/// Iterates over the keys and values of a database as raw bytes
+pub struct RawScan<'txn> {
+    // Declared before the transaction so the cursor is closed before the transaction ends
+    cursor: RawCursor,
+    _txn: lmdb::RoTransaction<'txn>,
+    // The error that ended the scan before its last entry
+    error: Option<StorageError>,
+}
+
+impl<'txn> RawScan<'txn> {
+    pub fn new(
+        db: lmdb::Database,
+        txn: lmdb::RoTransaction<'txn>,
+    ) -> Result<RawScan<'txn>, StorageError> {
+        Ok(RawScan {
+            cursor: RawCursor::open(&txn, db)?,
+            _txn: txn,
+            error: None,
+        })
+    }
+
+    /// The error that ended the scan before its last entry, see `RoQuery::error`
+    pub fn error(&self) -> Option<&StorageError> {
+        self.error.as_ref()
+    }
+}
+
+impl<'txn> Iterator for RawScan<'txn> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    /// Yields the next key and value.  An error ends the scan and is kept for `error`
     fn next(&mut self) -> Option<Self::Item> {
-        if self.iter.is_none() {
-            let mut cursor = self.txn.open_ro_cursor(self.db).unwrap();
-            self.iter = Some(cursor.iter());
+        match self.cursor.next()? {
+            Ok((key, value)) => Some((key.to_vec(), value.to_vec())),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
         }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.cursor.size_hint()
+    }
 
-        if let Some(iter) = &mut self.iter {
-            if let Some(record) = iter.next() {
-                return match T::from_binary(record.1) {
-                    Ok(record) => Some(record),
-                    Err(_) => None,
-                };
+    /// Jumps the cursor past `n` entries without copying them, then yields the one after
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self.cursor.skip(n) {
+            Ok(true) => self.next(),
+            Ok(false) => None,
+            Err(e) => {
+                self.error = Some(e);
+                None
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawScan;
+    use crate::{Key, ReadAgePolicy, Record, Storage, StorageError};
+    use lmdb::{Transaction, WriteFlags};
+    use serde::{Deserialize, Serialize};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        line: String,
+    }
+
+    #[test]
+    fn test_that_queries_skip_unreadable_records() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save_batch(
+                (1..=5)
+                    .map(|id| Station {
+                        id,
+                        line: "L".to_string(),
+                    })
+                    .collect(),
+            )
+            .expect("Could not save stations");
+
+        let db = storage.db("Station", Station::db_flags()).unwrap();
+        let mut txn = storage.env().unwrap().begin_rw_txn().unwrap();
+        let key: Vec<u8> = Key::from(3u32).into();
+        txn.put(db, &key, &[0xff], WriteFlags::empty()).unwrap();
+        txn.commit().unwrap();
+
+        let query = storage.query::<Station>().unwrap();
+        assert_eq!((0, Some(5)), query.size_hint());
+        let ids: Vec<u32> = query.map(|station| station.id).collect();
+        assert_eq!(vec![1, 2, 4, 5], ids);
+
+        let checked: Vec<Result<Station, StorageError>> =
+            storage.query_checked::<Station>().unwrap().collect();
+        assert_eq!(5, checked.len());
+        match &checked[2] {
+            Err(StorageError::Undecodable {
+                db_name,
+                key: found,
+            }) => {
+                assert_eq!("Station", *db_name);
+                assert_eq!(&key, found);
+            }
+            _ => panic!("Expected the corrupt record to be reported"),
+        }
+
+        // The unreadable record isn't counted when skipping ahead either, so `nth(3)` is the
+        // record the fourth `next` yields
+        let fourth = storage.query::<Station>().unwrap().nth(3);
+        assert_eq!(Some(5), fourth.map(|station| station.id));
+        let keys: Vec<u32> = storage
+            .keys::<Station>()
+            .unwrap()
+            .skip(3)
+            .map(Key::into_inner)
+            .collect();
+        assert_eq!(vec![4, 5], keys);
+    }
+
+    #[test]
+    fn test_that_key_queries_stop_at_keys_that_cant_be_decoded() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save_batch(
+                (1..=3)
+                    .map(|id| Station {
+                        id,
+                        line: "L".to_string(),
+                    })
+                    .collect(),
+            )
+            .expect("Could not save stations");
+
+        let db = storage.db("Station", Station::db_flags()).unwrap();
+        let mut txn = storage.env().unwrap().begin_rw_txn().unwrap();
+        // Station keys are four bytes, so this one sorts after 2 but can't be decoded
+        let key: Vec<u8> = vec![0, 0, 0, 2, 0];
+        txn.put(db, &key, &[0xff], WriteFlags::empty()).unwrap();
+        txn.commit().unwrap();
+
+        let mut query = storage.keys::<Station>().unwrap();
+        let ids: Vec<u32> = query.by_ref().map(Key::into_inner).collect();
+        assert_eq!(vec![1, 2], ids);
+        match query.error() {
+            Some(StorageError::InvalidKey {
+                db_name,
+                key: found,
+                ..
+            }) => {
+                assert_eq!("Station", *db_name);
+                assert_eq!(&key, found);
+            }
+            _ => panic!("Expected the key that can't be decoded to be reported"),
+        }
+    }
+
+    #[test]
+    fn test_that_a_raw_scan_keeps_the_error_that_ended_it() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save_batch(
+                (1..=3)
+                    .map(|id| Station {
+                        id,
+                        line: "L".to_string(),
+                    })
+                    .collect(),
+            )
+            .expect("Could not save stations");
+
+        let db = storage.db("Station", Station::db_flags()).unwrap();
+        let env = storage.env().unwrap();
+        let mut scan = RawScan::new(db, env.begin_ro_txn().unwrap()).unwrap();
+        let (key, _) = scan.next().expect("Missing first entry");
+        assert_eq!(Vec::<u8>::from(Key::from(1u32)), key);
+        assert!(scan.error().is_none());
+
+        // Fails the cursor partway through, the way a read transaction kept open too long does
+        scan.cursor.max_age = Some((Duration::from_millis(0), ReadAgePolicy::Abort));
+        sleep(Duration::from_millis(2));
+        assert_eq!(None, scan.next());
+        assert!(matches!(
+            scan.error(),
+            Some(StorageError::ReadTooOld { .. })
+        ));
+        assert_eq!(None, scan.next());
+    }
+
+    #[test]
+    fn test_that_a_raw_scan_jumps_ahead_without_reading_what_it_skips() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save_batch(
+                (1..=5)
+                    .map(|id| Station {
+                        id,
+                        line: "L".to_string(),
+                    })
+                    .collect(),
+            )
+            .expect("Could not save stations");
+
+        let db = storage.db("Station", Station::db_flags()).unwrap();
+        let env = storage.env().unwrap();
+        let mut scan = RawScan::new(db, env.begin_ro_txn().unwrap()).unwrap();
+        let key_of = |id: u32| Vec::<u8>::from(Key::from(id));
+        assert_eq!(Some(key_of(3)), scan.nth(2).map(|(key, _)| key));
+        assert_eq!((0, Some(2)), scan.size_hint());
+        assert_eq!(Some(key_of(4)), scan.next().map(|(key, _)| key));
+        assert_eq!(Some(key_of(5)), scan.next().map(|(key, _)| key));
+        assert_eq!(None, scan.nth(3));
+        assert!(scan.error().is_none());
+        drop(scan);
 
-        None
+        let mut scan = RawScan::new(db, env.begin_ro_txn().unwrap()).unwrap();
+        assert_eq!(None, scan.nth(5));
+        assert_eq!(None, scan.next());
     }
 }
@@ -0,0 +1,743 @@
+use lmdb::{Cursor, Transaction as LmdbTransaction};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use crate::index::{self, encode_value, index_db_name, normalize_value, Normalizer};
+use crate::metadata;
+use crate::type_tag::{self, TYPES_DB};
+use crate::usage;
+use crate::{Record, Storage, StorageError};
+
+/// A condition on a single field of a record
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Eq {
+        field: String,
+        value: Value,
+    },
+    Range {
+        field: String,
+        start: Bound<Value>,
+        end: Bound<Value>,
+    },
+}
+
+impl Filter {
+    fn eq<V: Serialize + ?Sized>(field: &str, value: &V) -> Filter {
+        Filter::Eq {
+            field: field.to_string(),
+            value: to_value(value),
+        }
+    }
+
+    fn range<V: Serialize, R: RangeBounds<V>>(field: &str, range: R) -> Filter {
+        let bound = |bound: Bound<&V>| match bound {
+            Bound::Included(value) => Bound::Included(to_value(value)),
+            Bound::Excluded(value) => Bound::Excluded(to_value(value)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        Filter::Range {
+            field: field.to_string(),
+            start: bound(range.start_bound()),
+            end: bound(range.end_bound()),
+        }
+    }
+
+    fn field(&self) -> &str {
+        match self {
+            Filter::Eq { field, .. } | Filter::Range { field, .. } => field,
+        }
+    }
+
+    // The same filter on a field by another name
+    fn renamed(&self, name: &str) -> Filter {
+        let mut filter = self.clone();
+        match &mut filter {
+            Filter::Eq { field, .. } | Filter::Range { field, .. } => *field = name.to_string(),
+        }
+        filter
+    }
+
+    // The filter with its values normalized the way the field's index normalizes them
+    fn normalized(&self, normalizers: &[Normalizer]) -> Filter {
+        let bound = |bound: &Bound<Value>| match bound {
+            Bound::Included(value) => Bound::Included(normalize_value(value, normalizers)),
+            Bound::Excluded(value) => Bound::Excluded(normalize_value(value, normalizers)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        match self {
+            Filter::Eq { field, value } => Filter::Eq {
+                field: field.clone(),
+                value: normalize_value(value, normalizers),
+            },
+            Filter::Range { field, start, end } => Filter::Range {
+                field: field.clone(),
+                start: bound(start),
+                end: bound(end),
+            },
+        }
+    }
+
+    // Values are compared through their index encoding, after the index's normalizers, so a scan
+    // matches exactly the records an index lookup would find.  Arrays match when any element
+    // does, like their indexes
+    fn matches(&self, record: &Value, normalizers: &[Normalizer]) -> bool {
+        let found = record
+            .get(self.field())
+            .map(|value| normalize_value(value, normalizers));
+        let found: Vec<Vec<u8>> = match found.as_ref() {
+            Some(Value::Array(values)) => values.iter().map(encode_value).collect(),
+            Some(value) => vec![encode_value(value)],
+            None => return false,
+        };
+
+        match self {
+            Filter::Eq { value, .. } => found.contains(&encode_value(value)),
+            Filter::Range { start, end, .. } => {
+                let start = encode_bound(start);
+                let end = encode_bound(end);
+                let range: (Bound<&[u8]>, Bound<&[u8]>) = (
+                    start.as_ref().map(Vec::as_slice),
+                    end.as_ref().map(Vec::as_slice),
+                );
+                found
+                    .iter()
+                    .any(|found| RangeBounds::<[u8]>::contains::<[u8]>(&range, found.as_slice()))
+            }
+        }
+    }
+}
+
+fn encode_bound(bound: &Bound<Value>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(value) => Bound::Included(encode_value(value)),
+        Bound::Excluded(value) => Bound::Excluded(encode_value(value)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// The field a filter names, by its own name or by the one it is serialized under.  Indexes are
+// named after the field
+fn field_name<T: Record>(name: &str) -> &str {
+    T::renamed_fields()
+        .iter()
+        .find(|(_, serialized)| *serialized == name)
+        .map_or(name, |(field, _)| field)
+}
+
+// The name a field is serialized under, which filters look up in the serialized record
+fn serialized_name<T: Record>(field: &str) -> &str {
+    T::renamed_fields()
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map_or(field, |(_, serialized)| serialized)
+}
+
+// The index on a field, named after it unless `#[index(name = "...")]` names it otherwise
+fn index_of<T: Record>(field: &str) -> Option<&'static str> {
+    T::renamed_indexes()
+        .iter()
+        .find(|(_, indexed)| *indexed == field)
+        .map(|(index, _)| *index)
+        .or_else(|| T::indexes().iter().find(|index| **index == field).copied())
+}
+
+// The normalizers a field's values go through in its index, none without an index
+fn normalizers<T: Record>(field: &str) -> &'static [Normalizer] {
+    index_of::<T>(field).map_or(&[], T::index_normalizers)
+}
+
+fn to_value<V: Serialize + ?Sized>(value: &V) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+/// How a query finds the records it checks against its filters, see `QueryBuilder::explain`.
+/// Entries are those of the index, which can hold a record more than once, or of the records
+/// scanned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryPlan {
+    /// Reads the records an index holds under one value
+    IndexLookup { index: String, entries: usize },
+    /// Reads the records an index holds under a range of values
+    IndexRange { index: String, entries: usize },
+    /// Reads every record of the type
+    FullScan { entries: usize },
+}
+
+impl QueryPlan {
+    /// How many candidates the query reads
+    pub fn estimated_entries(&self) -> usize {
+        match self {
+            QueryPlan::IndexLookup { entries, .. }
+            | QueryPlan::IndexRange { entries, .. }
+            | QueryPlan::FullScan { entries } => *entries,
+        }
+    }
+}
+
+/// A field of `T` that holds values of type `V`.
+///
+/// Fields are usually generated by `#[storable(fields)]`, which adds a `fields()` function to the
+/// record type, so filters on a field that doesn't exist or with a value of the wrong type don't
+/// compile.
+pub struct Field<T, V> {
+    name: &'static str,
+    phantom: PhantomData<fn() -> (T, V)>,
+}
+
+impl<T, V: Serialize> Field<T, V> {
+    pub fn new(name: &'static str) -> Field<T, V> {
+        Field {
+            name,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The name of the field as it is serialized, after any serde renaming
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Matches records whose field equals `value`
+    pub fn eq(&self, value: V) -> Condition<T> {
+        Condition::new(Filter::eq(self.name, &value))
+    }
+
+    /// Matches records whose field falls inside `range`
+    pub fn range<R: RangeBounds<V>>(&self, range: R) -> Condition<T> {
+        Condition::new(Filter::range(self.name, range))
+    }
+}
+
+/// A filter on one of `T`'s fields, passed to `QueryBuilder::filter`
+pub struct Condition<T> {
+    filter: Filter,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Condition<T> {
+    fn new(filter: Filter) -> Condition<T> {
+        Condition {
+            filter,
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// Builds a query over a type's records out of field filters.
+///
+/// When one of the filtered fields has a secondary index the candidates are read from the index,
+/// otherwise every record is scanned.  Either way every filter is checked against each record.
+/// Results come back in key order for scans, and in index order when an index was used.
+pub struct QueryBuilder<'s, T> {
+    storage: &'s mut Storage,
+    filters: Vec<Filter>,
+    skip: usize,
+    limit: Option<usize>,
+    phantom: PhantomData<T>,
+}
+
+impl<'s, T: Record> QueryBuilder<'s, T> {
+    pub(crate) fn new(storage: &'s mut Storage) -> QueryBuilder<'s, T> {
+        QueryBuilder {
+            storage,
+            filters: vec![],
+            skip: 0,
+            limit: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Only returns records whose `field` equals `value`
+    pub fn filter_eq<V: Serialize + ?Sized>(&mut self, field: &str, value: &V) -> &mut Self {
+        self.filters.push(Filter::eq(field, value));
+        self
+    }
+
+    /// Only returns records whose `field` falls inside `range`
+    pub fn filter_range<V: Serialize, R: RangeBounds<V>>(
+        &mut self,
+        field: &str,
+        range: R,
+    ) -> &mut Self {
+        self.filters.push(Filter::range(field, range));
+        self
+    }
+
+    /// Only returns records that meet a condition built from a typed `Field`
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// enum Party { Democrat, Republican }
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[db_name = "FieldMayor"]
+    /// #[storable(fields)]
+    /// struct Mayor {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   party: Party
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Mayor { id: 109, name: "Bill de Blasio".to_string(), party: Party::Democrat })?;
+    ///
+    ///     let democrats = storage
+    ///         .query_builder::<Mayor>()
+    ///         .filter(Mayor::fields().party().eq(Party::Democrat))
+    ///         .fetch()?;
+    ///     assert!(democrats.iter().any(|mayor| mayor.name == "Bill de Blasio"));
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn filter(&mut self, condition: Condition<T>) -> &mut Self {
+        self.filters.push(condition.filter);
+        self
+    }
+
+    /// Returns at most `limit` records
+    pub fn limit(&mut self, limit: usize) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skips the first `skip` matching records
+    pub fn skip(&mut self, skip: usize) -> &mut Self {
+        self.skip = skip;
+        self
+    }
+
+    // The first filter on a field that has a secondary index, along with the index
+    fn indexed_filter(filters: &[Filter]) -> Option<(&'static str, &Filter)> {
+        filters.iter().find_map(|filter| {
+            index_of::<T>(field_name::<T>(filter.field())).map(|index| (index, filter))
+        })
+    }
+
+    // The filters on the names fields are serialized under, with the values their indexes would
+    // hold
+    fn normalized_filters(&self) -> Vec<Filter> {
+        self.filters
+            .iter()
+            .map(|filter| {
+                let field = field_name::<T>(filter.field());
+                filter
+                    .renamed(serialized_name::<T>(field))
+                    .normalized(normalizers::<T>(field))
+            })
+            .collect()
+    }
+
+    /// Tells how `fetch` would find its candidate records, without reading any of them
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{QueryPlan, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   #[index]
+    ///   country: std::string::String,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, country: "AT".to_string(), name: "Vienna".to_string() })?;
+    ///     storage.save(&Place { id: 2, country: "AT".to_string(), name: "Graz".to_string() })?;
+    ///     storage.save(&Place { id: 3, country: "DE".to_string(), name: "Berlin".to_string() })?;
+    ///
+    ///     let plan = storage.query_builder::<Place>().filter_eq("country", "AT").explain()?;
+    ///     assert_eq!(QueryPlan::IndexLookup { index: "country".to_string(), entries: 2 }, plan);
+    ///
+    ///     let plan = storage.query_builder::<Place>().filter_eq("name", "Graz").explain()?;
+    ///     assert_eq!(QueryPlan::FullScan { entries: 3 }, plan);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn explain(&mut self) -> Result<QueryPlan, StorageError> {
+        let filters = self.normalized_filters();
+        let storage = self.storage.storage_for::<T>()?;
+        let db = match storage.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(QueryPlan::FullScan { entries: 0 }),
+        };
+        let index_db = match Self::indexed_filter(&filters) {
+            Some((index, _)) => storage.existing_db(&index_db_name(T::db_name(), index))?,
+            None => None,
+        };
+
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let plan = match (Self::indexed_filter(&filters), index_db) {
+            (Some((index, Filter::Eq { value, .. })), Some(index_db)) => QueryPlan::IndexLookup {
+                index: index.to_string(),
+                entries: index::lookup(&txn, index_db, &encode_value(value))?.len(),
+            },
+            (Some((index, Filter::Range { start, end, .. })), Some(index_db)) => {
+                let start = encode_bound(start);
+                let end = encode_bound(end);
+                let data = index::range(
+                    &txn,
+                    index_db,
+                    start.as_ref().map(Vec::as_slice),
+                    end.as_ref().map(Vec::as_slice),
+                )?;
+                QueryPlan::IndexRange {
+                    index: index.to_string(),
+                    entries: data.len(),
+                }
+            }
+            _ => QueryPlan::FullScan {
+                entries: usage::entries(&txn, db)?,
+            },
+        };
+        Ok(plan)
+    }
+
+    /// Runs the query and returns the matching records.  Filters can name a field by its own name
+    /// or by the one serde serializes it under
+    pub fn fetch(&mut self) -> Result<Vec<T>, StorageError> {
+        let filters = self.normalized_filters();
+        let storage = self.storage.storage_for::<T>()?;
+        let db = match storage.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(vec![]),
+        };
+        let index_db = match Self::indexed_filter(&filters) {
+            Some((index, _)) => storage.existing_db(&index_db_name(T::db_name(), index))?,
+            None => None,
+        };
+
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let candidates: Vec<Vec<u8>> = match (Self::indexed_filter(&filters), index_db) {
+            (Some((index, Filter::Eq { value, .. })), Some(index_db)) => {
+                let data = index::lookup(&txn, index_db, &encode_value(value))?;
+                index::record_keys(
+                    index::in_index_order::<T>(index, data),
+                    T::covering_indexes().contains(&index),
+                )?
+            }
+            (Some((index, Filter::Range { start, end, .. })), Some(index_db)) => {
+                let start = encode_bound(start);
+                let end = encode_bound(end);
+                let data = index::range(
+                    &txn,
+                    index_db,
+                    start.as_ref().map(Vec::as_slice),
+                    end.as_ref().map(Vec::as_slice),
+                )?;
+                let keys = index::record_keys(
+                    index::in_index_order::<T>(index, data),
+                    T::covering_indexes().contains(&index),
+                )?;
+
+                // A record can only be in the range once, even if the index holds it more often
+                let mut seen = BTreeSet::new();
+                keys.into_iter()
+                    .filter(|key| seen.insert(key.clone()))
+                    .collect()
+            }
+            _ => {
+                let mut cursor = txn.open_ro_cursor(db)?;
+                cursor.iter().map(|(key, _)| key.to_vec()).collect()
+            }
+        };
+
+        let mut results = vec![];
+        let mut skipped = 0;
+        for key in candidates {
+            if self.limit.is_some_and(|limit| results.len() >= limit) {
+                break;
+            }
+
+            let record = match txn.get(db, &key) {
+                Ok(bytes) => Some(metadata::decode::<T>(&key, bytes)?),
+                Err(lmdb::Error::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+            let record = match record {
+                Some(record) => record,
+                None => continue,
+            };
+
+            let value = to_value(&record);
+            if !filters.iter().all(|filter| {
+                filter.matches(&value, normalizers::<T>(field_name::<T>(filter.field())))
+            }) {
+                continue;
+            }
+
+            if skipped < self.skip {
+                skipped += 1;
+                continue;
+            }
+            results.push(record);
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryPlan;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Borough {
+        id: u32,
+        name: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[belongs_to(Borough, key = "borough_id")]
+    struct Councillor {
+        id: u32,
+        borough_id: u32,
+        party: String,
+    }
+
+    fn setup() -> Storage {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let parties = ["Democratic", "Republican", "Democratic", "Working Families"];
+        let councillors = (1..=12)
+            .map(|id| Councillor {
+                id,
+                borough_id: id % 5 + 1,
+                party: parties[id as usize % parties.len()].to_string(),
+            })
+            .collect();
+        storage
+            .save_batch(councillors)
+            .expect("Could not save councillors");
+        storage
+    }
+
+    fn ids(councillors: Vec<Councillor>) -> Vec<u32> {
+        councillors.iter().map(|c| c.id).collect()
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(fields)]
+    #[belongs_to(Borough, key = "borough_id")]
+    struct Precinct {
+        id: u32,
+        borough_id: u32,
+        name: String,
+        party: Party,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Party {
+        Democrat,
+        Republican,
+    }
+
+    #[test]
+    fn test_that_derived_fields_build_typed_filters() {
+        let mut storage = setup();
+        storage.truncate::<Precinct>().expect("Could not truncate");
+
+        let precincts = (1..=6)
+            .map(|id| Precinct {
+                id,
+                borough_id: id % 2 + 1,
+                name: format!("Precinct {}", id),
+                party: if id < 4 {
+                    Party::Democrat
+                } else {
+                    Party::Republican
+                },
+            })
+            .collect();
+        storage
+            .save_batch(precincts)
+            .expect("Could not save precincts");
+
+        let fields = Precinct::fields();
+        assert_eq!("party", fields.party().name());
+
+        let found: Vec<u32> = storage
+            .query_builder::<Precinct>()
+            .filter(fields.party().eq(Party::Republican))
+            .filter(fields.borough_id().range(2..))
+            .fetch()
+            .expect("Could not run query")
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(vec![5], found);
+    }
+
+    #[test]
+    fn test_that_unindexed_fields_are_filtered_by_scanning() {
+        let mut storage = setup();
+
+        let democrats = storage
+            .query_builder::<Councillor>()
+            .filter_eq("party", "Democratic")
+            .fetch()
+            .expect("Could not run query");
+        assert_eq!(vec![2, 4, 6, 8, 10, 12], ids(democrats));
+
+        let some = storage
+            .query_builder::<Councillor>()
+            .filter_eq("party", "Democratic")
+            .filter_range("id", 3..=10)
+            .skip(1)
+            .limit(2)
+            .fetch()
+            .expect("Could not run query");
+        assert_eq!(vec![6, 8], ids(some));
+
+        let none = storage
+            .query_builder::<Councillor>()
+            .filter_eq("mayor", "Ed Koch")
+            .fetch()
+            .expect("Could not run query");
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_that_indexed_fields_are_filtered_through_the_index() {
+        let mut storage = setup();
+
+        // The filter value doesn't need to be the same integer type as the field
+        let brooklyn = storage
+            .query_builder::<Councillor>()
+            .filter_eq("borough_id", &3u64)
+            .fetch()
+            .expect("Could not run query");
+        assert_eq!(vec![2, 7, 12], ids(brooklyn));
+
+        let outer = storage
+            .query_builder::<Councillor>()
+            .filter_range("borough_id", 4..)
+            .filter_eq("party", "Democratic")
+            .fetch()
+            .expect("Could not run query");
+        assert_eq!(vec![8, 4], ids(outer));
+
+        let first = storage
+            .query_builder::<Councillor>()
+            .filter_range("borough_id", ..=2)
+            .limit(1)
+            .fetch()
+            .expect("Could not run query");
+        assert_eq!(vec![5], ids(first));
+    }
+
+    #[test]
+    fn test_that_plans_tell_how_candidates_are_found() {
+        let mut storage = setup();
+
+        let lookup = storage
+            .query_builder::<Councillor>()
+            .filter_eq("party", "Democratic")
+            .filter_eq("borough_id", &3)
+            .explain()
+            .expect("Could not explain query");
+        assert_eq!(
+            QueryPlan::IndexLookup {
+                index: "borough_id".to_string(),
+                entries: 3
+            },
+            lookup
+        );
+
+        let range = storage
+            .query_builder::<Councillor>()
+            .filter_range("borough_id", 4..)
+            .explain()
+            .expect("Could not explain query");
+        assert_eq!(
+            QueryPlan::IndexRange {
+                index: "borough_id".to_string(),
+                entries: 4
+            },
+            range
+        );
+
+        let scan = storage
+            .query_builder::<Councillor>()
+            .filter_eq("party", "Democratic")
+            .explain()
+            .expect("Could not explain query");
+        assert_eq!(QueryPlan::FullScan { entries: 12 }, scan);
+        assert_eq!(12, scan.estimated_entries());
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(fields, codec = "json")]
+    #[serde(rename_all = "camelCase")]
+    struct Ward {
+        id: u32,
+        #[index]
+        ward_name: String,
+        #[serde(rename = "seats")]
+        seat_count: u32,
+    }
+
+    #[test]
+    fn test_that_filters_follow_serde_renames() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let wards = (1..=4)
+            .map(|id| Ward {
+                id,
+                ward_name: format!("Ward {}", id % 2),
+                seat_count: id,
+            })
+            .collect();
+        storage.save_batch(wards).expect("Could not save wards");
+
+        assert_eq!(
+            &[("ward_name", "wardName"), ("seat_count", "seats")],
+            Ward::renamed_fields()
+        );
+        assert_eq!("seats", Ward::fields().seat_count().name());
+
+        // Fields can be named either way, and the index on ward_name is used for both
+        for field in &["wardName", "ward_name"] {
+            let found: Vec<u32> = storage
+                .query_builder::<Ward>()
+                .filter_eq(field, "Ward 1")
+                .filter(Ward::fields().seat_count().range(2..))
+                .fetch()
+                .expect("Could not run query")
+                .iter()
+                .map(|ward| ward.id)
+                .collect();
+            assert_eq!(vec![3], found);
+        }
+    }
+}
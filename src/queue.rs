@@ -0,0 +1,319 @@
+//! A durable job queue stored in a single database.
+//!
+//! Jobs waiting to be taken are keyed by their priority and then by the order they were pushed
+//! in, so the first key is always the next job.  Taken jobs move to keys of their own until they
+//! are acknowledged, and go back in line if that doesn't happen within the visibility timeout.
+
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, RwTransaction, Transaction, WriteFlags};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::query;
+use crate::StorageError;
+
+const READY: u8 = b'r';
+const TAKEN: u8 = b't';
+const SEQUENCE: &[u8] = b"s";
+
+/// The name of the database that holds a queue's jobs
+pub(crate) fn queue_db_name(name: &str) -> String {
+    format!("{}#queue", name)
+}
+
+pub(crate) fn queue_db_flags() -> DatabaseFlags {
+    DatabaseFlags::empty()
+}
+
+// Higher priorities sort first, and jobs of the same priority in the order they were pushed
+fn ready_key(priority: u8, id: u64) -> Vec<u8> {
+    let mut key = vec![READY, u8::MAX - priority];
+    key.extend(&id.to_be_bytes());
+    key
+}
+
+fn taken_key(id: u64) -> Vec<u8> {
+    let mut key = vec![TAKEN];
+    key.extend(&id.to_be_bytes());
+    key
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_be_bytes(value)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+pub(crate) type Entry = (Vec<u8>, Vec<u8>);
+
+pub(crate) fn read_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+// The entries whose keys start with `prefix`, in key order
+fn entries_with_prefix<T: Transaction>(
+    txn: &T,
+    db: Database,
+    prefix: &[u8],
+    limit: usize,
+) -> Result<Vec<Entry>, StorageError> {
+    entries_from(txn, db, prefix, prefix, limit)
+}
+
+/// The entries from `start` on whose keys start with `prefix`, in key order
+pub(crate) fn entries_from<T: Transaction>(
+    txn: &T,
+    db: Database,
+    start: &[u8],
+    prefix: &[u8],
+    limit: usize,
+) -> Result<Vec<Entry>, StorageError> {
+    let cursor = txn.open_ro_cursor(db)?;
+    // Positioned by hand, since lmdb 0.8's `iter_from` panics when nothing sorts after `start`.
+    // LMDB refuses empty keys, so an empty `start` goes to the first entry instead
+    let positioned = match start {
+        [] => cursor.get(None, None, lmdb_sys::MDB_FIRST),
+        _ => cursor.get(Some(start), None, lmdb_sys::MDB_SET_RANGE),
+    };
+    let first = match positioned {
+        Ok((Some(key), value)) => (key, value),
+        Ok((None, _)) | Err(lmdb::Error::NotFound) => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = vec![];
+    for entry in std::iter::once(Ok(first))
+        .chain(query::checked_iter(&cursor))
+        .take(limit)
+    {
+        let (key, value) = entry?;
+        if !key.starts_with(prefix) {
+            break;
+        }
+        entries.push((key.to_vec(), value.to_vec()));
+    }
+    Ok(entries)
+}
+
+/// A job taken from a `Queue`, to be acknowledged once it is done
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery<T> {
+    /// Identifies the job when acknowledging it
+    pub id: u64,
+    /// The job as it was pushed
+    pub job: T,
+    /// How many times the job was taken, including this time
+    pub attempts: u32,
+}
+
+/// A persistent queue of jobs, see `Storage::queue`.
+///
+/// Jobs with a higher priority are taken first, and jobs of the same priority in the order they
+/// were pushed.  A taken job is hidden until it is acknowledged with `ack`, or until the
+/// visibility timeout runs out, in which case it is handed out again.  Every call runs in its own
+/// transaction, so separate processes can take jobs from the same queue.
+pub struct Queue<'s, T> {
+    env: &'s Environment,
+    db: Database,
+    visibility_timeout: Duration,
+    phantom: PhantomData<T>,
+}
+
+impl<'s, T: Serialize + DeserializeOwned> Queue<'s, T> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> Queue<'s, T> {
+        Queue {
+            env,
+            db,
+            visibility_timeout: Duration::from_secs(30),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Sets how long a taken job stays hidden before it is handed out again.  Defaults to 30
+    /// seconds
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Queue<'s, T> {
+        self.visibility_timeout = timeout;
+        self
+    }
+
+    /// Adds a job to the end of the queue
+    pub fn push(&self, job: &T) -> Result<u64, StorageError> {
+        self.push_with_priority(job, 0)
+    }
+
+    /// Adds a job that is taken before every job of a lower priority.  Returns the job's id
+    pub fn push_with_priority(&self, job: &T, priority: u8) -> Result<u64, StorageError> {
+        let mut value = 0u32.to_be_bytes().to_vec();
+        value.extend(bincode::serialize(job)?);
+
+        let mut txn = self.env.begin_rw_txn()?;
+        let id = match txn.get(self.db, &SEQUENCE) {
+            Ok(bytes) => read_u64(bytes) + 1,
+            Err(lmdb::Error::NotFound) => 1,
+            Err(e) => return Err(e.into()),
+        };
+        txn.put(self.db, &SEQUENCE, &id.to_be_bytes(), WriteFlags::empty())?;
+        txn.put(
+            self.db,
+            &ready_key(priority, id),
+            &value,
+            WriteFlags::empty(),
+        )?;
+        txn.commit()?;
+        Ok(id)
+    }
+
+    /// Takes the next job, hiding it from other takers until it is acknowledged or the visibility
+    /// timeout runs out.  Returns `None` when no job is waiting
+    pub fn pop(&self) -> Result<Option<Delivery<T>>, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let now = now_millis();
+        self.release_expired(&mut txn, now)?;
+
+        let (key, value) = match entries_with_prefix(&txn, self.db, &[READY], 1)?.pop() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let id = read_u64(&key[2..]);
+        let attempts = read_u32(&value) + 1;
+        let job = bincode::deserialize(&value[4..])?;
+
+        let deadline = now + self.visibility_timeout.as_millis() as u64;
+        let mut taken = deadline.to_be_bytes().to_vec();
+        taken.push(u8::MAX - key[1]);
+        taken.extend(&attempts.to_be_bytes());
+        taken.extend(&value[4..]);
+        txn.del(self.db, &key, None)?;
+        txn.put(self.db, &taken_key(id), &taken, WriteFlags::empty())?;
+        txn.commit()?;
+
+        Ok(Some(Delivery { id, job, attempts }))
+    }
+
+    /// Returns the job `pop` would take next without taking it
+    pub fn peek(&self) -> Result<Option<T>, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        self.release_expired(&mut txn, now_millis())?;
+        let next = match entries_with_prefix(&txn, self.db, &[READY], 1)?.pop() {
+            Some((_, value)) => Some(bincode::deserialize(&value[4..])?),
+            None => None,
+        };
+        txn.commit()?;
+        Ok(next)
+    }
+
+    /// Removes a taken job for good.  Returns false when the job isn't taken, because it was
+    /// acknowledged already or its visibility timeout ran out and it went back in line
+    pub fn ack(&self, id: u64) -> Result<bool, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let now = now_millis();
+        let expired = match txn.get(self.db, &taken_key(id)) {
+            Ok(taken) => read_u64(taken) <= now,
+            Err(lmdb::Error::NotFound) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if expired {
+            self.release_expired(&mut txn, now)?;
+            txn.commit()?;
+            return Ok(false);
+        }
+
+        txn.del(self.db, &taken_key(id), None)?;
+        txn.commit()?;
+        Ok(true)
+    }
+
+    /// The number of jobs that haven't been acknowledged, whether they are waiting or taken
+    pub fn len(&self) -> Result<usize, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        let ready = entries_with_prefix(&txn, self.db, &[READY], usize::MAX)?.len();
+        let taken = entries_with_prefix(&txn, self.db, &[TAKEN], usize::MAX)?.len();
+        Ok(ready + taken)
+    }
+
+    /// Whether every job has been acknowledged
+    pub fn is_empty(&self) -> Result<bool, StorageError> {
+        Ok(self.len()? == 0)
+    }
+
+    // Puts taken jobs whose visibility timeout ran out back in line, where they were before
+    fn release_expired(&self, txn: &mut RwTransaction, now: u64) -> Result<(), StorageError> {
+        for (key, taken) in entries_with_prefix(txn, self.db, &[TAKEN], usize::MAX)? {
+            if read_u64(&taken) > now {
+                continue;
+            }
+            let id = read_u64(&key[1..]);
+            txn.del(self.db, &key, None)?;
+            txn.put(
+                self.db,
+                &ready_key(taken[8], id),
+                &taken[9..].to_vec(),
+                WriteFlags::empty(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Email {
+        to: String,
+    }
+
+    fn email(to: &str) -> Email {
+        Email { to: to.to_string() }
+    }
+
+    #[test]
+    fn test_that_jobs_are_taken_in_order_until_acknowledged() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let emails = storage
+            .queue::<Email>("emails")
+            .expect("Could not open queue")
+            .visibility_timeout(Duration::from_millis(50));
+        assert_eq!(None, emails.pop().unwrap());
+
+        emails.push(&email("ada@example.com")).unwrap();
+        emails.push(&email("grace@example.com")).unwrap();
+        emails
+            .push_with_priority(&email("urgent@example.com"), 9)
+            .unwrap();
+        assert_eq!(3, emails.len().unwrap());
+        assert_eq!(Some(email("urgent@example.com")), emails.peek().unwrap());
+
+        let urgent = emails.pop().unwrap().unwrap();
+        assert_eq!(email("urgent@example.com"), urgent.job);
+        assert!(emails.ack(urgent.id).unwrap());
+        assert!(!emails.ack(urgent.id).unwrap());
+
+        let ada = emails.pop().unwrap().unwrap();
+        assert_eq!(email("ada@example.com"), ada.job);
+        assert_eq!(1, ada.attempts);
+        let grace = emails.pop().unwrap().unwrap();
+        assert_eq!(email("grace@example.com"), grace.job);
+        assert_eq!(None, emails.pop().unwrap());
+        assert!(emails.ack(grace.id).unwrap());
+
+        // Ada's job wasn't acknowledged in time, so it is handed out again
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!emails.ack(ada.id).unwrap());
+        let again = emails.pop().unwrap().unwrap();
+        assert_eq!((ada.id, 2), (again.id, again.attempts));
+        assert!(emails.ack(again.id).unwrap());
+        assert!(emails.is_empty().unwrap());
+    }
+}
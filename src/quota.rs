@@ -0,0 +1,160 @@
+use lmdb::{Database, RwTransaction, Transaction, WriteFlags};
+
+use crate::StorageError;
+
+/// The database a tenant's usage is kept in, inside the tenant's own environment
+pub(crate) const USAGE_DB: &str = "tenant#usage";
+const USAGE_KEY: &str = "usage";
+
+/// How much a tenant has stored, counting the keys and values of its records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantUsage {
+    pub bytes: u64,
+    pub entries: u64,
+}
+
+/// Limits on how much a tenant can store, see `Storage::set_quota`.  Neither limit is set by
+/// default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<u64>,
+}
+
+impl Quota {
+    /// Sets the most bytes of keys and values the tenant's records can take up
+    pub fn max_bytes(mut self, max_bytes: u64) -> Quota {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets the most records the tenant can have
+    pub fn max_entries(mut self, max_entries: u64) -> Quota {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    // A transaction that grows usage past a limit is rejected, one that only shrinks it isn't,
+    // so tenants over a lowered quota can still delete records
+    pub(crate) fn allows(&self, usage: TenantUsage, delta: UsageDelta) -> bool {
+        let within = |max: Option<u64>, used: u64, grew: bool| match max {
+            Some(max) => !grew || used <= max,
+            None => true,
+        };
+        within(self.max_bytes, usage.bytes, delta.bytes > 0)
+            && within(self.max_entries, usage.entries, delta.entries > 0)
+    }
+}
+
+/// The change a transaction makes to a tenant's usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct UsageDelta {
+    pub bytes: i64,
+    pub entries: i64,
+}
+
+impl UsageDelta {
+    pub(crate) fn add(&mut self, other: UsageDelta) {
+        self.bytes += other.bytes;
+        self.entries += other.entries;
+    }
+
+    pub(crate) fn apply(&self, usage: TenantUsage) -> TenantUsage {
+        TenantUsage {
+            bytes: usage.bytes.saturating_add_signed(self.bytes),
+            entries: usage.entries.saturating_add_signed(self.entries),
+        }
+    }
+}
+
+pub(crate) fn read(txn: &impl Transaction, db: Database) -> Result<TenantUsage, StorageError> {
+    match txn.get(db, &USAGE_KEY) {
+        Ok(bytes) if bytes.len() == 16 => {
+            let mut bytes_used = [0; 8];
+            let mut entries = [0; 8];
+            bytes_used.copy_from_slice(&bytes[..8]);
+            entries.copy_from_slice(&bytes[8..]);
+            Ok(TenantUsage {
+                bytes: u64::from_be_bytes(bytes_used),
+                entries: u64::from_be_bytes(entries),
+            })
+        }
+        Ok(_) => Err(lmdb::Error::Corrupted.into()),
+        Err(lmdb::Error::NotFound) => Ok(TenantUsage::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn write(
+    txn: &mut RwTransaction,
+    db: Database,
+    usage: TenantUsage,
+) -> Result<(), StorageError> {
+    let mut bytes = usage.bytes.to_be_bytes().to_vec();
+    bytes.extend(&usage.entries.to_be_bytes());
+    txn.put(db, &USAGE_KEY, &bytes, WriteFlags::empty())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Upload {
+        id: u32,
+        data: Vec<u8>,
+    }
+
+    fn upload(id: u32, len: usize) -> Upload {
+        Upload {
+            id,
+            data: vec![0; len],
+        }
+    }
+
+    #[test]
+    fn test_that_tenants_are_held_to_their_quota() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.set_quota("acme", Quota::default().max_entries(2).max_bytes(1000));
+
+        let acme = storage.scoped("acme").unwrap();
+        acme.save(&upload(1, 100)).unwrap();
+        acme.save(&upload(2, 100)).unwrap();
+        let usage = acme.usage().unwrap();
+        assert_eq!(2, usage.entries);
+        // Each key is the 4 byte id, each value the id, the data's length and the data
+        assert_eq!(2 * (4 + 4 + 8 + 100), usage.bytes);
+
+        match acme.save(&upload(3, 10)) {
+            Err(StorageError::QuotaExceeded { tenant, usage, .. }) => {
+                assert_eq!("acme", tenant);
+                assert_eq!(3, usage.entries);
+            }
+            _ => panic!("Expected a third upload to exceed the quota"),
+        }
+        assert!(acme.save(&upload(2, 2000)).is_err());
+        acme.save(&upload(2, 500))
+            .expect("Replacing a record adds no entries");
+        assert_eq!(2, acme.count::<Upload>().unwrap());
+
+        // Lowering the quota below what is used still lets the tenant delete
+        storage.set_quota("acme", Quota::default().max_entries(1).max_bytes(10));
+        let acme = storage.scoped("acme").unwrap();
+        acme.delete(&upload(2, 500)).unwrap();
+        assert_eq!(
+            TenantUsage {
+                bytes: 4 + 4 + 8 + 100,
+                entries: 1
+            },
+            acme.usage().unwrap()
+        );
+
+        let globex = storage.scoped("globex").unwrap();
+        globex.save(&upload(1, 5000)).unwrap();
+        assert_eq!(1, globex.usage().unwrap().entries);
+    }
+}
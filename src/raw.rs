@@ -0,0 +1,114 @@
+use lmdb::{Database, Environment, Transaction, WriteFlags};
+
+use crate::queue::{entries_from, Entry};
+use crate::{RawScan, StorageError};
+
+/// Untyped access to a named database, for data that wasn't written through a `Record` type.
+///
+/// Keys and values are plain bytes and are read and written as they are, with no envelope,
+/// indexes or validation.
+pub struct RawDb<'s> {
+    env: &'s Environment,
+    db: Database,
+}
+
+impl<'s> RawDb<'s> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> RawDb<'s> {
+        RawDb { env, db }
+    }
+
+    /// Stores `value` under `key`, replacing whatever was there
+    pub fn put_bytes(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.put(self.db, &key, &value, WriteFlags::empty())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns a copy of the value stored under `key`, or `None` when there isn't one
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        match txn.get(self.db, &key) {
+            Ok(value) => Ok(Some(value.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes `key`, returning whether there was a value to remove
+    pub fn delete_bytes(&self, key: &[u8]) -> Result<bool, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let deleted = match txn.del(self.db, &key, None) {
+            Ok(()) => true,
+            Err(lmdb::Error::NotFound) => false,
+            Err(e) => return Err(e.into()),
+        };
+        txn.commit()?;
+        Ok(deleted)
+    }
+
+    /// Iterates over every key and value in the database in key order.  An error ends the scan
+    /// early and is kept for `RawScan::error`
+    pub fn scan_bytes(&self) -> Result<RawScan<'s>, StorageError> {
+        RawScan::new(self.db, self.env.begin_ro_txn()?)
+    }
+
+    /// Copies at most `limit` keys and values in key order, starting at the first key that sorts
+    /// at or after `start`, so a scan can be read in pages
+    pub fn scan_from(&self, start: &[u8], limit: usize) -> Result<Vec<Entry>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        entries_from(&txn, self.db, start, &[], limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Tram {
+        id: u32,
+        route: String,
+    }
+
+    #[test]
+    fn test_that_raw_bytes_can_be_shared_with_records() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let tram = Tram {
+            id: 1,
+            route: "D".to_string(),
+        };
+        storage.save(&tram).expect("Could not save tram");
+
+        let trams = storage.raw("Tram").expect("Could not open raw db");
+        let key: Vec<u8> = Key::from(1u32).into();
+        assert_eq!(
+            Some(tram.to_binary().unwrap()),
+            trams.get_bytes(&key).unwrap()
+        );
+        assert_eq!(None, trams.get_bytes(b"missing").unwrap());
+
+        let written = Tram {
+            id: 2,
+            route: "71".to_string(),
+        };
+        let key: Vec<u8> = Key::from(2u32).into();
+        trams
+            .put_bytes(&key, &written.to_binary().unwrap())
+            .expect("Could not put bytes");
+        assert_eq!(2, trams.scan_bytes().unwrap().count());
+        let page = trams.scan_from(&key, 10).unwrap();
+        assert_eq!(
+            vec![key.clone()],
+            page.into_iter().map(|(key, _)| key).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(written), storage.get::<Tram, _>(2).unwrap());
+
+        let trams = storage.raw("Tram").expect("Could not open raw db");
+        assert!(trams.delete_bytes(&key).unwrap());
+        assert!(!trams.delete_bytes(&key).unwrap());
+    }
+}
@@ -0,0 +1,156 @@
+//! Read transactions that are reset and renewed instead of opened and closed for every read.
+//!
+//! Beginning a read transaction takes a reader slot and a lock, and ending it gives them back.
+//! The pool of a read handle keeps transactions it is done with reset, still holding their slot,
+//! and renews one for the next read, which only takes a fresh snapshot.
+
+use lmdb::{Environment, InactiveTransaction, RoTransaction};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::StorageError;
+
+/// How a read handle's pool of read transactions is used, see `RoStorage::read_pool_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadPoolStats {
+    /// How many reset transactions are waiting to be renewed
+    pub idle: usize,
+    /// How many transactions were begun because none was idle
+    pub opened: u64,
+    /// How many reads renewed an idle transaction
+    pub renewed: u64,
+}
+
+// A reset transaction.  Pooling opens the environment with `MDB_NOTLS`, which ties reader slots
+// to transactions instead of threads, so it can be renewed on any thread
+struct Idle(InactiveTransaction<'static>);
+
+unsafe impl Send for Idle {}
+
+pub(crate) struct ReadPool {
+    idle: Mutex<Vec<Idle>>,
+    capacity: usize,
+    opened: AtomicU64,
+    renewed: AtomicU64,
+    // Declared after `idle` so the environment outlives the transactions kept for it
+    env: Arc<Environment>,
+}
+
+impl ReadPool {
+    /// A pool that keeps up to `capacity` transactions, which has to be 0 unless the environment
+    /// was opened with `MDB_NOTLS`
+    pub(crate) fn new(env: Arc<Environment>, capacity: usize) -> ReadPool {
+        ReadPool {
+            idle: Mutex::new(vec![]),
+            capacity,
+            opened: AtomicU64::new(0),
+            renewed: AtomicU64::new(0),
+            env,
+        }
+    }
+
+    /// A read transaction on a snapshot taken now, which goes back to the pool when dropped
+    pub(crate) fn begin(&self) -> Result<PooledTxn<'_>, StorageError> {
+        let idle = self.idle.lock().expect("Poisoned lock").pop();
+        let txn = match idle {
+            Some(Idle(txn)) => {
+                self.renewed.fetch_add(1, Ordering::Relaxed);
+                txn.renew()?
+            }
+            None => {
+                self.opened.fetch_add(1, Ordering::Relaxed);
+                let txn = self.env.begin_ro_txn()?;
+                // Safe since the pool holds on to the environment for as long as it holds on to
+                // any of its transactions
+                unsafe { std::mem::transmute::<RoTransaction<'_>, RoTransaction<'static>>(txn) }
+            }
+        };
+        Ok(PooledTxn {
+            txn: Some(txn),
+            pool: self,
+        })
+    }
+
+    pub(crate) fn stats(&self) -> ReadPoolStats {
+        ReadPoolStats {
+            idle: self.idle.lock().expect("Poisoned lock").len(),
+            opened: self.opened.load(Ordering::Relaxed),
+            renewed: self.renewed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A read transaction borrowed from a `ReadPool`
+pub(crate) struct PooledTxn<'p> {
+    txn: Option<RoTransaction<'static>>,
+    pool: &'p ReadPool,
+}
+
+impl Deref for PooledTxn<'_> {
+    type Target = RoTransaction<'static>;
+
+    fn deref(&self) -> &RoTransaction<'static> {
+        self.txn.as_ref().expect("Transaction already returned")
+    }
+}
+
+impl Drop for PooledTxn<'_> {
+    fn drop(&mut self) {
+        let txn = match self.txn.take() {
+            Some(txn) => txn,
+            None => return,
+        };
+        let mut idle = self.pool.idle.lock().expect("Poisoned lock");
+        if idle.len() < self.pool.capacity {
+            idle.push(Idle(txn.reset()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage, StorageOptions};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Quote {
+        id: u32,
+        price: u32,
+    }
+
+    #[test]
+    fn test_that_read_transactions_are_renewed_across_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::default().read_pool(2);
+        let storage = Storage::open_with(dir.path(), options).unwrap();
+        let (reader, mut writer) = storage.split().unwrap();
+        writer.save(&Quote { id: 1, price: 10 }).unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(10, reader.get::<Quote, _>(1).unwrap().unwrap().price);
+        }
+        let stats = reader.read_pool_stats();
+        assert_eq!((1, 1, 4), (stats.idle, stats.opened, stats.renewed));
+
+        // A renewed transaction reads the latest commit, from whichever thread renews it
+        writer.save(&Quote { id: 1, price: 11 }).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = reader.clone();
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|_| reader.get::<Quote, _>(1).unwrap().unwrap().price)
+                        .all(|price| price == 11)
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        let stats = reader.read_pool_stats();
+        assert_eq!(205, stats.opened + stats.renewed);
+        assert!(stats.idle <= 2);
+    }
+}
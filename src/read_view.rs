@@ -0,0 +1,125 @@
+//! Reads that all see the same snapshot of a storage.
+
+use lmdb::{Database, Environment, RoTransaction, Transaction};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::index::{self, index_db_name};
+use crate::metadata;
+use crate::type_tag::{self, TYPES_DB};
+use crate::{query, usage};
+use crate::{Record, StorageError, StorageOptions};
+
+/// A read transaction that every read made through it shares, see `Storage::read_view`.
+///
+/// Everything read through a view is as it was when the view was taken, whatever is committed
+/// in the meantime, so the reads of one web request agree with each other.  The snapshot is
+/// released when the view is dropped.
+pub struct ReadView<'s> {
+    txn: RoTransaction<'s>,
+    dbs: &'s HashMap<String, Database>,
+    options: &'s StorageOptions,
+    partition: Option<&'static str>,
+}
+
+impl<'s> ReadView<'s> {
+    // Every database the view reads has to be open in `dbs` already, LMDB doesn't let a read
+    // transaction open handles while other transactions of the process may open them as well
+    pub(crate) fn new(
+        env: &'s Environment,
+        dbs: &'s HashMap<String, Database>,
+        options: &'s StorageOptions,
+        partition: Option<&'static str>,
+    ) -> Result<ReadView<'s>, StorageError> {
+        Ok(ReadView {
+            txn: env.begin_ro_txn()?,
+            dbs,
+            options,
+            partition,
+        })
+    }
+
+    // The database of `T`, or `None` when it didn't exist when the view was taken.  Types stored
+    // in another environment can't be read from this one's snapshot
+    fn db<T: Record>(&self) -> Result<Option<Database>, StorageError> {
+        if self.options.partition_of::<T>().is_some() && self.partition.is_none() {
+            return Err(StorageError::WrongPartition {
+                db_name: T::db_name(),
+            });
+        }
+        let db = match self.dbs.get(T::db_name()) {
+            Some(db) => *db,
+            None => return Ok(None),
+        };
+        type_tag::check::<T>(&self.txn, self.dbs.get(TYPES_DB).copied())?;
+        Ok(Some(db))
+    }
+
+    /// Retrieves a record by its key, or `None` when there is no such record
+    pub fn get<T: Record, K: Into<T::Key>>(&self, key: K) -> Result<Option<T>, StorageError> {
+        let db = match self.db::<T>()? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let key: Vec<u8> = key.into().into();
+        match self.txn.get(db, &key) {
+            Ok(bytes) => metadata::decode(&key, bytes).map(Some),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads every record of a type, in key order
+    pub fn all<T: Record>(&self) -> Result<Vec<T>, StorageError> {
+        let db = match self.db::<T>()? {
+            Some(db) => db,
+            None => return Ok(vec![]),
+        };
+        let cursor = self.txn.open_ro_cursor(db)?;
+        query::checked_iter(&cursor)
+            .map(|entry| entry.and_then(|(key, bytes)| metadata::decode(key, bytes)))
+            .collect()
+    }
+
+    /// The number of records of a type
+    pub fn count<T: Record>(&self) -> Result<usize, StorageError> {
+        match self.db::<T>()? {
+            Some(db) => usage::entries(&self.txn, db),
+            None => Ok(0),
+        }
+    }
+
+    /// Returns the records whose value of `index` is `value`, like `Storage::find_by_index`
+    pub fn find_by_index<T, V>(&self, index: &str, value: &V) -> Result<Vec<T>, StorageError>
+    where
+        T: Record,
+        V: Serialize + ?Sized,
+    {
+        if !T::indexes().contains(&index) {
+            return Err(StorageError::UnknownIndex {
+                db_name: T::db_name(),
+                index: index.to_string(),
+            });
+        }
+        let index_db = self.dbs.get(&index_db_name(T::db_name(), index));
+        let (index_db, db) = match (index_db, self.db::<T>()?) {
+            (Some(index_db), Some(db)) => (*index_db, db),
+            _ => return Ok(vec![]),
+        };
+
+        let value = serde_json::to_value(value)?;
+        let value = index::normalize_value(&value, T::index_normalizers(index));
+        let data = index::lookup(&self.txn, index_db, &index::encode_value(&value))?;
+        let data = index::in_index_order::<T>(index, data);
+
+        let mut results = vec![];
+        for key in index::record_keys(data, T::covering_indexes().contains(&index))? {
+            match self.txn.get(db, &key) {
+                Ok(bytes) => results.push(metadata::decode::<T>(&key, bytes)?),
+                Err(lmdb::Error::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(results)
+    }
+}
@@ -0,0 +1,93 @@
+//! Advice to the OS about how the memory map is about to be read.
+//!
+//! LMDB maps the whole data file, so the advice covers every database in the environment.  On
+//! platforms without `posix_fadvise` or `madvise` the advice is ignored.
+
+use lmdb::{Cursor, Environment, Transaction};
+use lmdb_sys as ffi;
+
+use crate::StorageError;
+
+/// How the records of a storage are about to be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPattern {
+    /// Moderate readahead, what the OS does unless told otherwise
+    Normal,
+    /// Records are read in key order, as in full scans, so pages are read ahead aggressively
+    Sequential,
+    /// Records are read by key in no particular order, so pages aren't read ahead
+    Random,
+}
+
+#[cfg(unix)]
+fn madvise(start: usize, len: usize, advice: libc::c_int) -> Result<(), StorageError> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let aligned = start - start % page_size;
+    match unsafe { libc::madvise(aligned as *mut libc::c_void, len + start - aligned, advice) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error().into()),
+    }
+}
+
+/// Tells the OS how the data file is going to be read.  LMDB only reports where its memory map
+/// lives when it is opened at a fixed address, so the advice is given for the file itself, whose
+/// readahead settings page faults in the map go by
+pub(crate) fn advise(env: &Environment, pattern: AccessPattern) -> Result<(), StorageError> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        let mut fd = 0;
+        match unsafe { ffi::mdb_env_get_fd(env.env(), &mut fd) } {
+            0 => (),
+            code => return Err(lmdb::Error::from_err_code(code).into()),
+        }
+
+        let advice = match pattern {
+            AccessPattern::Normal => libc::POSIX_FADV_NORMAL,
+            AccessPattern::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            AccessPattern::Random => libc::POSIX_FADV_RANDOM,
+        };
+        match unsafe { libc::posix_fadvise(fd, 0, 0, advice) } {
+            0 => (),
+            code => return Err(std::io::Error::from_raw_os_error(code).into()),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let _ = (env, pattern);
+
+    Ok(())
+}
+
+/// Walks a database so its branch and leaf pages are faulted in, and asks the OS to start reading
+/// the pages its values live in.  Returns the number of entries walked
+pub(crate) fn warm(env: &Environment, db: lmdb::Database) -> Result<usize, StorageError> {
+    let txn = env.begin_ro_txn()?;
+    let mut cursor = txn.open_ro_cursor(db)?;
+    let first = match cursor.get(None, None, ffi::MDB_FIRST) {
+        Ok((_, value)) => value,
+        Err(lmdb::Error::NotFound) => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    // Values that sit next to each other in the map are advised together
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    let mut entries = 0;
+    for value in std::iter::once(first).chain(cursor.iter().map(|(_, value)| value)) {
+        entries += 1;
+        let start = value.as_ptr() as usize;
+        match ranges.last_mut() {
+            Some((_, end)) if *end == start => *end += value.len(),
+            _ => ranges.push((start, start + value.len())),
+        }
+    }
+
+    #[cfg(unix)]
+    for (start, end) in ranges {
+        madvise(start, end - start, libc::MADV_WILLNEED)?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = ranges;
+
+    Ok(entries)
+}
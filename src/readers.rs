@@ -0,0 +1,107 @@
+//! The reader slots of an environment.
+//!
+//! Every read transaction takes a slot in the lock file and keeps the pages of the snapshot it
+//! reads from being reused until it ends.  LMDB doesn't record when a slot was taken, so how far
+//! behind a reader is gets measured in transactions committed since its snapshot.
+
+use lmdb::Environment;
+use lmdb_sys as ffi;
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+
+use crate::StorageError;
+
+/// A reader slot that belongs to a live or crashed process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReaderSlot {
+    /// The process that holds the slot
+    pub pid: u32,
+    /// The thread that holds the slot, as LMDB reports it
+    pub thread: u64,
+    /// The transaction the reader's snapshot was taken at, `None` when the slot is idle
+    pub txn_id: Option<u64>,
+    /// How many transactions have been committed since the snapshot was taken
+    pub lag: u64,
+}
+
+fn check(code: i32) -> Result<(), StorageError> {
+    match code {
+        0 => Ok(()),
+        code => Err(lmdb::Error::from_err_code(code).into()),
+    }
+}
+
+// Reads one line of `mdb_reader_list` output, "pid thread txnid" with "-" for an idle slot
+fn parse_slot(line: &str) -> Option<(u32, u64, Option<u64>)> {
+    let mut fields = line.split_whitespace();
+    let pid = fields.next()?.parse().ok()?;
+    let thread = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let txn_id = match fields.next()? {
+        "-" => None,
+        txn_id => Some(txn_id.parse().ok()?),
+    };
+
+    Some((pid, thread, txn_id))
+}
+
+extern "C" fn collect_line(msg: *const libc::c_char, ctx: *mut libc::c_void) -> libc::c_int {
+    let lines = unsafe { &mut *(ctx as *mut Vec<String>) };
+    let msg = unsafe { CStr::from_ptr(msg) };
+    lines.extend(msg.to_string_lossy().lines().map(str::to_string));
+    0
+}
+
+/// Lists the reader slots that are in use
+pub(crate) fn list(env: &Environment) -> Result<Vec<ReaderSlot>, StorageError> {
+    let mut lines: Vec<String> = vec![];
+    // lmdb-sys declares the callback as a pointer to a function pointer, but LMDB takes the
+    // function pointer itself
+    let func = collect_line as ffi::MDB_msg_func as *mut ffi::MDB_msg_func;
+    unsafe {
+        check(ffi::mdb_reader_list(
+            env.env(),
+            func,
+            &mut lines as *mut Vec<String> as *mut libc::c_void,
+        ))?
+    };
+
+    let last_txn_id = unsafe {
+        let mut info = MaybeUninit::<ffi::MDB_envinfo>::uninit();
+        check(ffi::mdb_env_info(env.env(), info.as_mut_ptr()))?;
+        info.assume_init().me_last_txnid as u64
+    };
+
+    Ok(lines
+        .iter()
+        .filter_map(|line| parse_slot(line))
+        .map(|(pid, thread, txn_id)| ReaderSlot {
+            pid,
+            thread,
+            txn_id,
+            lag: txn_id.map_or(0, |txn_id| last_txn_id.saturating_sub(txn_id)),
+        })
+        .collect())
+}
+
+/// Frees the slots held by processes that have exited.  Returns how many were freed
+pub(crate) fn clear_stale(env: &Environment) -> Result<usize, StorageError> {
+    let mut dead = 0;
+    unsafe { check(ffi::mdb_reader_check(env.env(), &mut dead))? };
+    Ok(dead as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_reader_list_lines_are_parsed() {
+        assert_eq!(None, parse_slot("    pid     thread     txnid"));
+        assert_eq!(None, parse_slot("(no active readers)"));
+        assert_eq!(
+            Some((4242, 0x7f3a, Some(17))),
+            parse_slot("      4242 7f3a 17")
+        );
+        assert_eq!(Some((4242, 0x7f3a, None)), parse_slot("      4242 7f3a -"));
+    }
+}
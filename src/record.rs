@@ -1,11 +1,15 @@
-use std::convert::Into;
+use std::convert::{Into, TryFrom};
 use std::marker::Sized;
 
-use serde::{de::DeserializeOwned, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use lmdb::{DatabaseFlags, WriteFlags};
+
+use crate::{FieldError, IndexEntry, KeyError, Normalizer, StorageError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 /// When a type conforms to this trait it allows it to be stored and retrieved from the database
 pub trait Record: Serialize + DeserializeOwned + Sized {
-    type Key: Into<Vec<u8>>;
+    type Key: Into<Vec<u8>> + for<'a> TryFrom<&'a [u8], Error = KeyError>;
 
     /// Used to determine the key to use to associate with the object in the database
     fn key(&self) -> Self::Key;
@@ -15,6 +19,124 @@ pub trait Record: Serialize + DeserializeOwned + Sized {
         "default"
     }
 
+    /// The name the type is known by in its database, see `StorageError::TypeMismatch`.  Defaults
+    /// to the type's path.  The derive uses the type's name, or the one set with
+    /// `#[storable(type_tag = "...")]` so a renamed type keeps reading its old database
+    fn type_tag() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// A fingerprint of the type's stored fields, recorded with its database so a changed layout
+    /// is noticed when the type is registered, see `Storage::register`.  The derive hashes the
+    /// fields' names and types.  Defaults to 0, which is never checked
+    fn schema_version() -> u64 {
+        0
+    }
+
+    /// The LMDB flags used when the record's database is created.  Defaults to none
+    #[cfg(not(target_arch = "wasm32"))]
+    fn db_flags() -> DatabaseFlags {
+        DatabaseFlags::empty()
+    }
+
+    /// The LMDB flags used when the record is written.  Defaults to none
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write_flags() -> WriteFlags {
+        WriteFlags::empty()
+    }
+
+    /// The names of the secondary indexes maintained for this type.  Defaults to none
+    fn indexes() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The indexes, out of `indexes`, that hold at most one record per value.  Saving a record
+    /// whose value is already taken by another fails with `StorageError::UniqueViolation`
+    fn unique_indexes() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The indexes, out of `indexes`, that store a projection of each record next to its key,
+    /// set with `#[index(include = "...")]`.  `Storage::project_by_index` reads the projections
+    /// without loading the records
+    fn covering_indexes() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The indexes, out of `indexes`, read from the greatest value down, set with
+    /// `#[index(order = "desc")]`.  Lookups and ranges on them return the records holding the
+    /// greatest values first, and those holding one value by descending key
+    fn descending_indexes() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The indexes named other than the field they index, through `#[index(name = "...")]`, as
+    /// `(index, field)` pairs.  `QueryBuilder` filters on the field go through the index.
+    /// Defaults to none
+    fn renamed_indexes() -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+
+    /// The normalizers the values of the index named `index` go through, set with
+    /// `#[index(normalize = "...")]`.  Defaults to none
+    fn index_normalizers(_index: &str) -> &'static [Normalizer] {
+        &[]
+    }
+
+    /// The fields serde serializes under another name, through `#[serde(rename_all = "...")]` or
+    /// `#[serde(rename = "...")]`, as `(field, serialized name)` pairs.  `QueryBuilder` filters
+    /// match the serialized record, so they go by these names.  Defaults to none
+    fn renamed_fields() -> &'static [(&'static str, &'static str)] {
+        &[]
+    }
+
+    /// The values this record contributes to its secondary indexes.  Fails when the fields a
+    /// covering index stores can't be serialized
+    fn index_entries(&self) -> Result<Vec<IndexEntry>, StorageError> {
+        Ok(vec![])
+    }
+
+    /// The text fields covered by the type's full-text index.  Defaults to none
+    fn fulltext_fields(&self) -> Vec<&str> {
+        vec![]
+    }
+
+    /// The `(latitude, longitude)` the type's geospatial index files the record under, set with
+    /// `#[geo = "lat, lon"]`.  Defaults to none
+    fn geo_point(&self) -> Option<(f64, f64)> {
+        None
+    }
+
+    /// The partition the type is stored in.  Partitioned types live in their own environment in
+    /// a subdirectory of the storage, named after the partition.  Defaults to none
+    fn partition() -> Option<&'static str> {
+        None
+    }
+
+    /// Whether stored values are wrapped in a `Metadata` envelope.  Defaults to false
+    fn has_metadata() -> bool {
+        false
+    }
+
+    /// Checks the record before it is saved.  Saving an invalid record fails with
+    /// `StorageError::Validation`.  Defaults to accepting every record
+    fn validate(&self) -> Result<(), Vec<FieldError>> {
+        Ok(())
+    }
+
+    /// Called on the value that is about to be written, right before it is serialized
+    fn before_save(&mut self) {}
+
+    /// Whether `before_save` does anything.  Records are saved by reference, so the hook runs on
+    /// a copy of the record and the copy is only made when this returns true.  Defaults to false
+    fn has_before_save() -> bool {
+        false
+    }
+
+    /// Called on every record read back from storage.  Fields marked with `#[storable(skip)]` hold
+    /// their `Default` value until then, so this is where caches and handles are set up again
+    fn after_load(&mut self) {}
+
     /// Serializes the record to binary
     fn to_binary(&self) -> Result<Vec<u8>, bincode::Error> {
         bincode::serialize(self)
@@ -24,6 +146,44 @@ pub trait Record: Serialize + DeserializeOwned + Sized {
     fn from_binary(bytes: &[u8]) -> Result<Self, bincode::Error> {
         bincode::deserialize(bytes)
     }
+
+    /// Whether a stored value was written as an earlier version of the type, which `from_binary`
+    /// upgrades as it reads it.  The derive tags values with their version when the earlier ones
+    /// are listed with `#[storable(versions = "v1::Place, v2::Place")]`, see `UpgradePolicy`.
+    /// Defaults to false
+    fn is_outdated(_bytes: &[u8]) -> bool {
+        false
+    }
+}
+
+/// A record with a borrowed form whose strings and byte slices point straight into the stored
+/// value, so it can be read with `Storage::view` without copying them.
+///
+/// The borrowed form is always read with bincode, so it has to have the same fields in the same
+/// order as the record, and this doesn't apply to types with a custom `from_binary`.
+pub trait RecordRef: Record {
+    /// The borrowed form, usually the same fields with `&'a str` in place of `String`
+    type Ref<'a>: Deserialize<'a>;
+}
+
+/// Deserializes a stored record and runs its `after_load` hook
+pub(crate) fn load<T: Record>(bytes: &[u8]) -> Result<T, bincode::Error> {
+    let mut record = T::from_binary(bytes)?;
+    record.after_load();
+    Ok(record)
+}
+
+/// Returns the copy of a record that should be written when its type has a `before_save` hook.
+/// The copy is made by serializing the record, which fails when its serializer does
+pub(crate) fn before_save<T: Record>(record: &T) -> Result<Option<T>, StorageError> {
+    if !T::has_before_save() {
+        return Ok(None);
+    }
+
+    let bytes = record.to_binary()?;
+    let mut copy = T::from_binary(&bytes)?;
+    copy.before_save();
+    Ok(Some(copy))
 }
 
 #[cfg(test)]
@@ -53,4 +213,222 @@ mod tests {
 
         assert_eq!("Thing", Thing::db_name());
     }
+
+    #[derive(Storable, Serialize, Deserialize)]
+    #[key = "id"]
+    #[db_flags = "REVERSE_KEY"]
+    #[write_flags = "NO_OVERWRITE"]
+    struct FlaggedThing {
+        id: u32,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(
+        serialize_with = "legacy_to_bytes",
+        deserialize_with = "legacy_from_bytes"
+    )]
+    struct LegacyThing {
+        id: u32,
+        body: String,
+    }
+
+    // A pipe separated format written by an older system
+    fn legacy_to_bytes(thing: &LegacyThing) -> Result<Vec<u8>, bincode::Error> {
+        Ok(format!("{}|{}", thing.id, thing.body).into_bytes())
+    }
+
+    fn legacy_from_bytes(bytes: &[u8]) -> Result<LegacyThing, bincode::Error> {
+        let invalid = || Box::new(bincode::ErrorKind::Custom("invalid legacy row".to_string()));
+        let row = std::str::from_utf8(bytes).map_err(|_| invalid())?;
+        let mut parts = row.splitn(2, '|');
+        let id = parts
+            .next()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(invalid)?;
+        let body = parts.next().ok_or_else(invalid)?.to_string();
+        Ok(LegacyThing { id, body })
+    }
+
+    #[test]
+    fn test_that_the_derive_macro_uses_custom_serialization_hooks() {
+        let thing = LegacyThing {
+            id: 7,
+            body: "Old school".to_string(),
+        };
+
+        let bytes = thing.to_binary().expect("Could not serialize");
+        assert_eq!(b"7|Old school".to_vec(), bytes);
+        assert_eq!(
+            thing,
+            LegacyThing::from_binary(&bytes).expect("Could not deserialize")
+        );
+        assert_eq!("LegacyThing", LegacyThing::db_name());
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug)]
+    #[key = "id"]
+    #[storable(serialize_with = "refuse_to_bytes", timestamps)]
+    struct SealedThing {
+        id: u32,
+        created_at: Option<std::time::SystemTime>,
+        updated_at: Option<std::time::SystemTime>,
+    }
+
+    fn refuse_to_bytes(_: &SealedThing) -> Result<Vec<u8>, bincode::Error> {
+        Err(Box::new(bincode::ErrorKind::Custom("sealed".to_string())))
+    }
+
+    #[test]
+    fn test_that_serializer_errors_are_returned_instead_of_panicking() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let sealed = SealedThing {
+            id: 1,
+            created_at: None,
+            updated_at: None,
+        };
+
+        let is_codec = |result| matches!(result, Err(StorageError::Codec { .. }));
+        assert!(is_codec(storage.save(&sealed)));
+        assert!(is_codec(storage.transaction(|tx| tx.save(&sealed))));
+        let mut batch = storage.batch();
+        batch.save(&sealed);
+        assert!(is_codec(batch.commit()));
+        assert_eq!(0, storage.count::<SealedThing>().unwrap());
+    }
+
+    #[test]
+    fn test_that_the_derive_macro_sets_database_and_write_flags() {
+        assert_eq!(DatabaseFlags::REVERSE_KEY, FlaggedThing::db_flags());
+        assert_eq!(WriteFlags::NO_OVERWRITE, FlaggedThing::write_flags());
+        assert_eq!(DatabaseFlags::empty(), Thing::db_flags());
+        assert_eq!(WriteFlags::empty(), Thing::write_flags());
+    }
+
+    // A handle that can't be serialized, so the derive has to leave it out
+    #[derive(Default)]
+    struct Handle(Option<std::fs::File>);
+
+    #[derive(Storable)]
+    #[storable(key = "id", after_load = "Document::index_words")]
+    struct Document {
+        id: u32,
+        body: String,
+        #[storable(skip)]
+        words: Vec<String>,
+        #[storable(skip)]
+        handle: Handle,
+    }
+
+    impl Document {
+        fn index_words(&mut self) {
+            self.words = self.body.split(' ').map(str::to_string).collect();
+        }
+    }
+
+    #[test]
+    fn test_that_skipped_fields_are_left_out_and_filled_in_after_loading() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let document = Document {
+            id: 1,
+            body: "hello there".to_string(),
+            words: vec!["stale".to_string()],
+            handle: Handle(None),
+        };
+        assert_eq!(
+            serde_json::json!({"id": 1, "body": "hello there"}),
+            serde_json::to_value(&document).unwrap()
+        );
+        storage.save(&document).expect("Could not save document");
+
+        let loaded = storage.get::<Document, _>(1).unwrap().unwrap();
+        assert_eq!("hello there", loaded.body);
+        assert_eq!(vec!["hello", "there"], loaded.words);
+        assert!(loaded.handle.0.is_none());
+    }
+
+    // Earlier layouts of `Stop`
+    mod v1 {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        pub struct Stop {
+            pub id: u32,
+            pub name: String,
+        }
+    }
+
+    mod v2 {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        pub struct Stop {
+            pub id: u32,
+            pub name: String,
+            pub platforms: u8,
+        }
+
+        impl From<super::v1::Stop> for Stop {
+            fn from(stop: super::v1::Stop) -> Stop {
+                Stop {
+                    id: stop.id,
+                    name: stop.name,
+                    platforms: 1,
+                }
+            }
+        }
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(versions = "v1::Stop, v2::Stop")]
+    struct Stop {
+        id: u32,
+        name: String,
+        platforms: Vec<String>,
+    }
+
+    impl From<v2::Stop> for Stop {
+        fn from(stop: v2::Stop) -> Stop {
+            Stop {
+                id: stop.id,
+                name: stop.name,
+                platforms: (1..=stop.platforms).map(|n| n.to_string()).collect(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_that_earlier_versions_are_upgraded_and_written_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let options =
+            crate::StorageOptions::default().upgrade::<Stop>(crate::UpgradePolicy::WriteBack);
+        let mut storage =
+            Storage::open_with(dir.path(), options).expect("Could not open db storage");
+
+        let old = v1::Stop {
+            id: 1,
+            name: "Karlsplatz".to_string(),
+        };
+        let mut bytes = vec![1];
+        bytes.extend(bincode::serialize(&old).unwrap());
+        assert!(Stop::is_outdated(&bytes));
+        let key: Vec<u8> = Key::from(1u32).into();
+        storage
+            .raw("Stop")
+            .unwrap()
+            .put_bytes(&key, &bytes)
+            .unwrap();
+
+        let stop = storage.get::<Stop, _>(1).unwrap().unwrap();
+        assert_eq!(vec!["1".to_string()], stop.platforms);
+        let stored = storage
+            .raw("Stop")
+            .unwrap()
+            .get_bytes(&key)
+            .unwrap()
+            .unwrap();
+        assert!(!Stop::is_outdated(&stored));
+        assert_eq!(stop, Stop::from_binary(&stored).unwrap());
+
+        assert!(Stop::from_binary(&[9]).is_err());
+        assert_eq!(0, Stop::schema_version());
+    }
 }
@@ -0,0 +1,297 @@
+//! Record types registered with a storage at runtime.
+//!
+//! Registering a type keeps functions that read and write it with its type erased, so tools like
+//! exporters and inspectors can work through every registered type without naming each one.
+
+use serde_json::Value;
+use std::any::{Any, TypeId};
+
+use crate::metadata;
+use crate::{Record, Storage, StorageError, Transaction};
+
+/// A record whose type is only known at runtime
+pub trait DynRecord: Any {
+    /// The database the record is stored in
+    fn db_name(&self) -> &'static str;
+
+    /// The bytes the record is stored under
+    fn key_bytes(&self) -> Vec<u8>;
+
+    /// The record's fields as JSON
+    fn to_json(&self) -> Value;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Record + 'static> DynRecord for T {
+    fn db_name(&self) -> &'static str {
+        T::db_name()
+    }
+
+    fn key_bytes(&self) -> Vec<u8> {
+        self.key().into()
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl dyn DynRecord {
+    /// Returns the record as `T` when that is its type
+    pub fn downcast_ref<T: Record + 'static>(&self) -> Option<&T> {
+        self.as_any().downcast_ref()
+    }
+}
+
+type ScanFn = fn(&mut Storage) -> Result<Vec<Box<dyn DynRecord>>, StorageError>;
+type GetFn = fn(&mut Storage, &[u8]) -> Result<Option<Box<dyn DynRecord>>, StorageError>;
+type ReadFn = fn(&mut Transaction, &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+type PutFn = fn(&mut Transaction, &[u8], &[u8]) -> Result<(), StorageError>;
+type DeleteFn = fn(&mut Transaction, &[u8]) -> Result<(), StorageError>;
+
+/// A registered record type, see `Storage::register`
+#[derive(Clone, Copy)]
+pub struct RecordType {
+    db_name: &'static str,
+    type_id: TypeId,
+    type_name: &'static str,
+    scan: ScanFn,
+    get: GetFn,
+    save_json: fn(&mut Storage, Value) -> Result<(), StorageError>,
+    // Work with the serialized records of a transaction, without their envelopes
+    unwrap: fn(&[u8]) -> Option<&[u8]>,
+    read: ReadFn,
+    put: PutFn,
+    delete: DeleteFn,
+}
+
+impl RecordType {
+    pub(crate) fn of<T: Record + 'static>() -> RecordType {
+        RecordType {
+            db_name: T::db_name(),
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            scan: |storage| {
+                Ok(storage
+                    .query::<T>()?
+                    .map(|record| Box::new(record) as Box<dyn DynRecord>)
+                    .collect())
+            },
+            get: |storage, key| {
+                Ok(storage
+                    .get_raw::<T>(key)?
+                    .map(|record| Box::new(record) as Box<dyn DynRecord>))
+            },
+            save_json: |storage, value| storage.save(&serde_json::from_value::<T>(value)?),
+            unwrap: metadata::unwrap::<T>,
+            read: |tx, key| {
+                Ok(tx
+                    .get_bytes::<T>(key)?
+                    .and_then(|bytes| metadata::unwrap::<T>(&bytes).map(<[u8]>::to_vec)))
+            },
+            put: |tx, key, value| {
+                let record = T::from_binary(value)?;
+                tx.put_record::<T>(key, value, &record.index_entries()?)
+            },
+            delete: |tx, key| match tx.get_bytes::<T>(key)? {
+                Some(_) => tx.delete_key::<T>(key),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// The database the type's records are stored in
+    pub fn db_name(&self) -> &'static str {
+        self.db_name
+    }
+
+    /// The Rust name of the type
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// Whether this is the record type of `T`
+    pub fn is<T: 'static>(&self) -> bool {
+        self.type_id == TypeId::of::<T>()
+    }
+
+    /// Reads every record of the type
+    pub fn scan(&self, storage: &mut Storage) -> Result<Vec<Box<dyn DynRecord>>, StorageError> {
+        (self.scan)(storage)
+    }
+
+    /// Reads the record stored under the raw key `key`
+    pub fn get(
+        &self,
+        storage: &mut Storage,
+        key: &[u8],
+    ) -> Result<Option<Box<dyn DynRecord>>, StorageError> {
+        (self.get)(storage, key)
+    }
+
+    /// Converts `value` to a record of the type and saves it
+    pub fn save_json(&self, storage: &mut Storage, value: Value) -> Result<(), StorageError> {
+        (self.save_json)(storage, value)
+    }
+
+    /// The serialized record within a stored value, `None` when its envelope can't be read
+    pub(crate) fn unwrap<'v>(&self, value: &'v [u8]) -> Option<&'v [u8]> {
+        (self.unwrap)(value)
+    }
+
+    /// The serialized record stored under `key`
+    pub(crate) fn read(
+        &self,
+        tx: &mut Transaction,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        (self.read)(tx, key)
+    }
+
+    /// Stores the serialized record `value` under `key` along with its index entries
+    pub(crate) fn put(
+        &self,
+        tx: &mut Transaction,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), StorageError> {
+        (self.put)(tx, key, value)
+    }
+
+    /// Deletes the record stored under `key`, if there is one
+    pub(crate) fn delete(&self, tx: &mut Transaction, key: &[u8]) -> Result<(), StorageError> {
+        (self.delete)(tx, key)
+    }
+}
+
+impl std::fmt::Debug for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecordType")
+            .field("db_name", &self.db_name)
+            .field("type_name", &self.type_name)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Planet {
+        id: u32,
+        name: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Moon {
+        id: u32,
+        planet_id: u32,
+    }
+
+    #[test]
+    fn test_that_registered_types_can_be_read_and_written_without_naming_them() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .register::<Planet>()
+            .and_then(|storage| storage.register::<Moon>())
+            .and_then(|storage| storage.register::<Planet>())
+            .expect("Could not register types");
+
+        let names: Vec<&str> = storage
+            .record_types()
+            .iter()
+            .map(RecordType::db_name)
+            .collect();
+        assert_eq!(vec!["Planet", "Moon"], names);
+
+        let moon = storage.record_type("Moon").unwrap();
+        moon.save_json(&mut storage, serde_json::json!({"id": 1, "planet_id": 3}))
+            .expect("Could not save moon");
+        assert!(moon
+            .save_json(&mut storage, serde_json::json!({"id": "one"}))
+            .is_err());
+        storage
+            .save(&Planet {
+                id: 3,
+                name: "Earth".to_string(),
+            })
+            .unwrap();
+
+        let mut exported = vec![];
+        for record_type in storage.record_types() {
+            for record in record_type.scan(&mut storage).unwrap() {
+                exported.push((record.db_name(), record.to_json()));
+            }
+        }
+        assert_eq!(
+            vec![
+                ("Planet", serde_json::json!({"id": 3, "name": "Earth"})),
+                ("Moon", serde_json::json!({"id": 1, "planet_id": 3})),
+            ],
+            exported
+        );
+
+        let key: Vec<u8> = Key::from(3u32).into();
+        let planet = storage
+            .record_type("Planet")
+            .unwrap()
+            .get(&mut storage, &key)
+            .unwrap()
+            .unwrap();
+        assert_eq!("Earth", planet.downcast_ref::<Planet>().unwrap().name);
+        assert!(planet.downcast_ref::<Moon>().is_none());
+    }
+
+    mod v2 {
+        use crate::{Key, Record};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+        #[key = "id"]
+        pub struct Planet {
+            id: u64,
+            radius_km: f64,
+        }
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[db_name = "Planet"]
+    struct DwarfPlanet {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_that_two_types_cant_be_registered_for_one_database() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.register::<Planet>().unwrap();
+
+        for result in [
+            storage.register::<v2::Planet>().map(|_| ()),
+            storage.register::<DwarfPlanet>().map(|_| ()),
+        ] {
+            match result {
+                Err(StorageError::DbNameTaken {
+                    db_name, existing, ..
+                }) => {
+                    assert_eq!("Planet", db_name);
+                    assert!(existing.ends_with("registry::tests::Planet"));
+                }
+                _ => panic!("Expected the database name to be taken"),
+            }
+        }
+        assert_eq!(1, storage.record_types().len());
+        assert!(storage.record_type("Planet").unwrap().is::<Planet>());
+    }
+}
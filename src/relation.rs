@@ -0,0 +1,267 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::index;
+use crate::{Record, StorageError, Transaction};
+
+/// What happens to the children of a record when the record is deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDelete {
+    /// Delete the children too
+    Cascade,
+    /// Refuse to delete the parent while it still has children
+    Restrict,
+    /// Keep the children but clear their foreign key, which must be an `Option`
+    SetNull,
+}
+
+/// A registered on-delete policy for one child type of a parent.  The child's type is erased, so
+/// the rule keeps the function that enforces the policy for that type.
+#[derive(Clone)]
+pub(crate) struct DeleteRule {
+    pub child: &'static str,
+    pub policy: OnDelete,
+    pub enforce: fn(&mut Transaction, &[u8], OnDelete) -> Result<(), StorageError>,
+}
+
+/// Delete rules keyed by the db_name of the parent they apply to
+pub(crate) type DeleteRules = HashMap<&'static str, Vec<DeleteRule>>;
+
+/// Applies `policy` to every `C` that belongs to the parent stored under the raw key `parent_key`
+pub(crate) fn enforce<P, C>(
+    tx: &mut Transaction,
+    parent_key: &[u8],
+    policy: OnDelete,
+) -> Result<(), StorageError>
+where
+    P: Record,
+    P::Key: Serialize,
+    C: BelongsTo<P>,
+{
+    let parent = match tx.get_record::<P>(parent_key)? {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    let children = tx.index_lookup::<C>(C::foreign_key(), &index::encode(&parent.key()))?;
+    if children.is_empty() {
+        return Ok(());
+    }
+
+    match policy {
+        OnDelete::Cascade => {
+            for key in children {
+                tx.delete_key::<C>(&key)?;
+            }
+        }
+        OnDelete::Restrict => {
+            return Err(StorageError::RestrictViolation {
+                parent: P::db_name(),
+                child: C::db_name(),
+                count: children.len(),
+            })
+        }
+        OnDelete::SetNull => {
+            for key in children {
+                if let Some(mut child) = tx.get_record::<C>(&key)? {
+                    if !child.clear_parent_key() {
+                        return Err(StorageError::NotNullable {
+                            child: C::db_name(),
+                            foreign_key: C::foreign_key(),
+                        });
+                    }
+                    tx.save(&child)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Describes a record that points at a parent record through a foreign key field.
+///
+/// Storage keeps a secondary index on the foreign key so the children of a parent can be found
+/// without scanning every child record.  Usually generated with `#[belongs_to(Parent, key = "field")]`.
+pub trait BelongsTo<P: Record>: Record {
+    /// The name of the field that holds the parent's key, also used as the name of the index
+    fn foreign_key() -> &'static str;
+
+    /// The key of the parent this record belongs to, if it has one
+    fn parent_key(&self) -> Option<P::Key>;
+
+    /// Detaches the record from its parent.  Returns false when the foreign key isn't optional
+    fn clear_parent_key(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, OnDelete, Record, Storage, StorageError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Council {
+        id: u32,
+        name: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[belongs_to(Council, key = "council_id")]
+    struct Motion {
+        id: u32,
+        council_id: u32,
+        title: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[belongs_to(Council, key = "council_id")]
+    struct Petition {
+        id: u32,
+        council_id: Option<u32>,
+    }
+
+    fn motion(id: u32, council_id: u32) -> Motion {
+        Motion {
+            id,
+            council_id,
+            title: format!("Motion {}", id),
+        }
+    }
+
+    fn setup() -> Storage {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        for id in 1..=2 {
+            storage
+                .save(&Council {
+                    id,
+                    name: format!("Council {}", id),
+                })
+                .expect("Could not save council");
+        }
+        storage
+            .save_batch(vec![motion(1, 1), motion(2, 1), motion(3, 2)])
+            .expect("Could not save motions");
+        storage
+    }
+
+    fn motion_ids(storage: &mut Storage, council_id: u32) -> Vec<u32> {
+        storage
+            .children_of::<Council, Motion, _>(council_id)
+            .expect("Could not fetch children")
+            .iter()
+            .map(|m| m.id)
+            .collect()
+    }
+
+    #[test]
+    fn test_that_children_are_found_through_the_foreign_key_index() {
+        let mut storage = setup();
+
+        assert_eq!(vec![1, 2], motion_ids(&mut storage, 1));
+        assert_eq!(vec![3], motion_ids(&mut storage, 2));
+
+        // Moving a child to another parent updates the index
+        storage.save(&motion(2, 2)).expect("Could not save motion");
+        assert_eq!(vec![1], motion_ids(&mut storage, 1));
+        assert_eq!(vec![2, 3], motion_ids(&mut storage, 2));
+
+        // Deleting a child removes it from the index
+        storage.delete(&motion(3, 2)).expect("Could not delete");
+        assert_eq!(vec![2], motion_ids(&mut storage, 2));
+    }
+
+    #[test]
+    fn test_that_we_can_cascade_deletes_to_children() {
+        let mut storage = setup();
+        let council = Council {
+            id: 1,
+            name: "Council 1".to_string(),
+        };
+
+        storage
+            .delete_cascade::<Council, Motion>(&council)
+            .expect("Could not delete");
+
+        assert!(motion_ids(&mut storage, 1).is_empty());
+        assert_eq!(vec![3], motion_ids(&mut storage, 2));
+        assert!(storage.get::<Council, _>(1).unwrap().is_none());
+        assert_eq!(1, storage.query::<Motion>().unwrap().count());
+    }
+
+    fn council(id: u32) -> Council {
+        Council {
+            id,
+            name: format!("Council {}", id),
+        }
+    }
+
+    #[test]
+    fn test_that_restrict_prevents_deleting_a_parent_with_children() {
+        let mut storage = setup();
+        storage.on_delete::<Council, Motion>(OnDelete::Restrict);
+
+        match storage.delete(&council(1)) {
+            Err(StorageError::RestrictViolation {
+                parent: "Council",
+                child: "Motion",
+                count: 2,
+            }) => (),
+            _ => panic!("Expected the delete to be restricted"),
+        }
+        assert!(storage.get::<Council, _>(1).is_ok());
+
+        storage.delete(&motion(1, 1)).expect("Could not delete");
+        storage.delete(&motion(2, 1)).expect("Could not delete");
+        storage.delete(&council(1)).expect("Could not delete");
+    }
+
+    #[test]
+    fn test_that_cascade_and_set_null_policies_are_applied_on_delete() {
+        let mut storage = setup();
+        storage.truncate::<Petition>().expect("Could not truncate");
+        storage.on_delete::<Council, Motion>(OnDelete::Cascade);
+        storage.on_delete::<Council, Petition>(OnDelete::SetNull);
+
+        storage
+            .save(&Petition {
+                id: 1,
+                council_id: Some(1),
+            })
+            .expect("Could not save petition");
+
+        storage.delete(&council(1)).expect("Could not delete");
+
+        assert!(motion_ids(&mut storage, 1).is_empty());
+        assert_eq!(vec![3], motion_ids(&mut storage, 2));
+
+        let petition: Option<Petition> = storage.get(1).expect("Could not get petition");
+        assert_eq!(
+            Some(Petition {
+                id: 1,
+                council_id: None
+            }),
+            petition
+        );
+        assert!(storage
+            .children_of::<Council, Petition, _>(1)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_that_set_null_requires_an_optional_foreign_key() {
+        let mut storage = setup();
+        storage.on_delete::<Council, Motion>(OnDelete::SetNull);
+
+        match storage.delete(&council(2)) {
+            Err(StorageError::NotNullable { .. }) => (),
+            _ => panic!("Expected the delete to fail"),
+        }
+        assert_eq!(vec![3], motion_ids(&mut storage, 2));
+    }
+}
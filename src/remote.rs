@@ -0,0 +1,416 @@
+//! A `StorageApi` backed by a nostalgia server over HTTP, for when a central store fits better
+//! than an embedded one.  Only built with the `remote` feature.
+//!
+//! The server holds raw databases and knows nothing about record types, so the client turns each
+//! save and delete into the writes a local transaction would make, index entries included, and
+//! sends them as one batch.  A batch carries the values the client read before building it, and
+//! the server refuses the whole batch when any of them changed in the meantime, in which case the
+//! client reads them again and retries.
+//!
+//! The protocol, with database names percent-encoded and keys hex-encoded in paths:
+//!
+//! * `GET /dbs` answers with the names of the databases as a JSON list
+//! * `GET /dbs/{db}/{key}` answers with the raw value, or 404 when there is none
+//! * `GET /dbs/{db}?start={key}&limit={n}` answers with up to `n` entries from `start` on, as a
+//!   JSON list of `{"key": .., "value": ..}` objects in hex
+//! * `POST /batch` takes a JSON object with the `expect`ed values and the `ops` to apply, as
+//!   journal `ChangeOp`s, and answers 409 when an expected value doesn't match
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::index::{self, index_db_flags, index_db_name};
+use crate::metadata;
+use crate::protocol::{Entry, Expected, WriteBatch};
+use crate::record;
+use crate::{ChangeOp, Record, StorageApi, StorageError};
+
+// How many times a batch is rebuilt when the values it was built from keep changing
+const MAX_ATTEMPTS: usize = 5;
+
+// How many entries a scan reads per request
+const PAGE: usize = 1000;
+
+// A raw key and its value
+type Pair = (Vec<u8>, Vec<u8>);
+
+fn remote(reason: impl ToString) -> StorageError {
+    StorageError::Remote {
+        reason: reason.to_string(),
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so database names fit in a path
+pub(crate) fn escape(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// The writes that remove the index entries of the record stored as `previous`
+fn remove_ops<T: Record>(key: &[u8], previous: Option<&[u8]>) -> Result<Vec<ChangeOp>, StorageError> {
+    let stored = match previous {
+        Some(bytes) => metadata::decode::<T>(key, bytes)?,
+        None => return Ok(vec![]),
+    };
+    Ok(stored
+        .index_entries()?
+        .into_iter()
+        .map(|entry| ChangeOp::Delete {
+            db: index_db_name(T::db_name(), entry.index),
+            key: entry.value,
+            value: Some(index::entry_data(key, entry.projection.as_deref())),
+        })
+        .collect())
+}
+
+/// The writes that replace the record stored as `previous` under `key` with `record`, the same
+/// ones a transaction makes.  Returns them with the stored value
+pub(crate) fn save_ops<T: Record>(
+    key: &[u8],
+    previous: Option<&[u8]>,
+    record: &T,
+) -> Result<(Vec<ChangeOp>, Vec<u8>), StorageError> {
+    let mut ops = remove_ops::<T>(key, previous)?;
+    let value = metadata::wrap::<T>(previous, &T::to_binary(record)?);
+    ops.push(ChangeOp::Put {
+        db: T::db_name().to_string(),
+        flags: T::db_flags().bits(),
+        key: key.to_vec(),
+        value: value.clone(),
+    });
+    for entry in record.index_entries()? {
+        ops.push(ChangeOp::Put {
+            db: index_db_name(T::db_name(), entry.index),
+            flags: index_db_flags().bits(),
+            key: entry.value,
+            value: index::entry_data(key, entry.projection.as_deref()),
+        });
+    }
+    Ok((ops, value))
+}
+
+/// The writes that delete the record stored as `previous` under `key` with its index entries
+pub(crate) fn delete_ops<T: Record>(
+    key: &[u8],
+    previous: Option<&[u8]>,
+) -> Result<Vec<ChangeOp>, StorageError> {
+    let mut ops = remove_ops::<T>(key, previous)?;
+    ops.push(ChangeOp::Delete {
+        db: T::db_name().to_string(),
+        key: key.to_vec(),
+        value: None,
+    });
+    Ok(ops)
+}
+
+/// A storage on a nostalgia server
+///
+/// # Examples
+/// ```no_run
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{remote::RemoteStorage, StorageApi, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut storage = RemoteStorage::new("http://localhost:7878");
+///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+///
+///     let place = storage.get::<Place, _>(1u32)?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RemoteStorage {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl RemoteStorage {
+    /// A storage on the server at `url`, like `http://localhost:7878`
+    pub fn new(url: &str) -> RemoteStorage {
+        RemoteStorage {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// The names of the databases on the server
+    pub fn databases(&self) -> Result<Vec<String>, StorageError> {
+        self.agent
+            .get(&format!("{}/dbs", self.url))
+            .call()
+            .map_err(remote)?
+            .into_json()
+            .map_err(Into::into)
+    }
+
+    /// The raw value stored under `key` in the database `db`
+    pub fn get_bytes(&self, db: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let url = format!("{}/dbs/{}/{}", self.url, escape(db), hex::encode(key));
+        match self.agent.get(&url).call() {
+            Ok(response) => {
+                let mut bytes = vec![];
+                response.into_reader().read_to_end(&mut bytes)?;
+                Ok(Some(bytes))
+            }
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(remote(e)),
+        }
+    }
+
+    /// At most `limit` raw entries of the database `db`, from `start` on in key order
+    pub fn scan(&self, db: &str, start: &[u8], limit: usize) -> Result<Vec<Pair>, StorageError> {
+        let url = format!("{}/dbs/{}", self.url, escape(db));
+        let entries: Vec<Entry> = self
+            .agent
+            .get(&url)
+            .query("start", &hex::encode(start))
+            .query("limit", &limit.to_string())
+            .call()
+            .map_err(remote)?
+            .into_json()?;
+        entries
+            .into_iter()
+            .map(|entry| {
+                let key = hex::decode(&entry.key).map_err(remote)?;
+                let value = hex::decode(&entry.value).map_err(remote)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    // Applies a batch, returning false when the server refused it because an expected value changed
+    fn write(&self, batch: &WriteBatch) -> Result<bool, StorageError> {
+        match self
+            .agent
+            .post(&format!("{}/batch", self.url))
+            .send_json(batch)
+        {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(409, _)) => Ok(false),
+            Err(e) => Err(remote(e)),
+        }
+    }
+
+    // Builds the writes of a batch from values read from the server, and builds them again while
+    // those values change before the batch is applied
+    fn write_with<F>(&self, mut build: F) -> Result<(), StorageError>
+    where
+        F: FnMut(&mut Reads) -> Result<Vec<ChangeOp>, StorageError>,
+    {
+        for _ in 0..MAX_ATTEMPTS {
+            let mut reads = Reads {
+                storage: self,
+                expect: vec![],
+            };
+            let ops = build(&mut reads)?;
+            let batch = WriteBatch {
+                expect: reads.expect,
+                ops,
+            };
+            if self.write(&batch)? {
+                return Ok(());
+            }
+        }
+        Err(remote(format!(
+            "records kept changing during {} attempts to write them",
+            MAX_ATTEMPTS
+        )))
+    }
+
+    fn save_all<T: Record>(&self, records: &[&T]) -> Result<(), StorageError> {
+        self.write_with(|reads| {
+            // Later records replace earlier ones with the same key
+            let mut written: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+            let mut ops = vec![];
+            for record in records {
+                let key: Vec<u8> = record.key().into();
+                let previous = match written.remove(&key) {
+                    Some(value) => Some(value),
+                    None => reads.get(T::db_name(), &key)?,
+                };
+                let (record_ops, value) = save_ops(&key, previous.as_deref(), *record)?;
+                ops.extend(record_ops);
+                written.insert(key, value);
+            }
+            Ok(ops)
+        })
+    }
+
+    fn records<T: Record>(&self) -> Result<Vec<T>, StorageError> {
+        let mut records = vec![];
+        let mut start = vec![];
+        loop {
+            let page = self.scan(T::db_name(), &start, PAGE)?;
+            for (key, value) in &page {
+                records.push(metadata::decode::<T>(key, value)?);
+            }
+            match page.last() {
+                Some((key, _)) if page.len() == PAGE => {
+                    start = key.clone();
+                    start.push(0);
+                }
+                _ => return Ok(records),
+            }
+        }
+    }
+}
+
+// The values a batch was built from, which the server checks before applying it
+struct Reads<'r> {
+    storage: &'r RemoteStorage,
+    expect: Vec<Expected>,
+}
+
+impl Reads<'_> {
+    fn get(&mut self, db: &str, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let value = self.storage.get_bytes(db, key)?;
+        self.expect.push(Expected {
+            db: db.to_string(),
+            key: key.to_vec(),
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+}
+
+impl StorageApi for RemoteStorage {
+    fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        let copy = record::before_save(record)?;
+        let record = copy.as_ref().unwrap_or(record);
+        record.validate().map_err(StorageError::Validation)?;
+        self.save_all(&[record])
+    }
+
+    fn save_batch<T: Record>(&mut self, records: Vec<T>) -> Result<(), StorageError> {
+        let copies = records
+            .iter()
+            .map(record::before_save)
+            .collect::<Result<Vec<_>, _>>()?;
+        let records: Vec<&T> = records
+            .iter()
+            .zip(&copies)
+            .map(|(record, copy)| copy.as_ref().unwrap_or(record))
+            .collect();
+        for record in &records {
+            record.validate().map_err(StorageError::Validation)?;
+        }
+        self.save_all(&records)
+    }
+
+    fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        let key: Vec<u8> = key.into().into();
+        match self.get_bytes(T::db_name(), &key)? {
+            Some(bytes) => metadata::decode(&key, &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError> {
+        let key: Vec<u8> = record.key().into();
+        let mut stored = false;
+        self.write_with(|reads| {
+            let previous = reads.get(T::db_name(), &key)?;
+            stored = previous.is_some();
+            match previous {
+                Some(previous) => delete_ops::<T>(&key, Some(&previous)),
+                None => Ok(vec![]),
+            }
+        })?;
+        Ok(stored)
+    }
+
+    fn all<T: Record>(&mut self) -> Result<Vec<T>, StorageError> {
+        self.records()
+    }
+
+    fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
+        Ok(self.records()?.into_iter().find(|record| p(record)))
+    }
+
+    fn count<T: Record>(&mut self) -> Result<usize, StorageError> {
+        Ok(self.records::<T>()?.len())
+    }
+
+    fn truncate<T: Record>(&mut self) -> Result<(), StorageError> {
+        let mut batch = WriteBatch::default();
+        batch.ops.push(ChangeOp::Clear {
+            db: T::db_name().to_string(),
+        });
+        for index in T::indexes() {
+            batch.ops.push(ChangeOp::Clear {
+                db: index_db_name(T::db_name(), index),
+            });
+        }
+        self.write(&batch).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Change, Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+    }
+
+    fn station(id: u32, line: &str) -> Station {
+        Station {
+            id,
+            line: line.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_that_batches_write_what_a_transaction_would() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let key: Vec<u8> = station(1, "").key().into();
+        let apply = |storage: &mut Storage, seq, ops| {
+            storage
+                .apply_changes(vec![Change { seq, ops }])
+                .expect("Could not apply batch");
+        };
+
+        let (ops, first) = save_ops(&key, None, &station(1, "M1")).unwrap();
+        apply(&mut storage, 0, ops);
+        let (ops, second) = save_ops(&key, Some(&first), &station(1, "M4")).unwrap();
+        // The index entry of the record being replaced is removed first
+        assert!(matches!(ops[0], ChangeOp::Delete { value: Some(_), .. }));
+        apply(&mut storage, 1, ops);
+        let m4: Vec<Station> = storage.find_by_index("line", "M4").unwrap();
+        assert_eq!(vec![station(1, "M4")], m4);
+        assert!(storage
+            .find_by_index::<Station, _>("line", "M1")
+            .unwrap()
+            .is_empty());
+
+        apply(&mut storage, 2, delete_ops::<Station>(&key, Some(&second)).unwrap());
+        assert!(storage.get::<Station, _>(1u32).unwrap().is_none());
+        assert!(storage
+            .find_by_index::<Station, _>("line", "M4")
+            .unwrap()
+            .is_empty());
+
+        assert_eq!("Station.line%23kv%20a", escape("Station.line#kv a"));
+    }
+}
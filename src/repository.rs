@@ -0,0 +1,94 @@
+use std::marker::PhantomData;
+
+use crate::{Record, Storage, StorageApi, StorageError};
+
+/// A handle to the records of a single type, see `Storage::repository`.
+///
+/// Code that only works with one type can take a `Repo` instead of the whole storage, and the
+/// type never has to be spelled out again at each call.  Repositories of any `StorageApi`, like
+/// `MemoryStorage`, work the same way.
+pub struct Repo<'s, T, S = Storage> {
+    storage: &'s mut S,
+    phantom: PhantomData<T>,
+}
+
+impl<'s, T: Record, S: StorageApi> Repo<'s, T, S> {
+    pub(crate) fn new(storage: &'s mut S) -> Repo<'s, T, S> {
+        Repo {
+            storage,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Saves a record, replacing any record stored under the same key
+    pub fn save(&mut self, record: &T) -> Result<(), StorageError> {
+        self.storage.save(record)
+    }
+
+    /// Retrieves a record by its key
+    pub fn get<K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        self.storage.get::<T, K>(key)
+    }
+
+    /// Deletes a record and returns whether it was stored
+    pub fn delete(&mut self, record: &T) -> Result<bool, StorageError> {
+        self.storage.delete(record)
+    }
+
+    /// Reads every record, in key order
+    pub fn all(&mut self) -> Result<Vec<T>, StorageError> {
+        self.storage.all::<T>()
+    }
+
+    /// Returns the first record that matches a predicate
+    pub fn find<P: Fn(&T) -> bool>(&mut self, p: P) -> Result<Option<T>, StorageError> {
+        self.storage.find::<T>(&p)
+    }
+
+    /// The number of records
+    pub fn count(&mut self) -> Result<usize, StorageError> {
+        self.storage.count::<T>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Mayor {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_that_a_repository_works_with_one_type() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let mut mayors = storage.repository::<Mayor>();
+        assert_eq!(0, mayors.count().unwrap());
+        for (id, name) in [
+            (105, "Ed Koch"),
+            (107, "Rudy Giuliani"),
+            (108, "Mike Bloomberg"),
+        ] {
+            mayors
+                .save(&Mayor {
+                    id,
+                    name: name.to_string(),
+                })
+                .unwrap();
+        }
+
+        assert_eq!(3, mayors.count().unwrap());
+        assert_eq!("Ed Koch", mayors.get(105).unwrap().unwrap().name);
+        let giuliani = mayors.find(|mayor| mayor.name.starts_with("Rudy")).unwrap();
+        assert_eq!(Some(107), giuliani.as_ref().map(|mayor| mayor.id));
+
+        mayors.delete(&giuliani.unwrap()).unwrap();
+        let ids: Vec<u32> = mayors.all().unwrap().iter().map(|mayor| mayor.id).collect();
+        assert_eq!(vec![105, 108], ids);
+    }
+}
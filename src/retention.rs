@@ -0,0 +1,137 @@
+//! Keeping log-style record types from growing without bound.
+//!
+//! A `Retention` registered with `Storage::retain` says which records of a type are kept.  The
+//! others are deleted by `Storage::apply_retention`, which a `Maintenance` can run on every pass,
+//! a chunk per write transaction like `Storage::delete_where`.
+
+use lmdb::{Transaction};
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::type_tag::{self, TYPES_DB};
+use crate::{query, usage};
+use crate::{Record, Storage, StorageError};
+
+/// Which records of `T` are kept, see `Storage::retain`
+pub enum Retention<T> {
+    /// Keeps the records whose timestamp, read by the function, is at most this old
+    MaxAge(Duration, fn(&T) -> SystemTime),
+    /// Keeps this many records, those with the greatest keys.  Suits types keyed by an id or time
+    /// that only grows
+    MaxCount(usize),
+}
+
+impl<T> Clone for Retention<T> {
+    fn clone(&self) -> Retention<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Retention<T> {}
+
+// A retention with its type erased, returning how many records it deleted
+pub(crate) type RetentionRule =
+    Arc<dyn Fn(&mut Storage) -> Result<usize, StorageError> + Send + Sync>;
+
+/// Retention rules by the database name of the type they're for
+pub(crate) type RetentionRules = HashMap<&'static str, RetentionRule>;
+
+pub(crate) fn rule<T: Record + 'static>(retention: Retention<T>) -> RetentionRule {
+    Arc::new(move |storage| apply(storage, retention))
+}
+
+fn apply<T: Record>(storage: &mut Storage, retention: Retention<T>) -> Result<usize, StorageError> {
+    match retention {
+        Retention::MaxAge(max_age, timestamp) => {
+            let cutoff = SystemTime::now() - max_age;
+            storage.delete_where::<T, _>(|record| timestamp(record) < cutoff)
+        }
+        Retention::MaxCount(count) => {
+            let storage = storage.storage_for::<T>()?;
+            match first_kept::<T>(storage, count)? {
+                Some(first) => {
+                    storage.delete_chunked::<T, _>(vec![], Bound::Excluded(first), &mut |_, _| Ok(true))
+                }
+                None => Ok(0),
+            }
+        }
+    }
+}
+
+// The smallest key of the `count` records of `T` with the greatest keys, or `None` when there
+// are no more records than that
+fn first_kept<T: Record>(
+    storage: &mut Storage,
+    count: usize,
+) -> Result<Option<Vec<u8>>, StorageError> {
+    let db = match storage.existing_db(T::db_name())? {
+        Some(db) => db,
+        None => return Ok(None),
+    };
+    let types = storage.existing_db(TYPES_DB)?;
+    let txn = storage.env()?.begin_ro_txn()?;
+    type_tag::check::<T>(&txn, types)?;
+
+    let stored = usage::entries(&txn, db)?;
+    if stored <= count {
+        return Ok(None);
+    }
+    let cursor = txn.open_ro_cursor(db)?;
+    let first = query::checked_iter(&cursor)
+        .nth(stored - count)
+        .transpose()?
+        .map(|(key, _)| key.to_vec());
+    Ok(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Event {
+        id: u64,
+        at: SystemTime,
+    }
+
+    #[test]
+    fn test_that_only_the_newest_records_are_kept() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        assert_eq!(0, storage.apply_retention::<Event>().unwrap());
+        storage.retain(Retention::<Event>::MaxCount(500));
+        assert_eq!(0, storage.apply_retention::<Event>().unwrap());
+
+        let now = SystemTime::now();
+        storage
+            .transaction(|tx| {
+                for id in 0..2500 {
+                    tx.save(&Event {
+                        id,
+                        at: now - Duration::from_secs(2500 - id),
+                    })?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(2000, storage.apply_retention::<Event>().unwrap());
+        let kept: Vec<u64> = storage
+            .query::<Event>()
+            .unwrap()
+            .map(|event| event.id)
+            .collect();
+        assert_eq!((2000..2500).collect::<Vec<_>>(), kept);
+
+        // A later retention replaces the earlier one
+        storage.retain(Retention::MaxAge(
+            Duration::from_millis(100_500),
+            |event: &Event| event.at,
+        ));
+        assert_eq!(400, storage.apply_retention::<Event>().unwrap());
+        assert_eq!(100, storage.count::<Event>().unwrap());
+    }
+}
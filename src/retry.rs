@@ -0,0 +1,158 @@
+//! Retrying write transactions that fail for reasons that pass on their own.
+
+use lmdb::{Environment, RwTransaction};
+use lmdb_sys as ffi;
+use std::time::Duration;
+
+/// How write transactions that can't be started because of another process or thread are retried,
+/// see `StorageOptions::retry`.  The wait doubles after every attempt, up to `max_backoff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times a transaction is tried, including the first
+    pub attempts: u32,
+    /// How long to wait before the first retry
+    pub backoff: Duration,
+    /// The longest wait between two attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sets the most times a transaction is tried, including the first
+    pub fn attempts(mut self, attempts: u32) -> RetryPolicy {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Sets how long to wait before the first retry
+    pub fn backoff(mut self, backoff: Duration) -> RetryPolicy {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the longest wait between two attempts
+    pub fn max_backoff(mut self, max_backoff: Duration) -> RetryPolicy {
+        self.max_backoff = max_backoff;
+        self
+    }
+}
+
+// Errors caused by another process or thread, which go away once it is done: the map was grown
+// by another process, every reader slot is taken, or a lock is held
+fn is_transient(error: &lmdb::Error) -> bool {
+    matches!(
+        error,
+        lmdb::Error::MapResized
+            | lmdb::Error::ReadersFull
+            | lmdb::Error::Other(libc::EBUSY)
+            | lmdb::Error::Other(libc::EAGAIN)
+    )
+}
+
+/// Calls `attempt` until it succeeds, fails with an error `retry` doesn't accept, or `policy`
+/// runs out of attempts.  Without a policy it is called once
+pub(crate) fn run<R, F, P>(
+    policy: Option<RetryPolicy>,
+    retry: P,
+    mut attempt: F,
+) -> Result<R, lmdb::Error>
+where
+    F: FnMut() -> Result<R, lmdb::Error>,
+    P: Fn(&lmdb::Error) -> bool,
+{
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return attempt(),
+    };
+
+    let mut backoff = policy.backoff;
+    let mut tried = 1;
+    loop {
+        match attempt() {
+            Err(e) if retry(&e) && tried < policy.attempts => {
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(policy.max_backoff);
+                tried += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Starts a write transaction, retrying transient errors under `policy`.
+///
+/// When another process grew the map, the environment has to take on the new size before any
+/// write can start, which LMDB only allows while no transaction is open in this process.  That is
+/// left to the caller to promise with `resizable`, otherwise the error is returned right away.
+pub(crate) fn begin_rw_txn(
+    env: &Environment,
+    policy: Option<RetryPolicy>,
+    resizable: bool,
+) -> Result<RwTransaction<'_>, lmdb::Error> {
+    let retry = |e: &lmdb::Error| is_transient(e) && (resizable || *e != lmdb::Error::MapResized);
+    run(policy, retry, || match env.begin_rw_txn() {
+        Err(lmdb::Error::MapResized) if resizable => {
+            match unsafe { ffi::mdb_env_set_mapsize(env.env(), 0) } {
+                0 => Err(lmdb::Error::MapResized),
+                code => Err(lmdb::Error::from_err_code(code)),
+            }
+        }
+        result => result,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_that_only_transient_errors_are_retried() {
+        let policy = RetryPolicy::default()
+            .attempts(3)
+            .backoff(Duration::from_micros(10));
+
+        let mut calls = 0;
+        let result = run(Some(policy), is_transient, || {
+            calls += 1;
+            if calls < 3 {
+                Err(lmdb::Error::ReadersFull)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(Ok(3), result);
+
+        let mut calls = 0;
+        let result: Result<(), _> = run(Some(policy), is_transient, || {
+            calls += 1;
+            Err(lmdb::Error::MapResized)
+        });
+        assert_eq!(Err(lmdb::Error::MapResized), result);
+        assert_eq!(3, calls);
+
+        let mut calls = 0;
+        let result: Result<(), _> = run(Some(policy), is_transient, || {
+            calls += 1;
+            Err(lmdb::Error::MapFull)
+        });
+        assert_eq!(Err(lmdb::Error::MapFull), result);
+        assert_eq!(1, calls);
+
+        let mut calls = 0;
+        let result: Result<(), _> = run(None, is_transient, || {
+            calls += 1;
+            Err(lmdb::Error::ReadersFull)
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+    }
+}
@@ -0,0 +1,87 @@
+//! Sequences that hand out the keys of records saved without one.
+
+use lmdb::{Database, RwTransaction, Transaction, WriteFlags};
+
+use crate::StorageError;
+
+/// The database the last key handed out for each record type is kept in, by database name
+pub(crate) const SEQUENCES_DB: &str = "nostalgia#sequences";
+
+pub(crate) fn read(
+    txn: &impl Transaction,
+    db: Database,
+    db_name: &str,
+) -> Result<u64, StorageError> {
+    match txn.get(db, &db_name) {
+        Ok(bytes) if bytes.len() == 8 => {
+            let mut last = [0; 8];
+            last.copy_from_slice(bytes);
+            Ok(u64::from_be_bytes(last))
+        }
+        Ok(_) => Err(lmdb::Error::Corrupted.into()),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn write(
+    txn: &mut RwTransaction,
+    db: Database,
+    db_name: &str,
+    last: u64,
+) -> Result<(), StorageError> {
+    txn.put(db, &db_name, &last.to_be_bytes(), WriteFlags::empty())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FieldError, Key, Record, Storage, StorageError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(builder)]
+    struct Mayor {
+        id: u32,
+        #[validate(length(min = 1))]
+        name: String,
+        party: Option<String>,
+    }
+
+    #[test]
+    fn test_that_built_records_are_saved_under_generated_keys() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Mayor {
+                id: 2,
+                name: "Koch".to_string(),
+                party: None,
+            })
+            .unwrap();
+
+        // Keys already taken are skipped, and keys that were set are kept
+        let dinkins = Mayor::builder().name("Dinkins").party("Democratic");
+        assert_eq!(1, dinkins.save(&mut storage).unwrap().id);
+        let giuliani = Mayor::builder()
+            .name("Giuliani")
+            .save(&mut storage)
+            .unwrap();
+        assert_eq!((3, None), (giuliani.id, giuliani.party));
+        let lindsay = Mayor::builder().id(10).name("Lindsay").save(&mut storage);
+        assert_eq!(10, lindsay.unwrap().id);
+        assert_eq!(4, storage.count::<Mayor>().unwrap());
+
+        // Nothing is saved, nor a key used up, when a field is missing or invalid
+        match Mayor::builder().party("Republican").save(&mut storage) {
+            Err(StorageError::Validation(errors)) => {
+                assert_eq!(vec![FieldError::new("name", "is required")], errors)
+            }
+            other => panic!("Expected a missing field, got {:?}", other),
+        }
+        assert!(Mayor::builder().name("").save(&mut storage).is_err());
+        let bloomberg = Mayor::builder().name("Bloomberg").save(&mut storage);
+        assert_eq!(4, bloomberg.unwrap().id);
+        assert_eq!(5, storage.count::<Mayor>().unwrap());
+    }
+}
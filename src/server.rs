@@ -0,0 +1,279 @@
+//! Serves a storage to `remote::RemoteStorage` clients over HTTP.  Only built with the `server`
+//! feature.
+//!
+//! The server speaks the protocol described in `remote`.  It reads and writes raw databases, so it
+//! needs no record types of its own; the clients send the writes their saves and deletes make.
+//! It also lists its databases at `GET /dbs`, for tools that inspect live data.
+//! With the `grpc` feature the same storage can be served over gRPC as well, see `grpc`.
+//!
+//! Reads go through the shared read handle and batches take turns on the write handle, both off
+//! the async workers since LMDB calls block.
+//!
+//! Anyone who can reach the server can write to it, so serve it on a trusted network only.
+//! Batches that clear whole databases are refused unless `ServerOptions::allow_clear` is set.
+
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+use crate::protocol::{Entry, WriteBatch};
+use crate::web::SharedStorage;
+use crate::{ChangeOp, StorageError};
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+// How many entries a scan answers with when the client doesn't ask for a number, and at most
+const MAX_LIMIT: usize = 1000;
+
+/// What the server lets its clients do
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerOptions {
+    allow_clear: bool,
+}
+
+impl ServerOptions {
+    /// Whether batches may clear whole databases, which `RemoteStorage::truncate` sends.  Off by
+    /// default, so a client can't wipe a database in a single request
+    pub fn allow_clear(mut self, allow: bool) -> ServerOptions {
+        self.allow_clear = allow;
+        self
+    }
+
+    // Whether the server applies `ops`
+    pub(crate) fn permits(&self, ops: &[ChangeOp]) -> bool {
+        self.allow_clear || !ops.iter().any(|op| matches!(op, ChangeOp::Clear { .. }))
+    }
+}
+
+#[derive(Deserialize)]
+struct Scan {
+    #[serde(default)]
+    start: String,
+    limit: Option<usize>,
+}
+
+fn failure(reason: impl ToString) -> Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, reason.to_string()).into_response()
+}
+
+fn bad_request(reason: impl ToString) -> Response {
+    (StatusCode::BAD_REQUEST, reason.to_string()).into_response()
+}
+
+// Runs `f` on the blocking thread pool
+async fn blocking<F>(f: F) -> Response
+where
+    F: FnOnce() -> Response + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(failure)
+}
+
+async fn databases(storage: SharedStorage) -> Response {
+    blocking(move || match storage.reader().database_names() {
+        Ok(names) => Json(names).into_response(),
+        Err(e) => failure(e),
+    })
+    .await
+}
+
+async fn value(storage: SharedStorage, Path((db, key)): Path<(String, String)>) -> Response {
+    let key = match hex::decode(&key) {
+        Ok(key) => key,
+        Err(e) => return bad_request(e),
+    };
+    blocking(move || match storage.reader().raw_get(&db, &key) {
+        Ok(Some(value)) => value.into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => failure(e),
+    })
+    .await
+}
+
+async fn scan(
+    storage: SharedStorage,
+    Path(db): Path<String>,
+    Query(scan): Query<Scan>,
+) -> Response {
+    let start = match hex::decode(&scan.start) {
+        Ok(start) => start,
+        Err(e) => return bad_request(e),
+    };
+    let limit = scan.limit.unwrap_or(MAX_LIMIT).min(MAX_LIMIT);
+    blocking(
+        move || match storage.reader().raw_entries(&db, &start, limit) {
+            Ok(entries) => {
+                let entries: Vec<Entry> = entries
+                    .into_iter()
+                    .map(|(key, value)| Entry {
+                        key: hex::encode(key),
+                        value: hex::encode(value),
+                    })
+                    .collect();
+                Json(entries).into_response()
+            }
+            Err(e) => failure(e),
+        },
+    )
+    .await
+}
+
+async fn batch(storage: SharedStorage, options: ServerOptions, batch: WriteBatch) -> Response {
+    if !options.permits(&batch.ops) {
+        return (StatusCode::FORBIDDEN, "clearing databases is not allowed").into_response();
+    }
+    blocking(
+        move || match storage.write(|storage| storage.apply_batch(&batch)) {
+            Ok(true) => StatusCode::NO_CONTENT.into_response(),
+            Ok(false) => StatusCode::CONFLICT.into_response(),
+            Err(e) => failure(e),
+        },
+    )
+    .await
+}
+
+/// The routes of the protocol, to serve on their own or nest into a larger application
+pub fn router(storage: SharedStorage) -> Router {
+    router_with(storage, ServerOptions::default())
+}
+
+/// The routes of the protocol, letting clients do what `options` allow
+pub fn router_with(storage: SharedStorage, options: ServerOptions) -> Router {
+    Router::new()
+        .route("/dbs", get(databases))
+        .route("/dbs/{db}", get(scan))
+        .route("/dbs/{db}/{key}", get(value))
+        .route(
+            "/batch",
+            post(move |storage, Json(writes)| batch(storage, options, writes)),
+        )
+        .with_state(storage)
+}
+
+/// Serves `storage` on `addr` until the server fails
+///
+/// # Examples
+/// ```no_run
+/// use nostalgia::{server, web::SharedStorage, Storage, StorageError};
+///
+/// fn main() -> Result<(), StorageError> {
+///     let storage = SharedStorage::new(Storage::new("/tmp/db")?)?;
+///     let runtime = tokio::runtime::Runtime::new()?;
+///     runtime.block_on(server::serve(storage, "127.0.0.1:7878"))
+/// }
+/// ```
+pub async fn serve<A: ToSocketAddrs>(storage: SharedStorage, addr: A) -> Result<(), StorageError> {
+    serve_with(storage, ServerOptions::default(), addr).await
+}
+
+/// Serves `storage` on `addr` until the server fails, letting clients do what `options` allow
+pub async fn serve_with<A: ToSocketAddrs>(
+    storage: SharedStorage,
+    options: ServerOptions,
+    addr: A,
+) -> Result<(), StorageError> {
+    let listener = TcpListener::bind(addr).await?;
+    axum::serve(listener, router_with(storage, options)).await?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "remote"))]
+mod tests {
+    use super::*;
+    use crate::protocol::Expected;
+    use crate::remote::RemoteStorage;
+    use crate::{Key, Record, Storage, StorageApi};
+    use serde::Serialize;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        #[index]
+        line: String,
+    }
+
+    fn station(id: u32, line: &str) -> Station {
+        Station {
+            id,
+            line: line.to_string(),
+        }
+    }
+
+    // Serves `app` on a free port for as long as `runtime` runs, returning its url
+    fn spawn(runtime: &tokio::runtime::Runtime, app: Router) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        runtime.spawn(async move {
+            let listener = TcpListener::from_std(listener).expect("Could not listen");
+            axum::serve(listener, app).await
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_that_remote_clients_read_and_write_through_the_server() {
+        let storage = Storage::temporary().expect("Could not open db storage");
+        let shared = SharedStorage::new(storage).expect("Could not share storage");
+        let runtime = tokio::runtime::Runtime::new().expect("Could not start runtime");
+        let mut remote = RemoteStorage::new(&spawn(&runtime, router(shared.clone())));
+        remote.save(&station(1, "M1")).unwrap();
+        remote
+            .save_batch(vec![station(2, "M1"), station(1, "M4")])
+            .unwrap();
+        assert_eq!(
+            Some(station(1, "M4")),
+            remote.get::<Station, _>(1u32).unwrap()
+        );
+        assert_eq!(2, remote.count::<Station>().unwrap());
+
+        // The index entries the client sent are kept up to date on the server
+        shared
+            .write(|storage| {
+                let m1: Vec<Station> = storage.find_by_index("line", "M1")?;
+                assert_eq!(vec![station(2, "M1")], m1);
+                // Batches built from values that changed since are refused
+                let stale = WriteBatch {
+                    expect: vec![Expected {
+                        db: Station::db_name().to_string(),
+                        key: station(1, "").key().into(),
+                        value: None,
+                    }],
+                    ops: vec![],
+                };
+                assert!(!storage.apply_batch(&stale)?);
+                Ok(())
+            })
+            .unwrap();
+
+        remote.delete(&station(2, "M1")).unwrap();
+        assert!(remote.get::<Station, _>(2u32).unwrap().is_none());
+        assert_eq!(vec![station(1, "M4")], remote.all::<Station>().unwrap());
+        assert!(remote
+            .databases()
+            .unwrap()
+            .contains(&Station::db_name().to_string()));
+    }
+
+    #[test]
+    fn test_that_clearing_databases_needs_to_be_allowed() {
+        let storage = Storage::temporary().expect("Could not open db storage");
+        let shared = SharedStorage::new(storage).expect("Could not share storage");
+        let runtime = tokio::runtime::Runtime::new().expect("Could not start runtime");
+        let mut remote = RemoteStorage::new(&spawn(&runtime, router(shared.clone())));
+        remote.save(&station(1, "M1")).unwrap();
+
+        assert!(remote.truncate::<Station>().is_err());
+        assert_eq!(1, remote.count::<Station>().unwrap());
+
+        let options = ServerOptions::default().allow_clear(true);
+        let mut remote = RemoteStorage::new(&spawn(&runtime, router_with(shared, options)));
+        remote.truncate::<Station>().unwrap();
+        assert_eq!(0, remote.count::<Station>().unwrap());
+    }
+}
@@ -0,0 +1,288 @@
+//! Serves a storage over gRPC, for tools written in any language.  Only built with the `grpc`
+//! feature.
+//!
+//! The service is described in `proto/nostalgia.proto`.  Like the HTTP routes it reads and writes
+//! raw databases by name, with keys and values as bytes, and refuses batches that clear databases
+//! unless `ServerOptions::allow_clear` is set.
+
+use std::net::SocketAddr;
+
+use tonic::{Request, Response, Status};
+
+use super::{ServerOptions, MAX_LIMIT};
+use crate::protocol::{self, WriteBatch};
+use crate::web::SharedStorage;
+use crate::{ChangeOp, StorageError};
+
+/// The messages, client and server generated from `proto/nostalgia.proto`
+#[allow(clippy::all, missing_docs)]
+pub mod proto {
+    tonic::include_proto!("nostalgia");
+}
+
+use proto::nostalgia_server::{Nostalgia, NostalgiaServer};
+use proto::op::Op;
+use proto::{
+    ApplyRequest, ApplyResponse, GetRequest, GetResponse, ListDatabasesRequest,
+    ListDatabasesResponse, ScanRequest, ScanResponse,
+};
+
+fn failure(e: StorageError) -> Status {
+    Status::internal(e.to_string())
+}
+
+// Runs `f` on the blocking thread pool
+async fn blocking<R, F>(f: F) -> Result<R, Status>
+where
+    F: FnOnce() -> Result<R, StorageError> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(failure)
+}
+
+// The write an op makes, `None` when the op is missing
+fn change_op(op: proto::Op) -> Option<ChangeOp> {
+    match op.op? {
+        Op::Put(put) => Some(ChangeOp::Put {
+            db: put.db,
+            flags: put.flags,
+            key: put.key,
+            value: put.value,
+        }),
+        Op::Delete(delete) => Some(ChangeOp::Delete {
+            db: delete.db,
+            key: delete.key,
+            value: delete.value,
+        }),
+        Op::Clear(clear) => Some(ChangeOp::Clear { db: clear.db }),
+    }
+}
+
+/// The gRPC service, to serve on its own with `serve` or next to other services
+///
+/// # Examples
+/// ```no_run
+/// use nostalgia::server::grpc::{serve, Service};
+/// use nostalgia::server::ServerOptions;
+/// use nostalgia::{web::SharedStorage, Storage, StorageError};
+///
+/// fn main() -> Result<(), StorageError> {
+///     let storage = SharedStorage::new(Storage::open_existing("/var/lib/places")?)?;
+///     let service = Service::new(storage, ServerOptions::default());
+///     let runtime = tokio::runtime::Runtime::new()?;
+///     runtime.block_on(serve(service, "127.0.0.1:7879".parse().unwrap()))
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Service {
+    storage: SharedStorage,
+    options: ServerOptions,
+}
+
+impl Service {
+    /// Serves `storage`, letting clients do what `options` allow
+    pub fn new(storage: SharedStorage, options: ServerOptions) -> Service {
+        Service { storage, options }
+    }
+
+    /// The service wrapped for `tonic::transport::Server::add_service`
+    pub fn into_server(self) -> NostalgiaServer<Service> {
+        NostalgiaServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl Nostalgia for Service {
+    async fn list_databases(
+        &self,
+        _request: Request<ListDatabasesRequest>,
+    ) -> Result<Response<ListDatabasesResponse>, Status> {
+        let storage = self.storage.clone();
+        let names = blocking(move || storage.reader().database_names()).await?;
+        Ok(Response::new(ListDatabasesResponse { names }))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let GetRequest { db, key } = request.into_inner();
+        let storage = self.storage.clone();
+        let value = blocking(move || storage.reader().raw_get(&db, &key)).await?;
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn scan(&self, request: Request<ScanRequest>) -> Result<Response<ScanResponse>, Status> {
+        let ScanRequest { db, start, limit } = request.into_inner();
+        let limit = match limit as usize {
+            0 => MAX_LIMIT,
+            limit => limit.min(MAX_LIMIT),
+        };
+        let storage = self.storage.clone();
+        let entries = blocking(move || storage.reader().raw_entries(&db, &start, limit)).await?;
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| proto::Entry { key, value })
+            .collect();
+        Ok(Response::new(ScanResponse { entries }))
+    }
+
+    async fn apply(
+        &self,
+        request: Request<ApplyRequest>,
+    ) -> Result<Response<ApplyResponse>, Status> {
+        let ApplyRequest { expect, ops } = request.into_inner();
+        let batch = WriteBatch {
+            expect: expect
+                .into_iter()
+                .map(|expected| protocol::Expected {
+                    db: expected.db,
+                    key: expected.key,
+                    value: expected.value,
+                })
+                .collect(),
+            ops: ops
+                .into_iter()
+                .map(change_op)
+                .collect::<Option<_>>()
+                .ok_or_else(|| Status::invalid_argument("op is missing"))?,
+        };
+        if !self.options.permits(&batch.ops) {
+            return Err(Status::permission_denied(
+                "clearing databases is not allowed",
+            ));
+        }
+
+        let storage = self.storage.clone();
+        let applied =
+            blocking(move || storage.write(|storage| storage.apply_batch(&batch))).await?;
+        Ok(Response::new(ApplyResponse { applied }))
+    }
+}
+
+/// Serves `service` on `addr` until the server fails
+pub async fn serve(service: Service, addr: SocketAddr) -> Result<(), StorageError> {
+    tonic::transport::Server::builder()
+        .add_service(service.into_server())
+        .serve(addr)
+        .await
+        .map_err(|e| StorageError::Server {
+            reason: e.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::nostalgia_client::NostalgiaClient;
+    use super::proto::{Clear, Put};
+    use super::*;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Station {
+        id: u32,
+        line: String,
+    }
+
+    fn put(db: &str, key: &[u8], value: &[u8]) -> proto::Op {
+        proto::Op {
+            op: Some(Op::Put(Put {
+                db: db.to_string(),
+                flags: 0,
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })),
+        }
+    }
+
+    #[test]
+    fn test_that_clients_read_and_write_raw_databases_over_grpc() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Station {
+                id: 1,
+                line: "U1".to_string(),
+            })
+            .expect("Could not save station");
+        let shared = SharedStorage::new(storage).expect("Could not share storage");
+        let service = Service::new(shared.clone(), ServerOptions::default());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Could not bind");
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let runtime = tokio::runtime::Runtime::new().expect("Could not start runtime");
+        runtime.spawn(serve(service, addr));
+
+        runtime.block_on(async {
+            let url = format!("http://{}", addr);
+            let endpoint = tonic::transport::Endpoint::from_shared(url).unwrap();
+            let mut client = loop {
+                match endpoint.connect().await {
+                    Ok(channel) => break NostalgiaClient::new(channel),
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+
+            let names = client.list_databases(ListDatabasesRequest {}).await;
+            assert!(names
+                .unwrap()
+                .into_inner()
+                .names
+                .contains(&"Station".to_string()));
+
+            let key: Vec<u8> = Key::from(1u32).into();
+            let get = GetRequest {
+                db: "Station".to_string(),
+                key: key.clone(),
+            };
+            let value = client.get(get).await.unwrap().into_inner().value;
+            let station: Station = bincode::deserialize(&value.unwrap()).unwrap();
+            assert_eq!("U1", station.line);
+
+            let apply = ApplyRequest {
+                expect: vec![],
+                ops: vec![put("Notes", b"a", b"1"), put("Notes", b"b", b"2")],
+            };
+            assert!(client.apply(apply).await.unwrap().into_inner().applied);
+            let scan = ScanRequest {
+                db: "Notes".to_string(),
+                start: b"b".to_vec(),
+                limit: 0,
+            };
+            let entries = client.scan(scan).await.unwrap().into_inner().entries;
+            assert_eq!(
+                vec![(b"b".to_vec(), b"2".to_vec())],
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect::<Vec<_>>()
+            );
+
+            // Batches whose expected values changed are refused, and so are clears
+            let stale = ApplyRequest {
+                expect: vec![proto::Expected {
+                    db: "Notes".to_string(),
+                    key: b"a".to_vec(),
+                    value: None,
+                }],
+                ops: vec![put("Notes", b"c", b"3")],
+            };
+            assert!(!client.apply(stale).await.unwrap().into_inner().applied);
+            let clear = ApplyRequest {
+                expect: vec![],
+                ops: vec![proto::Op {
+                    op: Some(Op::Clear(Clear {
+                        db: "Station".to_string(),
+                    })),
+                }],
+            };
+            let refused = client.apply(clear).await.unwrap_err();
+            assert_eq!(tonic::Code::PermissionDenied, refused.code());
+        });
+
+        assert_eq!(1, shared.reader().query::<Station>().unwrap().count());
+    }
+}
@@ -0,0 +1,298 @@
+//! A Redis-style sorted set stored in a single database.
+//!
+//! Every member is stored twice: once under its score followed by its name, so members come back
+//! ordered by score and then by name, and once under its name, so its score can be found without
+//! a scan.  Scores are encoded so their bytes sort in numeric order.
+
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, RwTransaction, Transaction, WriteFlags};
+use std::ops::{Bound, RangeBounds};
+
+use crate::query;
+use crate::{FieldError, StorageError};
+
+const BY_SCORE: u8 = b's';
+const BY_MEMBER: u8 = b'm';
+
+/// The name of the database that holds a sorted set
+pub(crate) fn sorted_set_db_name(name: &str) -> String {
+    format!("{}#zset", name)
+}
+
+pub(crate) fn sorted_set_db_flags() -> DatabaseFlags {
+    DatabaseFlags::empty()
+}
+
+// Flips the sign bit of positive scores and every bit of negative ones, so the bytes of any two
+// scores compare like the scores do
+fn encode_score(score: f64) -> [u8; 8] {
+    let bits = score.to_bits();
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    };
+    ordered.to_be_bytes()
+}
+
+fn decode_score(bytes: &[u8]) -> f64 {
+    let mut ordered = [0; 8];
+    ordered.copy_from_slice(&bytes[..8]);
+    let ordered = u64::from_be_bytes(ordered);
+    let bits = if ordered >> 63 == 1 {
+        ordered & !(1 << 63)
+    } else {
+        !ordered
+    };
+    f64::from_bits(bits)
+}
+
+fn score_key(score: f64, member: &str) -> Vec<u8> {
+    let mut key = vec![BY_SCORE];
+    key.extend(&encode_score(score));
+    key.extend(member.as_bytes());
+    key
+}
+
+fn member_key(member: &str) -> Vec<u8> {
+    let mut key = vec![BY_MEMBER];
+    key.extend(member.as_bytes());
+    key
+}
+
+fn score_of(
+    txn: &impl Transaction,
+    db: Database,
+    member: &str,
+) -> Result<Option<f64>, StorageError> {
+    match txn.get(db, &member_key(member)) {
+        Ok(bytes) => Ok(Some(decode_score(bytes))),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Members ordered by a score, see `Storage::sorted_set`.
+///
+/// Members are strings and each has one score; members with the same score are ordered by name.
+/// Scores that are NaN are rejected.  A member's name can't be longer than LMDB's key size less
+/// nine bytes, 502 bytes by default.
+pub struct SortedSet<'s> {
+    env: &'s Environment,
+    db: Database,
+}
+
+impl<'s> SortedSet<'s> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> SortedSet<'s> {
+        SortedSet { env, db }
+    }
+
+    /// Sets `member`'s score, adding it if it isn't in the set.  Returns whether it was added
+    pub fn zadd(&self, member: &str, score: f64) -> Result<bool, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let added = self.set(&mut txn, member, score)?;
+        txn.commit()?;
+        Ok(added)
+    }
+
+    /// Adds `by` to `member`'s score, adding it with a score of `by` if it isn't in the set.
+    /// Returns the new score
+    pub fn zincrby(&self, member: &str, by: f64) -> Result<f64, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let score = score_of(&txn, self.db, member)?.unwrap_or(0.0) + by;
+        self.set(&mut txn, member, score)?;
+        txn.commit()?;
+        Ok(score)
+    }
+
+    /// `member`'s score, or `None` when it isn't in the set
+    pub fn zscore(&self, member: &str) -> Result<Option<f64>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        score_of(&txn, self.db, member)
+    }
+
+    /// Removes `member`, returning whether it was in the set
+    pub fn zrem(&self, member: &str) -> Result<bool, StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        let score = match score_of(&txn, self.db, member)? {
+            Some(score) => score,
+            None => return Ok(false),
+        };
+        txn.del(self.db, &score_key(score, member), None)?;
+        txn.del(self.db, &member_key(member), None)?;
+        txn.commit()?;
+        Ok(true)
+    }
+
+    /// `member`'s position in the set, counting from 0 at the lowest score, or `None` when it
+    /// isn't in the set.  Takes time in proportion to the position
+    pub fn zrank(&self, member: &str) -> Result<Option<usize>, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        let score = match score_of(&txn, self.db, member)? {
+            Some(score) => score,
+            None => return Ok(None),
+        };
+
+        let target = score_key(score, member);
+        let mut rank = 0;
+        self.scan(&txn, &[BY_SCORE], |key| {
+            if key >= &target[..] {
+                return false;
+            }
+            rank += 1;
+            true
+        })?;
+        Ok(Some(rank))
+    }
+
+    /// The members whose score lies in `range` along with their scores, lowest first
+    pub fn zrange_by_score<R: RangeBounds<f64>>(
+        &self,
+        range: R,
+    ) -> Result<Vec<(String, f64)>, StorageError> {
+        let start = match range.start_bound() {
+            Bound::Included(min) | Bound::Excluded(min) => score_key(*min, ""),
+            Bound::Unbounded => vec![BY_SCORE],
+        };
+
+        let txn = self.env.begin_ro_txn()?;
+        let mut members = vec![];
+        self.scan(&txn, &start, |key| {
+            let score = decode_score(&key[1..9]);
+            if !range.contains(&score) {
+                // Scores equal to an excluded start are skipped, anything else ends the range
+                return matches!(range.start_bound(), Bound::Excluded(min) if *min == score);
+            }
+            members.push((String::from_utf8_lossy(&key[9..]).into_owned(), score));
+            true
+        })?;
+        Ok(members)
+    }
+
+    /// The number of members in the set
+    pub fn zcard(&self) -> Result<usize, StorageError> {
+        let txn = self.env.begin_ro_txn()?;
+        let mut count = 0;
+        self.scan(&txn, &[BY_MEMBER], |_| {
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    }
+
+    fn set(&self, txn: &mut RwTransaction, member: &str, score: f64) -> Result<bool, StorageError> {
+        if score.is_nan() {
+            return Err(StorageError::Validation(vec![FieldError::new(
+                "score",
+                "can't be NaN",
+            )]));
+        }
+
+        let previous = score_of(txn, self.db, member)?;
+        if let Some(previous) = previous {
+            txn.del(self.db, &score_key(previous, member), None)?;
+        }
+        txn.put(self.db, &score_key(score, member), &[], WriteFlags::empty())?;
+        txn.put(
+            self.db,
+            &member_key(member),
+            &encode_score(score),
+            WriteFlags::empty(),
+        )?;
+        Ok(previous.is_none())
+    }
+
+    // Calls `f` with the keys from `start` on that share its first byte, until it returns false
+    fn scan<F>(&self, txn: &impl Transaction, start: &[u8], mut f: F) -> Result<(), StorageError>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let cursor = txn.open_ro_cursor(self.db)?;
+        // Positioned by hand, since lmdb 0.8's `iter_from` panics when nothing sorts after `start`
+        let first = match cursor.get(Some(start), None, lmdb_sys::MDB_SET_RANGE) {
+            Ok((Some(key), value)) => (key, value),
+            Ok((None, _)) | Err(lmdb::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in std::iter::once(Ok(first)).chain(query::checked_iter(&cursor)) {
+            let (key, _) = entry?;
+            if key[0] != start[0] || !f(key) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+
+    #[test]
+    fn test_that_scores_keep_their_order_when_encoded() {
+        let scores = [
+            f64::NEG_INFINITY,
+            -10.5,
+            -0.0,
+            0.0,
+            1e-9,
+            3.0,
+            1e12,
+            f64::INFINITY,
+        ];
+        for pair in scores.windows(2) {
+            assert!(encode_score(pair[0]) <= encode_score(pair[1]));
+        }
+        for score in &scores {
+            assert_eq!(
+                score.to_bits(),
+                decode_score(&encode_score(*score)).to_bits()
+            );
+        }
+    }
+
+    #[test]
+    fn test_that_members_are_ranked_by_score() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let board = storage
+            .sorted_set("leaderboard")
+            .expect("Could not open sorted set");
+
+        assert!(board.zadd("ada", 30.0).unwrap());
+        assert!(board.zadd("grace", 10.0).unwrap());
+        assert!(board.zadd("edsger", -5.0).unwrap());
+        assert!(board.zadd("barbara", 10.0).unwrap());
+        assert!(!board.zadd("grace", 40.0).unwrap());
+        assert!(board.zadd("alan", f64::NAN).is_err());
+        assert_eq!(4, board.zcard().unwrap());
+
+        assert_eq!(Some(40.0), board.zscore("grace").unwrap());
+        assert_eq!(Some(0), board.zrank("edsger").unwrap());
+        assert_eq!(Some(3), board.zrank("grace").unwrap());
+        assert_eq!(None, board.zrank("alan").unwrap());
+
+        let middle = board.zrange_by_score(0.0..=30.0).unwrap();
+        assert_eq!(
+            vec![("barbara".to_string(), 10.0), ("ada".to_string(), 30.0)],
+            middle
+        );
+        assert_eq!(3, board.zrange_by_score(10.0..).unwrap().len());
+        assert_eq!(
+            vec!["ada", "grace"],
+            board
+                .zrange_by_score((Bound::Excluded(10.0), Bound::Unbounded))
+                .unwrap()
+                .iter()
+                .map(|(member, _)| member.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(15.0, board.zincrby("barbara", 5.0).unwrap());
+        assert_eq!(Some(1), board.zrank("barbara").unwrap());
+        assert!(board.zrem("edsger").unwrap());
+        assert!(!board.zrem("edsger").unwrap());
+        assert_eq!(Some(0), board.zrank("barbara").unwrap());
+        assert_eq!(3, board.zcard().unwrap());
+    }
+}
@@ -0,0 +1,349 @@
+//! Separate handles for reading and writing one environment.
+//!
+//! LMDB allows any number of readers but only one writer at a time.  `Storage::split` turns a
+//! storage into an `RoStorage`, which can be cloned and shared between threads, and a single
+//! `RwStorage` that makes every write, so the type system keeps writes in one place.
+
+use lmdb::{Database, Environment, Transaction as LmdbTransaction};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, RwLock};
+
+use crate::metadata::{self, Metadata};
+use crate::metrics::MetricsSink;
+use crate::read_pool::{ReadPool, ReadPoolStats};
+use crate::transaction::{counter_key, counters_db_name, decode_counter};
+use crate::type_tag::{self, TYPES_DB};
+use crate::{CheckedQuery, KeyQuery, RoQuery, Storage, StorageError, StorageOptions};
+use crate::{Record, RecordRef};
+
+/// A read-only handle to a storage, see `Storage::split`.  Clones share the environment and its
+/// database handles
+#[derive(Clone)]
+pub struct RoStorage {
+    pool: Arc<ReadPool>,
+    env: Arc<Environment>,
+    dbs: Arc<RwLock<HashMap<String, Database>>>,
+    options: Arc<StorageOptions>,
+    // The partition this handle reads, `None` for the one record types are routed from
+    partition: Option<&'static str>,
+    partitions: Arc<HashMap<&'static str, RoStorage>>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+}
+
+impl RoStorage {
+    pub(crate) fn new(
+        env: Arc<Environment>,
+        dbs: HashMap<String, Database>,
+        options: StorageOptions,
+        partition: Option<&'static str>,
+        partitions: HashMap<&'static str, RoStorage>,
+        metrics: Option<Arc<dyn MetricsSink>>,
+    ) -> RoStorage {
+        RoStorage {
+            pool: Arc::new(ReadPool::new(env.clone(), options.read_pool)),
+            env,
+            dbs: Arc::new(RwLock::new(dbs)),
+            options: Arc::new(options),
+            partition,
+            partitions: Arc::new(partitions),
+            metrics,
+        }
+    }
+
+    // The handle a type's records are read through
+    fn storage_for<T: Record>(&self) -> Result<&RoStorage, StorageError> {
+        match self.options.partition_of::<T>() {
+            Some(name) if self.partition.is_none() => {
+                self.partitions
+                    .get(name)
+                    .ok_or(StorageError::WrongPartition {
+                        db_name: T::db_name(),
+                    })
+            }
+            _ => Ok(self),
+        }
+    }
+
+    fn existing_db(&self, db_name: &str) -> Result<Option<Database>, StorageError> {
+        if let Some(db) = self.dbs.read().expect("Poisoned lock").get(db_name) {
+            return Ok(Some(*db));
+        }
+
+        match self.env.open_db(Some(db_name)) {
+            Ok(db) => {
+                let mut dbs = self.dbs.write().expect("Poisoned lock");
+                dbs.insert(db_name.to_string(), db);
+                Ok(Some(db))
+            }
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn record_read<T: Record>(&self, op: &'static str) {
+        if let Some(sink) = &self.metrics {
+            sink.operation(op, T::db_name());
+        }
+    }
+
+    /// Retrieves a record by its key, or `None` when there is no such record
+    pub fn get<T: Record, K: Into<T::Key>>(&self, key: K) -> Result<Option<T>, StorageError> {
+        let storage = self.storage_for::<T>()?;
+        storage.record_read::<T>("get");
+
+        let db = match storage.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.pool.begin()?;
+        type_tag::check::<T>(&*txn, types)?;
+        let key: Vec<u8> = key.into().into();
+        match txn.get(db, &key) {
+            Ok(bytes) => metadata::decode(&key, bytes).map(Some),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads a record in its borrowed form and hands it to `f`, like `Storage::view`
+    pub fn view<T, K, F, R>(&self, key: K, f: F) -> Result<Option<R>, StorageError>
+    where
+        T: RecordRef,
+        K: Into<T::Key>,
+        F: for<'a> FnOnce(T::Ref<'a>) -> R,
+    {
+        let storage = self.storage_for::<T>()?;
+        let db = match storage.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.pool.begin()?;
+        type_tag::check::<T>(&*txn, types)?;
+        let key: Vec<u8> = key.into().into();
+        let bytes = match txn.get(db, &key) {
+            Ok(bytes) => bytes,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let record = bincode::deserialize::<T::Ref<'_>>(metadata::stored::<T>(&key, bytes)?)
+            .map_err(|_| metadata::undecodable::<T>(&key))?;
+        Ok(Some(f(record)))
+    }
+
+    /// Returns the created and updated times of a record, like `Storage::metadata`
+    pub fn metadata<T: Record, K: Into<T::Key>>(
+        &self,
+        key: K,
+    ) -> Result<Option<Metadata>, StorageError> {
+        let storage = self.storage_for::<T>()?;
+        if !T::has_metadata() {
+            return Ok(None);
+        }
+
+        let db = match storage.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.pool.begin()?;
+        type_tag::check::<T>(&*txn, types)?;
+        match txn.get(db, &key.into().into()) {
+            Ok(bytes) => Ok(Metadata::read(bytes).map(|(metadata, _)| metadata)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the current value of one of a record's counters
+    pub fn counter<T: Record, K: Into<T::Key>>(
+        &self,
+        key: K,
+        counter: &str,
+    ) -> Result<i64, StorageError> {
+        let storage = self.storage_for::<T>()?;
+        let db = match storage.existing_db(&counters_db_name(T::db_name()))? {
+            Some(db) => db,
+            None => return Ok(0),
+        };
+        let txn = storage.pool.begin()?;
+
+        match txn.get(db, &counter_key(&key.into().into(), counter)) {
+            Ok(bytes) => Ok(decode_counter(bytes)),
+            Err(lmdb::Error::NotFound) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Iterates over all records in a type's database
+    pub fn query<T: Record>(&self) -> Result<RoQuery<'_, T>, StorageError> {
+        let storage = self.storage_for::<T>()?;
+        storage.record_read::<T>("query");
+        let db = storage.existing_db(T::db_name())?;
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.env.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        Ok(RoQuery::new(db, txn)?.max_read_age(self.options.max_read_age))
+    }
+
+    /// Iterates over all records in a type's database, yielding an error for each value that can't
+    /// be read
+    pub fn query_checked<T: Record>(&self) -> Result<CheckedQuery<'_, T>, StorageError> {
+        Ok(self.query()?.checked())
+    }
+
+    /// Iterates over the keys of all records in a type's database
+    pub fn keys<T: Record>(&self) -> Result<KeyQuery<'_, T>, StorageError> {
+        let storage = self.storage_for::<T>()?;
+        storage.record_read::<T>("keys");
+        let db = storage.existing_db(T::db_name())?;
+        let types = storage.existing_db(TYPES_DB)?;
+        let txn = storage.env.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        Ok(KeyQuery::new(db, txn)?.max_read_age(self.options.max_read_age))
+    }
+
+    /// Returns the first record that matches a predicate
+    pub fn find<T: Record>(&self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
+        let mut query = self.query::<T>()?;
+        Ok(query.find(p))
+    }
+
+    /// How the handle's read transactions have been reused, see `StorageOptions::read_pool`.
+    /// Clones share their pool, while partitions have one of their own
+    pub fn read_pool_stats(&self) -> ReadPoolStats {
+        self.pool.stats()
+    }
+
+    /// The names of every database in the environment
+    #[cfg(feature = "server")]
+    pub(crate) fn database_names(&self) -> Result<Vec<String>, StorageError> {
+        crate::usage::database_names(&self.env)
+    }
+
+    /// The raw value stored under `key` in the database `db_name`, whatever type it holds
+    #[cfg(feature = "server")]
+    pub(crate) fn raw_get(
+        &self,
+        db_name: &str,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let db = match self.existing_db(db_name)? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let txn = self.pool.begin()?;
+        match txn.get(db, &key) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// At most `limit` raw entries of the database `db_name`, from `start` on in key order
+    #[cfg(feature = "server")]
+    pub(crate) fn raw_entries(
+        &self,
+        db_name: &str,
+        start: &[u8],
+        limit: usize,
+    ) -> Result<Vec<crate::queue::Entry>, StorageError> {
+        let db = match self.existing_db(db_name)? {
+            Some(db) => db,
+            None => return Ok(vec![]),
+        };
+        let txn = self.pool.begin()?;
+        crate::queue::entries_from(&*txn, db, start, &[], limit)
+    }
+}
+
+/// The handle that writes to a split storage, see `Storage::split`.  It can do everything a
+/// `Storage` can, except reopen or compact the environment while read handles are still around
+pub struct RwStorage {
+    storage: Storage,
+}
+
+impl RwStorage {
+    pub(crate) fn new(storage: Storage) -> RwStorage {
+        RwStorage { storage }
+    }
+
+    /// Returns the storage once the read handles are no longer needed
+    pub fn into_inner(self) -> Storage {
+        self.storage
+    }
+}
+
+impl Deref for RwStorage {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+impl DerefMut for RwStorage {
+    fn deref_mut(&mut self) -> &mut Storage {
+        &mut self.storage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Visit {
+        id: u32,
+        page: String,
+    }
+
+    #[test]
+    fn test_that_read_handles_are_shared_between_threads() {
+        let storage = Storage::temporary().expect("Could not open db storage");
+        let (reader, mut writer) = storage.split().expect("Could not split storage");
+        writer.truncate::<Visit>().expect("Could not truncate");
+
+        let handles: Vec<_> = (0..4)
+            .map(|id| {
+                let reader = reader.clone();
+                std::thread::spawn(move || reader.get::<Visit, _>(id))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(None, handle.join().unwrap().unwrap());
+        }
+
+        writer
+            .save(&Visit {
+                id: 1,
+                page: "/".to_string(),
+            })
+            .unwrap();
+        let reader_clone = reader.clone();
+        let visit = std::thread::spawn(move || reader_clone.get::<Visit, _>(1))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("/".to_string()), visit.map(|visit| visit.page));
+        assert_eq!(1, reader.query::<Visit>().unwrap().count());
+
+        // A key nothing is stored under reads the same through either handle
+        assert_eq!(None, reader.get::<Visit, _>(2).unwrap());
+        assert_eq!(None, writer.get::<Visit, _>(2).unwrap());
+
+        assert!(matches!(writer.reopen(), Err(StorageError::Shared)));
+        drop(reader);
+        writer
+            .reopen()
+            .expect("Could not reopen once the readers are gone");
+    }
+}
@@ -1,34 +1,85 @@
-use lmdb::{Cursor, Database, Environment, Transaction};
+use lmdb::{Database, DatabaseFlags, Environment, RwTransaction, Transaction as LmdbTransaction};
+use serde::{de::DeserializeOwned, Serialize};
 use std::collections::HashMap;
-use std::fs::create_dir_all;
-use std::path::PathBuf;
-use thiserror::Error;
+use std::fs::{create_dir_all, remove_dir_all, rename};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tempfile::TempDir;
 
-use crate::Record;
-use crate::RoQuery;
+use crate::append_log::{log_db_flags, log_db_name, Log};
+use crate::attachment::{attachment_key, ATTACHMENTS_DB};
+use crate::blob::{self, BlobReader, BlobWriter};
+use crate::cache::ReadCache;
+use crate::capped::CAPPED_DB;
+use crate::diff::{self, Diff};
+use crate::dump;
+use crate::fulltext::{self, FULLTEXT_INDEX};
+use crate::geo::{self, BoundingBox, GEO_INDEX};
+use crate::graph::{edges_db_flags, Graph, EDGES_DB};
+use crate::index::{self, index_db_flags, index_db_name};
+use crate::journal::{self, Change, ChangeOp, ChangeSink, JOURNAL_DB, REPLICA_DB};
+use crate::kv::{kv_db_flags, kv_db_name, KvStore};
+use crate::maintenance::{Maintenance, MaintenanceThread};
+use crate::merge::{Merge, Merger, Mergers};
+use crate::metadata::{self, Metadata};
+use crate::metrics::{self, MetricsSink};
+use crate::migrate::{self, UpgradeProgress};
+use crate::options::{self, Durability, OpenMode, StorageOptions, UpgradePolicy};
+use crate::profile::{self, ValueProfile};
+use crate::queue::{queue_db_flags, queue_db_name, Queue};
+use crate::quota::{self, Quota, TenantUsage, USAGE_DB};
+use crate::readahead::{self, AccessPattern};
+use crate::readers::{self, ReaderSlot};
+use crate::record;
+use crate::registry::RecordType;
+use crate::relation::{self, DeleteRule, DeleteRules, OnDelete};
+use crate::retention::{self, Retention, RetentionRules};
+use crate::retry;
+use crate::sorted_set::{sorted_set_db_flags, sorted_set_db_name, SortedSet};
+use crate::split::{RoStorage, RwStorage};
+use crate::sync::{self, SyncChange, VersionVector, SYNC_DB};
+use crate::time_series::{time_series_db_flags, time_series_db_name, DataPoint, TimeSeries};
+use crate::transaction::{counter_key, counters_db_name, decode_counter};
+use crate::type_tag::{self, SCHEMAS_DB, TYPES_DB};
+use crate::usage::{self, DatabaseUsage, DiskUsage};
+use crate::RawDb;
+use crate::ReadView;
+use crate::Repo;
+use crate::StorageError;
+use crate::{Batch, BelongsTo, CheckedQuery, KeyQuery, QueryBuilder, RoQuery, Transaction};
+use crate::{Record, RecordRef, Tracked};
+
+// How many records `delete_where` and `delete_range` read per write transaction
+const DELETE_CHUNK: usize = 1000;
 
 /// Storage provides a simple interface for interacting with databases
 pub struct Storage {
-    env: Environment,
-    #[allow(dead_code)]
+    // Only `None` while the environment is being swapped out
+    env: Option<Arc<Environment>>,
     path: PathBuf,
-    dbs: HashMap<&'static str, lmdb::Database>,
-}
-
-/// Errors that can arise from interacting with Storage
-#[derive(Error, Debug)]
-pub enum StorageError {
-    #[error("could not access database directory")]
-    FileError {
-        #[from]
-        source: std::io::Error,
-    },
-
-    #[error("could not process database command")]
-    DBError {
-        #[from]
-        source: lmdb::Error,
-    },
+    options: StorageOptions,
+    dbs: HashMap<String, lmdb::Database>,
+    delete_rules: DeleteRules,
+    mergers: Mergers,
+    retention: RetentionRules,
+    // The partition this storage holds, `None` for the one record types are routed from
+    partition: Option<&'static str>,
+    partitions: HashMap<&'static str, Storage>,
+    // The tenant this storage is scoped to, and the tenant storages opened from it
+    tenant: Option<String>,
+    tenants: HashMap<String, Storage>,
+    // The quota this tenant's storage is held to, and the quotas set for tenants of this one
+    quota: Option<Quota>,
+    quotas: HashMap<String, Quota>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    registry: Vec<RecordType>,
+    caches: HashMap<&'static str, ReadCache>,
+    maintenance: Option<MaintenanceThread>,
+    // The directory of a temporary storage, removed when the storage is dropped.  Declared last
+    // so the environment is closed first
+    temporary: Option<TempDir>,
 }
 
 impl Storage {
@@ -63,34 +114,382 @@ impl Storage {
     /// ```
     ///
     pub fn new<P: Into<PathBuf>>(path: P) -> Result<Storage, StorageError> {
-        let mut builder = lmdb::Environment::new();
-        builder.set_max_dbs(2048);
-        builder.set_map_size(256 * 1024 * 1024);
+        Storage::open_with(path, StorageOptions::default())
+    }
 
+    /// Creates or Opens a storage directory with custom settings
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use nostalgia::{Storage, StorageError, StorageOptions};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let options = StorageOptions::default().map_size(512 * 1024 * 1024);
+    ///     let storage = Storage::open_with("/tmp/db-large", options)?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn open_with<P: Into<PathBuf>>(
+        path: P,
+        options: StorageOptions,
+    ) -> Result<Storage, StorageError> {
         let p = &path.into();
-        create_dir_all(p)?;
-        let env = builder.open(p).unwrap();
+        options.check_mode(p)?;
+        if options.create {
+            create_dir_all(p)?;
+        }
+        let env = options.open(p)?;
+        let caches = options
+            .caches
+            .iter()
+            .map(|(db_name, capacity)| (*db_name, ReadCache::new(*capacity)))
+            .collect();
 
         Ok(Storage {
-            env,
+            env: Some(Arc::new(env)),
             path: p.to_path_buf(),
+            options,
             dbs: HashMap::new(),
+            delete_rules: HashMap::new(),
+            mergers: HashMap::new(),
+            retention: HashMap::new(),
+            partition: None,
+            partitions: HashMap::new(),
+            tenant: None,
+            tenants: HashMap::new(),
+            quota: None,
+            quotas: HashMap::new(),
+            metrics: None,
+            registry: vec![],
+            caches,
+            maintenance: None,
+            temporary: None,
         })
     }
 
-    fn db(&mut self, db_name: &'static str) -> Result<Database, StorageError> {
+    /// Opens a storage in a new, uniquely named directory under the system's temporary directory,
+    /// which is removed along with everything in it when the storage is dropped.
+    ///
+    /// Every temporary storage is separate from the others, so tests can each use their own
+    /// without clearing out what other tests left behind.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let storage = Storage::temporary()?;
+    ///     let path = storage.path().to_path_buf();
+    ///     assert!(path.exists());
+    ///
+    ///     drop(storage);
+    ///     assert!(!path.exists());
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn temporary() -> Result<Storage, StorageError> {
+        let dir = tempfile::Builder::new().prefix("nostalgia-").tempdir()?;
+        let mut storage = Storage::new(dir.path())?;
+        storage.temporary = Some(dir);
+        Ok(storage)
+    }
+
+    /// Opens an environment that already exists, such as one written by another tool, without
+    /// creating any directories or databases.
+    ///
+    /// Databases are opened with the flags they were created with.  Records of types whose
+    /// database doesn't exist can't be saved, and `raw` and `raw_unnamed` read the data of
+    /// databases that weren't written by nostalgia.  Fails with `StorageError::Missing` when there
+    /// is no environment at `path`, see `OpenMode::MustExist`.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     assert!(Storage::open_existing("/tmp/nostalgia-nowhere").is_err());
+    ///
+    ///     Storage::new("/tmp/db")?;
+    ///     let storage = Storage::open_existing("/tmp/db")?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn open_existing<P: Into<PathBuf>>(path: P) -> Result<Storage, StorageError> {
+        let options = StorageOptions::default()
+            .create(false)
+            .open_mode(OpenMode::MustExist);
+        Storage::open_with(path, options)
+    }
+
+    /// The directory the storage was opened in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn db(
+        &mut self,
+        db_name: &str,
+        flags: lmdb::DatabaseFlags,
+    ) -> Result<Database, StorageError> {
         match self.dbs.get(db_name) {
             Some(db) => Ok(*db),
             None => {
-                let db = self
-                    .env
-                    .create_db(Some(db_name), lmdb::DatabaseFlags::empty())?;
-                self.dbs.insert(db_name, db);
+                let env = self.env()?;
+                let db = if self.options.create {
+                    env.create_db(Some(db_name), flags)?
+                } else {
+                    env.open_db(Some(db_name))?
+                };
+                self.dbs.insert(db_name.to_string(), db);
                 Ok(db)
             }
         }
     }
 
+    // Looks up a database for reading without creating it, so reads of types that were never
+    // saved don't need the write lock
+    pub(crate) fn existing_db(&mut self, db_name: &str) -> Result<Option<Database>, StorageError> {
+        if let Some(db) = self.dbs.get(db_name) {
+            return Ok(Some(*db));
+        }
+
+        match self.env()?.open_db(Some(db_name)) {
+            Ok(db) => {
+                self.dbs.insert(db_name.to_string(), db);
+                Ok(Some(db))
+            }
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn env(&self) -> Result<&Environment, StorageError> {
+        self.env.as_deref().ok_or(StorageError::Closed)
+    }
+
+    // Starts a write transaction, retrying transient failures under the storage's retry policy
+    pub(crate) fn begin_rw_txn(&self) -> Result<RwTransaction<'_>, StorageError> {
+        Ok(retry::begin_rw_txn(
+            self.env()?,
+            self.options.retry,
+            self.is_resizable(),
+        )?)
+    }
+
+    // Nothing else can have a transaction open while the storage is borrowed for a write, unless
+    // read handles share its environment
+    fn is_resizable(&self) -> bool {
+        self.env
+            .as_ref()
+            .is_some_and(|env| Arc::strong_count(env) == 1)
+    }
+
+    // Types with a partition are stored in their own environment in a subdirectory named after it,
+    // as is every type with `Layout::PerDatabase` or a location of its own
+    fn is_routed<T: Record>(&self) -> bool {
+        self.options.partition_of::<T>().is_some() && self.partition.is_none()
+    }
+
+    // Returns the storage for a routed type's partition, opening it with the same options if it
+    // isn't open yet
+    fn partition<T: Record>(&mut self) -> Result<&mut Storage, StorageError> {
+        let name = self
+            .options
+            .partition_of::<T>()
+            .expect("Only routed types have a partition");
+        if !self.partitions.contains_key(name) {
+            self.env()?;
+            let path = self.options.partition_path(&self.path, name);
+            let options = self.options.clone().open_mode(OpenMode::CreateIfMissing);
+            let mut storage = Storage::open_with(path, options)?;
+            storage.partition = Some(name);
+            storage.delete_rules = self.delete_rules.clone();
+            storage.metrics = self.metrics.clone();
+            self.partitions.insert(name, storage);
+        }
+
+        Ok(self
+            .partitions
+            .get_mut(name)
+            .expect("Partition was just opened"))
+    }
+
+    /// Returns the storage of one tenant, opening it with the same options if it isn't open yet.
+    ///
+    /// Each tenant's records live in an environment of their own, in the `tenants` subdirectory
+    /// of the storage, so tenants can't see each other's records, indexes or counters.  Tenant
+    /// ids may only contain ASCII letters, digits, `-` and `_`.  On-delete policies, registered
+    /// types and the metrics sink are shared with the tenants.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Invoice {
+    ///   id: u32,
+    ///   total: u64
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.scoped("acme")?.save(&Invoice { id: 1, total: 100 })?;
+    ///
+    ///     assert!(storage.scoped("acme")?.get::<Invoice, _>(1)?.is_some());
+    ///     assert!(storage.scoped("globex")?.get::<Invoice, _>(1)?.is_none());
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn scoped(&mut self, tenant: &str) -> Result<&mut Storage, StorageError> {
+        let valid = !tenant.is_empty()
+            && tenant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(StorageError::InvalidTenant {
+                tenant: tenant.to_string(),
+            });
+        }
+
+        if !self.tenants.contains_key(tenant) {
+            self.env()?;
+            let path = self.path.join("tenants").join(tenant);
+            let options = self.options.clone().open_mode(OpenMode::CreateIfMissing);
+            let mut storage = Storage::open_with(path, options)?;
+            storage.tenant = Some(tenant.to_string());
+            storage.quota = self.quotas.get(tenant).copied();
+            storage.delete_rules = self.delete_rules.clone();
+            storage.retention = self.retention.clone();
+            storage.metrics = self.metrics.clone();
+            storage.registry = self.registry.clone();
+            self.tenants.insert(tenant.to_string(), storage);
+        }
+
+        Ok(self
+            .tenants
+            .get_mut(tenant)
+            .expect("Tenant was just opened"))
+    }
+
+    /// The tenant this storage was scoped to with `scoped`, if any
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Limits how much a tenant can store.  A transaction that would take the tenant's usage past
+    /// the quota fails with `StorageError::QuotaExceeded` and nothing it wrote is kept.
+    ///
+    /// Usage counts the keys and values of records saved through the tenant's storage since it was
+    /// first scoped, see `usage`.  Counters, key-value stores and blobs aren't counted.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Quota, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Invoice {
+    ///   id: u32,
+    ///   total: u64
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.set_quota("acme", Quota::default().max_entries(1));
+    ///
+    ///     let acme = storage.scoped("acme")?;
+    ///     acme.save(&Invoice { id: 1, total: 100 })?;
+    ///     match acme.save(&Invoice { id: 2, total: 250 }) {
+    ///         Err(StorageError::QuotaExceeded { .. }) => (),
+    ///         _ => panic!("Expected the second invoice to be over the quota"),
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn set_quota(&mut self, tenant: &str, quota: Quota) {
+        self.quotas.insert(tenant.to_string(), quota);
+        if let Some(storage) = self.tenants.get_mut(tenant) {
+            storage.quota = Some(quota);
+        }
+    }
+
+    /// How much a tenant's storage holds.  Always zero for storages that aren't a tenant's
+    pub fn usage(&mut self) -> Result<TenantUsage, StorageError> {
+        let db = match self.existing_db(USAGE_DB)? {
+            Some(db) => db,
+            None => return Ok(TenantUsage::default()),
+        };
+        let txn = self.env()?.begin_ro_txn()?;
+        quota::read(&txn, db)
+    }
+
+    // The storage a type's records live in
+    pub(crate) fn storage_for<T: Record>(&mut self) -> Result<&mut Storage, StorageError> {
+        if self.is_routed::<T>() {
+            self.partition::<T>()
+        } else {
+            Ok(self)
+        }
+    }
+
+    // The names and flags of the databases holding a record type's secondary indexes and counters
+    fn companion_dbs<T: Record>() -> Vec<(String, DatabaseFlags)> {
+        let mut dbs: Vec<(String, DatabaseFlags)> = T::indexes()
+            .iter()
+            .map(|index| (index_db_name(T::db_name(), index), index_db_flags()))
+            .collect();
+        dbs.push((counters_db_name(T::db_name()), DatabaseFlags::empty()));
+        dbs
+    }
+
+    // The journal ops that empty `T`'s database along with its companion databases
+    fn clear_ops<T: Record>() -> Vec<ChangeOp> {
+        std::iter::once(T::db_name().to_string())
+            .chain(Storage::companion_dbs::<T>().into_iter().map(|(db, _)| db))
+            .map(|db| ChangeOp::Clear { db })
+            .collect()
+    }
+
+    // The change journal's database, `None` unless the storage keeps a journal
+    fn journal_db(&mut self) -> Result<Option<Database>, StorageError> {
+        if self.options.journal {
+            self.db(JOURNAL_DB, DatabaseFlags::empty()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn open_companion_dbs<T: Record>(&mut self) -> Result<Vec<Database>, StorageError> {
+        let create = self.options.create;
+        Storage::companion_dbs::<T>()
+            .iter()
+            .map(|(name, flags)| self.db(name, *flags))
+            // Without creating, companion databases that were never written are left out
+            .filter(|db| {
+                create
+                    || !matches!(
+                        db,
+                        Err(StorageError::DBError {
+                            source: lmdb::Error::NotFound
+                        })
+                    )
+            })
+            .collect()
+    }
+
     /// Serializes and Saves a record in one of the databases contained in storage.
     ///
     /// Input should implement the Record trait.  The database the record is saved to and the key
@@ -123,12 +522,55 @@ impl Storage {
     /// ```
     ///
     pub fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
-        let db = self.db(T::db_name())?;
-        let mut tx = self.env.begin_rw_txn()?;
-        let bytes = T::to_binary(record).expect("Could not serialize");
-        tx.put(db, &record.key().into(), &bytes, lmdb::WriteFlags::empty())?;
-        tx.commit()?;
-        Ok(())
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.save(record);
+        }
+
+        self.transaction(|tx| tx.save(record))
+    }
+
+    /// Saves `record` merged with the record stored under its key using `Merge::merge`, or as it
+    /// is when nothing is stored there yet.  Returns the record as saved.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Merge, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   names: Vec<std::string::String>
+    /// }
+    ///
+    /// impl Merge for Place {
+    ///     fn merge(ours: Place, theirs: Place) -> Place {
+    ///         let mut names = ours.names;
+    ///         names.extend(theirs.names);
+    ///         names.sort();
+    ///         names.dedup();
+    ///         Place { id: ours.id, names }
+    ///     }
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save_merge(Place { id: 1, names: vec!["Wien".to_string()] })?;
+    ///
+    ///     let place = storage.save_merge(Place { id: 1, names: vec!["Vienna".to_string()] })?;
+    ///     assert_eq!(vec!["Vienna", "Wien"], place.names);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn save_merge<T: Merge>(&mut self, record: T) -> Result<T, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.save_merge(record);
+        }
+
+        self.transaction(|tx| tx.save_merge(record))
     }
 
     /// Saves a group of records to the internal type's database
@@ -168,24 +610,24 @@ impl Storage {
     /// ```
     ///
     pub fn save_batch<T: Record>(&mut self, records: Vec<T>) -> Result<(), StorageError> {
-        let db = self.db(T::db_name())?;
-
-        let mut tx = self.env.begin_rw_txn()?;
-
-        for record in records {
-            let bytes = T::to_binary(&record).expect("Could not serialize");
-            tx.put(db, &record.key().into(), &bytes, lmdb::WriteFlags::empty())?;
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.save_batch(records);
         }
 
-        tx.commit()?;
-        Ok(())
+        self.transaction(|tx| {
+            for record in &records {
+                tx.save(record)?;
+            }
+            Ok(())
+        })
     }
 
-    /// Retrieves a record from the database
+    /// Retrieves a record from the database.  Returns `None` when no record is stored under the
+    /// key
     ///
     /// # Arguments
     /// * `key` - A Vec of usigned 8bit integers representing the key.  Will make this more sugar-y
-    /// eventually
+    ///   eventually
     ///
     /// # Examples
     /// ```
@@ -203,6 +645,7 @@ impl Storage {
     ///
     /// fn main() -> Result<(), StorageError> {
     ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Place { id: 2, name: "Paris".to_string() })?;
     ///
     ///     let paris: Place = storage.get(2)
     ///     .expect("Error fetching")
@@ -214,28 +657,105 @@ impl Storage {
     /// }
     /// ```
     pub fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
-        let db = self.db(T::db_name())?;
-        let txn = self.env.begin_ro_txn()?;
-        let cursor = txn.open_ro_cursor(db)?;
-        let result = cursor.get(Some(&key.into().into()), None, 15)?;
+        self.get_raw(&key.into().into())
+    }
+
+    // Retrieves a record by its raw key
+    pub(crate) fn get_raw<T: Record>(&mut self, key: &[u8]) -> Result<Option<T>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.get_raw(key);
+        }
+        self.record_read::<T>("get");
+
+        let db = match self.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let bytes = match txn.get(db, &key) {
+            Ok(bytes) => bytes,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let record = metadata::decode(key, bytes)?;
+        let outdated = metadata::unwrap::<T>(bytes).is_some_and(T::is_outdated);
+        drop(txn);
+
+        if outdated && self.options.upgrade_policy::<T>() != UpgradePolicy::OnRead {
+            self.transaction(|tx| tx.upgrade::<T>(key))?;
+        }
+        Ok(Some(record))
+    }
+
+    /// Retrieves a record like `get`, along with the bytes it is stored as, so
+    /// `save_if_changed` can tell whether it changed since.  See `Tracked`
+    pub fn get_tracked<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+    ) -> Result<Option<Tracked<T>>, StorageError> {
+        self.get_tracked_raw(&key.into().into())
+    }
+
+    fn get_tracked_raw<T: Record>(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<Tracked<T>>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.get_tracked_raw(key);
+        }
+        self.record_read::<T>("get");
+
+        let db = match self.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let stored = match txn.get(db, &key) {
+            Ok(bytes) => metadata::stored::<T>(key, bytes)?,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
 
-        match T::from_binary(result.1) {
-            Ok(record) => Ok(Some(record)),
-            Err(_) => Ok(None),
+        Ok(Some(Tracked::loaded(
+            record::load(stored)?,
+            stored.to_vec(),
+        )))
+    }
+
+    /// Saves a tracked record unless it would be stored as it is already, and returns whether it
+    /// was written.  The bytes saved are what the record is compared with the next time
+    pub fn save_if_changed<T: Record>(
+        &mut self,
+        tracked: &mut Tracked<T>,
+    ) -> Result<bool, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.save_if_changed(tracked);
         }
+        if !tracked.is_changed()? {
+            return Ok(false);
+        }
+
+        self.transaction(|tx| tx.save_if_changed(tracked))
     }
 
-    /// Deletes a record from the database
+    /// Retrieves a record like `get`, but shared behind an `Arc` so it can be kept in the type's
+    /// read cache.  Types without a cache, see `StorageOptions::cache`, are read from the database
+    /// every time.
     ///
-    /// # Arguments
-    /// * `record` - A type that implements the Record trait.
+    /// Saves and deletes made through this storage evict the records they change, while writes
+    /// made by other processes or through `raw` aren't seen until the record is evicted.
     ///
     /// # Examples
     /// ```
     /// #[macro_use]
     /// extern crate nostalgia_derive;
-    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use nostalgia::{Storage, StorageOptions, Record, Key, StorageError};
     /// use serde::{Serialize, Deserialize};
+    /// use std::sync::Arc;
     ///
     /// #[derive(Storable, Serialize, Deserialize)]
     /// #[key = "id"]
@@ -245,30 +765,61 @@ impl Storage {
     /// }
     ///
     /// fn main() -> Result<(), StorageError> {
-    ///     let mut storage = Storage::new("/tmp/db")?;
-    ///     let place = Place { id: 1, name: "Vienna".to_string() };
-    ///     storage.save(&place)?;
+    ///     let options = StorageOptions::default().cache::<Place>(100);
+    ///     let mut storage = Storage::open_with("/tmp/db", options)?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
     ///
-    ///     storage.delete(&place)?;
+    ///     let first = storage.get_shared::<Place, _>(1)?.expect("Empty record");
+    ///     let second = storage.get_shared::<Place, _>(1)?.expect("Empty record");
+    ///     assert!(Arc::ptr_eq(&first, &second));
     ///
     ///     Ok(())
     /// }
     /// ```
-    pub fn delete<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
-        let db = self.db(T::db_name())?;
-        let mut tx = self.env.begin_rw_txn()?;
-        tx.del(db, &record.key().into(), None)?;
-        tx.commit()?;
-        Ok(())
+    pub fn get_shared<T, K>(&mut self, key: K) -> Result<Option<Arc<T>>, StorageError>
+    where
+        T: Record + Send + Sync + 'static,
+        K: Into<T::Key>,
+    {
+        let storage = self.storage_for::<T>()?;
+        let key: Vec<u8> = key.into().into();
+        if let Some(record) = storage
+            .caches
+            .get_mut(T::db_name())
+            .and_then(|cache| cache.get::<T>(&key))
+        {
+            storage.record_read::<T>("get");
+            return Ok(Some(record));
+        }
+
+        let record = match storage.get_raw::<T>(&key) {
+            Ok(Some(record)) => Arc::new(record),
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if let Some(cache) = storage.caches.get_mut(T::db_name()) {
+            cache.insert(&key, record.clone());
+        }
+        Ok(Some(record))
     }
 
-    /// Returns an RoQuery object that allows you to Iterate over all records in a database.
+    // Drops every cached record of a type after its database was emptied
+    fn clear_cache<T: Record>(&mut self) {
+        if let Some(cache) = self.caches.get_mut(T::db_name()) {
+            cache.clear();
+        }
+    }
+
+    /// Reads a record in its borrowed form and hands it to `f` without copying its strings.
+    ///
+    /// The borrowed record points into the memory map, so it only lives as long as the read
+    /// transaction that `f` runs in.  Returns `None` when there is no record under `key`.
     ///
     /// # Examples
     /// ```
     /// #[macro_use]
     /// extern crate nostalgia_derive;
-    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use nostalgia::{Storage, Record, RecordRef, Key, StorageError};
     /// use serde::{Serialize, Deserialize};
     ///
     /// #[derive(Storable, Serialize, Deserialize)]
@@ -278,30 +829,59 @@ impl Storage {
     ///   name: std::string::String
     /// }
     ///
+    /// #[derive(Deserialize)]
+    /// struct PlaceRef<'a> {
+    ///   id: u32,
+    ///   name: &'a str
+    /// }
+    ///
+    /// impl RecordRef for Place {
+    ///     type Ref<'a> = PlaceRef<'a>;
+    /// }
+    ///
     /// fn main() -> Result<(), StorageError> {
     ///     let mut storage = Storage::new("/tmp/db")?;
-    ///     let query = storage.query::<Place>()?;
-    ///     
-    ///     for place in query {
-    ///         println!("{}", place.name);
-    ///     }
+    ///     storage.save(&Place { id: 16, name: "Lisbon".to_string() })?;
+    ///
+    ///     let length = storage.view::<Place, _, _, _>(16, |place| place.name.len())?;
+    ///     assert_eq!(Some(6), length);
     ///
     ///     Ok(())
     /// }
     /// ```
-    pub fn query<T: Record>(&mut self) -> Result<RoQuery<T>, StorageError> {
-        let db = self.db(T::db_name())?;
-        let txn = self.env.begin_ro_txn()?;
+    pub fn view<T, K, F, R>(&mut self, key: K, f: F) -> Result<Option<R>, StorageError>
+    where
+        T: RecordRef,
+        K: Into<T::Key>,
+        F: for<'a> FnOnce(T::Ref<'a>) -> R,
+    {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.view::<T, K, F, R>(key, f);
+        }
 
-        Ok(RoQuery {
-            phantom: std::marker::PhantomData::<T>,
-            db,
-            txn,
-            iter: None,
-        })
+        let db = match self.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let key: Vec<u8> = key.into().into();
+        let bytes = match txn.get(db, &key) {
+            Ok(bytes) => bytes,
+            Err(lmdb::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let record = bincode::deserialize::<T::Ref<'_>>(metadata::stored::<T>(&key, bytes)?)
+            .map_err(|_| metadata::undecodable::<T>(&key))?;
+        Ok(Some(f(record)))
     }
 
-    /// Returns the first record that matches a predicate
+    /// Returns the created and updated times of a record.
+    ///
+    /// Only types that opt in with `#[storable(metadata)]` keep these, for every other type and
+    /// for missing records this returns `None`.
     ///
     /// # Examples
     /// ```
@@ -312,54 +892,2973 @@ impl Storage {
     ///
     /// #[derive(Storable, Serialize, Deserialize)]
     /// #[key = "id"]
-    /// struct Place {
+    /// #[storable(metadata)]
+    /// struct Permit {
     ///   id: u32,
-    ///   name: std::string::String
+    ///   street: std::string::String
     /// }
     ///
     /// fn main() -> Result<(), StorageError> {
     ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Permit { id: 1, street: "Canal St".to_string() })?;
+    ///
+    ///     let metadata = storage.metadata::<Permit, _>(1)?.expect("Missing metadata");
+    ///     assert!(metadata.updated_at >= metadata.created_at);
     ///
-    ///     let place = storage.find::<Place>(&|p| p.name == "Istanbul")?;
-    ///     if let Some(istanbul) = place {
-    ///         assert_eq!(istanbul.name, "Istanbul");
-    ///     } else {
-    ///         assert_ne!(0, 0, "Could not find record");
-    ///     }
-    ///    
     ///     Ok(())
     /// }
-    pub fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
-        let mut query = self.query::<T>()?;
-        Ok(query.find(p))
-    }
-
-    /// Removes all records in the corresponding type's database
-    pub fn truncate<T: Record>(&mut self) -> Result<(), StorageError> {
-        let db = self.db(T::db_name())?;
-        let mut txn = self.env.begin_rw_txn()?;
-        txn.clear_db(db)?;
-        txn.commit()?;
-        Ok(())
-    }
+    /// ```
+    pub fn metadata<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+    ) -> Result<Option<Metadata>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.metadata::<T, K>(key);
+        }
 
-    /// Completely removes the database for a specific type
-    pub fn drop<T: Record>(&mut self) -> Result<(), StorageError> {
-        let db = self.db(T::db_name())?;
-        let mut txn = self.env.begin_rw_txn()?;
-        unsafe {
-            txn.drop_db(db)?;
+        if !T::has_metadata() {
+            return Ok(None);
         }
-        txn.commit()?;
 
-        self.dbs.remove(T::db_name());
-        Ok(())
+        let db = match self.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        match txn.get(db, &key.into().into()) {
+            Ok(bytes) => Ok(Metadata::read(bytes).map(|(metadata, _)| metadata)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Deletes a record from the database and returns whether it was stored.  Deleting a record
+    /// that isn't stored returns false, or fails with `StorageError::NotFound` when the storage
+    /// was opened with `StorageOptions::strict_deletes`
+    ///
+    /// # Arguments
+    /// * `record` - A type that implements the Record trait.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     let place = Place { id: 1, name: "Vienna".to_string() };
+    ///     storage.save(&place)?;
+    ///
+    ///     assert!(storage.delete(&place)?);
+    ///     assert!(!storage.delete(&place)?);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.delete(record);
+        }
+
+        self.transaction(|tx| tx.delete(record))
+    }
+
+    /// Deletes a record that has to be stored, failing with `StorageError::NotFound` when it
+    /// isn't
+    pub fn delete_existing<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.delete_existing(record);
+        }
+
+        self.transaction(|tx| tx.delete_existing(record))
+    }
+
+    /// Deletes every record of `T` that `predicate` picks and returns how many were deleted.
+    /// Records are read and deleted a chunk at a time, each chunk in a write transaction of its
+    /// own, so other writers get their turn in between.  Indexes, attachments and on-delete
+    /// policies are handled like with `delete`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     for (id, name) in [(1, "Vienna"), (2, "Venice"), (3, "Paris")] {
+    ///         storage.save(&Place { id, name: name.to_string() })?;
+    ///     }
+    ///
+    ///     assert_eq!(2, storage.delete_where::<Place, _>(|place| place.name.starts_with('V'))?);
+    ///     assert_eq!(1, storage.delete_range::<Place, u32, _>(3..)?);
+    ///     assert_eq!(0, storage.count::<Place>()?);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn delete_where<T, F>(&mut self, mut predicate: F) -> Result<usize, StorageError>
+    where
+        T: Record,
+        F: FnMut(&T) -> bool,
+    {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.delete_where::<T, F>(predicate);
+        }
+
+        let mut matches =
+            |key: &[u8], value: &[u8]| Ok(predicate(&metadata::decode::<T>(key, value)?));
+        self.delete_chunked::<T, _>(vec![], Bound::Unbounded, &mut matches)
+    }
+
+    /// Deletes every record of `T` whose key lies in `range`, without reading the records, and
+    /// returns how many were deleted.  Deletes a chunk at a time like `delete_where`
+    pub fn delete_range<T, K, R>(&mut self, range: R) -> Result<usize, StorageError>
+    where
+        T: Record,
+        K: Into<T::Key> + Clone,
+        R: RangeBounds<K>,
+    {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.delete_range::<T, K, R>(range);
+        }
+
+        let encode = |key: &K| -> Vec<u8> { key.clone().into().into() };
+        let start = match range.start_bound() {
+            Bound::Included(key) => encode(key),
+            Bound::Excluded(key) => {
+                let mut start = encode(key);
+                start.push(0);
+                start
+            }
+            Bound::Unbounded => vec![],
+        };
+        let end = range.end_bound().map(encode);
+        self.delete_chunked::<T, _>(start, end, &mut |_, _| Ok(true))
+    }
+
+    // Deletes the records of `T` from `start` on, up to `end`, that `matches` picks by their
+    // stored value, a chunk per write transaction
+    pub(crate) fn delete_chunked<T, F>(
+        &mut self,
+        mut start: Vec<u8>,
+        end: Bound<Vec<u8>>,
+        matches: &mut F,
+    ) -> Result<usize, StorageError>
+    where
+        T: Record,
+        F: FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    {
+        if self.existing_db(T::db_name())?.is_none() {
+            return Ok(0);
+        }
+
+        let mut deleted = 0;
+        loop {
+            let end = end.as_ref().map(Vec::as_slice);
+            let (count, next) =
+                self.transaction(|tx| tx.delete_chunk::<T, F>(&start, end, DELETE_CHUNK, matches))?;
+            deleted += count;
+            match next {
+                Some(next) => start = next,
+                None => return Ok(deleted),
+            }
+        }
+    }
+
+    /// Atomically reads, changes and writes back a single record.
+    ///
+    /// `f` gets the record stored under `key`, if there is one.  Whatever it returns is saved, and
+    /// returning `None` deletes the record.  The read and the write happen in one write
+    /// transaction, so no other writer can sneak in between them.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Place { id: 14, name: "Constantinople".to_string() })?;
+    ///
+    ///     let place = storage.modify::<Place, _, _>(14, |place| {
+    ///         place.map(|place| Place { name: "Istanbul".to_string(), ..place })
+    ///     })?;
+    ///     assert_eq!("Istanbul", place.unwrap().name);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn modify<T, K, F>(&mut self, key: K, f: F) -> Result<Option<T>, StorageError>
+    where
+        T: Record,
+        K: Into<T::Key>,
+        F: FnOnce(Option<T>) -> Option<T>,
+    {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.modify(key, f);
+        }
+
+        self.transaction(|tx| tx.modify(key, f))
+    }
+
+    /// Atomically replaces the record stored under `key` with `new`, or deletes it when `new` is
+    /// `None`, if the stored record is still `expected`.
+    ///
+    /// Records are compared by their serialized bytes, and `None` expects nothing to be stored
+    /// under the key.  When the stored record is a different one nothing is written, and it is
+    /// returned in `Err` so the caller can decide what to do with it.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    /// #[key = "id"]
+    /// struct Lease {
+    ///   id: u32,
+    ///   holder: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let lease = |holder: &str| Lease { id: 1, holder: holder.to_string() };
+    ///
+    ///     let won = storage.compare_and_swap(1, None, Some(&lease("node-1")))?;
+    ///     assert!(won.is_ok());
+    ///
+    ///     let lost = storage.compare_and_swap(1, None, Some(&lease("node-2")))?;
+    ///     assert_eq!(Err(Some(lease("node-1"))), lost);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn compare_and_swap<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+        expected: Option<&T>,
+        new: Option<&T>,
+    ) -> Result<Result<(), Option<T>>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.compare_and_swap(key, expected, new);
+        }
+
+        self.transaction(|tx| tx.compare_and_swap(key, expected, new))
+    }
+
+    /// Atomically adds `delta` to a named counter that belongs to a record and returns the new
+    /// value.
+    ///
+    /// Counters live in their own database next to the record type's, so bumping one never
+    /// rewrites the record.  A counter that was never incremented starts at zero, and counters are
+    /// only cleared when the type is truncated or dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     let before = storage.counter::<Place, _>(2, "visits")?;
+    ///     let after = storage.increment::<Place, _>(2, "visits", 3)?;
+    ///     assert_eq!(before + 3, after);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn increment<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+        counter: &str,
+        delta: i64,
+    ) -> Result<i64, StorageError> {
+        if self.is_routed::<T>() {
+            return self
+                .partition::<T>()?
+                .increment::<T, K>(key, counter, delta);
+        }
+
+        self.transaction(|tx| tx.increment::<T, K>(key, counter, delta))
+    }
+
+    /// Returns the current value of one of a record's counters
+    pub fn counter<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+        counter: &str,
+    ) -> Result<i64, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.counter::<T, K>(key, counter);
+        }
+
+        let db = match self.existing_db(&counters_db_name(T::db_name()))? {
+            Some(db) => db,
+            None => return Ok(0),
+        };
+        let txn = self.env()?.begin_ro_txn()?;
+
+        match txn.get(db, &counter_key(&key.into().into(), counter)) {
+            Ok(bytes) => Ok(decode_counter(bytes)),
+            Err(lmdb::Error::NotFound) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores `bytes` as the attachment called `name` of `record`, replacing any attachment of
+    /// that name.
+    ///
+    /// Attachments are kept apart from their record, so reading or saving the record doesn't
+    /// touch them, and are deleted along with it.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let vienna = Place { id: 1, name: "Vienna".to_string() };
+    ///     storage.save(&vienna)?;
+    ///
+    ///     storage.attach(&vienna, "map", b"<svg/>")?;
+    ///     assert_eq!(Some(b"<svg/>".to_vec()), storage.attachment(&vienna, "map")?);
+    ///
+    ///     storage.delete(&vienna)?;
+    ///     assert_eq!(None, storage.attachment(&vienna, "map")?);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn attach<T: Record>(
+        &mut self,
+        record: &T,
+        name: &str,
+        bytes: &[u8],
+    ) -> Result<(), StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.attach(record, name, bytes);
+        }
+
+        self.transaction(|tx| tx.attach(record, name, bytes))
+    }
+
+    /// Returns the attachment called `name` of `record`, if it has one
+    pub fn attachment<T: Record>(
+        &mut self,
+        record: &T,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.attachment(record, name);
+        }
+
+        let db = match self.existing_db(ATTACHMENTS_DB)? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let txn = self.env()?.begin_ro_txn()?;
+
+        match txn.get(db, &attachment_key::<T>(&record.key().into(), name)) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the attachment called `name` of `record`.  Returns false when it had none
+    pub fn detach<T: Record>(&mut self, record: &T, name: &str) -> Result<bool, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.detach(record, name);
+        }
+
+        self.transaction(|tx| tx.detach(record, name))
+    }
+
+    /// Runs `f` inside a single read-write transaction.
+    ///
+    /// Everything done through the `Transaction` handed to `f` is committed together when `f`
+    /// returns `Ok`, and thrown away when it returns an error.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     storage.transaction(|tx| {
+    ///         tx.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///         tx.save(&Place { id: 2, name: "Paris".to_string() })?;
+    ///         Ok(())
+    ///     })?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn transaction<R, F>(&mut self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce(&mut Transaction) -> Result<R, StorageError>,
+    {
+        let env = self.env.as_deref().ok_or(StorageError::Closed)?;
+        let started = Instant::now();
+        let txn = retry::begin_rw_txn(env, self.options.retry, self.is_resizable())?;
+        let waited = started.elapsed();
+        let mut tx = Transaction::new(
+            txn,
+            &mut self.dbs,
+            &self.delete_rules,
+            self.partition,
+            &self.options,
+            self.metrics.is_some() || !self.caches.is_empty() || self.options.slow_commit.is_some(),
+        );
+        if self.tenant.is_some() {
+            tx.track_usage();
+        }
+        if self.options.versions {
+            tx.track_versions();
+        }
+
+        let (tenant, quota) = (&self.tenant, self.quota);
+        let outcome = f(&mut tx).and_then(|result| match (tx.apply_usage()?, tenant, quota) {
+            (Some((usage, delta)), Some(tenant), Some(quota)) if !quota.allows(usage, delta) => {
+                Err(StorageError::QuotaExceeded {
+                    tenant: tenant.clone(),
+                    usage,
+                    quota,
+                })
+            }
+            _ => Ok(result),
+        });
+        let outcome = outcome.and_then(|result| tx.write_journal().map(|()| result));
+
+        match outcome {
+            Ok(result) => {
+                let writes = tx.take_writes();
+                let committing = Instant::now();
+                tx.commit()?;
+                let commit = committing.elapsed();
+                if self.options.slow_commit.is_some_and(|slow| commit >= slow) {
+                    metrics::log_slow_commit(commit, waited, &writes);
+                }
+                for write in &writes {
+                    if let (Some(cache), Some(key)) =
+                        (self.caches.get_mut(write.db_name), &write.key)
+                    {
+                        cache.remove(key);
+                    }
+                }
+                if let Some(sink) = &self.metrics {
+                    sink.transaction_duration(started.elapsed(), true);
+                    sink.write_lock_wait(waited);
+                    sink.commit_duration(commit);
+                    metrics::report(sink.as_ref(), &writes);
+                    let (used_bytes, map_size) = usage::map_usage(env)?;
+                    sink.map_utilization(used_bytes, map_size);
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                tx.abort();
+                if let Some(sink) = &self.metrics {
+                    sink.transaction_duration(started.elapsed(), false);
+                    sink.write_lock_wait(waited);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns a handle for reading and writing raw bytes in the named database, creating it if it
+    /// doesn't exist.  Meant for data shared with code that doesn't use nostalgia.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     let settings = storage.raw("settings")?;
+    ///
+    ///     settings.put_bytes(b"theme", b"dark")?;
+    ///     assert_eq!(Some(b"dark".to_vec()), settings.get_bytes(b"theme")?);
+    ///
+    ///     for (key, value) in settings.scan_bytes()? {
+    ///         println!("{:?} = {:?}", key, value);
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn raw(&mut self, db_name: &str) -> Result<RawDb<'_>, StorageError> {
+        let db = self.db(db_name, DatabaseFlags::empty())?;
+        Ok(RawDb::new(self.env()?, db))
+    }
+
+    /// Like `raw`, but returns `None` instead of creating the database when it doesn't exist, for
+    /// tools that only read what is there
+    pub fn raw_existing(&mut self, db_name: &str) -> Result<Option<RawDb<'_>>, StorageError> {
+        match self.existing_db(db_name)? {
+            Some(db) => Ok(Some(RawDb::new(self.env()?, db))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the key-value store called `name`, creating its database if it doesn't exist.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     let settings = storage.kv("settings")?;
+    ///
+    ///     settings.set("theme", &"dark".to_string())?;
+    ///     assert_eq!(Some("dark".to_string()), settings.get::<String>("theme")?);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn kv(&mut self, name: &str) -> Result<KvStore<'_>, StorageError> {
+        let db = self.db(&kv_db_name(name), kv_db_flags())?;
+        Ok(KvStore::new(self.env()?, db))
+    }
+
+    /// Returns the job queue called `name`, creating its database if it doesn't exist.  See
+    /// `Queue` for how jobs are handed out.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Email {
+    ///     to: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let emails = storage.queue::<Email>("emails")?;
+    ///     emails.push(&Email { to: "ada@example.com".to_string() })?;
+    ///
+    ///     while let Some(delivery) = emails.pop()? {
+    ///         println!("Sending to {}", delivery.job.to);
+    ///         emails.ack(delivery.id)?;
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn queue<T>(&mut self, name: &str) -> Result<Queue<'_, T>, StorageError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let db = self.db(&queue_db_name(name), queue_db_flags())?;
+        Ok(Queue::new(self.env()?, db))
+    }
+
+    /// Returns the append-only log called `name`, creating its database if it doesn't exist.  See
+    /// `Log` for how offsets are handed out.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// enum Event {
+    ///     Placed { order: u32 },
+    ///     Paid { order: u32 },
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let events = storage.log::<Event>("orders")?;
+    ///     events.append(&Event::Placed { order: 1 })?;
+    ///     let paid = events.append(&Event::Paid { order: 1 })?;
+    ///
+    ///     assert_eq!(2, events.read_from(0)?.len());
+    ///     events.truncate_before(paid)?;
+    ///     assert_eq!(1, events.read_from(0)?.len());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn log<T>(&mut self, name: &str) -> Result<Log<'_, T>, StorageError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let db = self.db(&log_db_name(name), log_db_flags())?;
+        Ok(Log::new(self.env()?, db))
+    }
+
+    /// Returns the time series called `name`, creating its database if it doesn't exist.  One
+    /// time series holds the points of any number of series, see `TimeSeries`.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Aggregate, DataPoint, Storage, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    /// use std::time::Duration;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Load {
+    ///     host: u64,
+    ///     at: u64,
+    ///     percent: f64,
+    /// }
+    ///
+    /// impl DataPoint for Load {
+    ///     fn series(&self) -> u64 { self.host }
+    ///     fn timestamp(&self) -> u64 { self.at }
+    ///     fn value(&self) -> f64 { self.percent }
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let load = storage.time_series::<Load>("load")?;
+    ///     for at in 0..120 {
+    ///         load.insert(&Load { host: 7, at: at * 1_000, percent: at as f64 })?;
+    ///     }
+    ///
+    ///     let per_minute = load.downsample(7, 0..120_000, Duration::from_secs(60), Aggregate::Max)?;
+    ///     assert_eq!(vec![59.0, 119.0], per_minute.iter().map(|b| b.value).collect::<Vec<_>>());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn time_series<T: DataPoint>(
+        &mut self,
+        name: &str,
+    ) -> Result<TimeSeries<'_, T>, StorageError> {
+        let db = self.db(&time_series_db_name(name), time_series_db_flags())?;
+        Ok(TimeSeries::new(self.env()?, db))
+    }
+
+    /// Returns the labeled edges between records, creating their database if it doesn't exist.
+    /// See `Graph` for how edges are kept.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Paris".to_string() })?;
+    ///     storage.save(&Place { id: 2, name: "Lyon".to_string() })?;
+    ///
+    ///     let graph = storage.graph()?;
+    ///     graph.link::<Place, Place, _, _>(1, 2, "train")?;
+    ///
+    ///     let reachable = graph.neighbors_labeled::<Place, Place, _>(1, "train")?;
+    ///     assert_eq!("Lyon", reachable[0].name);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn graph(&mut self) -> Result<Graph<'_>, StorageError> {
+        let db = self.db(EDGES_DB, edges_db_flags())?;
+        Ok(Graph::new(self.env()?, db))
+    }
+
+    /// Returns the sorted set called `name`, creating its database if it doesn't exist.  See
+    /// `SortedSet` for how members are ordered.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let scores = storage.sorted_set("high-scores")?;
+    ///     scores.zadd("ada", 120.0)?;
+    ///     scores.zadd("grace", 95.5)?;
+    ///     scores.zincrby("grace", 50.0)?;
+    ///
+    ///     assert_eq!(Some(1), scores.zrank("grace")?);
+    ///     let top = scores.zrange_by_score(100.0..)?;
+    ///     assert_eq!(vec![("ada".to_string(), 120.0), ("grace".to_string(), 145.5)], top);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn sorted_set(&mut self, name: &str) -> Result<SortedSet<'_>, StorageError> {
+        let db = self.db(&sorted_set_db_name(name), sorted_set_db_flags())?;
+        Ok(SortedSet::new(self.env()?, db))
+    }
+
+    /// Starts writing a blob, a value too large to store under a single key, replacing any blob
+    /// already stored as `name` once the writer is finished.  The writer holds the storage's
+    /// write lock until it is finished or dropped.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    /// use std::io::{Read, Write};
+    ///
+    /// fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     let mut writer = storage.blob_writer("report.parquet")?;
+    ///     writer.write_all(b"PAR1")?;
+    ///     writer.finish()?;
+    ///
+    ///     let mut contents = vec![];
+    ///     if let Some(mut reader) = storage.blob_reader("report.parquet")? {
+    ///         reader.read_to_end(&mut contents)?;
+    ///     }
+    ///     assert_eq!(b"PAR1".to_vec(), contents);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn blob_writer(&mut self, name: &str) -> Result<BlobWriter<'_>, StorageError> {
+        let manifests = self.db(blob::MANIFESTS_DB, blob::blob_db_flags())?;
+        let chunks = self.db(blob::CHUNKS_DB, blob::blob_db_flags())?;
+        BlobWriter::new(self.env()?, manifests, chunks, name)
+    }
+
+    /// Opens the blob stored as `name` for reading, or returns `None` when there is no such blob
+    pub fn blob_reader(&mut self, name: &str) -> Result<Option<BlobReader<'_>>, StorageError> {
+        let manifests = self.existing_db(blob::MANIFESTS_DB)?;
+        let chunks = self.existing_db(blob::CHUNKS_DB)?;
+        match (manifests, chunks) {
+            (Some(manifests), Some(chunks)) => {
+                BlobReader::open(self.env()?, manifests, chunks, name)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Deletes the blob stored as `name`.  Returns false when there was no such blob
+    pub fn delete_blob(&mut self, name: &str) -> Result<bool, StorageError> {
+        let manifests = self.existing_db(blob::MANIFESTS_DB)?;
+        let chunks = self.existing_db(blob::CHUNKS_DB)?;
+        let (manifests, chunks) = match (manifests, chunks) {
+            (Some(manifests), Some(chunks)) => (manifests, chunks),
+            _ => return Ok(false),
+        };
+
+        let mut txn = self.begin_rw_txn()?;
+        let deleted = blob::delete(&mut txn, manifests, chunks, name)?;
+        txn.commit()?;
+        Ok(deleted)
+    }
+
+    /// Returns a handle for reading and writing raw bytes in the environment's unnamed database,
+    /// which is where tools that don't use named databases keep their data.  When there are named
+    /// databases it also holds their names.
+    pub fn raw_unnamed(&mut self) -> Result<RawDb<'_>, StorageError> {
+        let env = self.env()?;
+        Ok(RawDb::new(env, env.open_db(None)?))
+    }
+
+    /// Returns a `Batch` that buffers saves and deletes until it is committed.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     let mut batch = storage.batch();
+    ///     batch.save(&Place { id: 1, name: "Vienna".to_string() });
+    ///
+    ///     let savepoint = batch.savepoint();
+    ///     batch.save(&Place { id: 2, name: "Pariss".to_string() });
+    ///     batch.rollback_to(savepoint);
+    ///
+    ///     batch.save(&Place { id: 2, name: "Paris".to_string() });
+    ///     batch.commit()?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    /// Returns an RoQuery object that allows you to Iterate over all records in a database.
+    ///
+    /// Values that can't be deserialized are skipped, see `query_checked` for a query that
+    /// reports them.  Querying a type that has never been saved yields nothing and doesn't create
+    /// its database.
+    ///
+    /// An error while reading, such as `ReadTooOld` under `ReadAgePolicy::Abort`, ends the query
+    /// as if it had run out of records.  Check `RoQuery::error` afterwards, or use
+    /// `query_checked`, which yields the error as its last item.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     let query = storage.query::<Place>()?;
+    ///     
+    ///     for place in query {
+    ///         println!("{}", place.name);
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn query<T: Record>(&mut self) -> Result<RoQuery<'_, T>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.query();
+        }
+
+        self.record_read::<T>("query");
+        let db = self.existing_db(T::db_name())?;
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        Ok(RoQuery::new(db, txn)?.max_read_age(self.options.max_read_age))
+    }
+
+    /// Iterates over all records in a database, yielding an error for each value that can't be
+    /// read instead of skipping it.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/nostalgia-query-checked")?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     for place in storage.query_checked::<Place>()? {
+    ///         println!("{}", place?.name);
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn query_checked<T: Record>(&mut self) -> Result<CheckedQuery<'_, T>, StorageError> {
+        Ok(self.query()?.checked())
+    }
+
+    /// Returns a `QueryBuilder` for filtering a type's records by field values.
+    ///
+    /// Filters name fields with strings, so queries can be put together at runtime, from HTTP
+    /// query parameters for instance, instead of being written as closures.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[db_name = "PartisanMayor"]
+    /// struct Mayor {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   party: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Mayor { id: 105, name: "Ed Koch".to_string(), party: "Democratic".to_string() })?;
+    ///     storage.save(&Mayor { id: 107, name: "Rudy Giuliani".to_string(), party: "Republican".to_string() })?;
+    ///
+    ///     let democrats = storage
+    ///         .query_builder::<Mayor>()
+    ///         .filter_eq("party", "Democratic")
+    ///         .filter_range("id", 100..110)
+    ///         .limit(10)
+    ///         .fetch()?;
+    ///     assert_eq!("Ed Koch", democrats[0].name);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn query_builder<T: Record>(&mut self) -> QueryBuilder<'_, T> {
+        QueryBuilder::new(self)
+    }
+
+    /// Returns an iterator over the keys of all records in a type's database.
+    ///
+    /// Only the keys are decoded, stored values are never deserialized, which makes this much
+    /// cheaper than `query` when only the ids are needed.  An error, or a key that can't be
+    /// decoded, ends the iteration early and is kept for `KeyQuery::error`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     for key in storage.keys::<Place>()? {
+    ///         println!("{}", key.value());
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn keys<T: Record>(&mut self) -> Result<KeyQuery<'_, T>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.keys();
+        }
+
+        self.record_read::<T>("keys");
+        let db = self.existing_db(T::db_name())?;
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        Ok(KeyQuery::new(db, txn)?.max_read_age(self.options.max_read_age))
+    }
+
+    /// Returns the number of records of a type.  The count is kept by the database, so no records
+    /// are read
+    pub fn count<T: Record>(&mut self) -> Result<usize, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.count::<T>();
+        }
+
+        let db = match self.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(0),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        usage::entries(&txn, db)
+    }
+
+    /// Returns a `Repo` for reading and writing the records of a single type.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Mayor {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     let mut mayors = storage.repository::<Mayor>();
+    ///
+    ///     mayors.save(&Mayor { id: 105, name: "Ed Koch".to_string() })?;
+    ///     let koch = mayors.get(105)?.expect("Empty record");
+    ///     assert_eq!("Ed Koch", koch.name);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn repository<T: Record>(&mut self) -> Repo<'_, T> {
+        Repo::new(self)
+    }
+
+    /// Returns the first record that matches a predicate
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Place { id: 3, name: "Istanbul".to_string() })?;
+    ///
+    ///     let place = storage.find::<Place>(&|p| p.name == "Istanbul")?;
+    ///     if let Some(istanbul) = place {
+    ///         assert_eq!(istanbul.name, "Istanbul");
+    ///     } else {
+    ///         assert_ne!(0, 0, "Could not find record");
+    ///     }
+    ///    
+    ///     Ok(())
+    /// }
+    pub fn find<T: Record>(&mut self, p: &dyn Fn(&T) -> bool) -> Result<Option<T>, StorageError> {
+        let mut query = self.query::<T>()?;
+        Ok(query.find(p))
+    }
+
+    /// Returns every `C` that belongs to the parent with the given key.
+    ///
+    /// Children are found through the index on their foreign key, so only the matching records
+    /// are read.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Mayor {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[belongs_to(Mayor, key = "mayor_id")]
+    /// struct Speech {
+    ///   id: u32,
+    ///   mayor_id: u32,
+    ///   title: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Mayor { id: 1, name: "Ed Koch".to_string() })?;
+    ///     storage.save(&Speech { id: 1, mayor_id: 1, title: "How'm I doing?".to_string() })?;
+    ///
+    ///     let speeches = storage.children_of::<Mayor, Speech, _>(1)?;
+    ///     assert_eq!("How'm I doing?", speeches[0].title);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn children_of<P, C, K>(&mut self, key: K) -> Result<Vec<C>, StorageError>
+    where
+        P: Record,
+        P::Key: Serialize,
+        C: BelongsTo<P>,
+        K: Into<P::Key>,
+    {
+        if self.is_routed::<C>() {
+            return self.partition::<C>()?.children_of::<P, C, K>(key);
+        }
+
+        let index_db = self.existing_db(&index_db_name(C::db_name(), C::foreign_key()))?;
+        let (index_db, db) = match (index_db, self.existing_db(C::db_name())?) {
+            (Some(index_db), Some(db)) => (index_db, db),
+            _ => return Ok(vec![]),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<C>(&txn, types)?;
+        let parent_key = index::encode(&key.into());
+
+        let mut children = vec![];
+        for key in index::lookup(&txn, index_db, &parent_key)? {
+            match txn.get(db, &key) {
+                Ok(bytes) => children.push(metadata::decode::<C>(&key, bytes)?),
+                Err(lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(children)
+    }
+
+    /// Returns the records whose full-text fields contain any of the words in `query`.
+    ///
+    /// Results are ranked by how often the query's words appear in each record, most frequent
+    /// first.  Types opt in to the full-text index with `#[fulltext = "field, other_field"]`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[fulltext = "name, bio"]
+    /// struct Mayor {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   bio: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.save(&Mayor {
+    ///         id: 13,
+    ///         name: "Ed Koch".to_string(),
+    ///         bio: "Mayor of New York City from 1978 to 1989".to_string(),
+    ///     })?;
+    ///
+    ///     let results = storage.search::<Mayor>("koch 1978")?;
+    ///     assert_eq!("Ed Koch", results[0].name);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn search<T: Record>(&mut self, query: &str) -> Result<Vec<T>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.search(query);
+        }
+
+        let index_db = self.existing_db(&index_db_name(T::db_name(), FULLTEXT_INDEX))?;
+        let (index_db, db) = match (index_db, self.existing_db(T::db_name())?) {
+            (Some(index_db), Some(db)) => (index_db, db),
+            _ => return Ok(vec![]),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        let terms = fulltext::tokenize(query);
+
+        let mut keys = std::collections::BTreeSet::new();
+        for term in &terms {
+            keys.extend(index::lookup(&txn, index_db, &index::encode(term))?);
+        }
+
+        let mut results = vec![];
+        for key in keys {
+            let record = match txn.get(db, &key) {
+                Ok(bytes) => Some(metadata::decode::<T>(&key, bytes)?),
+                Err(lmdb::Error::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some(record) = record {
+                let score = fulltext::term_frequency(&record.fulltext_fields(), &terms);
+                results.push((score, record));
+            }
+        }
+
+        results.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        Ok(results.into_iter().map(|(_, record)| record).collect())
+    }
+
+    /// Returns the records that hold `value` in the index named `index`, ordered by key.  Indexes
+    /// on a collection field, like a `Vec<String>` of tags, hold each element, so this finds every
+    /// record with a matching element.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   #[index]
+    ///   tags: Vec<std::string::String>
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+    ///     storage.save(&Place { id: 1, name: "Paris".to_string(), tags: tags(&["capital", "river"]) })?;
+    ///     storage.save(&Place { id: 2, name: "Lyon".to_string(), tags: tags(&["river"]) })?;
+    ///
+    ///     assert_eq!(2, storage.find_by_index::<Place, _>("tags", "river")?.len());
+    ///     assert_eq!("Paris", storage.find_by_index::<Place, _>("tags", "capital")?[0].name);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn find_by_index<T, V>(&mut self, index: &str, value: &V) -> Result<Vec<T>, StorageError>
+    where
+        T: Record,
+        V: Serialize + ?Sized,
+    {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.find_by_index(index, value);
+        }
+        if !T::indexes().contains(&index) {
+            return Err(StorageError::UnknownIndex {
+                db_name: T::db_name(),
+                index: index.to_string(),
+            });
+        }
+
+        let index_db = self.existing_db(&index_db_name(T::db_name(), index))?;
+        let (index_db, db) = match (index_db, self.existing_db(T::db_name())?) {
+            (Some(index_db), Some(db)) => (index_db, db),
+            _ => return Ok(vec![]),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        let mut results = vec![];
+        let value = serde_json::to_value(value)?;
+        let value = index::normalize_value(&value, T::index_normalizers(index));
+        let data = index::lookup(&txn, index_db, &index::encode_value(&value))?;
+        let data = index::in_index_order::<T>(index, data);
+        for key in index::record_keys(data, T::covering_indexes().contains(&index))? {
+            let record = match txn.get(db, &key) {
+                Ok(bytes) => Some(metadata::decode::<T>(&key, bytes)?),
+                Err(lmdb::Error::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+            results.extend(record);
+        }
+        Ok(results)
+    }
+
+    /// Returns the projections a covering index stores for the records whose value of `index` is
+    /// `value`, without loading the records.  A covering index is declared with
+    /// `#[index(include = "field, ...")]` and stores those fields of every record, in that order,
+    /// so `P` is usually a struct with the same fields in the same order.  Fails with
+    /// `StorageError::UncoveredIndex` for an index that doesn't include any fields.
+    ///
+    /// Projections are stored as part of LMDB's sorted duplicates, so together with the record's
+    /// key they can't be longer than 509 bytes by default.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   #[index(include = "id, name")]
+    ///   country: std::string::String,
+    ///   description: std::string::String
+    /// }
+    ///
+    /// #[derive(Deserialize, Debug, PartialEq)]
+    /// struct PlaceName {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let place = |id, name: &str| Place {
+    ///         id,
+    ///         name: name.to_string(),
+    ///         country: "France".to_string(),
+    ///         description: "A long text nobody lists".to_string(),
+    ///     };
+    ///     storage.save(&place(1, "Paris"))?;
+    ///     storage.save(&place(2, "Lyon"))?;
+    ///
+    ///     let names = storage.project_by_index::<Place, PlaceName, _>("country", "France")?;
+    ///     assert_eq!(PlaceName { id: 2, name: "Lyon".to_string() }, names[1]);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn project_by_index<T, P, V>(
+        &mut self,
+        index: &str,
+        value: &V,
+    ) -> Result<Vec<P>, StorageError>
+    where
+        T: Record,
+        P: DeserializeOwned,
+        V: Serialize + ?Sized,
+    {
+        if self.is_routed::<T>() {
+            return self
+                .partition::<T>()?
+                .project_by_index::<T, P, V>(index, value);
+        }
+        let index_db = match self.covering_index_db::<T>(index)? {
+            Some(index_db) => index_db,
+            None => return Ok(vec![]),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        let value =
+            index::normalize_value(&serde_json::to_value(value)?, T::index_normalizers(index));
+        let data = index::lookup(&txn, index_db, &index::encode_value(&value))?;
+        Self::projections(&index::in_index_order::<T>(index, data))
+    }
+
+    /// Returns the projections a covering index stores for the records whose value of `index`
+    /// lies in `range`, ordered by value and then by key, descending for a descending index,
+    /// without loading the records.  See `project_by_index`
+    pub fn project_range<T, P, V, R>(
+        &mut self,
+        index: &str,
+        range: R,
+    ) -> Result<Vec<P>, StorageError>
+    where
+        T: Record,
+        P: DeserializeOwned,
+        V: Serialize,
+        R: RangeBounds<V>,
+    {
+        if self.is_routed::<T>() {
+            return self
+                .partition::<T>()?
+                .project_range::<T, P, V, R>(index, range);
+        }
+        let index_db = match self.covering_index_db::<T>(index)? {
+            Some(index_db) => index_db,
+            None => return Ok(vec![]),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        let encode = |value: &V| -> Result<Vec<u8>, StorageError> {
+            let value = serde_json::to_value(value)?;
+            Ok(index::encode_value(&index::normalize_value(
+                &value,
+                T::index_normalizers(index),
+            )))
+        };
+        let start = match range.start_bound() {
+            Bound::Included(value) => Bound::Included(encode(value)?),
+            Bound::Excluded(value) => Bound::Excluded(encode(value)?),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(value) => Bound::Included(encode(value)?),
+            Bound::Excluded(value) => Bound::Excluded(encode(value)?),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let data = index::range(
+            &txn,
+            index_db,
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        )?;
+        Self::projections(&index::in_index_order::<T>(index, data))
+    }
+
+    // The database of one of `T`'s covering indexes, or `None` while nothing has been indexed
+    fn covering_index_db<T: Record>(
+        &mut self,
+        index: &str,
+    ) -> Result<Option<Database>, StorageError> {
+        if !T::indexes().contains(&index) {
+            return Err(StorageError::UnknownIndex {
+                db_name: T::db_name(),
+                index: index.to_string(),
+            });
+        }
+        if !T::covering_indexes().contains(&index) {
+            return Err(StorageError::UncoveredIndex {
+                db_name: T::db_name(),
+                index: index.to_string(),
+            });
+        }
+        self.existing_db(&index_db_name(T::db_name(), index))
+    }
+
+    fn projections<P: DeserializeOwned>(data: &[Vec<u8>]) -> Result<Vec<P>, StorageError> {
+        data.iter()
+            .map(|data| {
+                let (_, projection) = index::split_entry_data(data)?;
+                Ok(bincode::deserialize(projection)?)
+            })
+            .collect()
+    }
+
+    /// Returns the records whose point lies within `bounds`, ordered by key.  Types opt in to the
+    /// geospatial index with `#[geo = "lat_field, lon_field"]`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{BoundingBox, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[geo = "lat, lon"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   lat: f64,
+    ///   lon: f64
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Paris".to_string(), lat: 48.8566, lon: 2.3522 })?;
+    ///     storage.save(&Place { id: 2, name: "Lyon".to_string(), lat: 45.764, lon: 4.8357 })?;
+    ///
+    ///     let ile_de_france = BoundingBox::new(48.1, 1.4, 49.3, 3.6);
+    ///     let places = storage.within_bbox::<Place>(ile_de_france)?;
+    ///     assert_eq!(vec!["Paris"], places.iter().map(|p| &p.name[..]).collect::<Vec<_>>());
+    ///
+    ///     let nearest = storage.nearest::<Place>((45.0, 5.0), 1)?;
+    ///     assert_eq!("Lyon", nearest[0].name);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn within_bbox<T: Record>(&mut self, bounds: BoundingBox) -> Result<Vec<T>, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.within_bbox(bounds);
+        }
+
+        let index_db = self.existing_db(&index_db_name(T::db_name(), GEO_INDEX))?;
+        let (index_db, db) = match (index_db, self.existing_db(T::db_name())?) {
+            (Some(index_db), Some(db)) => (index_db, db),
+            _ => return Ok(vec![]),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+
+        let mut keys = std::collections::BTreeSet::new();
+        for (start, end) in geo::cell_ranges(&bounds) {
+            keys.extend(index::range(
+                &txn,
+                index_db,
+                Bound::Included(&start.to_be_bytes()[..]),
+                Bound::Included(&end.to_be_bytes()[..]),
+            )?);
+        }
+
+        let mut results = vec![];
+        for key in keys {
+            let record = match txn.get(db, &key) {
+                Ok(bytes) => Some(metadata::decode::<T>(&key, bytes)?),
+                Err(lmdb::Error::NotFound) => None,
+                Err(e) => return Err(e.into()),
+            };
+            // The cells take in codes from around the box as well
+            if let Some(record) =
+                record.filter(|r| r.geo_point().is_some_and(|p| bounds.contains(p)))
+            {
+                results.push(record);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Returns the `k` records whose point is closest to `point`, a `(latitude, longitude)`, nearest
+    /// first.  The area searched starts small and grows until it holds `k` records, so this
+    /// stays quick when records are dense around `point`.
+    pub fn nearest<T: Record>(
+        &mut self,
+        point: (f64, f64),
+        k: usize,
+    ) -> Result<Vec<T>, StorageError> {
+        if k == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut degrees = 0.01;
+        loop {
+            let mut found: Vec<(f64, T)> = self
+                .within_bbox::<T>(BoundingBox::around(point, degrees))?
+                .into_iter()
+                .filter_map(|record| Some((geo::distance(point, record.geo_point()?), record)))
+                .collect();
+            found.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            // Records outside the box are all further away than the circle it was drawn around
+            let reach = geo::arc_length(degrees);
+            if degrees >= 180.0 || found.iter().filter(|(d, _)| *d <= reach).count() >= k {
+                found.truncate(k);
+                return Ok(found.into_iter().map(|(_, record)| record).collect());
+            }
+            degrees *= 4.0;
+        }
+    }
+
+    /// Deletes a parent record along with every `C` that belongs to it in one transaction
+    pub fn delete_cascade<P, C>(&mut self, parent: &P) -> Result<(), StorageError>
+    where
+        P: Record,
+        P::Key: Serialize,
+        C: BelongsTo<P>,
+    {
+        if self.is_routed::<P>() {
+            return self.partition::<P>()?.delete_cascade::<P, C>(parent);
+        }
+
+        self.transaction(|tx| tx.delete_cascade::<P, C>(parent))
+    }
+
+    /// Sets what happens to the `C` records that belong to a `P` when that `P` is deleted.
+    ///
+    /// The policy is enforced inside the same transaction as the delete, so a `Restrict`
+    /// violation leaves everything untouched and returns `StorageError::RestrictViolation`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{OnDelete, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Mayor {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[belongs_to(Mayor, key = "mayor_id")]
+    /// struct Speech {
+    ///   id: u32,
+    ///   mayor_id: u32,
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.on_delete::<Mayor, Speech>(OnDelete::Restrict);
+    ///
+    ///     let koch = Mayor { id: 2, name: "Ed Koch".to_string() };
+    ///     storage.save(&koch)?;
+    ///     storage.save(&Speech { id: 2, mayor_id: 2 })?;
+    ///
+    ///     match storage.delete(&koch) {
+    ///         Err(StorageError::RestrictViolation { .. }) => (),
+    ///         _ => panic!("Mayor shouldn't be deleted while he has speeches"),
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn on_delete<P, C>(&mut self, policy: OnDelete)
+    where
+        P: Record,
+        P::Key: Serialize,
+        C: BelongsTo<P>,
+    {
+        let rules = self.delete_rules.entry(P::db_name()).or_default();
+        rules.retain(|rule| rule.child != C::db_name());
+        rules.push(DeleteRule {
+            child: C::db_name(),
+            policy,
+            enforce: |tx, key, policy| relation::enforce::<P, C>(tx, key, policy),
+        });
+
+        let partition = self.options.partition_of::<P>();
+        if let Some(partition) = partition.and_then(|name| self.partitions.get_mut(name)) {
+            partition.on_delete::<P, C>(policy);
+        }
+        for tenant in self.tenants.values_mut() {
+            tenant.on_delete::<P, C>(policy);
+        }
+    }
+
+    /// Merges records of type `T` with the stored ones when applying changes with `apply_changes`
+    /// or `apply_patch`, instead of overwriting them.  Their index entries are written for the
+    /// merged records, and deleting a record still deletes it.
+    pub fn merge_on_apply<T: Merge>(&mut self) -> &mut Self {
+        self.mergers.insert(T::db_name(), Merger::of::<T>());
+        self
+    }
+
+    /// Keeps only the records of `T` that `retention` allows, deleting the rest whenever
+    /// `apply_retention` runs, or a `Maintenance` that applies it.  Replaces any retention set
+    /// for `T` before.  Tenant storages opened from this one keep theirs the same way, while
+    /// maintenance started earlier doesn't see rules set after it.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Retention, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Visit {
+    ///   id: u64,
+    ///   at: SystemTime
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     let day = Duration::from_secs(24 * 60 * 60);
+    ///     storage.retain(Retention::MaxAge(30 * day, |visit: &Visit| visit.at));
+    ///
+    ///     storage.save(&Visit { id: 1, at: SystemTime::now() - 40 * day })?;
+    ///     storage.save(&Visit { id: 2, at: SystemTime::now() })?;
+    ///     assert_eq!(1, storage.apply_retention::<Visit>()?);
+    ///     assert_eq!(1, storage.count::<Visit>()?);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn retain<T: Record + 'static>(&mut self, retention: Retention<T>) -> &mut Self {
+        self.retention
+            .insert(T::db_name(), retention::rule(retention));
+        for tenant in self.tenants.values_mut() {
+            tenant.retain(retention);
+        }
+        self
+    }
+
+    /// Deletes the records of `T` its retention doesn't keep, see `retain`, and returns how many
+    /// were deleted.  Deletes nothing when `T` has no retention
+    pub fn apply_retention<T: Record>(&mut self) -> Result<usize, StorageError> {
+        match self.retention.get(T::db_name()).cloned() {
+            Some(rule) => rule(self),
+            None => Ok(0),
+        }
+    }
+
+    /// Registers a record type so it can be worked with through `record_types` without naming it.
+    /// Registering a type twice does nothing.
+    ///
+    /// Fails with `StorageError::DbNameTaken` when another type is registered for the same
+    /// database, as records of the two types couldn't be told apart.  Registering every type at
+    /// startup catches two types that ended up with the same `db_name` before either is saved.
+    ///
+    /// Also fails with `StorageError::SchemaChanged` when the type's records were written while
+    /// it had other fields, see `update_schema_version`.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.register::<Place>()?;
+    ///
+    ///     for record_type in storage.record_types() {
+    ///         for record in record_type.scan(&mut storage)? {
+    ///             println!("{}: {}", record_type.db_name(), record.to_json());
+    ///         }
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn register<T: Record + 'static>(&mut self) -> Result<&mut Self, StorageError> {
+        let current = T::schema_version();
+        match self.stored_schema_version::<T>()? {
+            Some(stored) if stored != 0 && current != 0 && stored != current => {
+                return Err(StorageError::SchemaChanged {
+                    db_name: T::db_name(),
+                    stored,
+                    current,
+                })
+            }
+            _ => (),
+        }
+        match self.record_type(T::db_name()) {
+            Some(record_type) if !record_type.is::<T>() => {
+                return Err(StorageError::DbNameTaken {
+                    db_name: T::db_name(),
+                    existing: record_type.type_name(),
+                    registered: std::any::type_name::<T>(),
+                })
+            }
+            Some(_) => (),
+            None => self.registry.push(RecordType::of::<T>()),
+        }
+        for tenant in self.tenants.values_mut() {
+            tenant.register::<T>()?;
+        }
+        if self.options.upgrade_policy::<T>() == UpgradePolicy::Eager {
+            self.migrate_all::<T, _>(|_| {})?;
+        }
+        Ok(self)
+    }
+
+    /// The schema version `T`'s records were written with, see `Record::schema_version`.  `None`
+    /// when none was recorded, like for databases written before versions were kept
+    pub fn stored_schema_version<T: Record>(&mut self) -> Result<Option<u64>, StorageError> {
+        let storage = self.storage_for::<T>()?;
+        let schemas = storage.existing_db(SCHEMAS_DB)?;
+        let txn = storage.env()?.begin_ro_txn()?;
+        type_tag::schema_version(&txn, schemas, T::db_name())
+    }
+
+    /// Records that `T`'s records were migrated to the type's current fields, so registering it
+    /// no longer fails with `StorageError::SchemaChanged`
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// mod v1 {
+    ///     use nostalgia::{Record, Key};
+    ///     use serde::{Serialize, Deserialize};
+    ///
+    ///     #[derive(Storable, Serialize, Deserialize)]
+    ///     #[storable(key = "id", db_name = "Place", type_tag = "Place")]
+    ///     pub struct Place {
+    ///         pub id: u32,
+    ///         pub name: std::string::String
+    ///     }
+    /// }
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String,
+    ///   country: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&v1::Place { id: 1, name: "Vienna".to_string() })?;
+    ///     assert!(storage.register::<Place>().is_err());
+    ///
+    ///     let old: Vec<v1::Place> = storage.query::<v1::Place>()?.collect();
+    ///     for place in old {
+    ///         storage.save(&Place { id: place.id, name: place.name, country: "AT".to_string() })?;
+    ///     }
+    ///     storage.update_schema_version::<Place>()?;
+    ///     storage.register::<Place>()?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn update_schema_version<T: Record>(&mut self) -> Result<(), StorageError> {
+        let storage = self.storage_for::<T>()?;
+        let schemas = storage.db(SCHEMAS_DB, DatabaseFlags::empty())?;
+        let journal = storage.journal_db()?;
+        let mut txn = storage.begin_rw_txn()?;
+        type_tag::set_schema_version::<T>(&mut txn, schemas)?;
+        if let Some(journal) = journal {
+            let ops = [ChangeOp::Put {
+                db: SCHEMAS_DB.to_string(),
+                flags: 0,
+                key: T::db_name().as_bytes().to_vec(),
+                value: T::schema_version().to_be_bytes().to_vec(),
+            }];
+            journal::append(&mut txn, journal, &ops)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The registered record types, in the order they were registered
+    pub fn record_types(&self) -> Vec<RecordType> {
+        self.registry.clone()
+    }
+
+    /// The registered record type stored in the database called `db_name`
+    pub fn record_type(&self, db_name: &str) -> Option<RecordType> {
+        self.registry
+            .iter()
+            .find(|record_type| record_type.db_name() == db_name)
+            .copied()
+    }
+
+    /// Sends counts of operations, bytes written, write transaction durations and map
+    /// utilization to `sink`, replacing any sink set before.  Partitions report to it too.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{MetricsSink, Storage, StorageError};
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// #[derive(Default)]
+    /// struct BytesWritten(AtomicU64);
+    ///
+    /// impl MetricsSink for BytesWritten {
+    ///     fn bytes_written(&self, _db_name: &str, bytes: usize) {
+    ///         self.0.fetch_add(bytes as u64, Ordering::Relaxed);
+    ///     }
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///     storage.set_metrics(BytesWritten::default());
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn set_metrics<M: MetricsSink + 'static>(&mut self, sink: M) {
+        self.set_metrics_sink(Arc::new(sink));
+    }
+
+    fn set_metrics_sink(&mut self, sink: Arc<dyn MetricsSink>) {
+        for partition in self.partitions.values_mut() {
+            partition.set_metrics_sink(sink.clone());
+        }
+        for tenant in self.tenants.values_mut() {
+            tenant.set_metrics_sink(sink.clone());
+        }
+        self.metrics = Some(sink);
+    }
+
+    // Counts a read of a record type's database
+    fn record_read<T: Record>(&self, op: &'static str) {
+        if let Some(sink) = &self.metrics {
+            sink.operation(op, T::db_name());
+        }
+    }
+
+    /// Reads every record of `T` and reports how large their keys and values are: a histogram of
+    /// value sizes, the averages and the largest records.  Takes as long as reading the whole
+    /// database.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Wien".to_string() })?;
+    ///     storage.save(&Place { id: 2, name: "Llanfairpwllgwyngyll".to_string() })?;
+    ///
+    ///     let profile = storage.profile::<Place>()?;
+    ///     assert_eq!(4.0, profile.average_key_bytes());
+    ///     for bucket in &profile.histogram {
+    ///         println!("up to {} bytes: {} records", bucket.max_bytes, bucket.records);
+    ///     }
+    ///     let key: Vec<u8> = Key::from(2u32).into();
+    ///     assert_eq!(key, profile.largest[0].0);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn profile<T: Record>(&mut self) -> Result<ValueProfile, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.profile::<T>();
+        }
+
+        let db = match self.existing_db(T::db_name())? {
+            Some(db) => db,
+            None => return Ok(ValueProfile::default()),
+        };
+        let types = self.existing_db(TYPES_DB)?;
+        let txn = self.env()?.begin_ro_txn()?;
+        type_tag::check::<T>(&txn, types)?;
+        profile::profile(&txn, db)
+    }
+
+    /// Reports how much space each database takes up, along with the size of the data file.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let storage = Storage::new("/tmp/db")?;
+    ///     let usage = storage.disk_usage()?;
+    ///
+    ///     for db in &usage.databases {
+    ///         println!("{}: {} records in {} bytes", db.name, db.entries, db.bytes);
+    ///     }
+    ///     assert!(usage.total_bytes() <= usage.used_bytes);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn disk_usage(&self) -> Result<DiskUsage, StorageError> {
+        usage::disk_usage(self.env()?, &self.path)
+    }
+
+    /// Lists every named database in the data directory with its entry count and size, including
+    /// ones written by types this program doesn't know about and the index and counter databases
+    /// kept next to record types.  Sorted by name.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let storage = Storage::new("/tmp/db")?;
+    ///
+    ///     for db in storage.list_databases()? {
+    ///         println!("{}: {} entries, {} bytes", db.name, db.entries, db.bytes);
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn list_databases(&self) -> Result<Vec<DatabaseUsage>, StorageError> {
+        usage::database_usage(self.env()?)
+    }
+
+    /// Lists the reader slots in use across every process that has the storage open.  A slot
+    /// whose snapshot lags far behind is a long-lived query keeping freed pages from being reused
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let storage = Storage::new("/tmp/db")?;
+    ///
+    ///     for reader in storage.readers()? {
+    ///         println!("pid {} is {} transactions behind", reader.pid, reader.lag);
+    ///     }
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn readers(&self) -> Result<Vec<ReaderSlot>, StorageError> {
+        readers::list(self.env()?)
+    }
+
+    /// Frees the reader slots of processes that exited without ending their read transactions.
+    /// Returns how many slots were freed
+    pub fn clear_stale_readers(&self) -> Result<usize, StorageError> {
+        readers::clear_stale(self.env()?)
+    }
+
+    /// Starts a background thread that runs the tasks in `maintenance` until the storage is
+    /// dropped or `stop_maintenance` is called, replacing the one already running.
+    ///
+    /// The thread works on its own handle to the environment, opened with the storage's options
+    /// and partitions, so its writes aren't seen by the storage's read caches.  The storage can't
+    /// be reopened or compacted while the thread is running.  See `Maintenance` for an example
+    pub fn start_maintenance(&mut self, maintenance: Maintenance) -> Result<(), StorageError> {
+        self.stop_maintenance();
+        let storage = self.sibling()?;
+        self.maintenance = Some(MaintenanceThread::start(storage, maintenance)?);
+        Ok(())
+    }
+
+    /// Stops the maintenance thread, waiting for the task it is running to finish
+    pub fn stop_maintenance(&mut self) {
+        self.maintenance.take();
+    }
+
+    // Another storage on the same environment and partitions, for use on another thread
+    fn sibling(&self) -> Result<Storage, StorageError> {
+        let partitions = self
+            .partitions
+            .iter()
+            .map(|(name, partition)| Ok((*name, partition.sibling()?)))
+            .collect::<Result<_, StorageError>>()?;
+
+        Ok(Storage {
+            env: Some(self.env.clone().ok_or(StorageError::Closed)?),
+            path: self.path.clone(),
+            options: self.options.clone(),
+            dbs: self.dbs.clone(),
+            delete_rules: self.delete_rules.clone(),
+            mergers: self.mergers.clone(),
+            retention: self.retention.clone(),
+            partition: self.partition,
+            partitions,
+            tenant: self.tenant.clone(),
+            tenants: HashMap::new(),
+            quota: self.quota,
+            quotas: self.quotas.clone(),
+            metrics: self.metrics.clone(),
+            registry: self.registry.clone(),
+            caches: HashMap::new(),
+            maintenance: None,
+            temporary: None,
+        })
+    }
+
+    /// Returns the number of pages LMDB has freed and will reuse before growing the data file
+    pub fn free_pages(&self) -> Result<usize, StorageError> {
+        usage::free_pages(self.env()?)
+    }
+
+    /// Tells the OS how records are about to be read, so it can read ahead for scans or hold off
+    /// for lookups.  The advice covers every record type in the storage and its open partitions.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{AccessPattern, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     storage.warm::<Place>()?;
+    ///     storage.query_hint(AccessPattern::Sequential)?;
+    ///     println!("{} places", storage.query::<Place>()?.count());
+    ///     storage.query_hint(AccessPattern::Normal)?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn query_hint(&self, pattern: AccessPattern) -> Result<(), StorageError> {
+        readahead::advise(self.env()?, pattern)?;
+        for partition in self.partitions.values() {
+            partition.query_hint(pattern)?;
+        }
+        Ok(())
+    }
+
+    /// Pulls a record type's database into memory ahead of use, returning the number of records
+    /// it holds
+    pub fn warm<T: Record>(&mut self) -> Result<usize, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.warm::<T>();
+        }
+
+        match self.existing_db(T::db_name())? {
+            Some(db) => readahead::warm(self.env()?, db),
+            None => Ok(0),
+        }
+    }
+
+    /// Rewrites the data file without its free pages.
+    ///
+    /// LMDB data files never shrink on their own.  A compacted copy is written next to the data
+    /// file and then renamed over it, so a crash halfway through leaves the original in place.
+    /// No other process should have the storage open while it is compacted.
+    pub fn compact(&mut self) -> Result<(), StorageError> {
+        self.check_not_shared()?;
+        let copy = self.path.join("nostalgia-compact");
+        if copy.exists() {
+            remove_dir_all(&copy)?;
+        }
+        create_dir_all(&copy)?;
+        usage::copy_compacted(self.env()?, &copy)?;
+
+        self.close();
+        rename(copy.join("data.mdb"), self.path.join("data.mdb"))?;
+        remove_dir_all(&copy)?;
+        self.reopen()
+    }
+
+    /// Writes every database in the environment to a tar archive at `path`, records of every type
+    /// along with indexes, raw databases and everything else, as of a single snapshot.  Returns
+    /// the number of entries written.
+    ///
+    /// The archive holds a `manifest.json` with the format version, `DUMP_VERSION`, and the name
+    /// and flags of every database, then one JSON Lines file per database with each key and value
+    /// in hex.  Partitions live in environments of their own and are dumped separately.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Paris".to_string() })?;
+    ///     let dir = tempfile::tempdir()?;
+    ///     let dump = dir.path().join("places.tar");
+    ///     storage.dump(&dump)?;
+    ///
+    ///     let mut restored = Storage::temporary()?;
+    ///     restored.load(&dump)?;
+    ///     assert_eq!("Paris", restored.get::<Place, _>(1u32)?.unwrap().name);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> Result<usize, StorageError> {
+        dump::dump(self.env()?, path.as_ref())
+    }
+
+    /// Restores a dump written by `dump` into this storage, which can't hold any databases yet.
+    /// Returns the number of entries restored.  The dump is loaded in a single transaction, so a
+    /// dump that fails to load leaves the storage empty.  No read handles may share the
+    /// environment while it loads
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, StorageError> {
+        self.check_not_shared()?;
+        dump::load(self.env()?, path.as_ref())
+    }
+
+    /// Returns at most `limit` changes from the journal, starting at the change numbered `seq`.
+    /// Storages keep a journal when opened with `StorageOptions::journal`.
+    ///
+    /// Fails with `StorageError::ReplicationGap` when the changes from `seq` on can't all be
+    /// read: they were truncated already, or `seq` lies past the end of the journal, which
+    /// happens when a follower is pointed at another storage than the one it followed.
+    pub fn read_journal(&mut self, seq: u64, limit: usize) -> Result<Vec<Change>, StorageError> {
+        let db = match self.existing_db(JOURNAL_DB)? {
+            Some(db) => db,
+            None if seq == 0 => return Ok(vec![]),
+            None => {
+                return Err(StorageError::ReplicationGap {
+                    expected: seq,
+                    found: 0,
+                })
+            }
+        };
+        let txn = self.env()?.begin_ro_txn()?;
+        let (changes, next) = journal::read(&txn, db, seq, limit)?;
+        let found = changes.first().map_or(next, |change| change.seq);
+        if found != seq {
+            return Err(StorageError::ReplicationGap {
+                expected: seq,
+                found,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Removes the changes before the one numbered `seq` from the journal, once every follower
+    /// has applied them.  Returns how many were removed
+    pub fn truncate_journal(&mut self, seq: u64) -> Result<usize, StorageError> {
+        let db = match self.existing_db(JOURNAL_DB)? {
+            Some(db) => db,
+            None => return Ok(0),
+        };
+        let mut txn = self.begin_rw_txn()?;
+        let removed = journal::truncate_before(&mut txn, db, seq)?;
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    /// Sends `sink` every change in the journal from the position it reports on, returning how
+    /// many were sent.  Called over and over, this keeps a follower on another machine up to
+    /// date with the storage, as long as the journal isn't truncated past the follower's
+    /// position.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, StorageOptions, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let mut leader = Storage::open_with(dir.path(), StorageOptions::default().journal(true))?;
+    ///     let mut follower = Storage::temporary()?;
+    ///
+    ///     leader.save(&Place { id: 1, name: "Paris".to_string() })?;
+    ///     leader.replicate_to(&mut follower)?;
+    ///     assert_eq!("Paris", follower.get::<Place, _>(1u32)?.unwrap().name);
+    ///
+    ///     leader.save(&Place { id: 2, name: "Lyon".to_string() })?;
+    ///     assert_eq!(1, leader.replicate_to(&mut follower)?);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn replicate_to<S: ChangeSink + ?Sized>(
+        &mut self,
+        sink: &mut S,
+    ) -> Result<usize, StorageError> {
+        // Sent in batches, so a long way behind doesn't mean holding the whole journal at once
+        const BATCH: usize = 1_000;
+        let mut position = sink.position()?;
+        let mut sent = 0;
+        loop {
+            let changes = self.read_journal(position, BATCH)?;
+            let last = match changes.last() {
+                Some(change) => change.seq,
+                None => return Ok(sent),
+            };
+            sent += changes.len();
+            sink.send(changes)?;
+            position = last + 1;
+        }
+    }
+
+    /// Applies changes read from another storage's journal, in one transaction, and returns the
+    /// sequence number of the next change expected.  Changes that were applied before are
+    /// skipped, so sending one twice does no harm, while a change that comes before the ones in
+    /// between fails with `StorageError::ReplicationGap` and nothing is applied.
+    ///
+    /// The storage is meant to be a follower only written to this way; records saved to it
+    /// directly can be overwritten by the changes it applies, unless their type is merged, see
+    /// `merge_on_apply`.
+    pub fn apply_changes<I>(&mut self, changes: I) -> Result<u64, StorageError>
+    where
+        I: IntoIterator<Item = Change>,
+    {
+        let mergers = self.mergers.clone();
+        let position = self.transaction(|tx| {
+            let mut position = tx.replica_position()?;
+            for change in changes {
+                if change.seq < position {
+                    continue;
+                }
+                if change.seq > position {
+                    return Err(StorageError::ReplicationGap {
+                        expected: position,
+                        found: change.seq,
+                    });
+                }
+                tx.apply_ops(&change.ops, &mergers)?;
+                // Kept in the follower's own journal too, one entry per change, so it can be
+                // followed in turn
+                tx.write_journal()?;
+                position += 1;
+            }
+            tx.set_replica_position(position)?;
+            Ok(position)
+        })?;
+
+        for cache in self.caches.values_mut() {
+            cache.clear();
+        }
+        Ok(position)
+    }
+
+    /// The highest counter this storage has seen from every replica, which a peer passes to
+    /// `changes_since` to get what this storage is missing.  Fails with
+    /// `StorageError::VersionsNotTracked` unless the storage was opened with
+    /// `StorageOptions::versions`
+    pub fn sync_state(&mut self) -> Result<VersionVector, StorageError> {
+        if !self.options.versions {
+            return Err(StorageError::VersionsNotTracked);
+        }
+        match self.existing_db(SYNC_DB)? {
+            Some(db) => sync::seen(&self.env()?.begin_ro_txn()?, db),
+            None => Ok(VersionVector::default()),
+        }
+    }
+
+    /// The current version of every record written at a point the peer whose sync state is
+    /// `peer` hasn't seen, deleted records included, to be applied with `apply_remote`.  Only
+    /// records of registered types are synced.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, StorageOptions, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let mut replicas = vec![];
+    ///     for name in &["desktop", "mobile"] {
+    ///         let options = StorageOptions::default().versions(true);
+    ///         let mut storage = Storage::open_with(dir.path().join(name), options)?;
+    ///         storage.register::<Place>()?;
+    ///         replicas.push(storage);
+    ///     }
+    ///     let (mut mobile, mut desktop) = (replicas.pop().unwrap(), replicas.pop().unwrap());
+    ///
+    ///     desktop.save(&Place { id: 1, name: "Paris".to_string() })?;
+    ///     let changes = desktop.changes_since(&mobile.sync_state()?)?;
+    ///     assert_eq!(1, mobile.apply_remote(changes)?);
+    ///
+    ///     assert_eq!("Paris", mobile.get::<Place, _>(1u32)?.unwrap().name);
+    ///     assert!(desktop.changes_since(&mobile.sync_state()?)?.is_empty());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn changes_since(&mut self, peer: &VersionVector) -> Result<Vec<SyncChange>, StorageError> {
+        let sync = match (self.options.versions, self.existing_db(SYNC_DB)?) {
+            (false, _) => return Err(StorageError::VersionsNotTracked),
+            (true, Some(db)) => db,
+            (true, None) => return Ok(vec![]),
+        };
+        let mut dbs = HashMap::new();
+        for record_type in self.registry.clone() {
+            if let Some(db) = self.existing_db(record_type.db_name())? {
+                dbs.insert(record_type.db_name(), db);
+            }
+        }
+
+        let txn = self.env()?.begin_ro_txn()?;
+        let mut changes = vec![];
+        for (db_name, key, version) in sync::unseen(&txn, sync, peer)? {
+            let value = match dbs.get(db_name.as_str()) {
+                Some(db) => match txn.get(*db, &key) {
+                    Ok(bytes) => Some(bytes.to_vec()),
+                    Err(lmdb::Error::NotFound) => None,
+                    Err(e) => return Err(e.into()),
+                },
+                None if self.record_type(&db_name).is_some() => None,
+                None => return Err(StorageError::UnregisteredType { db_name }),
+            };
+            changes.push(SyncChange {
+                db: db_name,
+                key,
+                value,
+                version: version.vector,
+                replica: version.replica,
+                counter: version.counter,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Applies changes a peer sent from `changes_since` in one transaction, returning how many
+    /// were new to this storage.  Versions that descend from the stored ones replace them, while
+    /// concurrent ones are merged for types registered with `merge_on_apply` and otherwise
+    /// resolved the same way on both sides, so two storages that exchange their changes end up
+    /// holding the same records.
+    ///
+    /// Fails with `StorageError::UnregisteredType` for records of types that weren't registered
+    /// with `register`, since their index entries can't be written without the type.
+    pub fn apply_remote<I>(&mut self, changes: I) -> Result<usize, StorageError>
+    where
+        I: IntoIterator<Item = SyncChange>,
+    {
+        if !self.options.versions {
+            return Err(StorageError::VersionsNotTracked);
+        }
+        let record_types = self.registry.clone();
+        let mergers = self.mergers.clone();
+        let applied = self.transaction(|tx| {
+            let mut applied = 0;
+            for change in changes {
+                let record_type = record_types
+                    .iter()
+                    .find(|record_type| record_type.db_name() == change.db)
+                    .ok_or_else(|| StorageError::UnregisteredType {
+                        db_name: change.db.clone(),
+                    })?;
+                if tx.apply_remote(&change, record_type, mergers.get(change.db.as_str()))? {
+                    applied += 1;
+                }
+            }
+            Ok(applied)
+        })?;
+
+        for cache in self.caches.values_mut() {
+            cache.clear();
+        }
+        Ok(applied)
+    }
+
+    /// Compares the databases of this storage with those of `other`, see `nostalgia::diff`
+    pub fn diff(&self, other: &Storage) -> Result<Diff, StorageError> {
+        diff::compare(self.env()?, other.env()?)
+    }
+
+    /// Applies a patch written by `Diff::write_patch` in one transaction, returning the number of
+    /// writes in it.  Applied to the first environment of the diff, the patch makes it hold what
+    /// the second one held; applied to anything else, it writes those same entries regardless.
+    pub fn apply_patch<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, StorageError> {
+        let ops = diff::read_patch(path.as_ref())?;
+        let mergers = self.mergers.clone();
+        self.transaction(|tx| tx.apply_ops(&ops, &mergers))?;
+
+        for cache in self.caches.values_mut() {
+            cache.clear();
+        }
+        Ok(ops.len())
+    }
+
+    /// Applies a batch sent by a remote client in one transaction, only when every value it
+    /// expects is still stored.  Returns whether it was applied
+    #[cfg(feature = "server")]
+    pub(crate) fn apply_batch(
+        &mut self,
+        batch: &crate::protocol::WriteBatch,
+    ) -> Result<bool, StorageError> {
+        let applied = self.transaction(|tx| {
+            for expected in &batch.expect {
+                if tx.raw_get(&expected.db, &expected.key)? != expected.value {
+                    return Ok(false);
+                }
+            }
+            tx.apply_ops(&batch.ops, &HashMap::new())?;
+            Ok(true)
+        })?;
+
+        if applied {
+            for cache in self.caches.values_mut() {
+                cache.clear();
+            }
+        }
+        Ok(applied)
+    }
+
+    /// Closes the environment and releases its file handles and memory map.
+    ///
+    /// Every call that touches the databases returns `StorageError::Closed` until the storage is
+    /// reopened.  Queries borrow the storage, so it can't be closed while one is being iterated.
+    ///
+    /// # Examples
+    /// ```
+    /// use nostalgia::{Storage, StorageError};
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     storage.close();
+    ///     assert!(!storage.is_open());
+    ///
+    ///     storage.reopen()?;
+    ///     assert!(storage.is_open());
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn close(&mut self) {
+        // Commits that weren't synced are written out before the environment goes away.  There is
+        // no one to report a failure to, the next open only misses those commits
+        if self.options.durability != Durability::Strict {
+            let _ = self.flush();
+        }
+        // Database handles belong to the environment they were opened in
+        self.dbs.clear();
+        self.partitions.clear();
+        self.tenants.clear();
+        // Another environment may be opened in its place
+        for cache in self.caches.values_mut() {
+            cache.clear();
+        }
+        self.env = None;
+    }
+
+    // LMDB doesn't allow an environment to be opened twice in one process, so one that read
+    // handles still use can't be opened again
+    fn check_not_shared(&self) -> Result<(), StorageError> {
+        let shared = |storage: &Storage| {
+            storage
+                .env
+                .as_ref()
+                .is_some_and(|env| Arc::strong_count(env) > 1)
+        };
+        let shared_tenant = self
+            .tenants
+            .values()
+            .any(|tenant| tenant.check_not_shared().is_err());
+        if shared(self) || self.partitions.values().any(shared) || shared_tenant {
+            Err(StorageError::Shared)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Takes a snapshot of the storage that reads can share, like those of one web request, so
+    /// they all see the same records.  The view holds a read transaction until it is dropped,
+    /// and borrows the storage so nothing reads outside the snapshot on this thread meanwhile.
+    /// Types in another partition can't be read through the view
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{ReadView, Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn place_name(view: &ReadView, id: u32) -> Result<Option<String>, StorageError> {
+    ///     Ok(view.get::<Place, _>(id)?.map(|place| place.name))
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     let view = storage.read_view()?;
+    ///     assert_eq!(Some("Vienna".to_string()), place_name(&view, 1)?);
+    ///     assert_eq!(1, view.count::<Place>()?);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn read_view(&mut self) -> Result<ReadView<'_>, StorageError> {
+        // The view can't open database handles itself, so every database is opened first
+        for name in usage::database_names(self.env()?)? {
+            self.existing_db(&name)?;
+        }
+        ReadView::new(self.env()?, &self.dbs, &self.options, self.partition)
+    }
+
+    /// Splits the storage into a handle for reading that can be cloned and shared between
+    /// threads, and the only handle that can write.
+    ///
+    /// Record types in a partition can only be read through the read handle when their partition
+    /// was opened before the split.  The environment can't be reopened or compacted while read
+    /// handles are still around.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let (reader, mut writer) = Storage::new("/tmp/db")?.split()?;
+    ///     writer.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     let handle = std::thread::spawn(move || reader.get::<Place, _>(1));
+    ///     let place = handle.join().unwrap()?.expect("Empty record");
+    ///     assert_eq!("Vienna", place.name);
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn split(self) -> Result<(RoStorage, RwStorage), StorageError> {
+        let reader = self.read_handle()?;
+        Ok((reader, RwStorage::new(self)))
+    }
+
+    fn read_handle(&self) -> Result<RoStorage, StorageError> {
+        let env = self.env.clone().ok_or(StorageError::Closed)?;
+        let mut partitions = HashMap::new();
+        for (name, partition) in &self.partitions {
+            partitions.insert(*name, partition.read_handle()?);
+        }
+
+        Ok(RoStorage::new(
+            env,
+            self.dbs.clone(),
+            self.options.clone(),
+            self.partition,
+            partitions,
+            self.metrics.clone(),
+        ))
+    }
+
+    /// Writes every commit out to disk, along with those of partitions and tenants.  Commits are
+    /// only left unsynced with a `Durability` other than `Strict`
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Durability, Storage, StorageOptions, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let options = StorageOptions::default().durability(Durability::Async);
+    ///     let mut storage = Storage::open_with("/tmp/nostalgia-flush", options)?;
+    ///
+    ///     for id in 0..1000 {
+    ///         storage.save(&Place { id, name: format!("Place {}", id) })?;
+    ///     }
+    ///     storage.flush()?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        self.env()?.sync(true)?;
+        for storage in self
+            .partitions
+            .values_mut()
+            .chain(self.tenants.values_mut())
+        {
+            storage.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Switches the open environment, its partitions and its tenants to another durability mode,
+    /// like for a bulk load.  Switching to `Strict` doesn't sync what was committed before, call
+    /// `flush` for that
+    pub fn set_durability(&mut self, durability: Durability) -> Result<(), StorageError> {
+        options::set_durability(self.env()?, durability, self.options.write_map)?;
+        self.options.durability = durability;
+        for storage in self
+            .partitions
+            .values_mut()
+            .chain(self.tenants.values_mut())
+        {
+            storage.set_durability(durability)?;
+        }
+        Ok(())
+    }
+
+    /// Returns false once the storage has been closed
+    pub fn is_open(&self) -> bool {
+        self.env.is_some()
+    }
+
+    /// Closes the environment and opens it again from the same directory.
+    ///
+    /// Picks up a data file that was swapped in by another process, or a map size that changed.
+    pub fn reopen(&mut self) -> Result<(), StorageError> {
+        let path = self.path.clone();
+        self.reopen_at(path)
+    }
+
+    /// Closes the environment and opens the one in another directory, creating it if needed.
+    /// Registered on-delete policies are kept.
+    pub fn reopen_at<P: Into<PathBuf>>(&mut self, path: P) -> Result<(), StorageError> {
+        self.check_not_shared()?;
+        self.close();
+
+        let path = path.into();
+        if self.options.create {
+            create_dir_all(&path)?;
+        }
+        self.env = Some(Arc::new(self.options.open(&path)?));
+        self.path = path;
+        Ok(())
+    }
+
+    /// Removes all records in the corresponding type's database
+    pub fn truncate<T: Record>(&mut self) -> Result<(), StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.truncate::<T>();
+        }
+
+        let db = self.db(T::db_name(), T::db_flags())?;
+        let companion_dbs = self.open_companion_dbs::<T>()?;
+        let types = self.existing_db(TYPES_DB)?;
+        let schemas = self.existing_db(SCHEMAS_DB)?;
+        let capped = self.existing_db(CAPPED_DB)?;
+        let journal = self.journal_db()?;
+        let mut txn = self.begin_rw_txn()?;
+        txn.clear_db(db)?;
+        for companion_db in companion_dbs {
+            txn.clear_db(companion_db)?;
+        }
+        // A capped type starts counting its bytes over
+        if let Some(capped) = capped {
+            match txn.del(capped, &T::db_name(), None) {
+                Ok(()) | Err(lmdb::Error::NotFound) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // The emptied database is `T`'s from now on
+        if let Some(types) = types {
+            type_tag::retag::<T>(&mut txn, types)?;
+        }
+        if let Some(schemas) = schemas {
+            type_tag::set_schema_version::<T>(&mut txn, schemas)?;
+        }
+        if let Some(journal) = journal {
+            let mut ops = Storage::clear_ops::<T>();
+            if capped.is_some() {
+                ops.push(ChangeOp::Delete {
+                    db: CAPPED_DB.to_string(),
+                    key: T::db_name().as_bytes().to_vec(),
+                    value: None,
+                });
+            }
+            ops.push(ChangeOp::Put {
+                db: TYPES_DB.to_string(),
+                flags: 0,
+                key: T::db_name().as_bytes().to_vec(),
+                value: T::type_tag().as_bytes().to_vec(),
+            });
+            journal::append(&mut txn, journal, &ops)?;
+        }
+        txn.commit()?;
+        self.clear_cache::<T>();
+        Ok(())
+    }
+
+    /// Rebuilds every index of `T` from the records already stored, returning how many records
+    /// were indexed.  Indexes are kept up to date on save and delete, so this is needed after
+    /// adding an `#[index]` or `#[unique]` to a type that has records, to fill the new index in.
+    /// Fails with `StorageError::UniqueViolation`, leaving the indexes as they were, when stored
+    /// records share a value a unique index can only hold once.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   #[unique]
+    ///   name: std::string::String,
+    ///   #[index]
+    ///   country: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Paris".to_string(), country: "FR".to_string() })?;
+    ///     assert!(storage
+    ///         .save(&Place { id: 2, name: "Paris".to_string(), country: "US".to_string() })
+    ///         .is_err());
+    ///
+    ///     assert_eq!(1, storage.reindex::<Place>()?);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn reindex<T: Record>(&mut self) -> Result<usize, StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.reindex::<T>();
+        }
+
+        self.transaction(|tx| tx.reindex::<T>())
+    }
+
+    /// Saves every record of `T` stored in an earlier version of the type back in the current
+    /// one, see `StorageOptions::upgrade`, returning how many were upgraded.  Records are read
+    /// and written a chunk at a time, each chunk in a write transaction of its own, and `progress`
+    /// is called after every chunk.  Stopping halfway leaves the rest to be upgraded on read.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///     let upgraded = storage.migrate_all::<Place, _>(|progress| {
+    ///         println!("{}/{} records read", progress.read, progress.records);
+    ///     })?;
+    ///     assert_eq!(0, upgraded);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn migrate_all<T, F>(&mut self, progress: F) -> Result<usize, StorageError>
+    where
+        T: Record,
+        F: FnMut(&UpgradeProgress),
+    {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.migrate_all::<T, F>(progress);
+        }
+
+        migrate::upgrade_all::<T, F>(self, progress)
+    }
+
+    /// Completely removes the database for a specific type
+    pub fn drop<T: Record>(&mut self) -> Result<(), StorageError> {
+        if self.is_routed::<T>() {
+            return self.partition::<T>()?.drop::<T>();
+        }
+
+        let db = self.db(T::db_name(), T::db_flags())?;
+        let companion_dbs = self.open_companion_dbs::<T>()?;
+        let types = self.existing_db(TYPES_DB)?;
+        let schemas = self.existing_db(SCHEMAS_DB)?;
+        let journal = self.journal_db()?;
+        let mut txn = self.begin_rw_txn()?;
+        unsafe {
+            txn.drop_db(db)?;
+            for companion_db in companion_dbs {
+                txn.drop_db(companion_db)?;
+            }
+        }
+        for db in types.into_iter().chain(schemas) {
+            type_tag::forget(&mut txn, db, T::db_name())?;
+        }
+        // Followers empty the databases instead of dropping them
+        if let Some(journal) = journal {
+            let mut ops = Storage::clear_ops::<T>();
+            for db in &[TYPES_DB, SCHEMAS_DB] {
+                ops.push(ChangeOp::Delete {
+                    db: db.to_string(),
+                    key: T::db_name().as_bytes().to_vec(),
+                    value: None,
+                });
+            }
+            journal::append(&mut txn, journal, &ops)?;
+        }
+        txn.commit()?;
+
+        self.dbs.remove(T::db_name());
+        for (name, _) in Storage::companion_dbs::<T>() {
+            self.dbs.remove(&name);
+        }
+        self.clear_cache::<T>();
+        Ok(())
+    }
+}
+
+impl ChangeSink for Storage {
+    fn position(&mut self) -> Result<u64, StorageError> {
+        match self.existing_db(REPLICA_DB)? {
+            Some(db) => journal::position(&self.env()?.begin_ro_txn()?, db),
+            None => Ok(0),
+        }
+    }
+
+    fn send(&mut self, changes: Vec<Change>) -> Result<(), StorageError> {
+        self.apply_changes(changes).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     use crate::Key;
     use fake::faker::name::en::Name;
     use fake::{Dummy, Fake, Faker};
@@ -386,6 +3885,58 @@ mod tests {
         }
     }
 
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Reading {
+        id: u32,
+        value: f64,
+    }
+
+    impl Record for Reading {
+        type Key = Key<u32>;
+
+        fn key(&self) -> Key<u32> {
+            Key::from(self.id)
+        }
+
+        fn db_name() -> &'static str {
+            "Reading"
+        }
+
+        fn write_flags() -> lmdb::WriteFlags {
+            lmdb::WriteFlags::NO_OVERWRITE
+        }
+    }
+
+    #[derive(Storable, Debug, Serialize, Deserialize, PartialEq)]
+    #[key = "id"]
+    #[storable(partition = "logs")]
+    struct AccessLog {
+        id: u32,
+        path: String,
+    }
+
+    #[derive(Storable, Debug, Serialize, Deserialize, PartialEq)]
+    #[key = "id"]
+    #[storable(metadata)]
+    struct Memo {
+        id: u32,
+        body: String,
+        attachment: Vec<u8>,
+    }
+
+    #[derive(Deserialize)]
+    struct MemoRef<'a> {
+        #[allow(dead_code)]
+        id: u32,
+        body: &'a str,
+        attachment: &'a [u8],
+    }
+
+    impl RecordRef for Memo {
+        type Ref<'a> = MemoRef<'a>;
+    }
+
+    #[allow(clippy::eq_op)]
     fn clear_db(storage: &mut Storage) {
         match storage.truncate::<Person>() {
             Ok(_) => assert_eq!(0, 0),
@@ -395,22 +3946,24 @@ mod tests {
 
     #[test]
     fn test_that_we_keep_track_of_db_references() {
-        let mut storage = Storage::new(std::env::temp_dir()).expect("Could not open db storage");
+        let mut storage = Storage::temporary().expect("Could not open db storage");
         assert_eq!(0, storage.dbs.len());
 
         let p: Person = Faker.fake();
         storage.save(&p).expect("Could not save record");
-        assert_eq!(1, storage.dbs.len());
+        // Along with the database that holds each database's type tag
+        assert_eq!(2, storage.dbs.len());
 
         match storage.drop::<Person>() {
-            Ok(_) => assert_eq!(0, storage.dbs.len()),
+            Ok(_) => assert_eq!(vec![TYPES_DB], storage.dbs.keys().collect::<Vec<_>>()),
             Err(_) => assert_ne!(0, 0, "Could not drop database"),
         }
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn test_that_we_can_insert_and_get_records_with_a_storage_object() {
-        let mut storage = Storage::new(std::env::temp_dir()).expect("Could not open db storage");
+        let mut storage = Storage::temporary().expect("Could not open db storage");
         clear_db(&mut storage);
 
         let person: Person = Faker.fake();
@@ -428,6 +3981,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::let_unit_value)]
     fn test_that_we_can_batch_insert_records_and_then_interate() {
         let records_to_create: u32 = 10000;
         let mut records: Vec<Person> = vec![];
@@ -438,7 +3992,7 @@ mod tests {
             });
         }
 
-        let mut storage = Storage::new(std::env::temp_dir()).expect("Could not open db storage");
+        let mut storage = Storage::temporary().expect("Could not open db storage");
         clear_db(&mut storage);
 
         let _ = storage.save_batch(records).expect("Could not save records");
@@ -451,4 +4005,640 @@ mod tests {
 
         assert_eq!(records_to_create, cnt);
     }
+
+    #[test]
+    fn test_that_we_can_iterate_over_keys_only() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        clear_db(&mut storage);
+
+        let records: Vec<Person> = (0..100)
+            .map(|idx| Person {
+                id: idx,
+                name: Name().fake(),
+            })
+            .collect();
+        storage.save_batch(records).expect("Could not save records");
+
+        let keys: Vec<u32> = storage
+            .keys::<Person>()
+            .expect("Could not build key query")
+            .map(Key::into_inner)
+            .collect();
+
+        assert_eq!((0..100).collect::<Vec<u32>>(), keys);
+    }
+
+    #[test]
+    fn test_that_compacting_reclaims_free_pages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("compact-test");
+        let mut storage = Storage::new(&path).expect("Could not open db storage");
+
+        let records: Vec<Person> = (0..5000)
+            .map(|idx| Person {
+                id: idx,
+                name: Name().fake(),
+            })
+            .collect();
+        storage.save_batch(records).expect("Could not save records");
+        clear_db(&mut storage);
+
+        let before = storage.disk_usage().expect("Could not read usage");
+        assert!(storage.free_pages().expect("Could not count free pages") > 0);
+        let person = before
+            .databases
+            .iter()
+            .find(|db| db.name == "Person")
+            .expect("Person db is missing");
+        assert_eq!(0, person.entries);
+
+        storage.compact().expect("Could not compact");
+        let after = storage.disk_usage().expect("Could not read usage");
+        assert!(after.used_bytes < before.used_bytes);
+        assert_eq!(0, storage.free_pages().expect("Could not count free pages"));
+
+        // The storage keeps working on the compacted file
+        storage
+            .save(&Person {
+                id: 1,
+                name: "Ed Koch".to_string(),
+            })
+            .expect("Could not save record");
+        assert!(storage.get::<Person, _>(1).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_that_we_can_close_and_reopen_storage() {
+        let tmp = tempfile::tempdir().unwrap();
+        let first = tmp.path().join("reopen-first");
+        let second = tmp.path().join("reopen-second");
+        let mut storage = Storage::new(&first).expect("Could not open db storage");
+
+        let koch = Person {
+            id: 1,
+            name: "Ed Koch".to_string(),
+        };
+        storage.save(&koch).expect("Could not save record");
+
+        storage.close();
+        match storage.get::<Person, _>(1) {
+            Err(StorageError::Closed) => (),
+            _ => panic!("Expected the storage to be closed"),
+        }
+
+        storage.reopen().expect("Could not reopen");
+        assert_eq!(Some(koch), storage.get::<Person, _>(1).unwrap());
+
+        storage.reopen_at(&second).expect("Could not reopen");
+        assert_eq!(0, storage.query::<Person>().unwrap().count());
+    }
+
+    #[test]
+    fn test_that_counters_are_kept_per_record() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.drop::<Person>().expect("Could not drop");
+
+        assert_eq!(0, storage.counter::<Person, _>(1, "logins").unwrap());
+        for _ in 0..10 {
+            storage
+                .increment::<Person, _>(1, "logins", 1)
+                .expect("Could not increment");
+        }
+        assert_eq!(7, storage.increment::<Person, _>(1, "logins", -3).unwrap());
+        assert_eq!(5, storage.increment::<Person, _>(2, "logins", 5).unwrap());
+        assert_eq!(
+            -1,
+            storage.increment::<Person, _>(1, "strikes", -1).unwrap()
+        );
+
+        assert_eq!(7, storage.counter::<Person, _>(1, "logins").unwrap());
+        assert_eq!(5, storage.counter::<Person, _>(2, "logins").unwrap());
+    }
+
+    #[test]
+    fn test_that_write_flags_are_applied_on_save() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .truncate::<Reading>()
+            .expect("Could not truncate Reading db");
+
+        let reading = Reading { id: 1, value: 0.5 };
+        storage.save(&reading).expect("Could not save record");
+
+        match storage.save(&reading) {
+            Err(StorageError::DBError {
+                source: lmdb::Error::KeyExist,
+            }) => (),
+            _ => panic!("Expected NO_OVERWRITE to reject the second save"),
+        }
+    }
+
+    #[test]
+    fn test_that_partitioned_types_are_stored_in_their_own_environment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("partitions");
+        let mut storage = Storage::new(&root).expect("Could not open db storage");
+
+        storage
+            .save(&AccessLog {
+                id: 1,
+                path: "/".to_string(),
+            })
+            .expect("Could not save log");
+        storage
+            .save(&Person {
+                id: 1,
+                name: "Ada".to_string(),
+            })
+            .expect("Could not save person");
+
+        assert!(root.join("logs").join("data.mdb").exists());
+        let names: Vec<String> = storage
+            .disk_usage()
+            .unwrap()
+            .databases
+            .into_iter()
+            .map(|db| db.name)
+            .collect();
+        assert!(names.contains(&"Person".to_string()));
+        assert!(!names.contains(&"AccessLog".to_string()));
+
+        let log: Option<AccessLog> = storage.get(1).unwrap();
+        assert_eq!("/", log.unwrap().path);
+        assert_eq!(1, storage.query::<AccessLog>().unwrap().count());
+
+        match storage.transaction(|tx| {
+            tx.save(&AccessLog {
+                id: 2,
+                path: "/about".to_string(),
+            })
+        }) {
+            Err(StorageError::WrongPartition { db_name }) => assert_eq!("AccessLog", db_name),
+            _ => panic!("Expected the transaction to reject a partitioned type"),
+        }
+    }
+
+    #[test]
+    fn test_that_tenants_only_see_their_own_records() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let ada = Person {
+            id: 1,
+            name: "Ada".to_string(),
+        };
+        storage.scoped("acme").unwrap().save(&ada).unwrap();
+        storage
+            .scoped("acme")
+            .unwrap()
+            .save(&AccessLog {
+                id: 1,
+                path: "/".to_string(),
+            })
+            .unwrap();
+
+        let acme = storage.scoped("acme").unwrap();
+        assert_eq!(Some("acme"), acme.tenant());
+        assert_eq!(Some(ada), acme.get::<Person, _>(1).unwrap());
+        assert_eq!(1, acme.count::<AccessLog>().unwrap());
+        assert_eq!(
+            None,
+            storage
+                .scoped("globex")
+                .unwrap()
+                .get::<Person, _>(1)
+                .unwrap()
+        );
+        assert_eq!(0, storage.count::<Person>().unwrap());
+        assert_eq!(None, storage.tenant());
+
+        for tenant in ["", "../acme", "a/b", "acme corp"] {
+            assert!(matches!(
+                storage.scoped(tenant),
+                Err(StorageError::InvalidTenant { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_that_records_can_be_viewed_in_their_borrowed_form() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Memo {
+                id: 1,
+                body: "Water the plants".to_string(),
+                attachment: vec![1, 2, 3],
+            })
+            .expect("Could not save memo");
+
+        let viewed = storage
+            .view::<Memo, _, _, _>(1, |memo| (memo.body.to_string(), memo.attachment.len()))
+            .expect("Could not view memo");
+        assert_eq!(Some(("Water the plants".to_string(), 3)), viewed);
+        assert_eq!(
+            None,
+            storage
+                .view::<Memo, _, _, _>(2, |memo| memo.body.len())
+                .unwrap()
+        );
+
+        let db = storage.db("Memo", Memo::db_flags()).unwrap();
+        let key: Vec<u8> = Key::from(3u32).into();
+        let mut txn = storage.env().unwrap().begin_rw_txn().unwrap();
+        txn.put(db, &key, &[0xff], lmdb::WriteFlags::empty())
+            .unwrap();
+        txn.commit().unwrap();
+        assert!(matches!(
+            storage.view::<Memo, _, _, _>(3, |memo| memo.body.len()),
+            Err(StorageError::Undecodable { db_name: "Memo", .. })
+        ));
+    }
+
+    #[test]
+    fn test_that_access_hints_and_warming_leave_records_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::default().readahead(false).write_map(true);
+        let mut storage =
+            Storage::open_with(dir.path(), options).expect("Could not open db storage");
+        assert_eq!(0, storage.warm::<Person>().unwrap());
+
+        storage
+            .save_batch(
+                (0..50)
+                    .map(|id| Person {
+                        id,
+                        name: "x".repeat(id as usize * 100),
+                    })
+                    .collect(),
+            )
+            .expect("Could not save people");
+
+        assert_eq!(50, storage.warm::<Person>().unwrap());
+        for pattern in &[
+            AccessPattern::Sequential,
+            AccessPattern::Random,
+            AccessPattern::Normal,
+        ] {
+            storage.query_hint(*pattern).expect("Could not advise");
+            assert_eq!(50, storage.query::<Person>().unwrap().count());
+        }
+    }
+
+    #[test]
+    fn test_that_databases_are_listed_with_their_entries() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        clear_db(&mut storage);
+        storage
+            .save_batch(vec![
+                Person {
+                    id: 1,
+                    name: "Ada".to_string(),
+                },
+                Person {
+                    id: 2,
+                    name: "Grace".to_string(),
+                },
+            ])
+            .expect("Could not save people");
+        storage.increment::<Person, _>(1, "logins", 1).unwrap();
+
+        let databases = storage.list_databases().expect("Could not list databases");
+        let names: Vec<&str> = databases.iter().map(|db| db.name.as_str()).collect();
+        let mut sorted = names.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, names);
+
+        let people = databases.iter().find(|db| db.name == "Person").unwrap();
+        assert_eq!(2, people.entries);
+        assert!(people.bytes > 0);
+        assert!(names.contains(&"Person#counters"));
+    }
+
+    #[test]
+    fn test_that_existing_environments_are_opened_without_changes() {
+        use lmdb::WriteFlags;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("existing");
+        assert!(Storage::open_existing(&dir).is_err());
+        assert!(!dir.exists());
+
+        // Written the way a tool without named databases would, plus one sorted duplicate db
+        create_dir_all(&dir).unwrap();
+        {
+            let env = lmdb::Environment::new().set_max_dbs(2).open(&dir).unwrap();
+            let dups = env
+                .create_db(Some("labels"), DatabaseFlags::DUP_SORT)
+                .unwrap();
+            let main = env.open_db(None).unwrap();
+            let mut txn = env.begin_rw_txn().unwrap();
+            txn.put(main, b"00000001", b"image", WriteFlags::empty())
+                .unwrap();
+            txn.put(dups, b"cat", b"1", WriteFlags::empty()).unwrap();
+            txn.put(dups, b"cat", b"2", WriteFlags::empty()).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let mut storage = Storage::open_existing(&dir).expect("Could not open existing storage");
+        let labels: Vec<(Vec<u8>, Vec<u8>)> = storage
+            .raw("labels")
+            .unwrap()
+            .scan_bytes()
+            .unwrap()
+            .collect();
+        assert_eq!(
+            vec![
+                (b"cat".to_vec(), b"1".to_vec()),
+                (b"cat".to_vec(), b"2".to_vec())
+            ],
+            labels
+        );
+        assert_eq!(
+            Some(b"image".to_vec()),
+            storage
+                .raw_unnamed()
+                .unwrap()
+                .get_bytes(b"00000001")
+                .unwrap()
+        );
+
+        assert!(storage.raw("missing").is_err());
+        assert!(storage
+            .save(&Person {
+                id: 1,
+                name: "Ada".to_string()
+            })
+            .is_err());
+        let names: Vec<String> = storage
+            .list_databases()
+            .unwrap()
+            .into_iter()
+            .map(|db| db.name)
+            .collect();
+        assert_eq!(vec!["labels".to_string()], names);
+    }
+
+    #[test]
+    fn test_that_reading_unsaved_types_does_not_create_their_database() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        assert_eq!(0, storage.query::<Person>().unwrap().count());
+        assert_eq!(0, storage.keys::<Person>().unwrap().count());
+        assert_eq!(None, storage.get::<Person, _>(1).unwrap());
+        assert_eq!(0, storage.counter::<Person, _>(1, "logins").unwrap());
+        assert!(storage.list_databases().unwrap().is_empty());
+
+        storage
+            .save(&Person {
+                id: 1,
+                name: "Ada".to_string(),
+            })
+            .expect("Could not save person");
+        assert!(storage.get::<Person, _>(2).unwrap().is_none());
+        assert_eq!(1, storage.query::<Person>().unwrap().count());
+    }
+
+    #[test]
+    fn test_that_open_readers_are_listed_and_old_queries_are_stopped() {
+        use crate::ReadAgePolicy;
+        use std::time::Duration;
+
+        let dir = tempfile::tempdir().unwrap();
+        let options =
+            StorageOptions::default().max_read_age(Duration::from_millis(1), ReadAgePolicy::Abort);
+        let mut storage =
+            Storage::open_with(dir.path(), options).expect("Could not open db storage");
+        storage
+            .save(&Person {
+                id: 1,
+                name: "Ada".to_string(),
+            })
+            .expect("Could not save person");
+
+        {
+            let txn = storage.env().unwrap().begin_ro_txn().unwrap();
+            let readers = storage.readers().expect("Could not list readers");
+            assert!(readers
+                .iter()
+                .any(|reader| reader.pid == std::process::id() && reader.txn_id.is_some()));
+            drop(txn);
+        }
+        assert_eq!(0, storage.clear_stale_readers().unwrap());
+
+        let mut query = storage.query::<Person>().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(query.next().is_none());
+        assert!(matches!(
+            query.error(),
+            Some(StorageError::ReadTooOld { .. })
+        ));
+        drop(query);
+
+        let mut checked = storage.query_checked::<Person>().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        match checked.next() {
+            Some(Err(StorageError::ReadTooOld { age })) => assert!(age >= Duration::from_millis(5)),
+            _ => panic!("Expected the query to be stopped"),
+        }
+        assert!(checked.next().is_none());
+    }
+
+    fn env_flags(storage: &Storage) -> lmdb::EnvironmentFlags {
+        let mut flags = 0;
+        unsafe { lmdb_sys::mdb_env_get_flags(storage.env().unwrap().env(), &mut flags) };
+        lmdb::EnvironmentFlags::from_bits_truncate(flags)
+    }
+
+    #[test]
+    fn test_that_durability_can_be_relaxed_for_bulk_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = StorageOptions::default().durability(Durability::Async);
+        let mut storage = Storage::open_with(dir.path(), options).expect("Could not open storage");
+        assert!(env_flags(&storage).contains(lmdb::EnvironmentFlags::NO_SYNC));
+
+        for id in 0..100 {
+            storage
+                .save(&Person {
+                    id,
+                    name: format!("Person {}", id),
+                })
+                .unwrap();
+        }
+        storage.flush().expect("Could not flush");
+
+        storage.set_durability(Durability::Relaxed).unwrap();
+        let flags = env_flags(&storage);
+        assert!(!flags.contains(lmdb::EnvironmentFlags::NO_SYNC));
+        assert!(flags.contains(lmdb::EnvironmentFlags::NO_META_SYNC));
+
+        storage.close();
+        storage.reopen().unwrap();
+        assert_eq!(100, storage.count::<Person>().unwrap());
+        assert_eq!(Durability::Relaxed, storage.options.durability);
+    }
+
+    #[test]
+    fn test_that_open_modes_check_whether_the_environment_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("typo");
+        let must_exist = StorageOptions::default().open_mode(OpenMode::MustExist);
+        assert!(matches!(
+            Storage::open_with(&path, must_exist.clone()),
+            Err(StorageError::Missing { .. })
+        ));
+        assert!(!path.exists());
+
+        let must_create = StorageOptions::default().open_mode(OpenMode::MustCreate);
+        Storage::open_with(&path, must_create.clone()).expect("Could not create storage");
+        assert!(matches!(
+            Storage::open_with(&path, must_create),
+            Err(StorageError::AlreadyExists { .. })
+        ));
+        Storage::open_with(&path, must_exist).expect("Could not open existing storage");
+
+        // An empty directory holds no environment yet
+        assert!(matches!(
+            Storage::open_existing(dir.path()),
+            Err(StorageError::Missing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_that_every_type_gets_an_environment_of_its_own_per_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = dir.path().join("other-disk");
+        let options = StorageOptions::default()
+            .layout(crate::Layout::PerDatabase)
+            .location::<AccessLog>(logs.clone());
+        let mut storage = Storage::open_with(dir.path().join("db"), options).unwrap();
+
+        let ada = Person {
+            id: 1,
+            name: "Ada".to_string(),
+        };
+        storage.save(&ada).unwrap();
+        storage
+            .save(&AccessLog {
+                id: 1,
+                path: "/".to_string(),
+            })
+            .unwrap();
+        assert!(dir
+            .path()
+            .join("db")
+            .join("Person")
+            .join("data.mdb")
+            .exists());
+        assert!(logs.join("data.mdb").exists());
+        assert!(!dir.path().join("db").join("logs").exists());
+
+        // One type per transaction, since each lives in another environment
+        match storage.transaction(|tx| tx.save(&ada)) {
+            Err(StorageError::WrongPartition { db_name }) => assert_eq!("Person", db_name),
+            _ => panic!("Expected the transaction to reject a type of another environment"),
+        }
+
+        let (reader, _) = storage.split().unwrap();
+        assert_eq!(Some(ada), reader.get::<Person, _>(1).unwrap());
+        assert_eq!(1, reader.query::<AccessLog>().unwrap().count());
+    }
+
+    #[test]
+    fn test_that_records_are_deleted_in_bulk_across_chunks() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .transaction(|tx| {
+                for id in 0..2500 {
+                    tx.save(&Person {
+                        id,
+                        name: format!("person {}", id),
+                    })?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            1250,
+            storage
+                .delete_where::<Person, _>(|person| person.id % 2 == 0)
+                .unwrap()
+        );
+        assert_eq!(
+            50,
+            storage.delete_range::<Person, u32, _>(100..200).unwrap()
+        );
+        assert_eq!(
+            1,
+            storage
+                .delete_range::<Person, u32, _>((Bound::Excluded(2497), Bound::Included(2499)))
+                .unwrap()
+        );
+        assert_eq!(
+            0,
+            storage.delete_range::<Person, u32, _>(100..=199).unwrap()
+        );
+
+        assert_eq!(1199, storage.count::<Person>().unwrap());
+        assert!(storage.get::<Person, _>(2497).unwrap().is_some());
+        assert!(storage.get::<Person, _>(2499).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_that_deleting_a_missing_record_is_reported_apart_from_lmdb_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = Storage::new(dir.path().join("lenient")).unwrap();
+        let ada = Person {
+            id: 1,
+            name: "Ada".to_string(),
+        };
+
+        // Before the type's database exists, and after
+        assert!(!storage.delete(&ada).unwrap());
+        assert!(storage.existing_db(Person::db_name()).unwrap().is_none());
+        storage.save(&ada).unwrap();
+        assert!(storage.delete(&ada).unwrap());
+        assert!(!storage.delete(&ada).unwrap());
+        match storage.delete_existing(&ada) {
+            Err(StorageError::NotFound { db_name, key }) => {
+                assert_eq!("Person", db_name);
+                assert_eq!(Vec::<u8>::from(Key::from(1u32)), key);
+            }
+            _ => panic!("Expected deleting a missing record to fail"),
+        }
+
+        let options = StorageOptions::default().strict_deletes(true);
+        let mut strict = Storage::open_with(dir.path().join("strict"), options).unwrap();
+        assert!(matches!(
+            strict.delete(&ada),
+            Err(StorageError::NotFound { .. })
+        ));
+        strict.save(&ada).unwrap();
+        strict.delete_existing(&ada).unwrap();
+        assert_eq!(0, strict.count::<Person>().unwrap());
+    }
+
+    #[test]
+    fn test_that_a_read_view_keeps_its_snapshot() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let person = |id, name: &str| Person {
+            id,
+            name: name.to_string(),
+        };
+        storage.save(&person(1, "Ada")).unwrap();
+        let mut writer = storage.sibling().unwrap();
+
+        let view = storage.read_view().unwrap();
+        writer.save(&person(1, "Ada Lovelace")).unwrap();
+        writer.save(&person(2, "Grace")).unwrap();
+        assert_eq!(Some(person(1, "Ada")), view.get::<Person, _>(1).unwrap());
+        assert_eq!(None, view.get::<Person, _>(2).unwrap());
+        assert_eq!(vec![person(1, "Ada")], view.all::<Person>().unwrap());
+        drop(view);
+
+        let view = storage.read_view().unwrap();
+        assert_eq!(2, view.count::<Person>().unwrap());
+        assert_eq!(
+            Some(person(1, "Ada Lovelace")),
+            view.get::<Person, _>(1).unwrap()
+        );
+    }
 }
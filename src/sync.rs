@@ -0,0 +1,361 @@
+//! Version vectors for syncing storages that each take writes, like a desktop and a mobile copy.
+//!
+//! Storages opened with `StorageOptions::versions` pick a random replica id and stamp every record
+//! write with a dot: the replica that made it and a counter that grows with each write the replica
+//! makes.  A record's version vector holds the highest counter of every replica whose writes the
+//! record has seen, so comparing two versions tells whether one descends from the other or both
+//! were written concurrently.  A storage's sync state holds the highest counter it has seen from
+//! each replica, and `Storage::changes_since` sends a peer the current version of every record
+//! last written at a dot the peer hasn't seen.
+//!
+//! Everything is kept in one database: the replica id, the highest counter seen per replica, the
+//! version of every record keyed by database and key, and each record's dot so versions can be
+//! read in the order replicas wrote them.
+
+use lmdb::{Database, RwTransaction, Transaction, WriteFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::queue::{entries_from, read_u64};
+use crate::StorageError;
+
+/// The database that holds replica ids, sync states and record versions
+pub(crate) const SYNC_DB: &str = "nostalgia#sync";
+
+const REPLICA_ID: &[u8] = b"id";
+const SEEN: u8 = b's';
+const VERSION: u8 = b'v';
+const DOT: u8 = b'd';
+
+/// The highest counter seen from each replica, for a record or for a whole storage
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(pub BTreeMap<u64, u64>);
+
+impl VersionVector {
+    /// The highest counter seen from `replica`, 0 when none was
+    pub fn get(&self, replica: u64) -> u64 {
+        self.0.get(&replica).copied().unwrap_or(0)
+    }
+
+    /// Whether this vector has seen every write `other` has
+    pub fn descends(&self, other: &VersionVector) -> bool {
+        other
+            .0
+            .iter()
+            .all(|(replica, counter)| self.get(*replica) >= *counter)
+    }
+
+    /// Adds the writes `other` has seen to this vector
+    pub fn join(&mut self, other: &VersionVector) {
+        for (replica, counter) in &other.0 {
+            let seen = self.0.entry(*replica).or_insert(0);
+            *seen = (*seen).max(*counter);
+        }
+    }
+}
+
+/// The version of a record one storage holds, as sent to a peer by `Storage::changes_since`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncChange {
+    /// The database the record is stored in
+    pub db: String,
+    /// The record's key
+    pub key: Vec<u8>,
+    /// The stored record, `None` when it was deleted
+    pub value: Option<Vec<u8>>,
+    /// The writes the record has seen
+    pub version: VersionVector,
+    /// The replica that made the record's last write
+    pub replica: u64,
+    /// The replica's counter at the record's last write
+    pub counter: u64,
+}
+
+/// A record's version vector and the dot of its last write
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Version {
+    pub vector: VersionVector,
+    pub replica: u64,
+    pub counter: u64,
+}
+
+fn version_key(db_name: &str, key: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![VERSION];
+    bytes.extend(db_name.as_bytes());
+    bytes.push(0);
+    bytes.extend(key);
+    bytes
+}
+
+fn dot_key(replica: u64, counter: u64) -> Vec<u8> {
+    let mut bytes = vec![DOT];
+    bytes.extend(&replica.to_be_bytes());
+    bytes.extend(&counter.to_be_bytes());
+    bytes
+}
+
+fn seen_key(replica: u64) -> Vec<u8> {
+    let mut bytes = vec![SEEN];
+    bytes.extend(&replica.to_be_bytes());
+    bytes
+}
+
+// The replica id of the storage, picked at random the first time one is needed.  `RandomState`
+// is seeded randomly, which is all the randomness ids need
+fn replica_id(txn: &mut RwTransaction, db: Database) -> Result<u64, StorageError> {
+    match txn.get(db, &REPLICA_ID) {
+        Ok(bytes) => return Ok(read_u64(bytes)),
+        Err(lmdb::Error::NotFound) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos()),
+    );
+    let id = hasher.finish();
+    txn.put(db, &REPLICA_ID, &id.to_be_bytes(), WriteFlags::empty())?;
+    Ok(id)
+}
+
+/// The highest counter the storage has seen from each replica
+pub(crate) fn seen(txn: &impl Transaction, db: Database) -> Result<VersionVector, StorageError> {
+    Ok(VersionVector(
+        entries_from(txn, db, &[SEEN], &[SEEN], usize::MAX)?
+            .into_iter()
+            .map(|(key, value)| (read_u64(&key[1..]), read_u64(&value)))
+            .collect(),
+    ))
+}
+
+// The highest counter the storage has seen from `replica`
+fn seen_from(txn: &impl Transaction, db: Database, replica: u64) -> Result<u64, StorageError> {
+    match txn.get(db, &seen_key(replica)) {
+        Ok(bytes) => Ok(read_u64(bytes)),
+        Err(lmdb::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records that the storage has seen `replica`'s write numbered `counter`
+pub(crate) fn see(
+    txn: &mut RwTransaction,
+    db: Database,
+    replica: u64,
+    counter: u64,
+) -> Result<(), StorageError> {
+    if counter > seen_from(txn, db, replica)? {
+        txn.put(
+            db,
+            &seen_key(replica),
+            &counter.to_be_bytes(),
+            WriteFlags::empty(),
+        )?;
+    }
+    Ok(())
+}
+
+/// The version of the record stored under `key` in `db_name`, `None` when it was never written
+pub(crate) fn version(
+    txn: &impl Transaction,
+    db: Database,
+    db_name: &str,
+    key: &[u8],
+) -> Result<Option<Version>, StorageError> {
+    match txn.get(db, &version_key(db_name, key)) {
+        Ok(bytes) => Ok(Some(bincode::deserialize(bytes)?)),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the version of a record, moving its dot to the one of `version`
+pub(crate) fn set_version(
+    txn: &mut RwTransaction,
+    db: Database,
+    db_name: &str,
+    key: &[u8],
+    version: &Version,
+) -> Result<(), StorageError> {
+    if let Some(previous) = self::version(txn, db, db_name, key)? {
+        match txn.del(db, &dot_key(previous.replica, previous.counter), None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    txn.put(
+        db,
+        &version_key(db_name, key),
+        &bincode::serialize(version)?,
+        WriteFlags::empty(),
+    )?;
+    txn.put(
+        db,
+        &dot_key(version.replica, version.counter),
+        &bincode::serialize(&(db_name, key))?,
+        WriteFlags::empty(),
+    )?;
+    Ok(())
+}
+
+/// Stamps a write this storage made to a record with its next dot
+pub(crate) fn stamp(
+    txn: &mut RwTransaction,
+    db: Database,
+    db_name: &str,
+    key: &[u8],
+) -> Result<(), StorageError> {
+    let replica = replica_id(txn, db)?;
+    let counter = seen_from(txn, db, replica)? + 1;
+    see(txn, db, replica, counter)?;
+
+    let mut vector = version(txn, db, db_name, key)?
+        .map(|version| version.vector)
+        .unwrap_or_default();
+    vector.0.insert(replica, counter);
+    let version = Version {
+        vector,
+        replica,
+        counter,
+    };
+    set_version(txn, db, db_name, key, &version)
+}
+
+/// The database, key and version of every record last written at a dot `peer` hasn't seen, in
+/// the order each replica wrote them
+pub(crate) fn unseen(
+    txn: &impl Transaction,
+    db: Database,
+    peer: &VersionVector,
+) -> Result<Vec<(String, Vec<u8>, Version)>, StorageError> {
+    let mut records = vec![];
+    for replica in seen(txn, db)?.0.keys() {
+        let prefix = &dot_key(*replica, 0)[..9];
+        let start = dot_key(*replica, peer.get(*replica) + 1);
+        for (_, value) in entries_from(txn, db, &start, prefix, usize::MAX)? {
+            let (db_name, key): (String, Vec<u8>) = bincode::deserialize(&value)?;
+            let version =
+                version(txn, db, &db_name, &key)?.ok_or_else(|| StorageError::Undecodable {
+                    db_name: SYNC_DB,
+                    key: version_key(&db_name, &key),
+                })?;
+            records.push((db_name, key, version));
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Merge, Record, Storage, StorageOptions};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq, Clone)]
+    #[key = "id"]
+    struct Note {
+        id: u32,
+        #[index]
+        folder: String,
+        text: String,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Tally {
+        id: u32,
+        count: u32,
+    }
+
+    impl Merge for Tally {
+        fn merge(ours: Tally, theirs: Tally) -> Tally {
+            Tally {
+                id: ours.id,
+                count: ours.count.max(theirs.count),
+            }
+        }
+    }
+
+    fn note(id: u32, folder: &str, text: &str) -> Note {
+        Note {
+            id,
+            folder: folder.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn replica() -> (tempfile::TempDir, Storage) {
+        let dir = tempfile::tempdir().expect("Could not create directory");
+        let mut storage = Storage::open_with(dir.path(), StorageOptions::default().versions(true))
+            .expect("Could not open db storage");
+        storage.register::<Note>().unwrap();
+        storage.register::<Tally>().unwrap();
+        storage.merge_on_apply::<Tally>();
+        (dir, storage)
+    }
+
+    // Sends each side the changes the other hasn't seen
+    fn sync(a: &mut Storage, b: &mut Storage) {
+        let to_b = a.changes_since(&b.sync_state().unwrap()).unwrap();
+        let to_a = b.changes_since(&a.sync_state().unwrap()).unwrap();
+        b.apply_remote(to_b).unwrap();
+        a.apply_remote(to_a).unwrap();
+    }
+
+    #[test]
+    fn test_that_two_replicas_converge() {
+        let (_desktop_dir, mut desktop) = replica();
+        let (_mobile_dir, mut mobile) = replica();
+
+        desktop.save(&note(1, "inbox", "milk")).unwrap();
+        mobile.save(&note(2, "work", "slides")).unwrap();
+        sync(&mut desktop, &mut mobile);
+        assert_eq!(
+            2,
+            desktop
+                .changes_since(&VersionVector::default())
+                .unwrap()
+                .len()
+        );
+        assert!(desktop
+            .changes_since(&mobile.sync_state().unwrap())
+            .unwrap()
+            .is_empty());
+        let inbox: Vec<Note> = mobile.find_by_index("folder", "inbox").unwrap();
+        assert_eq!(vec![note(1, "inbox", "milk")], inbox);
+
+        // A write that descends from the other side's replaces it, and is moved in its index
+        mobile.save(&note(1, "done", "milk")).unwrap();
+        sync(&mut desktop, &mut mobile);
+        assert_eq!(
+            Some(note(1, "done", "milk")),
+            desktop.get::<Note, _>(1u32).unwrap()
+        );
+        assert!(desktop
+            .find_by_index::<Note, _>("folder", "inbox")
+            .unwrap()
+            .is_empty());
+
+        // Concurrent writes end up the same on both sides, whichever wins
+        desktop.save(&note(2, "work", "slides v2")).unwrap();
+        mobile.delete(&note(2, "work", "")).unwrap();
+        desktop.save(&Tally { id: 1, count: 3 }).unwrap();
+        mobile.save(&Tally { id: 1, count: 5 }).unwrap();
+        sync(&mut desktop, &mut mobile);
+        sync(&mut desktop, &mut mobile);
+        assert_eq!(
+            desktop.get::<Note, _>(2u32).ok().flatten(),
+            mobile.get::<Note, _>(2u32).ok().flatten()
+        );
+        // Merged types take both sides into account
+        assert_eq!(5, desktop.get::<Tally, _>(1u32).unwrap().unwrap().count);
+        assert_eq!(5, mobile.get::<Tally, _>(1u32).unwrap().unwrap().count);
+        assert_eq!(desktop.sync_state().unwrap(), mobile.sync_state().unwrap());
+
+        let mut plain = Storage::temporary().expect("Could not open db storage");
+        assert!(plain.sync_state().is_err());
+    }
+}
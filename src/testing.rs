@@ -0,0 +1,152 @@
+//! Helpers for testing record types and key encodings with generated values.
+//!
+//! Values are generated with `fake`, so record types derive `fake::Dummy` for their fields to be
+//! filled in.  The helpers panic with a description of what went wrong, like `assert!` does.
+//! Only built with the `testing` feature.
+
+use fake::{Dummy, Fake, Faker};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+
+use crate::{Key, Record, StorageApi};
+
+/// Generates `count` values of `T`
+pub fn samples<T: Dummy<Faker>>(count: usize) -> Vec<T> {
+    (0..count).map(|_| Faker.fake()).collect()
+}
+
+/// Saves `count` generated records of `T`, then checks that each one reads back unchanged, that
+/// every record is returned in the order of its key's bytes, and that deleted records are gone.
+///
+/// The type's records are truncated first, so use a storage set aside for tests.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{testing, MemoryStorage, Record, Key};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize, fake::Dummy, Debug, PartialEq)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() {
+///     testing::assert_round_trips::<Place, _>(&mut MemoryStorage::new(), 100);
+/// }
+/// ```
+pub fn assert_round_trips<T, S>(storage: &mut S, count: usize)
+where
+    T: Record + Dummy<Faker> + Debug + PartialEq,
+    S: StorageApi,
+{
+    storage
+        .truncate::<T>()
+        .expect("Could not truncate the records");
+
+    // Generated records can share a key, in which case the last one saved is the one stored
+    let mut expected = BTreeMap::new();
+    for record in samples::<T>(count) {
+        storage.save(&record).expect("Could not save a record");
+        let key: Vec<u8> = record.key().into();
+        expected.insert(key, record);
+    }
+
+    for record in expected.values() {
+        let stored = storage
+            .get::<T, T::Key>(record.key())
+            .expect("Could not read a record back");
+        assert_eq!(
+            Some(record),
+            stored.as_ref(),
+            "record changed on the way back"
+        );
+    }
+
+    let all = storage.all::<T>().expect("Could not read the records");
+    assert_eq!(
+        expected.values().collect::<Vec<_>>(),
+        all.iter().collect::<Vec<_>>(),
+        "records weren't returned in the order of their keys"
+    );
+    assert_eq!(
+        expected.len(),
+        storage.count::<T>().expect("Could not count")
+    );
+
+    for record in expected.values() {
+        storage.delete(record).expect("Could not delete a record");
+    }
+    assert_eq!(
+        0,
+        storage.count::<T>().expect("Could not count"),
+        "deleted records are still stored"
+    );
+}
+
+/// Checks that the bytes of `values` encoded as keys sort the same way as the values, and that
+/// each one decodes back to the value it was encoded from
+pub fn assert_key_order<K>(values: &[K])
+where
+    K: Ord + Clone + Debug,
+    Key<K>: Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]>,
+{
+    let mut encoded: Vec<(Vec<u8>, &K)> = values
+        .iter()
+        .map(|value| (Key::from(value.clone()).into(), value))
+        .collect();
+    encoded.sort();
+
+    for pair in encoded.windows(2) {
+        assert!(
+            pair[0].1 <= pair[1].1,
+            "{:?} is encoded after {:?}",
+            pair[0].1,
+            pair[1].1
+        );
+    }
+    for (bytes, value) in encoded {
+        match Key::<K>::try_from(&bytes[..]) {
+            Ok(decoded) => assert_eq!(value, decoded.value(), "key changed on the way back"),
+            Err(_) => panic!("Could not decode the key of {:?}", value),
+        }
+    }
+}
+
+/// Like `assert_key_order`, with `count` generated values
+pub fn assert_generated_key_order<K>(count: usize)
+where
+    K: Ord + Clone + Debug + Dummy<Faker>,
+    Key<K>: Into<Vec<u8>> + for<'a> TryFrom<&'a [u8]>,
+{
+    assert_key_order(&samples::<K>(count));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryStorage, Storage};
+    use fake::faker::name::en::Name;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Dummy, Debug, PartialEq)]
+    #[key = "id"]
+    struct Account {
+        #[dummy(faker = "1..50")]
+        id: u64,
+        #[dummy(faker = "Name()")]
+        owner: String,
+        balance: i64,
+    }
+
+    #[test]
+    fn test_that_generated_records_round_trip() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        assert_round_trips::<Account, _>(&mut storage, 200);
+        assert_round_trips::<Account, _>(&mut MemoryStorage::new(), 200);
+    }
+}
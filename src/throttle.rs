@@ -0,0 +1,90 @@
+//! Pacing bulk writes, so a backfill leaves the disk to interactive readers now and then.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How fast a bulk write may go, see `Batch::throttle`.  The writes are split into transactions
+/// of `chunk` operations, and the writer sleeps between them whenever it is ahead of either limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttle {
+    /// The most bytes of keys and values written per second, if limited
+    pub bytes_per_sec: Option<u64>,
+    /// The most transactions committed per second, if limited
+    pub txns_per_sec: Option<u32>,
+    /// How many operations each transaction writes
+    pub chunk: usize,
+}
+
+impl Default for Throttle {
+    fn default() -> Throttle {
+        Throttle {
+            bytes_per_sec: None,
+            txns_per_sec: None,
+            chunk: 1000,
+        }
+    }
+}
+
+impl Throttle {
+    /// Sets the most bytes of keys and values written per second
+    pub fn bytes_per_sec(mut self, bytes: u64) -> Throttle {
+        self.bytes_per_sec = Some(bytes);
+        self
+    }
+
+    /// Sets the most transactions committed per second
+    pub fn txns_per_sec(mut self, txns: u32) -> Throttle {
+        self.txns_per_sec = Some(txns);
+        self
+    }
+
+    /// Sets how many operations each transaction writes
+    pub fn chunk(mut self, operations: usize) -> Throttle {
+        self.chunk = operations.max(1);
+        self
+    }
+}
+
+/// Keeps a run of commits under a throttle's limits
+pub(crate) struct Pacer {
+    throttle: Throttle,
+    started: Instant,
+    bytes: u64,
+    txns: u64,
+}
+
+impl Pacer {
+    pub(crate) fn new(throttle: Throttle) -> Pacer {
+        Pacer {
+            throttle,
+            started: Instant::now(),
+            bytes: 0,
+            txns: 0,
+        }
+    }
+
+    /// Counts a committed transaction that wrote `bytes`, then sleeps until the run is back
+    /// under the limits
+    pub(crate) fn committed(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.txns += 1;
+
+        let by_bytes = self
+            .throttle
+            .bytes_per_sec
+            .map(|limit| seconds(self.bytes, limit));
+        let by_txns = self
+            .throttle
+            .txns_per_sec
+            .map(|limit| seconds(self.txns, u64::from(limit)));
+        let due = by_bytes.into_iter().chain(by_txns).max();
+        if let Some(wait) = due.and_then(|due| due.checked_sub(self.started.elapsed())) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+// How long `amount` takes at `limit` per second
+fn seconds(amount: u64, limit: u64) -> Duration {
+    Duration::from_secs_f64(amount as f64 / limit.max(1) as f64)
+}
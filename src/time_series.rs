@@ -0,0 +1,322 @@
+//! Points of many series stored in a single database.
+//!
+//! Points are keyed by their series and then their timestamp, both in big endian, so the points
+//! of a series in a time range sit next to each other and come back in time order.  Downsampling
+//! folds points into buckets as the cursor passes over them, so only one running value per
+//! bucket is kept instead of the points themselves.
+
+use lmdb::{Cursor, Database, DatabaseFlags, Environment, Transaction, WriteFlags};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::query;
+use crate::queue::read_u64;
+use crate::StorageError;
+
+/// The name of the database that holds a time series' points
+pub(crate) fn time_series_db_name(name: &str) -> String {
+    format!("{}#series", name)
+}
+
+pub(crate) fn time_series_db_flags() -> DatabaseFlags {
+    DatabaseFlags::empty()
+}
+
+fn point_key(series: u64, timestamp: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&series.to_be_bytes());
+    key[8..].copy_from_slice(&timestamp.to_be_bytes());
+    key
+}
+
+/// A measurement stored in a `TimeSeries`
+pub trait DataPoint: Serialize + DeserializeOwned {
+    /// The series the point belongs to
+    fn series(&self) -> u64;
+
+    /// When the point was measured, usually in milliseconds since the epoch.  A point replaces
+    /// the point of its series with the same timestamp
+    fn timestamp(&self) -> u64;
+
+    /// The value downsampling aggregates
+    fn value(&self) -> f64;
+}
+
+/// How the values in a bucket are combined when downsampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    /// The mean of the values
+    Avg,
+    /// The smallest value
+    Min,
+    /// The largest value
+    Max,
+}
+
+/// The points of a series in one interval, combined by `TimeSeries::downsample`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    /// The timestamp the interval starts at
+    pub start: u64,
+    /// How many points fell in the interval
+    pub count: usize,
+    /// The points' values combined
+    pub value: f64,
+}
+
+impl Bucket {
+    fn new(start: u64, value: f64) -> Bucket {
+        Bucket {
+            start,
+            count: 1,
+            value,
+        }
+    }
+
+    fn add(&mut self, value: f64, aggregate: Aggregate) {
+        self.count += 1;
+        self.value = match aggregate {
+            // Kept as a sum until the bucket is done
+            Aggregate::Avg => self.value + value,
+            Aggregate::Min => self.value.min(value),
+            Aggregate::Max => self.value.max(value),
+        };
+    }
+
+    fn finish(mut self, aggregate: Aggregate) -> Bucket {
+        if aggregate == Aggregate::Avg {
+            self.value /= self.count as f64;
+        }
+        self
+    }
+}
+
+/// Points of many series, see `Storage::time_series`.
+///
+/// Timestamps are plain numbers, so any unit works as long as a series sticks to one; buckets
+/// given to `downsample` as a `Duration` are taken to be in milliseconds.
+pub struct TimeSeries<'s, T> {
+    env: &'s Environment,
+    db: Database,
+    phantom: PhantomData<T>,
+}
+
+impl<'s, T: DataPoint> TimeSeries<'s, T> {
+    pub(crate) fn new(env: &'s Environment, db: Database) -> TimeSeries<'s, T> {
+        TimeSeries {
+            env,
+            db,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Stores a point in its series
+    pub fn insert(&self, point: &T) -> Result<(), StorageError> {
+        self.insert_all(std::slice::from_ref(point))
+    }
+
+    /// Stores points in one transaction
+    pub fn insert_all(&self, points: &[T]) -> Result<(), StorageError> {
+        let mut txn = self.env.begin_rw_txn()?;
+        for point in points {
+            let key = point_key(point.series(), point.timestamp());
+            txn.put(
+                self.db,
+                &key,
+                &bincode::serialize(point)?,
+                WriteFlags::empty(),
+            )?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The points of `series` with timestamps in `range`, oldest first
+    pub fn range(&self, series: u64, range: Range<u64>) -> Result<Vec<T>, StorageError> {
+        let mut points = vec![];
+        self.fold(series, range, |_, value| {
+            points.push(bincode::deserialize(value)?);
+            Ok(())
+        })?;
+        Ok(points)
+    }
+
+    /// Splits `range` into intervals of `bucket`, starting at `range.start`, and combines the
+    /// values of the points of `series` in each.  Intervals without points are left out
+    pub fn downsample(
+        &self,
+        series: u64,
+        range: Range<u64>,
+        bucket: Duration,
+        aggregate: Aggregate,
+    ) -> Result<Vec<Bucket>, StorageError> {
+        let width = (bucket.as_millis() as u64).max(1);
+        let origin = range.start;
+        let mut buckets = vec![];
+        let mut current: Option<Bucket> = None;
+        self.fold(series, range, |timestamp, value| {
+            let value = bincode::deserialize::<T>(value)?.value();
+            let start = origin + (timestamp - origin) / width * width;
+            match current.as_mut() {
+                Some(bucket) if bucket.start == start => bucket.add(value, aggregate),
+                _ => {
+                    buckets.extend(current.replace(Bucket::new(start, value)));
+                }
+            }
+            Ok(())
+        })?;
+        buckets.extend(current);
+
+        Ok(buckets
+            .into_iter()
+            .map(|bucket| bucket.finish(aggregate))
+            .collect())
+    }
+
+    /// Removes the points of `series` older than `timestamp`, returning how many were removed
+    pub fn remove_before(&self, series: u64, timestamp: u64) -> Result<usize, StorageError> {
+        let mut keys = vec![];
+        self.fold(series, 0..timestamp, |timestamp, _| {
+            keys.push(point_key(series, timestamp));
+            Ok(())
+        })?;
+
+        let mut txn = self.env.begin_rw_txn()?;
+        for key in &keys {
+            txn.del(self.db, key, None)?;
+        }
+        txn.commit()?;
+        Ok(keys.len())
+    }
+
+    // Calls `f` with the timestamp and value of every point of `series` in `range`, in order
+    fn fold<F>(&self, series: u64, range: Range<u64>, mut f: F) -> Result<(), StorageError>
+    where
+        F: FnMut(u64, &[u8]) -> Result<(), StorageError>,
+    {
+        if range.start >= range.end {
+            return Ok(());
+        }
+
+        let txn = self.env.begin_ro_txn()?;
+        let cursor = txn.open_ro_cursor(self.db)?;
+        let start = point_key(series, range.start);
+        let end = point_key(series, range.end);
+        // Positioned by hand, since lmdb 0.8's `iter_from` panics when nothing sorts after `start`
+        let first = match cursor.get(Some(&start), None, lmdb_sys::MDB_SET_RANGE) {
+            Ok((Some(key), value)) => (key, value),
+            Ok((None, _)) | Err(lmdb::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in std::iter::once(Ok(first)).chain(query::checked_iter(&cursor)) {
+            let (key, value) = entry?;
+            if key >= &end[..] {
+                break;
+            }
+            f(read_u64(&key[8..]), value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Reading {
+        sensor: u64,
+        at: u64,
+        celsius: f64,
+    }
+
+    impl DataPoint for Reading {
+        fn series(&self) -> u64 {
+            self.sensor
+        }
+
+        fn timestamp(&self) -> u64 {
+            self.at
+        }
+
+        fn value(&self) -> f64 {
+            self.celsius
+        }
+    }
+
+    fn reading(sensor: u64, at: u64, celsius: f64) -> Reading {
+        Reading {
+            sensor,
+            at,
+            celsius,
+        }
+    }
+
+    #[test]
+    fn test_that_points_are_read_and_downsampled_by_series() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let readings = storage
+            .time_series::<Reading>("readings")
+            .expect("Could not open time series");
+        readings
+            .insert_all(&[
+                reading(1, 1_000, 20.0),
+                reading(1, 1_500, 22.0),
+                reading(1, 2_200, 18.0),
+                reading(1, 4_100, 30.0),
+                reading(2, 1_200, 99.0),
+            ])
+            .unwrap();
+        readings.insert(&reading(1, 0, 10.0)).unwrap();
+
+        let points = readings.range(1, 1_000..2_200).unwrap();
+        assert_eq!(
+            vec![reading(1, 1_000, 20.0), reading(1, 1_500, 22.0)],
+            points
+        );
+        assert_eq!(1, readings.range(2, 0..u64::MAX).unwrap().len());
+        assert!(readings.range(3, 0..u64::MAX).unwrap().is_empty());
+
+        let second = Duration::from_secs(1);
+        let avg = readings
+            .downsample(1, 1_000..5_000, second, Aggregate::Avg)
+            .unwrap();
+        assert_eq!(
+            vec![
+                Bucket {
+                    start: 1_000,
+                    count: 2,
+                    value: 21.0
+                },
+                Bucket {
+                    start: 2_000,
+                    count: 1,
+                    value: 18.0
+                },
+                Bucket {
+                    start: 4_000,
+                    count: 1,
+                    value: 30.0
+                },
+            ],
+            avg
+        );
+        let max = readings
+            .downsample(1, 0..5_000, Duration::from_secs(5), Aggregate::Max)
+            .unwrap();
+        assert_eq!((5, 30.0), (max[0].count, max[0].value));
+        let min = readings
+            .downsample(1, 0..5_000, Duration::from_secs(5), Aggregate::Min)
+            .unwrap();
+        assert_eq!(10.0, min[0].value);
+
+        assert_eq!(3, readings.remove_before(1, 2_000).unwrap());
+        assert_eq!(2, readings.range(1, 0..u64::MAX).unwrap().len());
+        assert_eq!(1, readings.range(2, 0..u64::MAX).unwrap().len());
+    }
+}
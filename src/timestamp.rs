@@ -0,0 +1,82 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A type that can hold the `created_at` and `updated_at` fields managed by `#[timestamps]`
+pub trait Timestamp: Clone {
+    /// The current time
+    fn now() -> Self;
+
+    /// Whether the field hasn't been set yet, in which case `created_at` is filled in on save
+    fn is_unset(&self) -> bool;
+}
+
+impl Timestamp for SystemTime {
+    fn now() -> Self {
+        SystemTime::now()
+    }
+
+    fn is_unset(&self) -> bool {
+        *self == UNIX_EPOCH
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Timestamp for chrono::DateTime<chrono::Utc> {
+    fn now() -> Self {
+        chrono::Utc::now()
+    }
+
+    fn is_unset(&self) -> bool {
+        self.timestamp() == 0 && self.timestamp_subsec_nanos() == 0
+    }
+}
+
+impl<T: Timestamp> Timestamp for Option<T> {
+    fn now() -> Self {
+        Some(T::now())
+    }
+
+    fn is_unset(&self) -> bool {
+        self.as_ref().is_none_or(T::is_unset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[timestamps]
+    struct Complaint {
+        id: u32,
+        text: String,
+        created_at: Option<SystemTime>,
+        updated_at: Option<SystemTime>,
+    }
+
+    #[test]
+    fn test_that_timestamps_are_set_on_save() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let complaint = Complaint {
+            id: 1,
+            text: "Noise on Bleecker St".to_string(),
+            created_at: None,
+            updated_at: None,
+        };
+        storage.save(&complaint).expect("Could not save complaint");
+
+        let first: Complaint = storage.get(1).unwrap().expect("Missing complaint");
+        assert!(first.created_at.is_some());
+        assert_eq!(first.created_at, first.updated_at);
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        storage.save(&first).expect("Could not save complaint");
+
+        let second: Complaint = storage.get(1).unwrap().expect("Missing complaint");
+        assert_eq!(first.created_at, second.created_at);
+        assert!(second.updated_at > first.updated_at);
+    }
+}
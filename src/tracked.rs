@@ -0,0 +1,169 @@
+//! Records that remember how they were stored, so saving them again can be skipped.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::{record, Record, StorageError};
+
+/// A record along with the bytes it was stored as, see `Storage::get_tracked`.
+///
+/// `Storage::save_if_changed` compares the bytes the record would be saved as with those, and
+/// skips the write, index maintenance included, when they're the same.  Jobs that save every
+/// record they read only write the ones they changed.
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use nostalgia::{Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let mut storage = Storage::temporary()?;
+///     storage.save(&Place { id: 1, name: "Vienna".to_string() })?;
+///
+///     let mut place = storage.get_tracked::<Place, _>(1)?.expect("Empty record");
+///     assert!(!storage.save_if_changed(&mut place)?);
+///
+///     place.name = "Wien".to_string();
+///     assert!(storage.save_if_changed(&mut place)?);
+///     assert!(!storage.save_if_changed(&mut place)?);
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Tracked<T> {
+    record: T,
+    // The bytes last read or saved, `None` for a record that wasn't stored through this
+    stored: Option<Vec<u8>>,
+}
+
+impl<T: Record> Tracked<T> {
+    /// Tracks a record that wasn't read from the storage, which is saved the first time
+    pub fn new(record: T) -> Tracked<T> {
+        Tracked {
+            record,
+            stored: None,
+        }
+    }
+
+    pub(crate) fn loaded(record: T, stored: Vec<u8>) -> Tracked<T> {
+        Tracked {
+            record,
+            stored: Some(stored),
+        }
+    }
+
+    /// Whether saving the record would write anything other than the bytes it is stored as
+    pub fn is_changed(&self) -> Result<bool, StorageError> {
+        Ok(!self.is_stored_as(&self.to_save()?.1))
+    }
+
+    /// Returns the record, no longer tracked
+    pub fn into_inner(self) -> T {
+        self.record
+    }
+
+    /// The record as it would be saved, after its `before_save` hook, along with its bytes
+    pub(crate) fn to_save(&self) -> Result<(Option<T>, Vec<u8>), StorageError> {
+        let copy = record::before_save(&self.record)?;
+        let bytes = copy.as_ref().unwrap_or(&self.record).to_binary()?;
+        Ok((copy, bytes))
+    }
+
+    pub(crate) fn is_stored_as(&self, bytes: &[u8]) -> bool {
+        self.stored.as_deref() == Some(bytes)
+    }
+
+    /// Takes `bytes` as what the record is stored as from now on
+    pub(crate) fn saved(&mut self, bytes: Vec<u8>) {
+        self.stored = Some(bytes);
+    }
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.record
+    }
+}
+
+impl<T> DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(metadata)]
+    struct Account {
+        id: u32,
+        #[index]
+        plan: String,
+    }
+
+    #[test]
+    fn test_that_only_changed_records_are_saved_again() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let mut new = Tracked::new(Account {
+            id: 1,
+            plan: "free".to_string(),
+        });
+        assert!(storage.save_if_changed(&mut new).unwrap());
+        storage
+            .save(&Account {
+                id: 2,
+                plan: "free".to_string(),
+            })
+            .unwrap();
+        let written = |storage: &mut Storage, id: u32| {
+            storage
+                .metadata::<Account, _>(id)
+                .unwrap()
+                .unwrap()
+                .updated_at
+        };
+        let before = [written(&mut storage, 1), written(&mut storage, 2)];
+        // Saving stamps the time in milliseconds
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let saved = storage
+            .transaction(|tx| {
+                let mut saved = vec![];
+                for id in 1..=2 {
+                    let mut account = tx.get_tracked::<Account, _>(id)?.unwrap();
+                    if id == 2 {
+                        account.plan = "pro".to_string();
+                    }
+                    saved.push(tx.save_if_changed(&mut account)?);
+                }
+                Ok(saved)
+            })
+            .unwrap();
+        assert_eq!(vec![false, true], saved);
+        assert_eq!(before[0], written(&mut storage, 1));
+        assert_ne!(before[1], written(&mut storage, 2));
+
+        let mut account = storage.get_tracked::<Account, _>(2).unwrap().unwrap();
+        assert!(!account.is_changed().unwrap());
+        account.plan = "free".to_string();
+        assert!(storage.save_if_changed(&mut account).unwrap());
+        let free: Vec<Account> = storage.find_by_index("plan", "free").unwrap();
+        assert_eq!(2, free.len());
+    }
+}
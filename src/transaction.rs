@@ -0,0 +1,1465 @@
+use lmdb::{
+    Cursor, Database, DatabaseFlags, RwTransaction, Transaction as LmdbTransaction, WriteFlags,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ops::Bound;
+
+use crate::attachment::{self, attachment_key, attachments_prefix, ATTACHMENTS_DB};
+use crate::capped::{self, Cap, CAPPED_DB};
+use crate::index::{self, index_db_flags, index_db_name, IndexEntry};
+use crate::journal::{self, ChangeOp, JOURNAL_DB, REPLICA_DB};
+use crate::kv::{kv_db_flags, kv_db_name};
+use crate::merge::{Merge, Merger, Mergers};
+use crate::metadata;
+use crate::metrics::Write;
+use crate::queue::entries_from;
+use crate::quota::{self, TenantUsage, UsageDelta, USAGE_DB};
+use crate::record;
+use crate::registry::RecordType;
+use crate::relation::DeleteRules;
+use crate::sequence::{self, SEQUENCES_DB};
+use crate::sync::{self, SyncChange, Version, SYNC_DB};
+use crate::type_tag::{self, SCHEMAS_DB, TYPES_DB};
+use crate::usage;
+use crate::{BelongsTo, FieldError, Record, StorageError, StorageOptions, Tracked};
+
+/// The name of the database that holds a record type's counters
+pub(crate) fn counters_db_name(db_name: &str) -> String {
+    format!("{}#counters", db_name)
+}
+
+/// Counters are keyed by the length of the record's key, the key and then the counter's name, so
+/// no two record and counter pairs share a key
+pub(crate) fn counter_key(key: &[u8], counter: &str) -> Vec<u8> {
+    let mut bytes = (key.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(key);
+    bytes.extend(counter.as_bytes());
+    bytes
+}
+
+pub(crate) fn decode_counter(bytes: &[u8]) -> i64 {
+    let mut value = [0; 8];
+    if bytes.len() == 8 {
+        value.copy_from_slice(bytes);
+    }
+    i64::from_be_bytes(value)
+}
+
+/// A read-write transaction that can save, fetch and delete records of any type.
+///
+/// Changes are only persisted when the closure passed to `Storage::transaction` returns `Ok`.
+pub struct Transaction<'txn> {
+    txn: RwTransaction<'txn>,
+    dbs: &'txn mut HashMap<String, Database>,
+    delete_rules: &'txn DeleteRules,
+    partition: Option<&'static str>,
+    // Whether databases that don't exist yet are created and which environment each type is
+    // stored in, among others
+    options: &'txn StorageOptions,
+    created: Vec<String>,
+    // Writes to report to the storage's metrics sink and read caches, `None` when it has neither
+    writes: Option<Vec<Write>>,
+    // How the transaction changes the tenant's usage, `None` unless the storage is a tenant's
+    usage: Option<UsageDelta>,
+    // The record databases whose type tag this transaction checked
+    tagged: HashSet<&'static str>,
+    // Writes to add to the change journal on commit, `None` unless the storage keeps one
+    journal: Option<Vec<ChangeOp>>,
+    // Whether record writes are stamped with a version, see `StorageOptions::versions`
+    versioned: bool,
+}
+
+impl<'txn> Transaction<'txn> {
+    pub(crate) fn new(
+        txn: RwTransaction<'txn>,
+        dbs: &'txn mut HashMap<String, Database>,
+        delete_rules: &'txn DeleteRules,
+        partition: Option<&'static str>,
+        options: &'txn StorageOptions,
+        record_writes: bool,
+    ) -> Transaction<'txn> {
+        Transaction {
+            txn,
+            dbs,
+            delete_rules,
+            partition,
+            options,
+            created: vec![],
+            writes: if record_writes { Some(vec![]) } else { None },
+            usage: None,
+            tagged: HashSet::new(),
+            journal: if options.journal { Some(vec![]) } else { None },
+            versioned: false,
+        }
+    }
+
+    /// Keeps track of how the records written change the tenant's usage
+    pub(crate) fn track_usage(&mut self) {
+        self.usage = Some(UsageDelta::default());
+    }
+
+    pub(crate) fn track_versions(&mut self) {
+        self.versioned = true;
+    }
+
+    // Stamps a write to the record stored under `key` with this storage's next version
+    fn stamp(&mut self, db_name: &str, key: &[u8]) -> Result<(), StorageError> {
+        if !self.versioned {
+            return Ok(());
+        }
+        let db = self.db_named(SYNC_DB, DatabaseFlags::empty())?;
+        sync::stamp(&mut self.txn, db, db_name, key)
+    }
+
+    fn add_usage(&mut self, bytes: i64, entries: i64) {
+        if let Some(usage) = self.usage.as_mut() {
+            usage.add(UsageDelta { bytes, entries });
+        }
+    }
+
+    /// Adds the change in usage to the tenant's stored usage, returning the new usage along with
+    /// the change.  Returns `None` when usage isn't tracked or didn't change
+    pub(crate) fn apply_usage(
+        &mut self,
+    ) -> Result<Option<(TenantUsage, UsageDelta)>, StorageError> {
+        let delta = match self.usage {
+            Some(delta) if delta != UsageDelta::default() => delta,
+            _ => return Ok(None),
+        };
+
+        let db = self.db_named(USAGE_DB, DatabaseFlags::empty())?;
+        let usage = delta.apply(quota::read(&self.txn, db)?);
+        quota::write(&mut self.txn, db, usage)?;
+        Ok(Some((usage, delta)))
+    }
+
+    fn record_write<T: Record>(&mut self, op: &'static str, key: Option<&[u8]>, bytes: usize) {
+        if let Some(writes) = self.writes.as_mut() {
+            writes.push(Write {
+                op,
+                db_name: T::db_name(),
+                bytes,
+                key: key.map(<[u8]>::to_vec),
+            });
+        }
+    }
+
+    // Keeps a write for the change journal, when the storage keeps one
+    fn journal(&mut self, op: impl FnOnce() -> ChangeOp) {
+        if let Some(ops) = self.journal.as_mut() {
+            ops.push(op());
+        }
+    }
+
+    /// Adds the writes made so far to the change journal as one change, when the storage keeps a
+    /// journal and the transaction wrote anything
+    pub(crate) fn write_journal(&mut self) -> Result<(), StorageError> {
+        let ops = match self.journal.as_mut() {
+            Some(ops) if !ops.is_empty() => std::mem::take(ops),
+            _ => return Ok(()),
+        };
+        let db = self.db_named(JOURNAL_DB, DatabaseFlags::empty())?;
+        journal::append(&mut self.txn, db, &ops)?;
+        Ok(())
+    }
+
+    /// The sequence number of the next change a follower expects, see `Storage::apply_changes`
+    pub(crate) fn replica_position(&mut self) -> Result<u64, StorageError> {
+        let db = self.db_named(REPLICA_DB, DatabaseFlags::empty())?;
+        journal::position(&self.txn, db)
+    }
+
+    pub(crate) fn set_replica_position(&mut self, seq: u64) -> Result<(), StorageError> {
+        let db = self.db_named(REPLICA_DB, DatabaseFlags::empty())?;
+        journal::set_position(&mut self.txn, db, seq)
+    }
+
+    /// Replays writes read from another storage's journal or from a patch.  Records of the types
+    /// in `mergers` are merged with the stored ones, and the incoming index entries of those types
+    /// are skipped since the merged records bring their own
+    pub(crate) fn apply_ops(
+        &mut self,
+        ops: &[ChangeOp],
+        mergers: &Mergers,
+    ) -> Result<(), StorageError> {
+        let mut dbs = HashMap::new();
+        for op in ops {
+            match (mergers.get(op.db()), op) {
+                (Some(merger), ChangeOp::Put { key, value, .. }) => (merger.put)(self, key, value)?,
+                (Some(merger), ChangeOp::Delete { key, .. }) => (merger.delete)(self, key)?,
+                (None, ChangeOp::Put { db, .. }) | (None, ChangeOp::Delete { db, .. })
+                    if mergers.values().any(|merger| merger.index_dbs.contains(db)) => {}
+                _ => {
+                    journal::apply(&mut self.txn, &mut dbs, std::slice::from_ref(op))?;
+                    self.journal(|| op.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the version of a record another storage holds, see `Storage::apply_remote`.
+    /// Versions this storage has seen already are skipped and ones that descend from the stored
+    /// version replace it.  Concurrent versions are merged when the type has a `merger`, and
+    /// otherwise the one last written at the higher dot wins, so both sides pick the same one.
+    /// Returns whether the change was new to this storage
+    pub(crate) fn apply_remote(
+        &mut self,
+        change: &SyncChange,
+        record_type: &RecordType,
+        merger: Option<&Merger>,
+    ) -> Result<bool, StorageError> {
+        let db = self.db_named(SYNC_DB, DatabaseFlags::empty())?;
+        sync::see(&mut self.txn, db, change.replica, change.counter)?;
+        let local = sync::version(&self.txn, db, &change.db, &change.key)?.unwrap_or_default();
+        if local.vector.descends(&change.version) {
+            return Ok(false);
+        }
+
+        let theirs = match &change.value {
+            Some(value) => {
+                Some(
+                    record_type
+                        .unwrap(value)
+                        .ok_or_else(|| StorageError::Undecodable {
+                            db_name: record_type.db_name(),
+                            key: change.key.clone(),
+                        })?,
+                )
+            }
+            None => None,
+        };
+        if change.version.descends(&local.vector) {
+            let remote = Version {
+                vector: change.version.clone(),
+                replica: change.replica,
+                counter: change.counter,
+            };
+            self.write_remote(record_type, &change.key, theirs, &remote)?;
+            return Ok(true);
+        }
+
+        let mut vector = local.vector.clone();
+        vector.join(&change.version);
+        let remote = Version {
+            vector: vector.clone(),
+            replica: change.replica,
+            counter: change.counter,
+        };
+        let local = Version {
+            vector,
+            replica: local.replica,
+            counter: local.counter,
+        };
+        let remote_wins = (remote.replica, remote.counter) > (local.replica, local.counter);
+        let ours = record_type.read(self, &change.key)?;
+        match (merger, ours, theirs) {
+            (Some(merger), Some(ours), Some(theirs)) => {
+                let merged = (merger.merge)(&ours, theirs)?;
+                let (as_theirs, as_ours) = (merged == theirs, merged == ours);
+                if as_theirs && (remote_wins || !as_ours) {
+                    self.write_remote(record_type, &change.key, Some(theirs), &remote)?;
+                } else {
+                    sync::set_version(&mut self.txn, db, &change.db, &change.key, &local)?;
+                    // A merge that neither side had yet is a new write of this storage's
+                    if !as_ours {
+                        record_type.put(self, &change.key, &merged)?;
+                    }
+                }
+            }
+            (_, _, theirs) if remote_wins => {
+                self.write_remote(record_type, &change.key, theirs, &remote)?
+            }
+            _ => sync::set_version(&mut self.txn, db, &change.db, &change.key, &local)?,
+        }
+        Ok(true)
+    }
+
+    // Writes a record as another storage holds it, keeping that storage's version
+    fn write_remote(
+        &mut self,
+        record_type: &RecordType,
+        key: &[u8],
+        value: Option<&[u8]>,
+        version: &Version,
+    ) -> Result<(), StorageError> {
+        let versioned = std::mem::replace(&mut self.versioned, false);
+        let written = match value {
+            Some(value) => record_type.put(self, key, value),
+            None => record_type.delete(self, key),
+        };
+        self.versioned = versioned;
+        written?;
+
+        let db = self.db_named(SYNC_DB, DatabaseFlags::empty())?;
+        sync::set_version(&mut self.txn, db, record_type.db_name(), key, version)
+    }
+
+    /// Takes the writes made so far, so they can be reported once the transaction commits
+    pub(crate) fn take_writes(&mut self) -> Vec<Write> {
+        self.writes.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // A transaction only covers the environment it was started in
+    fn check_partition<T: Record>(&self) -> Result<(), StorageError> {
+        if self.options.partition_of::<T>() == self.partition {
+            Ok(())
+        } else {
+            Err(StorageError::WrongPartition {
+                db_name: T::db_name(),
+            })
+        }
+    }
+
+    fn db<T: Record>(&mut self) -> Result<Database, StorageError> {
+        self.check_partition::<T>()?;
+        let db = self.db_named(T::db_name(), T::db_flags())?;
+        if !self.tagged.contains(T::db_name()) {
+            self.tag::<T>()?;
+            self.tagged.insert(T::db_name());
+        }
+        Ok(db)
+    }
+
+    // Tags the type's database for it, or fails if it holds another type's records.  The schema
+    // version is recorded along with the first tag
+    fn tag<T: Record>(&mut self) -> Result<(), StorageError> {
+        let types = match self.metadata_db(TYPES_DB)? {
+            Some(types) => types,
+            None => return Ok(()),
+        };
+        type_tag::tag::<T>(&mut self.txn, types)?;
+        self.journal(|| ChangeOp::Put {
+            db: TYPES_DB.to_string(),
+            flags: 0,
+            key: T::db_name().as_bytes().to_vec(),
+            value: T::type_tag().as_bytes().to_vec(),
+        });
+
+        if T::schema_version() != 0 {
+            if let Some(schemas) = self.metadata_db(SCHEMAS_DB)? {
+                if type_tag::schema_version(&self.txn, Some(schemas), T::db_name())?.is_none() {
+                    type_tag::set_schema_version::<T>(&mut self.txn, schemas)?;
+                    self.journal(|| ChangeOp::Put {
+                        db: SCHEMAS_DB.to_string(),
+                        flags: 0,
+                        key: T::db_name().as_bytes().to_vec(),
+                        value: T::schema_version().to_be_bytes().to_vec(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Environments opened without creating databases are only checked once they have the database
+    fn metadata_db(&mut self, db_name: &str) -> Result<Option<Database>, StorageError> {
+        match self.db_named(db_name, DatabaseFlags::empty()) {
+            Ok(db) => Ok(Some(db)),
+            Err(StorageError::DBError {
+                source: lmdb::Error::NotFound,
+            }) if !self.options.create => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn index_db<T: Record>(&mut self, index: &str) -> Result<Database, StorageError> {
+        self.check_partition::<T>()?;
+        self.db_named(&index_db_name(T::db_name(), index), index_db_flags())
+    }
+
+    fn db_named(&mut self, db_name: &str, flags: DatabaseFlags) -> Result<Database, StorageError> {
+        if let Some(db) = self.dbs.get(db_name) {
+            return Ok(*db);
+        }
+
+        // Safe because the handle is only cached while this transaction (or its parent) commits,
+        // see `abort`, and no other transaction can be creating databases while we hold the
+        // environment's write lock.
+        let db = if self.options.create {
+            unsafe { self.txn.create_db(Some(db_name), flags)? }
+        } else {
+            unsafe { self.txn.open_db(Some(db_name))? }
+        };
+        self.dbs.insert(db_name.to_string(), db);
+        self.created.push(db_name.to_string());
+        Ok(db)
+    }
+
+    pub(crate) fn get_bytes<T: Record>(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let db = self.db::<T>()?;
+
+        match self.txn.get(db, &key) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // The database `db_name`, or `None` when it doesn't exist, without creating it
+    fn existing_db_named(&mut self, db_name: &str) -> Result<Option<Database>, StorageError> {
+        if let Some(db) = self.dbs.get(db_name) {
+            return Ok(Some(*db));
+        }
+
+        // Safe for the same reason as in `db_named`
+        match unsafe { self.txn.open_db(Some(db_name)) } {
+            Ok(db) => {
+                self.dbs.insert(db_name.to_string(), db);
+                self.created.push(db_name.to_string());
+                Ok(Some(db))
+            }
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The raw value stored under `key` in the database `db_name`, without creating the database
+    #[cfg(feature = "server")]
+    pub(crate) fn raw_get(
+        &mut self,
+        db_name: &str,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let db = match self.existing_db_named(db_name)? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+
+        match self.txn.get(db, &key) {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes an already serialized record along with its index entries, replacing the entries
+    /// of any record previously stored under the same key.
+    pub(crate) fn put_record<T: Record>(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        entries: &[IndexEntry],
+    ) -> Result<(), StorageError> {
+        let cap = self.options.cap::<T>();
+        let previous = if T::has_metadata() || self.usage.is_some() || cap.is_some() {
+            self.get_bytes::<T>(key)?
+        } else {
+            None
+        };
+        let value = if T::has_metadata() {
+            metadata::wrap::<T>(previous.as_deref(), value)
+        } else {
+            value.to_vec()
+        };
+        // Evicting every other record wouldn't make room for one bigger than the cap
+        if let Some(max_bytes) = cap.and_then(|cap| cap.max_bytes) {
+            let bytes = (key.len() + value.len()) as u64;
+            if bytes > max_bytes {
+                return Err(StorageError::OverCap {
+                    db_name: T::db_name(),
+                    bytes,
+                    max_bytes,
+                });
+            }
+        }
+
+        self.check_unique::<T>(key, entries)?;
+        if !T::indexes().is_empty() {
+            self.remove_index_entries::<T>(key)?;
+        }
+        match &previous {
+            Some(previous) => self.add_usage(value.len() as i64 - previous.len() as i64, 0),
+            None => self.add_usage((key.len() + value.len()) as i64, 1),
+        }
+
+        let db = self.db::<T>()?;
+        self.txn.put(db, &key, &value, T::write_flags())?;
+        self.journal(|| ChangeOp::Put {
+            db: T::db_name().to_string(),
+            flags: T::db_flags().bits(),
+            key: key.to_vec(),
+            value: value.clone(),
+        });
+        self.record_write::<T>("save", Some(key), value.len());
+        self.stamp(T::db_name(), key)?;
+        self.put_index_entries::<T>(key, entries)?;
+
+        if let Some(cap) = cap {
+            let previous = previous.map_or(0, |previous| key.len() + previous.len());
+            self.add_capped_bytes::<T>((key.len() + value.len()) as i64 - previous as i64)?;
+            self.evict::<T>(cap)?;
+        }
+        Ok(())
+    }
+
+    // Adds `delta` to the bytes counted for capped `T`, returning the new count
+    fn add_capped_bytes<T: Record>(&mut self, delta: i64) -> Result<u64, StorageError> {
+        let db = self.db_named(CAPPED_DB, DatabaseFlags::empty())?;
+        let bytes = capped::read_bytes(&self.txn, db, T::db_name())?.saturating_add_signed(delta);
+        capped::write_bytes(&mut self.txn, db, T::db_name(), bytes)?;
+        self.journal(|| ChangeOp::Put {
+            db: CAPPED_DB.to_string(),
+            flags: 0,
+            key: T::db_name().as_bytes().to_vec(),
+            value: bytes.to_be_bytes().to_vec(),
+        });
+        Ok(bytes)
+    }
+
+    // Deletes the records of `T` with the smallest keys until it is within `cap`
+    fn evict<T: Record>(&mut self, cap: Cap) -> Result<(), StorageError> {
+        loop {
+            let db = self.db::<T>()?;
+            let entries = usage::entries(&self.txn, db)? as u64;
+            let counts = self.db_named(CAPPED_DB, DatabaseFlags::empty())?;
+            let bytes = capped::read_bytes(&self.txn, counts, T::db_name())?;
+            let oldest = match entries_from(&self.txn, db, &[], &[], 1)?.pop() {
+                Some((oldest, _)) => oldest,
+                // Nothing is left to count, whatever the count says
+                None if bytes > 0 => {
+                    return self.add_capped_bytes::<T>(-(bytes as i64)).map(|_| ())
+                }
+                None => return Ok(()),
+            };
+            if cap.allows(entries, bytes) {
+                return Ok(());
+            }
+            self.delete_key::<T>(&oldest)?;
+        }
+    }
+
+    /// Saves the record stored under `key` back in the current version of `T` when it was
+    /// written in an earlier one, returning whether it was
+    pub(crate) fn upgrade<T: Record>(&mut self, key: &[u8]) -> Result<bool, StorageError> {
+        let bytes = match self.get_bytes::<T>(key)? {
+            Some(bytes) => bytes,
+            None => return Ok(false),
+        };
+        if !metadata::unwrap::<T>(&bytes).is_some_and(T::is_outdated) {
+            return Ok(false);
+        }
+
+        let record = metadata::decode::<T>(key, &bytes)?;
+        let value = T::to_binary(&record)?;
+        self.put_record::<T>(key, &value, &record.index_entries()?)?;
+        Ok(true)
+    }
+
+    /// Writes the index entries of the record stored under `key` again, returning whether there
+    /// was one
+    pub(crate) fn reindex_record<T: Record>(&mut self, key: &[u8]) -> Result<bool, StorageError> {
+        let record = match self.get_bytes::<T>(key)? {
+            Some(bytes) => metadata::decode::<T>(key, &bytes)?,
+            None => return Ok(false),
+        };
+
+        let entries = record.index_entries()?;
+        self.check_unique::<T>(key, &entries)?;
+        self.remove_index_entries::<T>(key)?;
+        self.put_index_entries::<T>(key, &entries)?;
+        Ok(true)
+    }
+
+    fn put_index_entries<T: Record>(
+        &mut self,
+        key: &[u8],
+        entries: &[IndexEntry],
+    ) -> Result<(), StorageError> {
+        for entry in entries {
+            let db = self.index_db::<T>(entry.index)?;
+            let data = index::entry_data(key, entry.projection.as_deref());
+            self.txn.put(db, &entry.value, &data, WriteFlags::empty())?;
+            self.journal(|| ChangeOp::Put {
+                db: index_db_name(T::db_name(), entry.index),
+                flags: index_db_flags().bits(),
+                key: entry.value.clone(),
+                value: data,
+            });
+        }
+        Ok(())
+    }
+
+    // Fails when a unique index already holds one of the values for a record under another key
+    fn check_unique<T: Record>(
+        &mut self,
+        key: &[u8],
+        entries: &[IndexEntry],
+    ) -> Result<(), StorageError> {
+        let unique = entries
+            .iter()
+            .filter(|entry| T::unique_indexes().contains(&entry.index));
+        for entry in unique {
+            let keys = self.index_lookup::<T>(entry.index, &entry.value)?;
+            if keys.iter().any(|other| other != key) {
+                return Err(StorageError::UniqueViolation {
+                    db_name: T::db_name(),
+                    index: entry.index,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds every index of `T` from the records stored, returning how many records were
+    /// indexed
+    pub(crate) fn reindex<T: Record>(&mut self) -> Result<usize, StorageError> {
+        for index in T::indexes() {
+            let db = self.index_db::<T>(index)?;
+            self.txn.clear_db(db)?;
+            self.journal(|| ChangeOp::Clear {
+                db: index_db_name(T::db_name(), index),
+            });
+        }
+
+        let db = self.db::<T>()?;
+        let stored: Vec<(Vec<u8>, Vec<u8>)> = {
+            let mut cursor = self.txn.open_ro_cursor(db)?;
+            cursor
+                .iter()
+                .map(|(key, value)| (key.to_vec(), value.to_vec()))
+                .collect()
+        };
+
+        for (key, value) in &stored {
+            let record = metadata::decode::<T>(key, value)?;
+            let entries = record.index_entries()?;
+            self.check_unique::<T>(key, &entries)?;
+            self.put_index_entries::<T>(key, &entries)?;
+        }
+        Ok(stored.len())
+    }
+
+    /// Deletes a record by its raw key along with its index entries, enforcing the on-delete
+    /// policies registered for its children
+    pub(crate) fn delete_key<T: Record>(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        let delete_rules = self.delete_rules;
+        for rule in delete_rules.get(T::db_name()).into_iter().flatten() {
+            (rule.enforce)(self, key, rule.policy)?;
+        }
+
+        if !T::indexes().is_empty() {
+            self.remove_index_entries::<T>(key)?;
+        }
+        self.remove_attachments::<T>(key)?;
+
+        let capped = self.options.cap::<T>().is_some();
+        if self.usage.is_some() || capped {
+            if let Some(previous) = self.get_bytes::<T>(key)? {
+                let bytes = (key.len() + previous.len()) as i64;
+                self.add_usage(-bytes, -1);
+                if capped {
+                    self.add_capped_bytes::<T>(-bytes)?;
+                }
+            }
+        }
+
+        let db = self.db::<T>()?;
+        self.txn.del(db, &key, None)?;
+        self.journal(|| ChangeOp::Delete {
+            db: T::db_name().to_string(),
+            key: key.to_vec(),
+            value: None,
+        });
+        self.record_write::<T>("delete", Some(key), 0);
+        self.stamp(T::db_name(), key)
+    }
+
+    /// Reads up to `limit` records of `T` from `start` on and deletes those up to `end` that
+    /// `matches` picks by their stored value.  Returns how many were deleted, and where the next
+    /// chunk starts unless this was the last one
+    pub(crate) fn delete_chunk<T, F>(
+        &mut self,
+        start: &[u8],
+        end: Bound<&[u8]>,
+        limit: usize,
+        matches: &mut F,
+    ) -> Result<(usize, Option<Vec<u8>>), StorageError>
+    where
+        T: Record,
+        F: FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    {
+        let db = self.db::<T>()?;
+        let page = entries_from(&self.txn, db, start, &[], limit)?;
+
+        let mut deleted = 0;
+        for (key, value) in &page {
+            let past_end = match end {
+                Bound::Included(end) => key.as_slice() > end,
+                Bound::Excluded(end) => key.as_slice() >= end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return Ok((deleted, None));
+            }
+            if matches(key, value)? {
+                self.delete_key::<T>(key)?;
+                deleted += 1;
+            }
+        }
+
+        Ok(match page.last() {
+            Some((last, _)) if page.len() == limit => {
+                let mut next = last.clone();
+                next.push(0);
+                (deleted, Some(next))
+            }
+            _ => (deleted, None),
+        })
+    }
+
+    fn remove_index_entries<T: Record>(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        let stored = match self.get_bytes::<T>(key)? {
+            Some(bytes) => Some(metadata::decode::<T>(key, &bytes)?),
+            None => None,
+        };
+
+        if let Some(stored) = stored {
+            for entry in stored.index_entries()? {
+                let db = self.index_db::<T>(entry.index)?;
+                let data = index::entry_data(key, entry.projection.as_deref());
+                index::remove(&mut self.txn, db, &entry.value, &data)?;
+                self.journal(|| ChangeOp::Delete {
+                    db: index_db_name(T::db_name(), entry.index),
+                    key: entry.value,
+                    value: Some(data),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the raw keys of the records stored under `value` in one of `T`'s indexes
+    pub(crate) fn index_lookup<T: Record>(
+        &mut self,
+        index: &str,
+        value: &[u8],
+    ) -> Result<Vec<Vec<u8>>, StorageError> {
+        let db = self.index_db::<T>(index)?;
+        let data = index::in_index_order::<T>(index, index::lookup(&self.txn, db, value)?);
+        index::record_keys(data, T::covering_indexes().contains(&index))
+    }
+
+    /// Fetches and deserializes a record by its raw key
+    pub(crate) fn get_record<T: Record>(&mut self, key: &[u8]) -> Result<Option<T>, StorageError> {
+        match self.get_bytes::<T>(key)? {
+            Some(bytes) => metadata::decode(key, &bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Serializes and saves a record as part of the transaction
+    pub fn save<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        let copy = record::before_save(record)?;
+        let record = copy.as_ref().unwrap_or(record);
+        record.validate().map_err(StorageError::Validation)?;
+
+        let bytes = T::to_binary(record)?;
+        let key: Vec<u8> = record.key().into();
+        self.put_record::<T>(&key, &bytes, &record.index_entries()?)
+    }
+
+    /// The next key of the sequence kept for `T`, passing over keys records are stored under
+    /// already.  Keys handed out by a transaction that isn't committed are handed out again
+    ///
+    /// `#[storable(builder)]` generates a builder whose `save` takes its key from here when none
+    /// was set.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// #[storable(builder)]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::temporary()?;
+    ///
+    ///     let vienna = Place::builder().name("Vienna").save(&mut storage)?;
+    ///     let id = storage.transaction(|tx| tx.next_key::<Place, u32>())?;
+    ///     assert_eq!((1, 2), (vienna.id, id));
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn next_key<T, K>(&mut self) -> Result<K, StorageError>
+    where
+        T: Record,
+        K: TryFrom<u64> + Into<T::Key> + Clone,
+    {
+        let exhausted = || StorageError::KeysExhausted {
+            db_name: T::db_name(),
+        };
+        let db = self.db_named(SEQUENCES_DB, DatabaseFlags::empty())?;
+        let mut last = sequence::read(&self.txn, db, T::db_name())?;
+        let key = loop {
+            last = last.checked_add(1).ok_or_else(exhausted)?;
+            let key = K::try_from(last).map_err(|_| exhausted())?;
+            let bytes: Vec<u8> = key.clone().into().into();
+            if self.get_bytes::<T>(&bytes)?.is_none() {
+                break key;
+            }
+        };
+
+        sequence::write(&mut self.txn, db, T::db_name(), last)?;
+        self.journal(|| ChangeOp::Put {
+            db: SEQUENCES_DB.to_string(),
+            flags: 0,
+            key: T::db_name().as_bytes().to_vec(),
+            value: last.to_be_bytes().to_vec(),
+        });
+        Ok(key)
+    }
+
+    /// Saves a tracked record unless it would be stored as it is already, see `Tracked`.  Returns
+    /// whether it was written
+    pub fn save_if_changed<T: Record>(
+        &mut self,
+        tracked: &mut Tracked<T>,
+    ) -> Result<bool, StorageError> {
+        let (copy, bytes) = tracked.to_save()?;
+        if tracked.is_stored_as(&bytes) {
+            return Ok(false);
+        }
+
+        let record: &T = copy.as_ref().unwrap_or(tracked);
+        record.validate().map_err(StorageError::Validation)?;
+        let key: Vec<u8> = record.key().into();
+        self.put_record::<T>(&key, &bytes, &record.index_entries()?)?;
+        tracked.saved(bytes);
+        Ok(true)
+    }
+
+    /// Saves `record` merged with the record stored under its key, see `Merge`, and returns the
+    /// record as saved
+    pub fn save_merge<T: Merge>(&mut self, record: T) -> Result<T, StorageError> {
+        let key: Vec<u8> = record.key().into();
+        let merged = match self.get_record::<T>(&key)? {
+            Some(ours) => T::merge(ours, record),
+            None => record,
+        };
+
+        let merged_key: Vec<u8> = merged.key().into();
+        if merged_key != key {
+            return Err(StorageError::Validation(vec![FieldError::new(
+                "key",
+                "can't be changed by merging",
+            )]));
+        }
+        self.save(&merged)?;
+        Ok(merged)
+    }
+
+    /// Retrieves a record, including any changes made earlier in this transaction
+    pub fn get<T: Record, K: Into<T::Key>>(&mut self, key: K) -> Result<Option<T>, StorageError> {
+        let key: Vec<u8> = key.into().into();
+        self.get_record::<T>(&key)
+    }
+
+    /// Retrieves a record along with the bytes it is stored as, see `Storage::get_tracked`
+    pub fn get_tracked<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+    ) -> Result<Option<Tracked<T>>, StorageError> {
+        let key: Vec<u8> = key.into().into();
+        let bytes = match self.get_bytes::<T>(&key)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        let stored = metadata::stored::<T>(&key, &bytes)?;
+        Ok(Some(Tracked::loaded(
+            record::load(stored)?,
+            stored.to_vec(),
+        )))
+    }
+
+    /// Replaces the record stored under `key` with `new`, or deletes it when `new` is `None`, but
+    /// only while the stored record serializes to the same bytes as `expected`.  `None` expects
+    /// no record to be stored.  Returns the stored record in `Err` when it isn't the expected one,
+    /// and nothing is written
+    pub fn compare_and_swap<T: Record, K: Into<T::Key>>(
+        &mut self,
+        key: K,
+        expected: Option<&T>,
+        new: Option<&T>,
+    ) -> Result<Result<(), Option<T>>, StorageError> {
+        let key: Vec<u8> = key.into().into();
+        if let Some(record) = new {
+            let new_key: Vec<u8> = record.key().into();
+            if new_key != key {
+                return Err(StorageError::Validation(vec![FieldError::new(
+                    "key",
+                    "doesn't match the key being swapped",
+                )]));
+            }
+        }
+
+        let stored = self.get_bytes::<T>(&key)?;
+        let expected = expected.map(T::to_binary).transpose()?;
+        let unchanged = match (&stored, &expected) {
+            (Some(stored), Some(expected)) => {
+                metadata::unwrap::<T>(stored) == Some(expected.as_slice())
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        if !unchanged {
+            let stored = stored
+                .map(|bytes| metadata::decode(&key, &bytes))
+                .transpose()?;
+            return Ok(Err(stored));
+        }
+
+        match new {
+            Some(record) => self.save(record)?,
+            None if stored.is_some() => self.delete_key::<T>(&key)?,
+            None => (),
+        }
+        Ok(Ok(()))
+    }
+
+    /// Reads a record, hands it to `f` and saves what `f` returns, or deletes the record when
+    /// `f` returns `None`.  Returns the record as it was left
+    pub fn modify<T, K, F>(&mut self, key: K, f: F) -> Result<Option<T>, StorageError>
+    where
+        T: Record,
+        K: Into<T::Key>,
+        F: FnOnce(Option<T>) -> Option<T>,
+    {
+        let key: Vec<u8> = key.into().into();
+        let current = self.get_record::<T>(&key)?;
+        let existed = current.is_some();
+
+        match f(current) {
+            Some(record) => {
+                self.save(&record)?;
+                Ok(Some(record))
+            }
+            None => {
+                if existed {
+                    self.delete_key::<T>(&key)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Adds `delta` to one of a record's counters and returns the new value
+    pub fn increment<T, K>(
+        &mut self,
+        key: K,
+        counter: &str,
+        delta: i64,
+    ) -> Result<i64, StorageError>
+    where
+        T: Record,
+        K: Into<T::Key>,
+    {
+        self.check_partition::<T>()?;
+        let db = self.db_named(&counters_db_name(T::db_name()), DatabaseFlags::empty())?;
+        let counter_key = counter_key(&key.into().into(), counter);
+
+        let current = match self.txn.get(db, &counter_key) {
+            Ok(bytes) => decode_counter(bytes),
+            Err(lmdb::Error::NotFound) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let value = current.wrapping_add(delta);
+        self.txn
+            .put(db, &counter_key, &value.to_be_bytes(), WriteFlags::empty())?;
+        self.journal(|| ChangeOp::Put {
+            db: counters_db_name(T::db_name()),
+            flags: 0,
+            key: counter_key,
+            value: value.to_be_bytes().to_vec(),
+        });
+        self.record_write::<T>("increment", None, 0);
+        Ok(value)
+    }
+
+    /// Stores `bytes` as the attachment called `name` of `record`, replacing any attachment of
+    /// that name.  Attachments are deleted along with their record, see `Storage::attach`
+    pub fn attach<T: Record>(
+        &mut self,
+        record: &T,
+        name: &str,
+        bytes: &[u8],
+    ) -> Result<(), StorageError> {
+        self.check_partition::<T>()?;
+        let db = self.db_named(ATTACHMENTS_DB, DatabaseFlags::empty())?;
+        let key = attachment_key::<T>(&record.key().into(), name);
+        self.txn.put(db, &key, &bytes, WriteFlags::empty())?;
+        self.journal(|| ChangeOp::Put {
+            db: ATTACHMENTS_DB.to_string(),
+            flags: 0,
+            key,
+            value: bytes.to_vec(),
+        });
+        self.record_write::<T>("attach", None, bytes.len());
+        Ok(())
+    }
+
+    /// Returns the attachment called `name` of `record`, if it has one
+    pub fn attachment<T: Record>(
+        &mut self,
+        record: &T,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.check_partition::<T>()?;
+        let db = match self.existing_db_named(ATTACHMENTS_DB)? {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+
+        match self
+            .txn
+            .get(db, &attachment_key::<T>(&record.key().into(), name))
+        {
+            Ok(bytes) => Ok(Some(bytes.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the attachment called `name` of `record`.  Returns false when it had none
+    pub fn detach<T: Record>(&mut self, record: &T, name: &str) -> Result<bool, StorageError> {
+        self.check_partition::<T>()?;
+        let db = match self.existing_db_named(ATTACHMENTS_DB)? {
+            Some(db) => db,
+            None => return Ok(false),
+        };
+
+        let key = attachment_key::<T>(&record.key().into(), name);
+        match self.txn.del(db, &key, None) {
+            Ok(()) => {
+                self.journal(|| ChangeOp::Delete {
+                    db: ATTACHMENTS_DB.to_string(),
+                    key,
+                    value: None,
+                });
+                self.record_write::<T>("detach", None, 0);
+                Ok(true)
+            }
+            Err(lmdb::Error::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Removes every attachment of the record stored under `key`
+    fn remove_attachments<T: Record>(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        let db = match self.existing_db_named(ATTACHMENTS_DB)? {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+        for key in attachment::delete_all(&mut self.txn, db, &attachments_prefix::<T>(key))? {
+            self.journal(|| ChangeOp::Delete {
+                db: ATTACHMENTS_DB.to_string(),
+                key,
+                value: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Deletes a record as part of the transaction and returns whether it was stored, see
+    /// `Storage::delete`
+    pub fn delete<T: Record>(&mut self, record: &T) -> Result<bool, StorageError> {
+        let key: Vec<u8> = record.key().into();
+        if self.delete_stored::<T>(&key)? {
+            return Ok(true);
+        }
+        if self.options.strict_deletes {
+            return Err(StorageError::NotFound {
+                db_name: T::db_name(),
+                key,
+            });
+        }
+        Ok(false)
+    }
+
+    /// Deletes a record that has to be stored as part of the transaction, failing with
+    /// `StorageError::NotFound` when it isn't
+    pub fn delete_existing<T: Record>(&mut self, record: &T) -> Result<(), StorageError> {
+        let key: Vec<u8> = record.key().into();
+        if self.delete_stored::<T>(&key)? {
+            return Ok(());
+        }
+        Err(StorageError::NotFound {
+            db_name: T::db_name(),
+            key,
+        })
+    }
+
+    // Deletes the record stored under `key` like `delete_key`, or returns false when there is
+    // none, without creating the type's database
+    fn delete_stored<T: Record>(&mut self, key: &[u8]) -> Result<bool, StorageError> {
+        self.check_partition::<T>()?;
+        if self.existing_db_named(T::db_name())?.is_none() {
+            return Ok(false);
+        }
+        let db = self.db::<T>()?;
+        match self.txn.get(db, &key) {
+            Ok(_) => {}
+            Err(lmdb::Error::NotFound) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        self.delete_key::<T>(key)?;
+        Ok(true)
+    }
+
+    /// Stores `value` under `key` in the key-value store called `name`, see `Storage::kv`
+    pub fn set_value<V: Serialize>(
+        &mut self,
+        name: &str,
+        key: &str,
+        value: &V,
+    ) -> Result<(), StorageError> {
+        let db = self.db_named(&kv_db_name(name), kv_db_flags())?;
+        let bytes = bincode::serialize(value)?;
+        self.txn.put(db, &key, &bytes, WriteFlags::empty())?;
+        self.journal(|| ChangeOp::Put {
+            db: kv_db_name(name),
+            flags: kv_db_flags().bits(),
+            key: key.as_bytes().to_vec(),
+            value: bytes,
+        });
+        Ok(())
+    }
+
+    /// Returns the value stored under `key` in the key-value store called `name`
+    pub fn get_value<V: DeserializeOwned>(
+        &mut self,
+        name: &str,
+        key: &str,
+    ) -> Result<Option<V>, StorageError> {
+        let db = self.db_named(&kv_db_name(name), kv_db_flags())?;
+        match self.txn.get(db, &key) {
+            Ok(bytes) => Ok(Some(bincode::deserialize(bytes)?)),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the value stored under `key` in the key-value store called `name`.  Returns false
+    /// when there wasn't one
+    pub fn remove_value(&mut self, name: &str, key: &str) -> Result<bool, StorageError> {
+        let db = self.db_named(&kv_db_name(name), kv_db_flags())?;
+        match self.txn.del(db, &key, None) {
+            Ok(()) => {
+                self.journal(|| ChangeOp::Delete {
+                    db: kv_db_name(name),
+                    key: key.as_bytes().to_vec(),
+                    value: None,
+                });
+                Ok(true)
+            }
+            Err(lmdb::Error::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns every `C` that belongs to the parent with the given key
+    pub fn children_of<P, C, K>(&mut self, key: K) -> Result<Vec<C>, StorageError>
+    where
+        P: Record,
+        P::Key: Serialize,
+        C: BelongsTo<P>,
+        K: Into<P::Key>,
+    {
+        let parent_key = index::encode(&key.into());
+        let mut children = vec![];
+
+        for key in self.index_lookup::<C>(C::foreign_key(), &parent_key)? {
+            children.extend(self.get_record::<C>(&key)?);
+        }
+        Ok(children)
+    }
+
+    /// Deletes a parent record along with every `C` that belongs to it
+    pub fn delete_cascade<P, C>(&mut self, parent: &P) -> Result<(), StorageError>
+    where
+        P: Record,
+        P::Key: Serialize,
+        C: BelongsTo<P>,
+    {
+        let parent_key = index::encode(&parent.key());
+
+        for key in self.index_lookup::<C>(C::foreign_key(), &parent_key)? {
+            self.delete_key::<C>(&key)?;
+        }
+        self.delete_key::<P>(&parent.key().into())
+    }
+
+    /// Runs `f` inside a child transaction.
+    ///
+    /// When `f` returns an error only the child's changes are thrown away, the work already done
+    /// in this transaction is kept and the error is handed back so the caller can fall back to
+    /// something else.
+    ///
+    /// # Examples
+    /// ```
+    /// #[macro_use]
+    /// extern crate nostalgia_derive;
+    /// use nostalgia::{Storage, Record, Key, StorageError};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Storable, Serialize, Deserialize)]
+    /// #[key = "id"]
+    /// struct Place {
+    ///   id: u32,
+    ///   name: std::string::String
+    /// }
+    ///
+    /// fn main() -> Result<(), StorageError> {
+    ///     let mut storage = Storage::new("/tmp/db")?;
+    ///
+    ///     storage.transaction(|tx| {
+    ///         tx.save(&Place { id: 1, name: "Vienna".to_string() })?;
+    ///
+    ///         let attempt = tx.nested(|child| {
+    ///             child.save(&Place { id: 404, name: "Atlantis".to_string() })?;
+    ///             Err::<(), _>(std::io::Error::other("sunk").into())
+    ///         });
+    ///
+    ///         if attempt.is_err() {
+    ///             assert!(tx.get::<Place, _>(404)?.is_none());
+    ///         }
+    ///
+    ///         Ok(())
+    ///     })?;
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn nested<R, F>(&mut self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce(&mut Transaction) -> Result<R, StorageError>,
+    {
+        let txn = self.txn.begin_nested_txn()?;
+        let mut child = Transaction::new(
+            txn,
+            self.dbs,
+            self.delete_rules,
+            self.partition,
+            self.options,
+            self.writes.is_some(),
+        );
+        if self.usage.is_some() {
+            child.track_usage();
+        }
+        child.versioned = self.versioned;
+
+        match f(&mut child) {
+            Ok(result) => {
+                let writes = child.take_writes();
+                if let (Some(usage), Some(child_usage)) = (self.usage.as_mut(), child.usage) {
+                    usage.add(child_usage);
+                }
+                let ops = child.journal.take();
+                if let (Some(journal), Some(ops)) = (self.journal.as_mut(), ops) {
+                    journal.extend(ops);
+                }
+                let created = child.commit()?;
+                self.created.extend(created);
+                if let Some(parent_writes) = self.writes.as_mut() {
+                    parent_writes.extend(writes);
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                child.abort();
+                Err(e)
+            }
+        }
+    }
+
+    /// Commits the transaction, returning the names of the databases it created
+    pub(crate) fn commit(self) -> Result<Vec<String>, StorageError> {
+        self.txn.commit()?;
+        Ok(self.created)
+    }
+
+    /// Aborts the transaction and forgets any database handles that were created by it, since
+    /// LMDB closes those handles when the transaction that opened them doesn't commit.
+    pub(crate) fn abort(self) {
+        for name in &self.created {
+            self.dbs.remove(name.as_str());
+        }
+        self.txn.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Key, Record, Storage, StorageError};
+    use lmdb::{Transaction as _, WriteFlags};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Ledger {
+        id: u32,
+        balance: i64,
+    }
+
+    impl Record for Ledger {
+        type Key = Key<u32>;
+
+        fn key(&self) -> Key<u32> {
+            Key::from(self.id)
+        }
+
+        fn db_name() -> &'static str {
+            "Ledger"
+        }
+    }
+
+    fn failure() -> StorageError {
+        std::io::Error::other("rolled back").into()
+    }
+
+    #[test]
+    fn test_that_an_aborted_child_keeps_the_parents_work() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.truncate::<Ledger>().expect("Could not truncate");
+
+        storage
+            .transaction(|tx| {
+                tx.save(&Ledger { id: 1, balance: 10 })?;
+
+                let child = tx.nested(|child| {
+                    child.save(&Ledger { id: 2, balance: 20 })?;
+                    Err::<(), _>(failure())
+                });
+                assert!(child.is_err());
+
+                tx.nested(|child| child.save(&Ledger { id: 3, balance: 30 }))
+            })
+            .expect("Could not run transaction");
+
+        let one: Result<Option<Ledger>, StorageError> = storage.get(1);
+        assert_eq!(Ledger { id: 1, balance: 10 }, one.unwrap().unwrap());
+        assert!(storage.get::<Ledger, _>(2).unwrap().is_none());
+        let three: Result<Option<Ledger>, StorageError> = storage.get(3);
+        assert_eq!(Ledger { id: 3, balance: 30 }, three.unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_that_modify_reads_and_writes_in_one_transaction() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        let deposit = |ledger: Option<Ledger>| {
+            let mut ledger = ledger.unwrap_or(Ledger { id: 7, balance: 0 });
+            ledger.balance += 25;
+            Some(ledger)
+        };
+        storage.modify(7, deposit).expect("Could not modify");
+        let ledger = storage.modify(7, deposit).expect("Could not modify");
+        assert_eq!(Some(Ledger { id: 7, balance: 50 }), ledger);
+
+        let closed = storage
+            .modify::<Ledger, _, _>(7, |_| None)
+            .expect("Could not modify");
+        assert_eq!(None, closed);
+        assert_eq!(0, storage.query::<Ledger>().unwrap().count());
+    }
+
+    #[test]
+    fn test_that_a_failed_transaction_discards_everything() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage.truncate::<Ledger>().expect("Could not truncate");
+
+        let result = storage.transaction(|tx| {
+            tx.save(&Ledger { id: 4, balance: 40 })?;
+            assert!(tx.get::<Ledger, _>(4)?.is_some());
+            Err::<(), _>(failure())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(0, storage.query::<Ledger>().unwrap().count());
+    }
+
+    #[test]
+    fn test_that_a_record_is_only_swapped_while_it_is_unchanged() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        let opened = Ledger { id: 1, balance: 0 };
+        let credited = Ledger { id: 1, balance: 50 };
+
+        assert_eq!(
+            Ok(()),
+            storage.compare_and_swap(1, None, Some(&opened)).unwrap()
+        );
+        assert_eq!(
+            Err(Some(Ledger { id: 1, balance: 0 })),
+            storage.compare_and_swap(1, None, Some(&credited)).unwrap()
+        );
+        assert_eq!(
+            Ok(()),
+            storage
+                .compare_and_swap(1, Some(&opened), Some(&credited))
+                .unwrap()
+        );
+        assert_eq!(
+            Err(Some(Ledger { id: 1, balance: 50 })),
+            storage.compare_and_swap(1, Some(&opened), None).unwrap()
+        );
+        assert!(storage
+            .compare_and_swap(2, Some(&credited), Some(&credited))
+            .is_err());
+
+        assert_eq!(
+            Ok(()),
+            storage.compare_and_swap(1, Some(&credited), None).unwrap()
+        );
+        assert_eq!(
+            Err(None),
+            storage
+                .compare_and_swap(1, Some(&credited), Some(&opened))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_that_a_corrupt_record_is_reported_instead_of_missing() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Ledger { id: 1, balance: 10 })
+            .expect("Could not save ledger");
+
+        let db = storage.db("Ledger", Ledger::db_flags()).unwrap();
+        let mut txn = storage.env().unwrap().begin_rw_txn().unwrap();
+        let key: Vec<u8> = Key::from(1u32).into();
+        txn.put(db, &key, &[0xff], WriteFlags::empty()).unwrap();
+        txn.commit().unwrap();
+
+        let undecodable = |result: Result<_, StorageError>| {
+            matches!(result, Err(StorageError::Undecodable { db_name: "Ledger", .. }))
+        };
+        assert!(undecodable(storage.get::<Ledger, _>(1).map(|_| ())));
+        assert!(undecodable(
+            storage
+                .modify(1, |_| Some(Ledger { id: 1, balance: 0 }))
+                .map(|_| ())
+        ));
+        assert!(undecodable(
+            storage
+                .compare_and_swap(1, None, Some(&Ledger { id: 1, balance: 0 }))
+                .map(|_| ())
+        ));
+
+        let txn = storage.env().unwrap().begin_ro_txn().unwrap();
+        assert_eq!(&[0xff], txn.get(db, &key).unwrap());
+    }
+}
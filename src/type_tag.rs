@@ -0,0 +1,234 @@
+//! The type each record database holds.
+//!
+//! The first transaction that writes to a record type's database stores the type's
+//! `Record::type_tag` under the database's name.  Reads and writes through another type then fail
+//! with `StorageError::TypeMismatch` instead of decoding one type's values as another.
+//!
+//! The type's `Record::schema_version` is recorded along with it, so `Storage::register` can tell
+//! when the type's fields changed since its records were written.
+
+use lmdb::{Database, RwTransaction, Transaction, WriteFlags};
+
+use crate::{Record, StorageError};
+
+/// The database that maps each record database's name to the tag of the type stored in it
+pub(crate) const TYPES_DB: &str = "nostalgia#types";
+/// The database that maps each record database's name to the schema version of its records
+pub(crate) const SCHEMAS_DB: &str = "nostalgia#schemas";
+
+/// Fails when `T`'s database was tagged for another type.  Databases written before tags were
+/// kept, or in an environment without any tags, aren't checked
+pub(crate) fn check<T: Record>(
+    txn: &impl Transaction,
+    types: Option<Database>,
+) -> Result<(), StorageError> {
+    let types = match types {
+        Some(types) => types,
+        None => return Ok(()),
+    };
+
+    match txn.get(types, &T::db_name()) {
+        Ok(stored) if stored == T::type_tag().as_bytes() => Ok(()),
+        Ok(stored) => Err(StorageError::TypeMismatch {
+            db_name: T::db_name(),
+            stored: String::from_utf8_lossy(stored).into_owned(),
+            expected: T::type_tag(),
+        }),
+        Err(lmdb::Error::NotFound) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks `T`'s database like `check`, tagging it for `T` if it isn't tagged yet
+pub(crate) fn tag<T: Record>(txn: &mut RwTransaction, types: Database) -> Result<(), StorageError> {
+    check::<T>(txn, Some(types))?;
+    retag::<T>(txn, types)
+}
+
+/// Tags `T`'s database for `T`, replacing any other type's tag
+pub(crate) fn retag<T: Record>(
+    txn: &mut RwTransaction,
+    types: Database,
+) -> Result<(), StorageError> {
+    txn.put(types, &T::db_name(), &T::type_tag(), WriteFlags::empty())?;
+    Ok(())
+}
+
+/// Forgets what is kept about `db_name` in `db`, which is `TYPES_DB` or `SCHEMAS_DB`
+pub(crate) fn forget(
+    txn: &mut RwTransaction,
+    db: Database,
+    db_name: &str,
+) -> Result<(), StorageError> {
+    match txn.del(db, &db_name, None) {
+        Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The schema version `db_name`'s records were written with, if one was recorded
+pub(crate) fn schema_version(
+    txn: &impl Transaction,
+    schemas: Option<Database>,
+    db_name: &str,
+) -> Result<Option<u64>, StorageError> {
+    let schemas = match schemas {
+        Some(schemas) => schemas,
+        None => return Ok(None),
+    };
+
+    match txn.get(schemas, &db_name) {
+        Ok(bytes) if bytes.len() == 8 => {
+            let mut version = [0; 8];
+            version.copy_from_slice(bytes);
+            Ok(Some(u64::from_be_bytes(version)))
+        }
+        Ok(_) => Err(lmdb::Error::Corrupted.into()),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records that `T`'s database holds records of `T`'s current schema version
+pub(crate) fn set_schema_version<T: Record>(
+    txn: &mut RwTransaction,
+    schemas: Database,
+) -> Result<(), StorageError> {
+    txn.put(
+        schemas,
+        &T::db_name(),
+        &T::schema_version().to_be_bytes(),
+        WriteFlags::empty(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Storage};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[db_name = "sensors"]
+    struct Sensor {
+        id: u32,
+        celsius: f64,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[db_name = "sensors"]
+    struct Reading {
+        id: u32,
+        sensor_id: u32,
+    }
+
+    fn assert_mismatch<R: std::fmt::Debug>(result: Result<R, StorageError>) {
+        match result {
+            Err(StorageError::TypeMismatch {
+                db_name,
+                stored,
+                expected,
+            }) => {
+                assert_eq!("sensors", db_name);
+                assert_eq!("Sensor", stored);
+                assert_eq!("Reading", expected);
+            }
+            other => panic!("Expected a type mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_that_a_database_cant_be_read_or_written_as_another_type() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Sensor {
+                id: 1,
+                celsius: 21.5,
+            })
+            .unwrap();
+
+        assert_mismatch(storage.get::<Reading, _>(1));
+        assert_mismatch(storage.query::<Reading>().map(|query| query.count()));
+        assert_mismatch(storage.save(&Reading {
+            id: 2,
+            sensor_id: 1,
+        }));
+        assert_mismatch(storage.transaction(|tx| tx.get::<Reading, _>(1)));
+        let (reader, _) = storage.split().unwrap();
+        assert_mismatch(reader.get::<Reading, _>(1));
+
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        storage
+            .save(&Sensor {
+                id: 1,
+                celsius: 0.0,
+            })
+            .unwrap();
+        storage.truncate::<Reading>().unwrap();
+        storage
+            .save(&Reading {
+                id: 2,
+                sensor_id: 1,
+            })
+            .unwrap();
+        assert!(storage.get::<Sensor, _>(2).is_err());
+
+        storage.drop::<Reading>().unwrap();
+        storage
+            .save(&Sensor {
+                id: 1,
+                celsius: 0.0,
+            })
+            .unwrap();
+        assert_eq!(1, storage.count::<Sensor>().unwrap());
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[storable(key = "id", db_name = "gauges", type_tag = "Gauge")]
+    struct GaugeV1 {
+        id: u32,
+        value: f64,
+    }
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[storable(key = "id", db_name = "gauges", type_tag = "Gauge")]
+    struct GaugeV2 {
+        id: u32,
+        value: f64,
+        unit: String,
+    }
+
+    #[test]
+    fn test_that_a_changed_schema_is_noticed_on_register() {
+        assert_ne!(GaugeV1::schema_version(), GaugeV2::schema_version());
+
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+        assert_eq!(None, storage.stored_schema_version::<GaugeV1>().unwrap());
+        storage.save(&GaugeV1 { id: 1, value: 0.5 }).unwrap();
+        assert_eq!(
+            Some(GaugeV1::schema_version()),
+            storage.stored_schema_version::<GaugeV1>().unwrap()
+        );
+
+        match storage.register::<GaugeV2>() {
+            Err(StorageError::SchemaChanged {
+                db_name,
+                stored,
+                current,
+            }) => {
+                assert_eq!("gauges", db_name);
+                assert_eq!(GaugeV1::schema_version(), stored);
+                assert_eq!(GaugeV2::schema_version(), current);
+            }
+            _ => panic!("Expected the schema to have changed"),
+        }
+
+        storage.update_schema_version::<GaugeV2>().unwrap();
+        storage
+            .register::<GaugeV2>()
+            .expect("The schema was updated");
+    }
+}
@@ -0,0 +1,161 @@
+//! Disk usage figures read straight from LMDB.
+//!
+//! The lmdb crate only exposes statistics for the environment as a whole, so the per-database and
+//! free page numbers are read through lmdb-sys.
+
+use lmdb::{Cursor, Environment, Transaction};
+use lmdb_sys as ffi;
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::path::Path;
+
+use crate::StorageError;
+
+/// How much space a single named database takes up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseUsage {
+    pub name: String,
+    pub entries: usize,
+    pub depth: u32,
+    pub pages: usize,
+    pub bytes: u64,
+}
+
+/// How much space the databases in a storage directory take up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Usage of every named database, sorted by name
+    pub databases: Vec<DatabaseUsage>,
+    /// Bytes of the map that have been written to, including free pages
+    pub used_bytes: u64,
+    /// The size of the data file on disk
+    pub file_bytes: u64,
+    /// The maximum size the data file can grow to
+    pub map_size: u64,
+}
+
+impl DiskUsage {
+    /// Bytes taken up by records and indexes across every named database
+    pub fn total_bytes(&self) -> u64 {
+        self.databases.iter().map(|db| db.bytes).sum()
+    }
+}
+
+fn check(code: i32) -> Result<(), StorageError> {
+    match code {
+        0 => Ok(()),
+        code => Err(lmdb::Error::from_err_code(code).into()),
+    }
+}
+
+/// The names of every named database in the environment
+pub(crate) fn database_names(env: &Environment) -> Result<Vec<String>, StorageError> {
+    let main = env.open_db(None)?;
+    let txn = env.begin_ro_txn()?;
+    let mut cursor = txn.open_ro_cursor(main)?;
+
+    Ok(cursor
+        .iter()
+        .filter_map(|(name, _)| String::from_utf8(name.to_vec()).ok())
+        .collect())
+}
+
+/// The number of entries in a database as of the transaction's snapshot
+pub(crate) fn entries<T: Transaction>(txn: &T, db: lmdb::Database) -> Result<usize, StorageError> {
+    let stat = unsafe {
+        let mut stat = MaybeUninit::<ffi::MDB_stat>::uninit();
+        check(ffi::mdb_stat(txn.txn(), db.dbi(), stat.as_mut_ptr()))?;
+        stat.assume_init()
+    };
+    Ok(stat.ms_entries)
+}
+
+/// Usage of every named database in the environment, sorted by name
+pub(crate) fn database_usage(env: &Environment) -> Result<Vec<DatabaseUsage>, StorageError> {
+    let mut databases = vec![];
+    for name in database_names(env)? {
+        // Other tools can keep plain records in the unnamed database next to the names
+        let db = match env.open_db(Some(&name)) {
+            Ok(db) => db,
+            Err(lmdb::Error::Incompatible) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let txn = env.begin_ro_txn()?;
+        let stat = unsafe {
+            let mut stat = MaybeUninit::<ffi::MDB_stat>::uninit();
+            check(ffi::mdb_stat(txn.txn(), db.dbi(), stat.as_mut_ptr()))?;
+            stat.assume_init()
+        };
+
+        let pages = stat.ms_branch_pages + stat.ms_leaf_pages + stat.ms_overflow_pages;
+        databases.push(DatabaseUsage {
+            name,
+            entries: stat.ms_entries,
+            depth: stat.ms_depth,
+            pages,
+            bytes: pages as u64 * u64::from(stat.ms_psize),
+        });
+    }
+
+    Ok(databases)
+}
+
+/// Bytes of the map that have been written to and the most bytes it can grow to
+pub(crate) fn map_usage(env: &Environment) -> Result<(u64, u64), StorageError> {
+    let info = unsafe {
+        let mut info = MaybeUninit::<ffi::MDB_envinfo>::uninit();
+        check(ffi::mdb_env_info(env.env(), info.as_mut_ptr()))?;
+        info.assume_init()
+    };
+    let page_size = u64::from(env.stat()?.page_size());
+
+    Ok((
+        (info.me_last_pgno as u64 + 1) * page_size,
+        info.me_mapsize as u64,
+    ))
+}
+
+pub(crate) fn disk_usage(env: &Environment, path: &Path) -> Result<DiskUsage, StorageError> {
+    let databases = database_usage(env)?;
+    let (used_bytes, map_size) = map_usage(env)?;
+
+    Ok(DiskUsage {
+        databases,
+        used_bytes,
+        file_bytes: std::fs::metadata(path.join("data.mdb"))?.len(),
+        map_size,
+    })
+}
+
+/// Counts the pages on LMDB's free list, which is kept in database 0 as lists of page numbers
+/// whose first element is the length of the list
+pub(crate) fn free_pages(env: &Environment) -> Result<usize, StorageError> {
+    let txn = env.begin_ro_txn()?;
+    let mut pages = 0;
+
+    unsafe {
+        let mut cursor = std::ptr::null_mut();
+        check(ffi::mdb_cursor_open(txn.txn(), 0, &mut cursor))?;
+
+        let mut key = MaybeUninit::<ffi::MDB_val>::zeroed().assume_init();
+        let mut data = MaybeUninit::<ffi::MDB_val>::zeroed().assume_init();
+        let mut op = ffi::MDB_FIRST;
+        let result = loop {
+            match ffi::mdb_cursor_get(cursor, &mut key, &mut data, op) {
+                0 => pages += std::ptr::read_unaligned(data.mv_data as *const usize),
+                ffi::MDB_NOTFOUND => break Ok(pages),
+                code => break check(code).map(|_| pages),
+            }
+            op = ffi::MDB_NEXT;
+        };
+
+        ffi::mdb_cursor_close(cursor);
+        result
+    }
+}
+
+/// Writes a compacted copy of the environment into `dir`, leaving out free pages
+pub(crate) fn copy_compacted(env: &Environment, dir: &Path) -> Result<(), StorageError> {
+    let dir = CString::new(dir.to_string_lossy().as_bytes()).map_err(std::io::Error::other)?;
+    check(unsafe { ffi::mdb_env_copy2(env.env(), dir.as_ptr(), ffi::MDB_CP_COMPACT) })
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// A rule a single field of a record broke
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new<F: Into<String>, M: Into<String>>(field: F, message: M) -> FieldError {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record, Storage, StorageError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    #[storable(validate_with = "check_borough")]
+    struct Landmark {
+        id: u32,
+        #[validate(length(min = 1, max = 40))]
+        name: String,
+        #[validate(range(min = 1898, max = 2100))]
+        designated: u16,
+        borough: String,
+    }
+
+    fn check_borough(landmark: &Landmark) -> Result<(), Vec<FieldError>> {
+        match landmark.borough.as_str() {
+            "Manhattan" | "Brooklyn" | "Queens" | "Bronx" | "Staten Island" => Ok(()),
+            _ => Err(vec![FieldError::new("borough", "is not a borough")]),
+        }
+    }
+
+    fn landmark(id: u32, name: &str, designated: u16, borough: &str) -> Landmark {
+        Landmark {
+            id,
+            name: name.to_string(),
+            designated,
+            borough: borough.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_that_invalid_records_are_never_saved() {
+        let mut storage = Storage::temporary().expect("Could not open db storage");
+
+        storage
+            .save(&landmark(1, "Grand Central", 1967, "Manhattan"))
+            .expect("Could not save landmark");
+
+        match storage.save(&landmark(2, "", 1700, "Hoboken")) {
+            Err(StorageError::Validation(errors)) => assert_eq!(
+                vec![
+                    FieldError::new("borough", "is not a borough"),
+                    FieldError::new("name", "must be at least 1 long"),
+                    FieldError::new("designated", "must be at least 1898"),
+                ],
+                errors
+            ),
+            _ => panic!("Expected the save to fail validation"),
+        }
+        assert!(storage.get::<Landmark, _>(2).unwrap().is_none());
+
+        let mut batch = storage.batch();
+        batch.save(&landmark(3, "Flatiron Building", 1966, "Manhattan"));
+        batch.save(&landmark(4, "Coney Island Cyclone", 2200, "Brooklyn"));
+        match batch.commit() {
+            Err(StorageError::Validation(errors)) => assert_eq!(1, errors.len()),
+            _ => panic!("Expected the batch to fail validation"),
+        }
+        assert_eq!(1, storage.query::<Landmark>().unwrap().count());
+    }
+}
@@ -0,0 +1,131 @@
+//! Glue for backing a web service with a storage.
+//!
+//! `SharedStorage` wraps a `StorageHandle` so axum handlers can take it as an argument directly.
+//! Like the handle it is `Send + Sync + Clone`, so it also works as actix-web's `web::Data` or as
+//! axum state.  Only built with the `web` feature.
+
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::request::Parts;
+use std::convert::Infallible;
+use std::ops::Deref;
+
+use crate::{RoStorage, Storage, StorageError, StorageHandle};
+
+/// A `StorageHandle` that request handlers can take as an argument
+///
+/// # Examples
+/// ```
+/// #[macro_use]
+/// extern crate nostalgia_derive;
+/// use axum::{extract::Path, routing::get, Router};
+/// use nostalgia::{web::SharedStorage, Storage, Record, Key, StorageError};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Storable, Serialize, Deserialize)]
+/// #[key = "id"]
+/// struct Place {
+///   id: u32,
+///   name: std::string::String
+/// }
+///
+/// async fn place_name(storage: SharedStorage, Path(id): Path<u32>) -> String {
+///     match storage.reader().get::<Place, _>(id) {
+///         Ok(Some(place)) => place.name,
+///         _ => String::new(),
+///     }
+/// }
+///
+/// fn main() -> Result<(), StorageError> {
+///     let storage = SharedStorage::new(Storage::temporary()?)?;
+///     storage.write(|storage| storage.save(&Place { id: 1, name: "Vienna".to_string() }))?;
+///
+///     let app: Router = Router::new()
+///         .route("/places/{id}", get(place_name))
+///         .with_state(storage);
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct SharedStorage(StorageHandle);
+
+impl SharedStorage {
+    /// Splits `storage` into the handles that are shared, see `StorageHandle::new`
+    pub fn new(storage: Storage) -> Result<SharedStorage, StorageError> {
+        Ok(SharedStorage(StorageHandle::new(storage)?))
+    }
+
+    /// The handle reads go through
+    pub fn reader(&self) -> &RoStorage {
+        &self.0
+    }
+}
+
+impl From<StorageHandle> for SharedStorage {
+    fn from(handle: StorageHandle) -> SharedStorage {
+        SharedStorage(handle)
+    }
+}
+
+impl Deref for SharedStorage {
+    type Target = StorageHandle;
+
+    fn deref(&self) -> &StorageHandle {
+        &self.0
+    }
+}
+
+impl<S> FromRequestParts<S> for SharedStorage
+where
+    SharedStorage: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(_parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(SharedStorage::from_ref(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Key, Record};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+    #[key = "id"]
+    struct Hit {
+        id: u32,
+        path: String,
+    }
+
+    #[test]
+    fn test_that_handlers_on_other_threads_can_read_and_write() {
+        let storage = Storage::temporary().expect("Could not open db storage");
+        let shared = SharedStorage::new(storage).expect("Could not share storage");
+        shared
+            .write(|storage| storage.truncate::<Hit>())
+            .expect("Could not truncate");
+
+        let handlers: Vec<_> = (0..8)
+            .map(|id| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    shared.write(|storage| {
+                        storage.save(&Hit {
+                            id,
+                            path: format!("/{}", id),
+                        })
+                    })?;
+                    shared.reader().get::<Hit, _>(id)
+                })
+            })
+            .collect();
+        for handler in handlers {
+            assert!(handler.join().unwrap().unwrap().is_some());
+        }
+
+        assert_eq!(8, shared.reader().query::<Hit>().unwrap().count());
+    }
+}
@@ -0,0 +1,73 @@
+//! The IndexedDB storage, run in a browser with `wasm-pack test --headless --firefox -- --features
+//! wasm --test indexed_db`
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+#[macro_use]
+extern crate nostalgia_derive;
+
+use nostalgia::{IndexedDbStorage, Key, Record, StorageApi};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+#[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+#[key = "id"]
+struct Place {
+    id: u32,
+    name: String,
+}
+
+#[derive(Storable, Serialize, Deserialize, Debug, PartialEq)]
+#[key = "id"]
+struct Visit {
+    id: u32,
+    place_id: u32,
+}
+
+fn place(id: u32, name: &str) -> Place {
+    Place {
+        id,
+        name: name.to_string(),
+    }
+}
+
+#[wasm_bindgen_test]
+async fn test_that_flushed_writes_are_there_when_reopened() {
+    let mut storage = IndexedDbStorage::open("flushed").await.unwrap();
+    storage.truncate::<Place>().unwrap();
+    storage
+        .save_batch(vec![place(1, "Vienna"), place(2, "Graz")])
+        .unwrap();
+    storage.save(&place(3, "Linz")).unwrap();
+    storage.save(&place(2, "Salzburg")).unwrap();
+    assert!(storage.delete(&place(1, "Vienna")).unwrap());
+    assert!(!storage.delete(&place(4, "Bregenz")).unwrap());
+    storage.flush().await.unwrap();
+
+    let mut reopened = IndexedDbStorage::open("flushed").await.unwrap();
+    assert_eq!(
+        vec![place(2, "Salzburg"), place(3, "Linz")],
+        reopened.all::<Place>().unwrap()
+    );
+    assert_eq!(Some(place(3, "Linz")), reopened.get::<Place, _>(3).unwrap());
+    assert_eq!(None, reopened.get::<Place, _>(1).unwrap());
+}
+
+#[wasm_bindgen_test]
+async fn test_that_truncating_a_type_leaves_the_others() {
+    let mut storage = IndexedDbStorage::open("truncated").await.unwrap();
+    storage.truncate::<Place>().unwrap();
+    storage.truncate::<Visit>().unwrap();
+    storage.save(&place(1, "Vienna")).unwrap();
+    storage.save(&Visit { id: 1, place_id: 1 }).unwrap();
+    storage.truncate::<Place>().unwrap();
+    storage.flush().await.unwrap();
+
+    let mut reopened = IndexedDbStorage::open("truncated").await.unwrap();
+    assert_eq!(0, reopened.count::<Place>().unwrap());
+    assert_eq!(
+        vec![Visit { id: 1, place_id: 1 }],
+        reopened.all::<Visit>().unwrap()
+    );
+}